@@ -3,9 +3,22 @@ use mdns::Error;
 use std::time::Duration;
 
 const SERVICE_NAME: &str = "_googlecast._tcp.local";
+#[cfg(any(feature = "runtime-async-std", feature = "runtime-tokio"))]
 #[cfg_attr(feature = "runtime-async-std", async_std::main)]
-#[cfg_attr(feature = "runtime-tokio", tokio::main)]
+#[cfg_attr(
+    all(feature = "runtime-tokio", not(feature = "runtime-async-std")),
+    tokio::main
+)]
 async fn main() -> Result<(), Error> {
+    run().await
+}
+
+#[cfg(not(any(feature = "runtime-async-std", feature = "runtime-tokio")))]
+fn main() -> Result<(), Error> {
+    smol::block_on(run())
+}
+
+async fn run() -> Result<(), Error> {
     let stream = mdns::discover::all(SERVICE_NAME, Duration::from_secs(15))?.listen();
     pin_mut!(stream);
     while let Some(Ok(response)) = stream.next().await {
@@ -0,0 +1,39 @@
+//! Answers DNS queries for `.local` names on 127.0.0.1:5300, or the address
+//! given as the first argument.
+//!
+//! Try it with `dig -p 5300 @127.0.0.1 _http._tcp.local PTR`.
+
+use mdns::Error;
+use std::net::SocketAddr;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:5300";
+
+#[cfg(any(feature = "runtime-async-std", feature = "runtime-tokio"))]
+#[cfg_attr(feature = "runtime-async-std", async_std::main)]
+#[cfg_attr(
+    all(feature = "runtime-tokio", not(feature = "runtime-async-std")),
+    tokio::main
+)]
+async fn main() -> Result<(), Error> {
+    run().await
+}
+
+#[cfg(not(any(feature = "runtime-async-std", feature = "runtime-tokio")))]
+fn main() -> Result<(), Error> {
+    smol::block_on(run())
+}
+
+async fn run() -> Result<(), Error> {
+    let addr: SocketAddr = std::env::args()
+        .nth(1)
+        .as_deref()
+        .unwrap_or(DEFAULT_ADDR)
+        .parse()
+        .expect("invalid address");
+
+    let _gateway = mdns::gateway::bind(addr)?;
+    println!("answering for .local on {}", addr);
+
+    futures_util::future::pending::<()>().await;
+    Ok(())
+}
@@ -0,0 +1,40 @@
+use futures_util::{pin_mut, stream::StreamExt};
+use mdns::register::{Event, Host, Service};
+use mdns::Error;
+use std::time::Duration;
+
+const SERVICE_TYPE: &str = "_http._tcp.local";
+const HOST_NAME: &str = "example.local";
+#[cfg(any(feature = "runtime-async-std", feature = "runtime-tokio"))]
+#[cfg_attr(feature = "runtime-async-std", async_std::main)]
+#[cfg_attr(
+    all(feature = "runtime-tokio", not(feature = "runtime-async-std")),
+    tokio::main
+)]
+async fn main() -> Result<(), Error> {
+    run().await
+}
+
+#[cfg(not(any(feature = "runtime-async-std", feature = "runtime-tokio")))]
+fn main() -> Result<(), Error> {
+    smol::block_on(run())
+}
+
+async fn run() -> Result<(), Error> {
+    let responder = mdns::register::all()?;
+    let host = responder.register_host(Host::new(HOST_NAME));
+    host.follow_interfaces(Duration::from_secs(30));
+    let _registration = responder.register(Service::new("Example", SERVICE_TYPE, HOST_NAME, 8080));
+
+    let events = responder.events();
+    pin_mut!(events);
+    while let Some(event) = events.next().await {
+        match event {
+            Event::Registered { name, .. } => println!("advertising {}", name),
+            Event::NameConflict {
+                name, renamed_to, ..
+            } => println!("{} is taken, trying {:?}", name, renamed_to),
+        }
+    }
+    Ok(())
+}
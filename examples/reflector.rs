@@ -0,0 +1,41 @@
+//! Reflects mDNS traffic between the interfaces given as arguments, each
+//! written as `name=address/prefix`, e.g.
+//! `cargo run --example reflector iot=10.0.20.1/24 staff=10.0.10.1/24`.
+
+use mdns::proto::reflector::{Interface, Reflector};
+use mdns::Error;
+use std::net::Ipv4Addr;
+
+#[cfg(any(feature = "runtime-async-std", feature = "runtime-tokio"))]
+#[cfg_attr(feature = "runtime-async-std", async_std::main)]
+#[cfg_attr(
+    all(feature = "runtime-tokio", not(feature = "runtime-async-std")),
+    tokio::main
+)]
+async fn main() -> Result<(), Error> {
+    run().await
+}
+
+#[cfg(not(any(feature = "runtime-async-std", feature = "runtime-tokio")))]
+fn main() -> Result<(), Error> {
+    smol::block_on(run())
+}
+
+async fn run() -> Result<(), Error> {
+    let mut reflector = Reflector::new();
+    for arg in std::env::args().skip(1) {
+        reflector =
+            reflector.interface(parse_interface(&arg).expect("expected name=address/prefix"));
+    }
+
+    let _reflection = mdns::reflect::interfaces(reflector)?;
+    futures_util::future::pending::<()>().await;
+    Ok(())
+}
+
+fn parse_interface(arg: &str) -> Option<Interface> {
+    let (name, subnet) = arg.split_once('=')?;
+    let (addr, prefix_len) = subnet.split_once('/')?;
+    let addr: Ipv4Addr = addr.parse().ok()?;
+    Some(Interface::new(name, addr, prefix_len.parse().ok()?))
+}
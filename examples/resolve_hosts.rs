@@ -4,16 +4,32 @@ use std::time::Duration;
 const SERVICE_NAME: &str = "_http._tcp.local";
 const HOSTS: [&str; 2] = ["server1._http._tcp.local", "server2._http._tcp.local"];
 
+#[cfg(any(feature = "runtime-async-std", feature = "runtime-tokio"))]
 #[cfg_attr(feature = "runtime-async-std", async_std::main)]
-#[cfg_attr(feature = "runtime-tokio", tokio::main)]
+#[cfg_attr(
+    all(feature = "runtime-tokio", not(feature = "runtime-async-std")),
+    tokio::main
+)]
 async fn main() -> Result<(), Error> {
-    let responses = mdns::resolve::multiple(SERVICE_NAME, &HOSTS, Duration::from_secs(15)).await?;
+    run().await
+}
+
+#[cfg(not(any(feature = "runtime-async-std", feature = "runtime-tokio")))]
+fn main() -> Result<(), Error> {
+    smol::block_on(run())
+}
 
-    for response in responses {
-        if let (Some(host), Some(ip)) = (response.hostname(), response.ip_addr()) {
+async fn run() -> Result<(), Error> {
+    let report = mdns::resolve::multiple(SERVICE_NAME, &HOSTS, Duration::from_secs(15)).await?;
+
+    for (host, response) in report.resolved {
+        if let Some(ip) = response.ip_addr() {
             println!("found host {} at {}", host, ip)
         }
     }
+    for host in report.missing {
+        println!("host {} not found", host);
+    }
 
     Ok(())
 }
@@ -0,0 +1,306 @@
+//! A command-line tool for browsing and querying mDNS, in the spirit of
+//! `avahi-browse` and `dns-sd`.
+//!
+//! Built with the `cli` cargo feature.
+
+use clap::{Parser, Subcommand, ValueEnum};
+use futures_util::{pin_mut, StreamExt};
+use mdns::{Error, Record, RecordKind, Response};
+use std::collections::HashSet;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+/// The name every DNS-SD responder answers with the service types it offers.
+const SERVICE_TYPES: &str = "_services._dns-sd._udp.local";
+
+#[derive(Debug, Parser)]
+#[command(name = "mdns", version, about = "Browse and query multicast DNS")]
+struct Options {
+    /// The address of the interface to query on.
+    #[arg(short, long, global = true, default_value = "0.0.0.0")]
+    interface: Ipv4Addr,
+
+    /// Stop after this many seconds.
+    ///
+    /// `browse` and `types` run until interrupted unless given, the other
+    /// commands default to 5 seconds.
+    #[arg(short, long, global = true, value_parser = parse_timeout)]
+    timeout: Option<Duration>,
+
+    /// Seconds between repeated queries, at least 1.
+    #[arg(long, global = true, default_value = "5", value_parser = parse_interval)]
+    interval: Duration,
+
+    /// How to print what is found.
+    #[arg(short, long, global = true, value_enum, default_value = "table")]
+    format: Format,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Lists the instances of a service type, e.g. `_http._tcp`.
+    Browse { service_type: String },
+    /// Resolves a service instance, e.g. `Kitchen._googlecast._tcp.local`.
+    Resolve { instance: String },
+    /// Looks up the addresses of a host, e.g. `printer.local`.
+    Lookup { host: String },
+    /// Asks for records of any type, e.g. `printer.local AAAA`.
+    Query { name: String, record_type: String },
+    /// Lists the service types advertised on the network.
+    Types,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// One aligned row per record.
+    Table,
+    /// One JSON object per response.
+    Json,
+}
+
+/// What a command asks for, and when it is done.
+struct Lookup {
+    name: String,
+    query_type: dns_parser::QueryType,
+    /// Whether to stop at the first answer.
+    first_only: bool,
+    default_timeout: Option<Duration>,
+}
+
+#[async_std::main]
+async fn main() {
+    let options = Options::parse();
+
+    let code = match run(options).await {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            eprintln!("mdns: {}", e);
+            2
+        }
+    };
+    std::process::exit(code);
+}
+
+/// Runs a command, returning whether anything was found.
+async fn run(options: Options) -> Result<bool, Error> {
+    let lookup = match options.command {
+        Command::Browse { ref service_type } => Lookup {
+            name: qualify(service_type),
+            query_type: dns_parser::QueryType::PTR,
+            first_only: false,
+            default_timeout: None,
+        },
+        Command::Resolve { ref instance } => Lookup {
+            name: qualify(instance),
+            query_type: dns_parser::QueryType::All,
+            first_only: true,
+            default_timeout: Some(Duration::from_secs(5)),
+        },
+        Command::Lookup { ref host } => Lookup {
+            name: qualify(host),
+            query_type: dns_parser::QueryType::All,
+            first_only: true,
+            default_timeout: Some(Duration::from_secs(5)),
+        },
+        Command::Query {
+            ref name,
+            ref record_type,
+        } => Lookup {
+            name: qualify(name),
+            query_type: parse_query_type(record_type)?,
+            first_only: false,
+            default_timeout: Some(Duration::from_secs(5)),
+        },
+        Command::Types => Lookup {
+            name: SERVICE_TYPES.to_owned(),
+            query_type: dns_parser::QueryType::PTR,
+            first_only: false,
+            default_timeout: None,
+        },
+    };
+
+    let timeout = options.timeout.or(lookup.default_timeout);
+
+    let stream = mdns::discover::interface(&lookup.name, options.interval, options.interface)?
+        .query_type(lookup.query_type)
+        .listen();
+    pin_mut!(stream);
+
+    let mut printer = Printer::new(options.format);
+    let process = async {
+        while let Some(response) = stream.next().await {
+            printer.print(&response?);
+            if lookup.first_only {
+                break;
+            }
+        }
+        Ok::<(), Error>(())
+    };
+
+    match timeout {
+        Some(timeout) => {
+            let runtime = mdns::runtime::default();
+            if let Ok(result) = mdns::runtime::timeout(&*runtime, timeout, process).await {
+                result?;
+            }
+        }
+        None => process.await?,
+    }
+
+    Ok(printer.found)
+}
+
+/// Appends the `.local` domain to a name that doesn't have it.
+fn qualify(name: &str) -> String {
+    let name = name.trim_end_matches('.');
+    if name.ends_with(".local") {
+        name.to_owned()
+    } else {
+        format!("{}.local", name)
+    }
+}
+
+/// The longest `--timeout` or `--interval` accepted, a little over a year.
+const MAX_SECONDS: f64 = (1u64 << 25) as f64;
+
+fn parse_timeout(value: &str) -> Result<Duration, String> {
+    parse_seconds(value, 0.0)
+}
+
+fn parse_interval(value: &str) -> Result<Duration, String> {
+    parse_seconds(value, 1.0)
+}
+
+/// Parses a number of seconds, which must be at least `min` and finite.
+fn parse_seconds(value: &str, min: f64) -> Result<Duration, String> {
+    let seconds: f64 = value
+        .parse()
+        .map_err(|_| format!("`{}` isn't a number of seconds", value))?;
+    if !(min..=MAX_SECONDS).contains(&seconds) {
+        return Err(format!(
+            "must be between {} and {} seconds",
+            min, MAX_SECONDS
+        ));
+    }
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+fn parse_query_type(name: &str) -> Result<dns_parser::QueryType, Error> {
+    use dns_parser::QueryType::*;
+
+    let query_type = match name.to_ascii_uppercase().as_str() {
+        "A" => A,
+        "AAAA" => AAAA,
+        "CNAME" => CNAME,
+        "HINFO" => HINFO,
+        "MX" => MX,
+        "NS" => NS,
+        "PTR" => PTR,
+        "SRV" => SRV,
+        "TXT" => TXT,
+        "ANY" | "*" => All,
+        other => match other
+            .parse::<u16>()
+            .ok()
+            .and_then(|code| dns_parser::QueryType::parse(code).ok())
+        {
+            Some(query_type) => query_type,
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("unknown record type {}", name),
+                )
+                .into())
+            }
+        },
+    };
+
+    Ok(query_type)
+}
+
+struct Printer {
+    format: Format,
+    /// Rows already printed, so repeated announcements don't flood the table.
+    seen: HashSet<String>,
+    found: bool,
+}
+
+impl Printer {
+    fn new(format: Format) -> Self {
+        Printer {
+            format,
+            seen: HashSet::new(),
+            found: false,
+        }
+    }
+
+    fn print(&mut self, response: &Response) {
+        if !self.found && self.format == Format::Table {
+            println!("{:<48} {:<6} {:>6}  DATA", "NAME", "TYPE", "TTL");
+        }
+        self.found = true;
+
+        match self.format {
+            Format::Json => match serde_json::to_string(response) {
+                Ok(json) => println!("{}", json),
+                Err(e) => eprintln!("mdns: cannot serialize response: {}", e),
+            },
+            Format::Table => {
+                for record in response.answers.iter().chain(response.additional.iter()) {
+                    let (record_type, data) = describe(record);
+                    let row = format!(
+                        "{:<48} {:<6} {:>6}  {}",
+                        record.name, record_type, record.ttl, data
+                    );
+                    if self
+                        .seen
+                        .insert(format!("{} {} {}", record.name, record_type, data))
+                    {
+                        println!("{}", row);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The type of a record, and its data in presentation format.
+fn describe(record: &Record) -> (&'static str, String) {
+    match record.kind {
+        RecordKind::A(addr) => ("A", addr.to_string()),
+        RecordKind::AAAA(addr) => ("AAAA", addr.to_string()),
+        RecordKind::CNAME(ref name) => ("CNAME", name.clone()),
+        RecordKind::MX {
+            preference,
+            ref exchange,
+        } => ("MX", format!("{} {}", preference, exchange)),
+        RecordKind::NS(ref name) => ("NS", name.clone()),
+        RecordKind::SRV {
+            priority,
+            weight,
+            port,
+            ref target,
+        } => (
+            "SRV",
+            format!("{} {} {} {}", priority, weight, port, target),
+        ),
+        RecordKind::TXT(ref entries) => {
+            let mut entries: Vec<String> = entries
+                .iter()
+                .map(|(key, value)| match value {
+                    mdns::TxtRecordValue::None => key.to_string(),
+                    mdns::TxtRecordValue::Empty => format!("{}=", key),
+                    mdns::TxtRecordValue::Value(value) => format!("{}={}", key, value),
+                })
+                .collect();
+            entries.sort();
+            ("TXT", entries.join(" "))
+        }
+        RecordKind::PTR(ref name) => ("PTR", name.clone()),
+        RecordKind::Unimplemented(ref data) => ("?", format!("{} bytes", data.len())),
+    }
+}
@@ -9,8 +9,9 @@
 //!
 //! const SERVICE_NAME: &'static str = "_googlecast._tcp.local";
 //!
+//! # #[cfg(any(feature = "runtime-async-std", feature = "runtime-tokio"))]
 //! #[cfg_attr(feature = "runtime-async-std", async_std::main)]
-//! #[cfg_attr(feature = "runtime-tokio", tokio::main)]
+//! #[cfg_attr(all(feature = "runtime-tokio", not(feature = "runtime-async-std")), tokio::main)]
 //! async fn main() -> Result<(), Error> {
 //!     let stream = mdns::discover::all(SERVICE_NAME, Duration::from_secs(15))?.listen();
 //!     pin_mut!(stream);
@@ -21,33 +22,47 @@
 //!
 //!     Ok(())
 //! }
+//! # #[cfg(not(any(feature = "runtime-async-std", feature = "runtime-tokio")))]
+//! # fn main() {}
 //! ```
 
-use crate::{mDNSListener, Error, Response};
+use crate::{mDNSListener, trace, Error, Response};
+
+pub use crate::proto::filter::{Filter, TxtPredicate};
+pub use crate::proto::Event;
 
 use std::time::Duration;
 
-use crate::mdns::{mDNSSender, mdns_interface};
+use crate::mdns::{mDNSSender, mdns_interface, mdns_transport};
+use crate::metrics::Metrics;
+use crate::proto::Querier;
+use crate::runtime::{self, Runtime};
+use crate::socket::SocketConfig;
+use crate::transport::Transport;
+use async_stream::try_stream;
 use futures_core::Stream;
-use futures_util::{future::ready, stream::select, StreamExt};
-use std::net::Ipv4Addr;
+use futures_util::future::ready;
+use futures_util::StreamExt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
 
 /// A multicast DNS discovery request.
 ///
 /// This represents a single lookup of a single service name.
 ///
 /// This object can be iterated over to yield the received mDNS responses.
+///
+/// All of the protocol logic lives in [`Querier`](crate::proto::Querier);
+/// this type only connects it to a socket and a clock.
 pub struct Discovery {
-    service_name: String,
+    querier: Querier,
+    runtime: Arc<dyn Runtime>,
+    /// The address of the interface we are bound to, if known.
+    interface_addr: IpAddr,
+    metrics: Option<Metrics>,
 
     mdns_sender: mDNSSender,
     mdns_listener: mDNSListener,
-
-    /// Whether we should ignore empty responses.
-    ignore_empty: bool,
-
-    /// The interval we should send mDNS queries.
-    send_request_interval: Duration,
 }
 
 /// Gets an iterator over all responses for a given service on all interfaces.
@@ -67,67 +82,206 @@ pub fn interface<S>(
 where
     S: AsRef<str>,
 {
-    let service_name = service_name.as_ref().to_string();
-    let (mdns_listener, mdns_sender) = mdns_interface(service_name.clone(), interface_addr)?;
+    interface_with_runtime(
+        runtime::default(),
+        service_name,
+        mdns_query_interval,
+        interface_addr,
+    )
+}
+
+/// Gets an iterator over all responses for a given service on a given interface,
+/// driven by the given runtime.
+pub fn interface_with_runtime<S>(
+    runtime: Arc<dyn Runtime>,
+    service_name: S,
+    mdns_query_interval: Duration,
+    interface_addr: Ipv4Addr,
+) -> Result<Discovery, Error>
+where
+    S: AsRef<str>,
+{
+    let config = SocketConfig::new().interface(interface_addr);
+
+    with_config(runtime, &config, service_name, mdns_query_interval)
+}
+
+/// Gets an iterator over all responses for a given service, on a socket set
+/// up as `config` describes, driven by the given runtime.
+pub fn with_config<S>(
+    runtime: Arc<dyn Runtime>,
+    config: &SocketConfig,
+    service_name: S,
+    mdns_query_interval: Duration,
+) -> Result<Discovery, Error>
+where
+    S: AsRef<str>,
+{
+    let (mdns_listener, mdns_sender) = mdns_interface(&runtime, config)?;
 
     Ok(Discovery {
-        service_name,
+        querier: Querier::new(service_name, mdns_query_interval),
+        runtime,
+        interface_addr: config.interface_addr().into(),
+        metrics: None,
         mdns_sender,
         mdns_listener,
-        ignore_empty: true,
-        send_request_interval: mdns_query_interval,
     })
 }
 
+/// Gets an iterator over all responses for a given service received on the
+/// given transport, driven by the given runtime.
+///
+/// This is mostly useful with the simulated network in
+/// [`transport::sim`](crate::transport::sim).
+pub fn with_transport<S>(
+    runtime: Arc<dyn Runtime>,
+    transport: Arc<dyn Transport>,
+    service_name: S,
+    mdns_query_interval: Duration,
+) -> Discovery
+where
+    S: AsRef<str>,
+{
+    let (mdns_listener, mdns_sender) = mdns_transport(transport);
+
+    Discovery {
+        querier: Querier::new(service_name, mdns_query_interval),
+        runtime,
+        interface_addr: Ipv4Addr::UNSPECIFIED.into(),
+        metrics: None,
+        mdns_sender,
+        mdns_listener,
+    }
+}
+
 impl Discovery {
     /// Sets whether or not we should ignore empty responses.
     ///
     /// Defaults to `true`.
     pub fn ignore_empty(mut self, ignore: bool) -> Self {
-        self.ignore_empty = ignore;
+        self.querier = self.querier.ignore_empty(ignore);
+        self
+    }
+
+    /// Sets the type of records to ask for.
+    ///
+    /// Defaults to `PTR`, which lists the instances of a service.
+    pub fn query_type(mut self, query_type: dns_parser::QueryType) -> Self {
+        self.querier = self.querier.query_type(query_type);
         self
     }
 
+    /// Only yields responses that match `filter`, on top of answering the
+    /// question. Every filter given must match.
+    ///
+    /// ```rust,no_run
+    /// use mdns::discover::{Filter, TxtPredicate};
+    /// use std::time::Duration;
+    ///
+    /// # fn main() -> Result<(), mdns::Error> {
+    /// let ultras = mdns::discover::all("_googlecast._tcp.local", Duration::from_secs(15))?
+    ///     .filter(Filter::new().txt("md", TxtPredicate::Equals("Chromecast Ultra".into())));
+    /// # drop(ultras);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.querier = self.querier.filter(filter);
+        self
+    }
+
+    /// Counts the traffic and responses of this discovery into `metrics`.
+    pub fn metrics(mut self, metrics: &Metrics) -> Self {
+        let tap = metrics.tap(self.interface_addr);
+        self.mdns_sender.metrics = Some(tap.clone());
+        self.mdns_listener.metrics = Some(tap);
+        self.metrics = Some(metrics.clone());
+        self
+    }
+
+    /// Records every datagram sent and received by this discovery.
+    ///
+    /// Each discovery shows up as its own interface in the capture.
+    #[cfg(feature = "pcap")]
+    pub fn record(self, recorder: &crate::pcap::Recorder) -> Result<Self, Error> {
+        self.mdns_sender.tap.attach(recorder)?;
+        Ok(self)
+    }
+
+    /// Sends the first query straight away, and then again every query interval,
+    /// yielding each response that answers it.
+    ///
+    /// Datagrams that can't be parsed are logged and skipped.
     pub fn listen(self) -> impl Stream<Item = Result<Response, Error>> {
-        let ignore_empty = self.ignore_empty;
-        let service_name = self.service_name;
-        let response_stream = self.mdns_listener.listen();
-        let sender = self.mdns_sender.clone();
-
-        let response_stream = response_stream.map(StreamResult::Response);
-        let interval_stream = crate::runtime::create_interval_stream(self.send_request_interval)
-            .map(move |_| {
-                let mut sender = sender.clone();
-                crate::runtime::spawn(async move {
-                    let _ = sender.send_request().await;
-                });
-                StreamResult::Interval
-            });
-
-        let stream = select(response_stream, interval_stream);
-        stream
-            .filter_map(|stream_result| async {
-                match stream_result {
-                    StreamResult::Interval => None,
-                    StreamResult::Response(res) => Some(res),
+        self.events().filter_map(|event| {
+            ready(match event {
+                Ok(Event::Response(response)) => Some(Ok(response)),
+                Ok(Event::Malformed { source, packet }) => {
+                    log::warn!("malformed packet from {}: {}", source, packet);
+                    None
                 }
+                Err(e) => Some(Err(e)),
             })
-            .filter(move |res| {
-                ready(match res {
-                    Ok(response) => {
-                        (!response.is_empty() || !ignore_empty)
-                            && response
-                                .answers
-                                .iter()
-                                .any(|record| record.name == service_name)
-                    }
-                    Err(_) => true,
-                })
-            })
+        })
     }
-}
 
-pub enum StreamResult {
-    Interval,
-    Response(Result<crate::Response, crate::Error>),
+    /// Like [`listen`](Self::listen), but also yields an
+    /// [`Event::Malformed`] for each datagram that couldn't be parsed.
+    ///
+    /// Errors receiving from the socket are retried, and a broken socket is
+    /// replaced, so the stream only ends if that is impossible, e.g. on a
+    /// transport given to [`with_transport`].
+    pub fn events(self) -> impl Stream<Item = Result<Event, Error>> {
+        let Discovery {
+            mut querier,
+            runtime,
+            interface_addr,
+            metrics,
+            mdns_sender,
+            mut mdns_listener,
+        } = self;
+        let span = trace::discovery(querier.service_name(), interface_addr);
+
+        let events = try_stream! {
+            loop {
+                while let Some(transmit) = querier.poll_transmit() {
+                    if let Err(e) = mdns_sender.send(&transmit).await {
+                        log::warn!("query not sent: {}", e);
+                    }
+                }
+
+                while let Some(event) = querier.poll_event() {
+                    if let Some(ref metrics) = metrics {
+                        match event {
+                            Event::Response(_) => metrics.matched(),
+                            Event::Malformed { .. } => metrics.malformed(),
+                        }
+                    }
+                    yield event;
+                }
+
+                let received = match querier.poll_timeout() {
+                    Some(deadline) => {
+                        let wait = deadline.saturating_duration_since(runtime.now());
+                        runtime::timeout(&*runtime, wait, mdns_listener.recv())
+                            .await
+                            .ok()
+                    }
+                    None => None,
+                };
+
+                match received {
+                    Some(result) => {
+                        let (count, source) = result?;
+                        let datagram = &mdns_listener.recv_buffer[..count];
+                        querier.handle_datagram(runtime.now(), source, datagram);
+                    }
+                    None => querier.handle_timeout(runtime.now()),
+                }
+            }
+        };
+
+        trace::stream(span, events)
+    }
 }
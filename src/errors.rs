@@ -1,17 +1,227 @@
+#![allow(non_local_definitions)] // err-derive expands its impls inside anonymous consts
+
+use crate::resolve::Report;
+use crate::Response;
+
 use err_derive::Error;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
 
-#[cfg(feature = "runtime-async-std")]
-pub use async_std::future::TimeoutError;
+/// The size of a DNS message header.
+const HEADER_LEN: usize = 12;
+/// How many bytes of a malformed packet are shown.
+const PREVIEW_LEN: usize = 16;
 
-#[cfg(feature = "runtime-tokio")]
-pub use tokio::time::error::Elapsed as TimeoutError;
+/// The error returned when an operation did not complete in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Error)]
+#[error(display = "operation timed out")]
+pub struct TimeoutError;
 
 #[derive(Debug, Error)]
 pub enum Error {
-    #[error(display = "_0")]
-    Io(#[error(source)] std::io::Error),
-    #[error(display = "_0")]
-    Dns(#[error(source)] dns_parser::Error),
-    #[error(display = "_0")]
-    TimeoutError(#[error(source)] TimeoutError),
+    /// Any other I/O error, such as failing to set a socket option.
+    #[error(display = "I/O error: {}", _0)]
+    Io(#[error(source)] io::Error),
+    /// Another socket is bound to the address, without allowing it to be
+    /// shared.
+    #[error(display = "{} is already in use", addr)]
+    AddrInUse { addr: SocketAddr },
+    /// Binding to the address needs privileges we don't have, as low port
+    /// numbers often do.
+    #[error(display = "not permitted to bind to {}", addr)]
+    PermissionDenied { addr: SocketAddr },
+    #[error(display = "failed to bind to {}: {}", addr, source)]
+    Bind {
+        addr: SocketAddr,
+        #[error(source)]
+        source: io::Error,
+    },
+    #[error(
+        display = "failed to join {} on interface {}: {}",
+        group,
+        interface,
+        source
+    )]
+    JoinMulticast {
+        group: Ipv4Addr,
+        interface: Ipv4Addr,
+        #[error(source)]
+        source: io::Error,
+    },
+    #[error(display = "failed to send to {}: {}", destination, source)]
+    Send {
+        destination: SocketAddr,
+        #[error(source)]
+        source: io::Error,
+    },
+    /// The time ran out, with `partial` holding what was found until then.
+    #[error(display = "operation timed out")]
+    Timeout { partial: Vec<Response> },
+    /// A resolution failed partway, with `partial` holding what it found
+    /// until then.
+    #[error(display = "resolution failed: {}", source)]
+    Interrupted {
+        partial: Box<Report>,
+        #[error(source)]
+        source: Box<Error>,
+    },
+}
+
+/// A packet that couldn't be parsed.
+///
+/// ```rust
+/// use mdns::proto::{Event, Querier};
+/// use std::time::{Duration, Instant};
+///
+/// let mut querier = Querier::new("_http._tcp.local", Duration::from_secs(15));
+/// let source = "192.168.1.10:5353".parse().unwrap();
+///
+/// // One question for "_http", whose type and class were cut off.
+/// let datagram = [0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 5, b'_', b'h', b't', b't', b'p', 0];
+/// querier.handle_datagram(Instant::now(), source, &datagram);
+///
+/// match querier.poll_event() {
+///     Some(Event::Malformed { packet, .. }) => {
+///         assert_eq!(packet.offset, 12);
+///         assert_eq!(packet.preview, &datagram[12..]);
+///     }
+///     other => panic!("{:?}", other),
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MalformedPacket {
+    /// Where in the packet the problem is: the start of the question or
+    /// record that couldn't be parsed, or 0 if the header is at fault.
+    pub offset: usize,
+    /// The first few bytes from `offset` on.
+    pub preview: Vec<u8>,
+    /// What is wrong.
+    pub reason: String,
+}
+
+impl Error {
+    /// Describes a failure to bind to `addr`.
+    pub(crate) fn bind(addr: SocketAddr, source: io::Error) -> Self {
+        match source.kind() {
+            io::ErrorKind::AddrInUse => Error::AddrInUse { addr },
+            io::ErrorKind::PermissionDenied => Error::PermissionDenied { addr },
+            _ => Error::Bind { addr, source },
+        }
+    }
+}
+
+impl From<TimeoutError> for Error {
+    fn from(_: TimeoutError) -> Self {
+        Error::Timeout {
+            partial: Vec::new(),
+        }
+    }
+}
+
+impl MalformedPacket {
+    /// Describes why `dns_parser` couldn't parse `datagram`.
+    pub(crate) fn new(datagram: &[u8], error: &dns_parser::Error) -> Self {
+        let offset = locate(datagram).min(datagram.len());
+
+        MalformedPacket {
+            offset,
+            preview: datagram[offset..]
+                .iter()
+                .take(PREVIEW_LEN)
+                .copied()
+                .collect(),
+            reason: error.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for MalformedPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte {} [", self.reason, self.offset)?;
+        for (i, byte) in self.preview.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        write!(f, "]")
+    }
+}
+
+/// Finds the question or record that keeps `datagram` from being parsed.
+///
+/// The sections are walked first, which finds anything running past the
+/// end. If they all fit, the shortest run of questions and records that
+/// fails to parse is searched for, which finds bad contents.
+fn locate(datagram: &[u8]) -> usize {
+    if datagram.len() < HEADER_LEN {
+        return 0;
+    }
+
+    let count = |i: usize| usize::from(u16::from_be_bytes([datagram[i], datagram[i + 1]]));
+    let questions = count(4);
+    let records = count(6) + count(8) + count(10);
+
+    let mut starts = Vec::new();
+    let mut pos = HEADER_LEN;
+    for item in 0..questions + records {
+        starts.push(pos);
+        let fixed = if item < questions { 4 } else { 10 };
+        pos = match skip_name(datagram, pos) {
+            Some(end) if end + fixed <= datagram.len() => end + fixed,
+            _ => return pos,
+        };
+        if item >= questions {
+            let rdata_len = count(pos - 2);
+            if pos + rdata_len > datagram.len() {
+                return starts[item];
+            }
+            pos += rdata_len;
+        }
+    }
+    starts.push(pos);
+
+    // Once a question or record fails, every longer prefix does too.
+    let fails = |items| dns_parser::Packet::parse(&prefix(datagram, &starts, items)).is_err();
+    let (mut good, mut bad) = (0, starts.len() - 1);
+    if fails(good) || !fails(bad) {
+        return 0;
+    }
+    while bad - good > 1 {
+        let middle = (good + bad) / 2;
+        if fails(middle) {
+            bad = middle;
+        } else {
+            good = middle;
+        }
+    }
+    starts[bad - 1]
+}
+
+/// The first `items` questions and records of a packet, with the counts in
+/// the header to match.
+fn prefix(datagram: &[u8], starts: &[usize], items: usize) -> Vec<u8> {
+    let mut prefix = datagram[..starts[items]].to_vec();
+    let mut left = items;
+    for i in [4, 6, 8, 10] {
+        let count = usize::from(u16::from_be_bytes([datagram[i], datagram[i + 1]])).min(left);
+        left -= count;
+        prefix[i..i + 2].copy_from_slice(&(count as u16).to_be_bytes());
+    }
+    prefix
+}
+
+/// The position just past the name at `pos`, if it is well formed.
+fn skip_name(datagram: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *datagram.get(pos)?;
+        match len & 0xc0 {
+            0x00 if len == 0 => return Some(pos + 1),
+            0x00 => pos += 1 + usize::from(len),
+            // a compression pointer ends the name
+            0xc0 => return Some(pos + 2),
+            _ => return None,
+        }
+    }
 }
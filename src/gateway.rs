@@ -0,0 +1,433 @@
+//! A unicast DNS server that answers for `.local` names with mDNS.
+//!
+//! Programs that only speak ordinary DNS can't resolve Bonjour names on
+//! their own. A [`Gateway`] runs the state machine in
+//! [`proto::gateway`](crate::proto::gateway) behind a DNS server, so that
+//! pointing such programs (or the system's stub resolver) at it for the
+//! `local` domain is enough, much like a discovery proxy (RFC 8766). Answers
+//! are cached for as long as their TTLs allow.
+//!
+//! ```rust
+//! use futures_util::{pin_mut, StreamExt};
+//! use mdns::proto::wire::{Message, Question};
+//! use mdns::register::{self, Host};
+//! use mdns::transport::sim::Network;
+//! use std::net::{Ipv4Addr, SocketAddr};
+//!
+//! let network = Network::new();
+//! let lan = network.link();
+//! let printer = network.host("printer");
+//! printer.interface(&lan, [192, 168, 1, 30]);
+//! let server = network.host("server");
+//! let server_addr = server.interface(&lan, [192, 168, 1, 2]).addr();
+//! let laptop = network.host("laptop");
+//! laptop.interface(&lan, [192, 168, 1, 20]);
+//!
+//! let responder = register::with_transport(network.runtime(), printer.bind(5353));
+//! let _host = responder.register_host(Host::new("printer.local").address(Ipv4Addr::new(192, 168, 1, 30)));
+//! let events = responder.events();
+//! pin_mut!(events);
+//! network.block_on(events.next());
+//!
+//! let _gateway = mdns::gateway::with_transports(network.runtime(), server.bind(5353), server.bind(53));
+//!
+//! let mut query = Message::query(7);
+//! query.recursion_desired = true;
+//! query.questions.push(Question::new("printer.local", dns_parser::QueryType::A));
+//! let client = laptop.bind(40000);
+//! let mut buffer = [0; 512];
+//! let (count, _) = network.block_on(async {
+//!     client.send_to(&query.encode(), SocketAddr::new(server_addr, 53)).await.unwrap();
+//!     client.recv_from(&mut buffer).await.unwrap()
+//! });
+//!
+//! let reply = dns_parser::Packet::parse(&buffer[..count]).unwrap();
+//! assert_eq!(reply.header.id, 7);
+//! assert_eq!(reply.answers.len(), 1);
+//! ```
+
+use crate::mdns::{mDNSSender, mdns_interface, mdns_transport};
+use crate::metrics::{self, Metrics};
+use crate::proto::gateway::{self, Protocol, QueryId};
+use crate::runtime::{self, Runtime};
+use crate::socket::SocketConfig;
+use crate::transport::{Connection, Listener, Transport};
+use crate::{mDNSListener, Error};
+
+use futures_util::future::{select, Either};
+use futures_util::pin_mut;
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The largest query we accept over UDP.
+const MAX_QUERY_SIZE: usize = 4096;
+
+/// How long a TCP client may stay quiet before the connection is closed.
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Answers DNS queries for `.local` names, for as long as it is alive.
+pub struct Gateway {
+    state: Arc<Mutex<State>>,
+    /// Wakes the task up after a TCP query came in. Closed when the gateway
+    /// is dropped, which stops every task.
+    wake: async_channel::Sender<()>,
+    /// Where the traffic of the mDNS socket is recorded.
+    #[cfg(feature = "pcap")]
+    tap: crate::pcap::TapSlot,
+}
+
+/// What the tasks of a gateway share.
+struct State {
+    gateway: gateway::Gateway,
+    /// Where to send the reply to each query still being looked up.
+    clients: HashMap<QueryId, Client>,
+    /// Counts the mDNS traffic, once metrics are attached.
+    tap: Option<metrics::Tap>,
+}
+
+/// A client waiting for a reply.
+enum Client {
+    Udp(SocketAddr),
+    Tcp(async_channel::Sender<Vec<u8>>),
+}
+
+/// Serves DNS over UDP and TCP on `addr`, looking names up with mDNS on all
+/// interfaces.
+///
+/// Port 53 usually needs special privileges; a loopback address such as
+/// `127.0.0.53` keeps the gateway to this machine.
+pub fn bind(addr: SocketAddr) -> Result<Gateway, Error> {
+    bind_with_runtime(runtime::default(), addr)
+}
+
+/// Serves DNS over UDP and TCP on `addr`, driven by the given runtime.
+pub fn bind_with_runtime(runtime: Arc<dyn Runtime>, addr: SocketAddr) -> Result<Gateway, Error> {
+    let (mdns_listener, mdns_sender) = mdns_interface(&runtime, &SocketConfig::new())?;
+
+    let udp = std::net::UdpSocket::bind(addr).map_err(|e| Error::bind(addr, e))?;
+    udp.set_nonblocking(true)?;
+    let udp = runtime.udp_socket(udp)?;
+    let tcp = std::net::TcpListener::bind(addr).map_err(|e| Error::bind(addr, e))?;
+    tcp.set_nonblocking(true)?;
+    let tcp = runtime.tcp_listener(tcp)?;
+
+    Ok(Gateway::spawn(
+        runtime,
+        mdns_sender,
+        mdns_listener,
+        udp,
+        Some(tcp),
+    ))
+}
+
+/// Serves DNS over UDP on the `dns` transport, looking names up with mDNS on
+/// the `mdns` one.
+///
+/// This is mostly useful with the simulated network in
+/// [`transport::sim`](crate::transport::sim).
+pub fn with_transports(
+    runtime: Arc<dyn Runtime>,
+    mdns: Arc<dyn Transport>,
+    dns: Arc<dyn Transport>,
+) -> Gateway {
+    let (mdns_listener, mdns_sender) = mdns_transport(mdns);
+
+    Gateway::spawn(runtime, mdns_sender, mdns_listener, dns, None)
+}
+
+impl Gateway {
+    fn spawn(
+        runtime: Arc<dyn Runtime>,
+        mdns_sender: mDNSSender,
+        mdns_listener: mDNSListener,
+        udp: Arc<dyn Transport>,
+        tcp: Option<Arc<dyn Listener>>,
+    ) -> Self {
+        let state = Arc::new(Mutex::new(State {
+            gateway: gateway::Gateway::new(),
+            clients: HashMap::new(),
+            tap: None,
+        }));
+        let (wake, woken) = async_channel::bounded(1);
+        let (stopping, stopped) = async_channel::bounded::<()>(1);
+        #[cfg(feature = "pcap")]
+        let tap = mdns_sender.tap.clone();
+
+        if let Some(tcp) = tcp {
+            let accept = accept(state.clone(), runtime.clone(), tcp, wake.clone(), stopped);
+            runtime.spawn(Box::pin(accept));
+        }
+
+        let state_task = state.clone();
+        let runtime_task = runtime.clone();
+        runtime.spawn(Box::pin(async move {
+            run(
+                state_task,
+                runtime_task,
+                mdns_sender,
+                mdns_listener,
+                udp,
+                woken,
+            )
+            .await;
+            drop(stopping);
+        }));
+
+        Gateway {
+            state,
+            wake,
+            #[cfg(feature = "pcap")]
+            tap,
+        }
+    }
+
+    /// Sets how long to wait for mDNS answers before replying.
+    ///
+    /// See [`proto::gateway::Gateway::timeout`](crate::proto::gateway::Gateway::timeout).
+    pub fn timeout(self, timeout: Duration) -> Self {
+        self.state.lock().unwrap().gateway.set_timeout(timeout);
+        self
+    }
+
+    /// Counts the gateway's mDNS traffic and cache use into `metrics`.
+    pub fn metrics(self, metrics: &Metrics) -> Self {
+        let mut state = self.state.lock().unwrap();
+        state.gateway.set_metrics(metrics.clone());
+        state.tap = Some(metrics.tap(Ipv4Addr::UNSPECIFIED.into()));
+        drop(state);
+        self
+    }
+
+    /// Records every mDNS datagram the gateway sends and receives. The DNS
+    /// queries and replies aren't recorded.
+    #[cfg(feature = "pcap")]
+    pub fn record(self, recorder: &crate::pcap::Recorder) -> Result<Self, Error> {
+        self.tap.attach(recorder)?;
+        Ok(self)
+    }
+}
+
+impl Drop for Gateway {
+    fn drop(&mut self) {
+        self.wake.close();
+    }
+}
+
+/// Feeds the gateway with queries, mDNS datagrams and timeouts until it is
+/// dropped.
+async fn run(
+    state: Arc<Mutex<State>>,
+    runtime: Arc<dyn Runtime>,
+    mut mdns_sender: mDNSSender,
+    mut mdns_listener: mDNSListener,
+    udp: Arc<dyn Transport>,
+    woken: async_channel::Receiver<()>,
+) {
+    let mut query = vec![0; MAX_QUERY_SIZE];
+
+    loop {
+        let (transmits, replies, deadline) = {
+            let mut state = state.lock().unwrap();
+            let transmits: Vec<_> = std::iter::from_fn(|| state.gateway.poll_transmit()).collect();
+            let mut replies = Vec::new();
+            while let Some(reply) = state.gateway.poll_reply() {
+                if let Some(client) = state.clients.remove(&reply.query) {
+                    replies.push((client, reply.contents));
+                }
+            }
+            mdns_sender.metrics = state.tap.clone();
+            mdns_listener.metrics = state.tap.clone();
+            (transmits, replies, state.gateway.poll_timeout())
+        };
+
+        for transmit in transmits {
+            if let Err(e) = mdns_sender.send(&transmit).await {
+                log::warn!("{}", e);
+            }
+        }
+        for (client, contents) in replies {
+            match client {
+                Client::Udp(addr) => {
+                    if let Err(e) = udp.send_to(&contents, addr).await {
+                        log::warn!("failed to reply to {}: {}", addr, e);
+                    }
+                }
+                // The connection may have gone away in the meantime.
+                Client::Tcp(sender) => {
+                    let _ = sender.try_send(contents);
+                }
+            }
+        }
+
+        let wakeup = {
+            let mdns = async {
+                let received = match deadline {
+                    Some(deadline) => {
+                        let wait = deadline.saturating_duration_since(runtime.now());
+                        runtime::timeout(&*runtime, wait, mdns_listener.recv())
+                            .await
+                            .ok()
+                    }
+                    None => Some(mdns_listener.recv().await),
+                };
+                Wakeup::Mdns(received)
+            };
+            let dns = async { Wakeup::Query(udp.recv_from(&mut query).await) };
+            let woken = async {
+                match woken.recv().await {
+                    Ok(()) => Wakeup::Woken,
+                    Err(_) => Wakeup::Stopped,
+                }
+            };
+            pin_mut!(mdns, dns, woken);
+
+            match select(select(mdns, dns), woken).await {
+                Either::Left((either, _)) => either.factor_first().0,
+                Either::Right((wakeup, _)) => wakeup,
+            }
+        };
+
+        let now = runtime.now();
+        match wakeup {
+            Wakeup::Mdns(Some(Ok((count, source)))) => {
+                let datagram = &mdns_listener.recv_buffer[..count];
+                state
+                    .lock()
+                    .unwrap()
+                    .gateway
+                    .handle_datagram(now, source, datagram);
+            }
+            Wakeup::Mdns(Some(Err(e))) => {
+                log::error!("gateway stopped: {}", e);
+                break;
+            }
+            Wakeup::Mdns(None) => state.lock().unwrap().gateway.handle_timeout(now),
+            Wakeup::Query(Ok((count, source))) => {
+                let mut state = state.lock().unwrap();
+                let id = state
+                    .gateway
+                    .handle_query(now, &query[..count], Protocol::Udp);
+                state.clients.insert(id, Client::Udp(source));
+            }
+            Wakeup::Query(Err(e)) => log::warn!("failed to receive a query: {}", e),
+            Wakeup::Woken => {}
+            Wakeup::Stopped => break,
+        }
+    }
+
+    // Lets TCP clients still waiting for a reply know there won't be one.
+    state.lock().unwrap().clients.clear();
+}
+
+/// What the task woke up for.
+enum Wakeup {
+    /// An mDNS datagram, or `None` when the gateway's timeout came first.
+    Mdns(Option<Result<(usize, SocketAddr), Error>>),
+    Query(io::Result<(usize, SocketAddr)>),
+    Woken,
+    Stopped,
+}
+
+/// Serves TCP clients until the gateway stops.
+async fn accept(
+    state: Arc<Mutex<State>>,
+    runtime: Arc<dyn Runtime>,
+    listener: Arc<dyn Listener>,
+    wake: async_channel::Sender<()>,
+    stopped: async_channel::Receiver<()>,
+) {
+    loop {
+        let accepted = {
+            let accept = listener.accept();
+            let stop = stopped.recv();
+            pin_mut!(stop);
+
+            match select(accept, stop).await {
+                Either::Left((accepted, _)) => accepted,
+                Either::Right(_) => break,
+            }
+        };
+
+        match accepted {
+            Ok((connection, addr)) => {
+                let serve = serve(state.clone(), runtime.clone(), connection, wake.clone());
+                runtime.spawn(Box::pin(async move {
+                    if let Err(e) = serve.await {
+                        log::debug!("closed connection from {}: {}", addr, e);
+                    }
+                }));
+            }
+            Err(e) => log::warn!("failed to accept a connection: {}", e),
+        }
+    }
+}
+
+/// Answers the queries of a TCP client, one at a time, until it hangs up or
+/// stays quiet for too long.
+async fn serve(
+    state: Arc<Mutex<State>>,
+    runtime: Arc<dyn Runtime>,
+    mut connection: Box<dyn Connection>,
+    wake: async_channel::Sender<()>,
+) -> io::Result<()> {
+    loop {
+        let read = read_message(&mut *connection);
+        let query = match runtime::timeout(&*runtime, TCP_IDLE_TIMEOUT, read).await {
+            Ok(Ok(Some(query))) => query,
+            Ok(Ok(None)) | Err(_) => return Ok(()),
+            Ok(Err(e)) => return Err(e),
+        };
+
+        let (sender, receiver) = async_channel::bounded(1);
+        {
+            let mut state = state.lock().unwrap();
+            let id = state
+                .gateway
+                .handle_query(runtime.now(), &query, Protocol::Tcp);
+            state.clients.insert(id, Client::Tcp(sender));
+        }
+        // A full channel means the task is going to wake up anyway, and a
+        // closed one that the gateway is gone.
+        let _ = wake.try_send(());
+
+        let reply = match receiver.recv().await {
+            Ok(reply) => reply,
+            Err(_) => return Ok(()),
+        };
+        let mut framed = (reply.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(&reply);
+        connection.write_all(&framed).await?;
+    }
+}
+
+/// Reads a length-prefixed DNS message, or `None` if the client hung up
+/// before sending one.
+async fn read_message(connection: &mut dyn Connection) -> io::Result<Option<Vec<u8>>> {
+    let mut length = [0; 2];
+    if !read_exact(connection, &mut length).await? {
+        return Ok(None);
+    }
+
+    let mut message = vec![0; u16::from_be_bytes(length).into()];
+    if !read_exact(connection, &mut message).await? {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some(message))
+}
+
+/// Fills `buf`, returning `false` if the connection was closed before
+/// anything was read.
+async fn read_exact(connection: &mut dyn Connection, buf: &mut [u8]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match connection.read(&mut buf[filled..]).await? {
+            0 if filled == 0 => return Ok(false),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            count => filled += count,
+        }
+    }
+    Ok(true)
+}
@@ -0,0 +1,82 @@
+//! Listing the addresses of this machine's network interfaces.
+
+use std::io;
+use std::net::IpAddr;
+
+/// An address of one of this machine's interfaces that are up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct InterfaceAddr {
+    pub(crate) addr: IpAddr,
+    /// The length of the subnet prefix, if the interface has a netmask.
+    pub(crate) prefix_len: Option<u32>,
+    pub(crate) loopback: bool,
+}
+
+/// The addresses of this machine's interfaces that are up.
+#[cfg(unix)]
+pub(crate) fn interface_addrs() -> io::Result<Vec<InterfaceAddr>> {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    /// Reads an address of either family, and how many leading bits of it
+    /// are set.
+    unsafe fn read(addr: *const libc::sockaddr) -> Option<(IpAddr, u32)> {
+        let addr = addr.as_ref()?;
+        match addr.sa_family as i32 {
+            libc::AF_INET => {
+                let addr = &*(addr as *const libc::sockaddr as *const libc::sockaddr_in);
+                let bits = u32::from_be(addr.sin_addr.s_addr);
+                Some((IpAddr::V4(Ipv4Addr::from(bits)), bits.leading_ones()))
+            }
+            libc::AF_INET6 => {
+                let addr = &*(addr as *const libc::sockaddr as *const libc::sockaddr_in6);
+                let bits = u128::from_be_bytes(addr.sin6_addr.s6_addr);
+                Some((IpAddr::V6(Ipv6Addr::from(bits)), bits.leading_ones()))
+            }
+            _ => None,
+        }
+    }
+
+    let mut addrs = Vec::new();
+    let mut interfaces: *mut libc::ifaddrs = std::ptr::null_mut();
+
+    // Safety: getifaddrs hands back a linked list that stays valid until it
+    // is given to freeifaddrs, and every address is read according to its
+    // family.
+    unsafe {
+        if libc::getifaddrs(&mut interfaces) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut current = interfaces;
+        while let Some(interface) = current.as_ref() {
+            current = interface.ifa_next;
+
+            let flags = interface.ifa_flags as i32;
+            if flags & libc::IFF_UP == 0 {
+                continue;
+            }
+            if let Some((addr, _)) = read(interface.ifa_addr) {
+                addrs.push(InterfaceAddr {
+                    addr,
+                    prefix_len: read(interface.ifa_netmask).map(|(_, prefix_len)| prefix_len),
+                    loopback: flags & libc::IFF_LOOPBACK != 0,
+                });
+            }
+        }
+
+        libc::freeifaddrs(interfaces);
+    }
+
+    Ok(addrs)
+}
+
+/// The addresses of this machine's interfaces that are up.
+///
+/// Only supported on Unix for now.
+#[cfg(not(unix))]
+pub(crate) fn interface_addrs() -> io::Result<Vec<InterfaceAddr>> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "listing interfaces is not supported on this platform",
+    ))
+}
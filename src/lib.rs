@@ -25,8 +25,9 @@
 //! /// Every Chromecast will respond to the service name in this example.
 //! const SERVICE_NAME: &'static str = "_googlecast._tcp.local";
 //!
+//! # #[cfg(any(feature = "runtime-async-std", feature = "runtime-tokio"))]
 //! #[cfg_attr(feature = "runtime-async-std", async_std::main)]
-//! #[cfg_attr(feature = "runtime-tokio", tokio::main)]
+//! #[cfg_attr(all(feature = "runtime-tokio", not(feature = "runtime-async-std")), tokio::main)]
 //! async fn main() -> Result<(), Error> {
 //!     // Iterate through responses from each Cast device, asking for new devices every 15s
 //!     let stream = mdns::discover::all(SERVICE_NAME, Duration::from_secs(15))?.listen();
@@ -46,6 +47,8 @@
 //!
 //!     Ok(())
 //! }
+//! # #[cfg(not(any(feature = "runtime-async-std", feature = "runtime-tokio")))]
+//! # fn main() {}
 //!
 //! fn to_ip_addr(record: &Record) -> Option<IpAddr> {
 //!     match record.kind {
@@ -58,22 +61,36 @@
 
 #![recursion_limit = "1024"]
 
-#[cfg(all(feature = "runtime-async-std", feature = "runtime-tokio"))]
-compile_error!("\"runtime-async-std\" and \"runtime-tokio\" cannot be enabled simultaneously");
+#[cfg(not(any(
+    feature = "runtime-async-std",
+    feature = "runtime-tokio",
+    feature = "runtime-smol"
+)))]
+compile_error!("At least one runtime (\"runtime-async-std\", \"runtime-tokio\" or \"runtime-smol\") cargo feature must be enabled");
 
-#[cfg(not(any(feature = "runtime-async-std", feature = "runtime-tokio")))]
-compile_error!("At least one runtime (\"runtime-async-std\" or \"runtime-tokio\") cargo feature must be enabled");
-
-pub use self::errors::Error;
+pub use self::errors::{Error, MalformedPacket, TimeoutError};
 pub use self::response::{Record, RecordKind, Response, TxtRecordValue};
 
 pub mod discover;
+pub mod gateway;
+pub mod metrics;
+#[cfg(feature = "pcap")]
+pub mod pcap;
+pub mod proto;
+pub mod reflect;
+pub mod register;
 pub mod resolve;
-
-mod runtime;
+pub mod runtime;
+pub mod socket;
+pub mod transport;
 
 mod errors;
+mod interfaces;
+mod link;
 mod mdns;
 mod response;
+#[cfg(test)]
+mod testing;
+mod trace;
 
 pub use self::mdns::mDNSListener;
@@ -0,0 +1,254 @@
+//! Telling responses from the local link apart from spoofed ones.
+//!
+//! RFC 6762 section 11 has receivers ignore responses that didn't come from
+//! the local link: either the source address is on a directly connected
+//! subnet, or the packet still has the IP TTL of 255 it was sent with, which
+//! no router would have left alone.
+//!
+//! The TTL of received packets is only known on Linux and Android, where it
+//! is asked for with `IP_RECVTTL` and `IPV6_RECVHOPLIMIT`. Elsewhere the
+//! source address is all there is to go by.
+
+use crate::interfaces::interface_addrs;
+use crate::proto::is_response;
+use crate::socket::SourceValidation;
+
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// The IP TTL every mDNS packet is sent with.
+const LINK_LOCAL_TTL: u8 = 255;
+/// How long the list of subnets is used before it is read again, to keep up
+/// with interfaces coming and going.
+const SUBNET_REFRESH: Duration = Duration::from_secs(30);
+
+/// Decides which received datagrams are passed on.
+#[derive(Clone, Debug)]
+pub(crate) struct LinkCheck {
+    validation: SourceValidation,
+    /// The subnets of this machine's interfaces, or `None` if they couldn't
+    /// be listed.
+    subnets: Option<Vec<Subnet>>,
+    /// When `subnets` was last read.
+    refreshed: Option<Instant>,
+}
+
+/// The addresses of a subnet, as an address and a prefix length.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Subnet {
+    addr: IpAddr,
+    prefix_len: u32,
+}
+
+impl LinkCheck {
+    pub(crate) fn new(validation: SourceValidation) -> Self {
+        LinkCheck {
+            validation,
+            subnets: None,
+            refreshed: None,
+        }
+    }
+
+    /// Whether a datagram received from `source` with the IP TTL `ttl`, if
+    /// known, should be handled.
+    ///
+    /// Only responses are checked. Queries are let through as they are.
+    pub(crate) fn accepts(&mut self, source: SocketAddr, ttl: Option<u8>, datagram: &[u8]) -> bool {
+        if self.validation == SourceValidation::Off
+            || !is_response(datagram)
+            || ttl == Some(LINK_LOCAL_TTL)
+            || source.ip().is_loopback()
+        {
+            return true;
+        }
+
+        match (self.is_on_subnet(source.ip()), ttl) {
+            (Some(true), _) => return true,
+            // nothing to go by
+            (None, None) => return true,
+            _ => {}
+        }
+
+        match self.validation {
+            SourceValidation::Warn => {
+                log::warn!("response from {} is not from the local link", source);
+                true
+            }
+            _ => {
+                log::debug!("ignoring response from {}, off the local link", source);
+                false
+            }
+        }
+    }
+
+    /// Whether `addr` is on one of our subnets, or `None` if we don't know
+    /// our subnets.
+    fn is_on_subnet(&mut self, addr: IpAddr) -> Option<bool> {
+        let stale = self
+            .refreshed
+            .map_or(true, |refreshed| refreshed.elapsed() >= SUBNET_REFRESH);
+        if stale {
+            self.subnets = match local_subnets() {
+                Ok(subnets) => Some(subnets),
+                Err(e) => {
+                    log::debug!("failed to list subnets: {}", e);
+                    None
+                }
+            };
+            self.refreshed = Some(Instant::now());
+        }
+
+        let subnets = self.subnets.as_ref()?;
+        Some(subnets.iter().any(|subnet| subnet.contains(addr)))
+    }
+}
+
+impl Subnet {
+    fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(subnet), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(subnet) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(subnet), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(subnet) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The subnets of this machine's interfaces that are up.
+fn local_subnets() -> io::Result<Vec<Subnet>> {
+    Ok(interface_addrs()?
+        .into_iter()
+        .filter_map(|interface| {
+            Some(Subnet {
+                addr: interface.addr,
+                prefix_len: interface.prefix_len?,
+            })
+        })
+        .collect())
+}
+
+/// Asks for the IP TTL of every datagram received on `socket`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn enable_recv_ttl(socket: &UdpSocket) -> io::Result<()> {
+    use std::os::raw::c_int;
+    use std::os::unix::io::AsRawFd;
+
+    let (level, option) = match socket.local_addr()? {
+        SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_RECVTTL),
+        SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_RECVHOPLIMIT),
+    };
+    let enable: c_int = 1;
+
+    // Safety: the option value is a c_int, as both options expect.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            option,
+            (&enable as *const c_int).cast(),
+            std::mem::size_of_val(&enable) as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Asks for the IP TTL of every datagram received on `socket`.
+///
+/// Only supported on Linux and Android, so this does nothing elsewhere.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn enable_recv_ttl(_: &UdpSocket) -> io::Result<()> {
+    Ok(())
+}
+
+/// Receives a datagram on a non-blocking socket, along with the IP TTL it
+/// arrived with if [`enable_recv_ttl`] was called on the socket.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn recv_with_ttl(
+    fd: std::os::unix::io::RawFd,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<u8>)> {
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV6};
+    use std::os::raw::c_int;
+
+    // Room for one control message holding a c_int, suitably aligned.
+    let mut control = [0u64; 8];
+    // Safety: all of these are plain C structs, for which zero is valid.
+    let mut source: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    let mut message: libc::msghdr = unsafe { mem::zeroed() };
+    message.msg_name = (&mut source as *mut libc::sockaddr_storage).cast();
+    message.msg_namelen = mem::size_of_val(&source) as libc::socklen_t;
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr().cast();
+    message.msg_controllen = mem::size_of_val(&control) as _;
+
+    // Safety: every pointer in the message points to a live buffer of the
+    // length given next to it.
+    let count = unsafe { libc::recvmsg(fd, &mut message, 0) };
+    if count < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safety: the kernel filled in the source address and control messages,
+    // and each is read according to its type.
+    let (source, ttl) = unsafe {
+        let source = match source.ss_family as i32 {
+            libc::AF_INET => {
+                let addr = &*(&source as *const libc::sockaddr_storage as *const libc::sockaddr_in);
+                SocketAddr::new(
+                    Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)).into(),
+                    u16::from_be(addr.sin_port),
+                )
+            }
+            libc::AF_INET6 => {
+                let addr =
+                    &*(&source as *const libc::sockaddr_storage as *const libc::sockaddr_in6);
+                SocketAddrV6::new(
+                    Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                    u16::from_be(addr.sin6_port),
+                    addr.sin6_flowinfo,
+                    addr.sin6_scope_id,
+                )
+                .into()
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "datagram from an unknown address family",
+                ))
+            }
+        };
+
+        let mut ttl = None;
+        let mut header = libc::CMSG_FIRSTHDR(&message);
+        while let Some(current) = header.as_ref() {
+            let is_ttl = (current.cmsg_level, current.cmsg_type)
+                == (libc::IPPROTO_IP, libc::IP_TTL)
+                || (current.cmsg_level, current.cmsg_type)
+                    == (libc::IPPROTO_IPV6, libc::IPV6_HOPLIMIT);
+            if is_ttl {
+                let value = std::ptr::read_unaligned(libc::CMSG_DATA(header).cast::<c_int>());
+                ttl = u8::try_from(value).ok();
+            }
+            header = libc::CMSG_NXTHDR(&message, header);
+        }
+
+        (source, ttl)
+    };
+
+    Ok((count as usize, source, ttl))
+}
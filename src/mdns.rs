@@ -1,86 +1,102 @@
-use crate::{runtime::AsyncUdpSocket, Error, Response};
-
-use std::{io, net::Ipv4Addr};
+use crate::link::LinkCheck;
+use crate::proto::reassembly::Reassembler;
+use crate::proto::{Transmit, MULTICAST_ADDR, MULTICAST_PORT};
+use crate::runtime::Runtime;
+use crate::socket::SocketConfig;
+use crate::transport::Transport;
+use crate::{metrics, trace, Error, MalformedPacket, Response};
 
 use async_stream::try_stream;
 use futures_core::Stream;
 use std::sync::Arc;
 
-#[cfg(not(target_os = "windows"))]
-use net2::unix::UnixUdpBuilderExt;
 use std::net::SocketAddr;
-
-/// The IP address for the mDNS multicast socket.
-const MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
-const MULTICAST_PORT: u16 = 5353;
+use std::time::Instant;
 
 pub fn mdns_interface(
-    service_name: String,
-    interface_addr: Ipv4Addr,
+    runtime: &Arc<dyn Runtime>,
+    config: &SocketConfig,
 ) -> Result<(mDNSListener, mDNSSender), Error> {
-    let socket = create_socket()?;
-
-    socket.set_multicast_loop_v4(false)?;
-    socket.set_nonblocking(true)?; // explicitly set nonblocking for wider compatability
-    socket.join_multicast_v4(&MULTICAST_ADDR, &interface_addr)?;
-
-    let socket = crate::runtime::make_async_socket(socket)?;
+    config.open(runtime)
+}
 
-    let recv_buffer = vec![0; 4096];
+/// Wraps an already set up transport.
+pub fn mdns_transport(transport: Arc<dyn Transport>) -> (mDNSListener, mDNSSender) {
+    mdns_socket(transport, &SocketConfig::default())
+}
 
-    Ok((
+/// Wraps a transport set up as `config` describes.
+pub(crate) fn mdns_socket(
+    transport: Arc<dyn Transport>,
+    config: &SocketConfig,
+) -> (mDNSListener, mDNSSender) {
+    let recv_buffer = vec![0; config.max_datagram_len()];
+    let group = config.group_addr();
+    #[cfg(feature = "pcap")]
+    let tap = crate::pcap::TapSlot::new(config.interface_addr().into(), group);
+
+    (
         mDNSListener {
-            recv: socket.clone(),
+            recv: transport.clone(),
             recv_buffer,
+            group,
+            link: LinkCheck::new(config.validation()),
+            metrics: None,
+            #[cfg(feature = "pcap")]
+            tap: tap.clone(),
         },
         mDNSSender {
-            service_name,
-            send: socket,
+            send: transport,
+            group,
+            metrics: None,
+            #[cfg(feature = "pcap")]
+            tap,
         },
-    ))
-}
-
-const ADDR_ANY: Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
-
-#[cfg(not(target_os = "windows"))]
-fn create_socket() -> io::Result<std::net::UdpSocket> {
-    net2::UdpBuilder::new_v4()?
-        .reuse_address(true)?
-        .reuse_port(true)?
-        .bind((ADDR_ANY, MULTICAST_PORT))
-}
-
-#[cfg(target_os = "windows")]
-fn create_socket() -> io::Result<std::net::UdpSocket> {
-    net2::UdpBuilder::new_v4()?
-        .reuse_address(true)?
-        .bind((ADDR_ANY, MULTICAST_PORT))
+    )
 }
 
 /// An mDNS sender on a specific interface.
 #[derive(Debug, Clone)]
 #[allow(non_camel_case_types)]
 pub struct mDNSSender {
-    service_name: String,
-    send: Arc<AsyncUdpSocket>,
+    send: Arc<dyn Transport>,
+    /// Where the standard mDNS group is sent to instead.
+    group: SocketAddr,
+    pub(crate) metrics: Option<metrics::Tap>,
+    /// Shared with the listener of the same socket.
+    #[cfg(feature = "pcap")]
+    pub(crate) tap: crate::pcap::TapSlot,
 }
 
 impl mDNSSender {
-    /// Send multicasted DNS queries.
-    pub async fn send_request(&mut self) -> Result<(), Error> {
-        let mut builder = dns_parser::Builder::new_query(0, false);
-        let prefer_unicast = false;
-        builder.add_question(
-            &self.service_name,
-            prefer_unicast,
-            dns_parser::QueryType::PTR,
-            dns_parser::QueryClass::IN,
-        );
-        let packet_data = builder.build().unwrap();
-
-        let addr = SocketAddr::new(MULTICAST_ADDR.into(), MULTICAST_PORT);
-
-        self.send.send_to(&packet_data, addr).await?;
+    /// Sends a datagram produced by the protocol state machine.
+    ///
+    /// The state machines only know the standard group and port, which are
+    /// swapped for the configured ones here.
+    pub(crate) async fn send(&self, transmit: &Transmit) -> Result<(), Error> {
+        let destination = if transmit.destination.ip() == MULTICAST_ADDR {
+            self.group
+        } else if transmit.destination.port() == MULTICAST_PORT {
+            SocketAddr::new(transmit.destination.ip(), self.group.port())
+        } else {
+            transmit.destination
+        };
+
+        #[cfg(feature = "pcap")]
+        self.tap.outbound(destination, &transmit.contents);
+
+        self.send
+            .send_to(&transmit.contents, destination)
+            .await
+            .map_err(|source| Error::Send {
+                destination,
+                source,
+            })?;
+
+        trace::sent(destination, &transmit.contents);
+        if let Some(ref metrics) = self.metrics {
+            metrics.outbound(&transmit.contents);
+        }
         Ok(())
     }
 }
@@ -89,23 +105,71 @@ impl mDNSSender {
 #[derive(Debug, Clone)]
 #[allow(non_camel_case_types)]
 pub struct mDNSListener {
-    pub(crate) recv: Arc<AsyncUdpSocket>,
+    pub(crate) recv: Arc<dyn Transport>,
     pub(crate) recv_buffer: Vec<u8>,
+    /// The group and port standing in for the standard ones.
+    group: SocketAddr,
+    link: LinkCheck,
+    pub(crate) metrics: Option<metrics::Tap>,
+    #[cfg(feature = "pcap")]
+    pub(crate) tap: crate::pcap::TapSlot,
 }
 
 impl mDNSListener {
+    /// Yields every response received.
+    ///
+    /// Responses split across datagrams with the TC bit are joined into one.
+    /// There is no timer behind the stream, so a response whose rest never
+    /// arrives is only yielded once the next datagram is received.
     pub fn listen(mut self) -> impl Stream<Item = Result<Response, Error>> {
+        let mut reassembler = Reassembler::new();
+
         try_stream! {
             loop {
-                let (count, _) = self.recv.recv_from(&mut self.recv_buffer).await?;
+                let (count, source) = self.recv().await?;
 
                 if count > 0 {
-                    match dns_parser::Packet::parse(&self.recv_buffer[..count]) {
+                    reassembler.handle_datagram(Instant::now(), source, &self.recv_buffer[..count]);
+                }
+
+                while let Some(datagram) = reassembler.poll_datagram() {
+                    match dns_parser::Packet::parse(&datagram.contents) {
                         Ok(raw_packet) => yield Response::from_packet(&raw_packet),
-                        Err(e) => log::warn!("{}, {:?}", e, &self.recv_buffer[..count]),
+                        Err(e) => {
+                            let packet = MalformedPacket::new(&datagram.contents, &e);
+                            log::warn!("malformed packet from {}: {}", datagram.source, packet);
+                        }
                     }
                 }
             }
         }
     }
+
+    /// Receives a single datagram into `recv_buffer`.
+    ///
+    /// Responses that didn't come from the local link are skipped, unless
+    /// the socket was set up otherwise. Datagrams from the configured port
+    /// are reported as coming from the standard one, so that they aren't
+    /// taken for legacy unicast queries.
+    pub(crate) async fn recv(&mut self) -> Result<(usize, SocketAddr), Error> {
+        loop {
+            let (count, source, ttl) = self.recv.recv_with_ttl(&mut self.recv_buffer).await?;
+            let datagram = &self.recv_buffer[..count];
+
+            #[cfg(feature = "pcap")]
+            self.tap.inbound(source, datagram);
+            trace::received(source, datagram);
+            if let Some(ref metrics) = self.metrics {
+                metrics.inbound(datagram);
+            }
+
+            if !self.link.accepts(source, ttl, datagram) {
+                continue;
+            }
+            if source.port() == self.group.port() {
+                return Ok((count, SocketAddr::new(source.ip(), MULTICAST_PORT)));
+            }
+            return Ok((count, source));
+        }
+    }
 }
@@ -0,0 +1,189 @@
+//! Counters of the mDNS traffic seen, to tell why a device wasn't found.
+//!
+//! A [`Metrics`] is attached to any number of discoveries with
+//! [`Discovery::metrics`](crate::discover::Discovery::metrics), and to a
+//! unicast DNS gateway with
+//! [`Gateway::metrics`](crate::gateway::Gateway::metrics). Each of them adds
+//! to the same counters, and [`Metrics::snapshot`] reads them all at once.
+//!
+//! ```rust
+//! use futures_util::{pin_mut, StreamExt};
+//! use mdns::metrics::Metrics;
+//! use mdns::register::{self, Service};
+//! use mdns::transport::sim::Network;
+//! use std::time::Duration;
+//!
+//! let network = Network::new();
+//! let lan = network.link();
+//! let printer = network.host("printer");
+//! printer.interface(&lan, [192, 168, 1, 20]);
+//! let laptop = network.host("laptop");
+//! laptop.interface(&lan, [192, 168, 1, 10]);
+//!
+//! let responder = register::with_transport(network.runtime(), printer.bind(5353));
+//! responder.register(Service::new("Office", "_ipp._tcp.local", "printer.local", 631));
+//!
+//! let metrics = Metrics::new();
+//! let stream = mdns::discover::with_transport(
+//!     network.runtime(),
+//!     laptop.bind(5353),
+//!     "_ipp._tcp.local",
+//!     Duration::from_secs(1),
+//! )
+//! .metrics(&metrics)
+//! .listen();
+//! pin_mut!(stream);
+//! network.block_on(stream.next()).unwrap().unwrap();
+//!
+//! let snapshot = metrics.snapshot();
+//! assert!(snapshot.queries_sent >= 1);
+//! assert_eq!(snapshot.responses_matched, 1);
+//! assert!(snapshot.responses_received >= snapshot.responses_matched);
+//! ```
+
+use crate::proto::is_response;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Shared counters of mDNS traffic.
+///
+/// Clones count into the same counters.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    queries_sent: AtomicU64,
+    responses_received: AtomicU64,
+    responses_matched: AtomicU64,
+    malformed_packets: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_expiries: AtomicU64,
+    interfaces: Mutex<HashMap<IpAddr, InterfaceTotals>>,
+}
+
+/// The counters at one point in time.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Snapshot {
+    /// Queries multicast by discoveries and the gateway.
+    pub queries_sent: u64,
+    /// Responses received from any host, whatever they answered.
+    pub responses_received: u64,
+    /// Responses that answered what a discovery asked for.
+    pub responses_matched: u64,
+    /// Datagrams that couldn't be parsed.
+    pub malformed_packets: u64,
+    /// Gateway queries answered from the cache, without asking the network.
+    pub cache_hits: u64,
+    /// Cached records dropped because their TTL ran out.
+    pub cache_expiries: u64,
+    /// The traffic on each interface, by its address. Sockets bound to every
+    /// interface count towards `0.0.0.0`.
+    pub interfaces: HashMap<IpAddr, InterfaceTotals>,
+}
+
+/// The traffic on one interface.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InterfaceTotals {
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    pub packets_received: u64,
+    pub bytes_received: u64,
+}
+
+/// Counts the traffic on one interface into a [`Metrics`].
+#[derive(Clone, Debug)]
+pub(crate) struct Tap {
+    metrics: Metrics,
+    interface: IpAddr,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads every counter.
+    pub fn snapshot(&self) -> Snapshot {
+        let inner = &self.inner;
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        Snapshot {
+            queries_sent: load(&inner.queries_sent),
+            responses_received: load(&inner.responses_received),
+            responses_matched: load(&inner.responses_matched),
+            malformed_packets: load(&inner.malformed_packets),
+            cache_hits: load(&inner.cache_hits),
+            cache_expiries: load(&inner.cache_expiries),
+            interfaces: inner.interfaces.lock().unwrap().clone(),
+        }
+    }
+
+    pub(crate) fn tap(&self, interface: IpAddr) -> Tap {
+        Tap {
+            metrics: self.clone(),
+            interface,
+        }
+    }
+
+    pub(crate) fn matched(&self) {
+        self.inner.responses_matched.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn malformed(&self) {
+        self.inner.malformed_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn cache_hit(&self) {
+        self.inner.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn cache_expired(&self, count: usize) {
+        self.inner
+            .cache_expiries
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    fn interface<F>(&self, interface: IpAddr, update: F)
+    where
+        F: FnOnce(&mut InterfaceTotals),
+    {
+        let mut interfaces = self.inner.interfaces.lock().unwrap();
+        update(interfaces.entry(interface).or_default());
+    }
+}
+
+impl Tap {
+    pub(crate) fn outbound(&self, datagram: &[u8]) {
+        if !is_response(datagram) {
+            self.metrics
+                .inner
+                .queries_sent
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        self.metrics.interface(self.interface, |totals| {
+            totals.packets_sent += 1;
+            totals.bytes_sent += datagram.len() as u64;
+        });
+    }
+
+    pub(crate) fn inbound(&self, datagram: &[u8]) {
+        if is_response(datagram) {
+            self.metrics
+                .inner
+                .responses_received
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        self.metrics.interface(self.interface, |totals| {
+            totals.packets_received += 1;
+            totals.bytes_received += datagram.len() as u64;
+        });
+    }
+}
@@ -0,0 +1,754 @@
+//! Reading and writing packet captures.
+//!
+//! A [`Reader`] pulls the mDNS datagrams (UDP port 5353, over IPv4 or IPv6) out
+//! of a pcap or pcapng file, such as one saved by Wireshark or tcpdump. They
+//! can then be fed through the same parsing and filtering as live traffic:
+//! [`listen`] yields what [`mDNSListener::listen`](crate::mDNSListener::listen)
+//! would have, and [`discover`] yields what
+//! [`Discovery::listen`](crate::discover::Discovery::listen) would have.
+//!
+//! Going the other way, a [`Recorder`] captures the datagrams a discovery,
+//! responder, gateway or reflection sends and receives into a pcapng file,
+//! without needing tcpdump or root.
+//!
+//! ```rust,no_run
+//! use futures_util::{pin_mut, stream::StreamExt};
+//!
+//! async fn replay() -> Result<(), mdns::Error> {
+//!     let capture = mdns::pcap::Reader::open("customer-site.pcapng")?;
+//!     let stream = mdns::pcap::discover(capture, "_googlecast._tcp.local");
+//!     pin_mut!(stream);
+//!
+//!     while let Some(response) = stream.next().await {
+//!         println!("{:?}", response?);
+//!     }
+//!
+//!     Ok(())
+//! }
+//! ```
+
+use crate::proto::{Event, Querier, MULTICAST_PORT};
+use crate::{Error, MalformedPacket, Response};
+
+use futures_core::Stream;
+use futures_util::stream;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+mod writer;
+
+pub(crate) use self::writer::TapSlot;
+pub use self::writer::{Direction, Recorder, Writer};
+
+/// An mDNS datagram found in a capture.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Datagram {
+    /// When the packet was captured, relative to the Unix epoch.
+    pub timestamp: Duration,
+    /// The address the datagram was sent from.
+    pub source: SocketAddr,
+    /// The address the datagram was sent to.
+    pub destination: SocketAddr,
+    /// The UDP payload, which should be a DNS packet.
+    pub payload: Vec<u8>,
+}
+
+/// Reads mDNS datagrams out of a pcap or pcapng capture.
+///
+/// Packets that are not UDP to or from port 5353, or that cannot be decoded,
+/// are skipped.
+#[derive(Debug)]
+pub struct Reader<R> {
+    input: R,
+    format: Format,
+}
+
+#[derive(Debug)]
+enum Format {
+    Pcap {
+        endian: Endian,
+        nanos: bool,
+        link_type: u32,
+    },
+    PcapNg {
+        endian: Endian,
+        interfaces: Vec<NgInterface>,
+    },
+}
+
+#[derive(Debug)]
+struct NgInterface {
+    link_type: u32,
+    /// The length of a timestamp unit.
+    resolution: Resolution,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Resolution {
+    Decimal(u32),
+    Binary(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Endian {
+    Little,
+    Big,
+}
+
+const PCAP_MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_PACKET: u32 = 2;
+const PCAPNG_SIMPLE_PACKET: u32 = 3;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+
+const OPTION_END: u16 = 0;
+const OPTION_IF_TSRESOL: u16 = 9;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LOOP: u32 = 108;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+
+const IPPROTO_UDP: u8 = 17;
+
+/// The longest record or block read. Captures rarely keep more than 256 KiB
+/// of a frame, so a longer one means the file is corrupt, and reading it
+/// would only allocate whatever its header asks for.
+const MAX_RECORD_LEN: usize = 256 * 1024;
+
+impl Reader<BufReader<File>> {
+    /// Opens a capture file.
+    pub fn open<P>(path: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        Reader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R> Reader<R>
+where
+    R: Read,
+{
+    /// Reads the file header of a capture, detecting whether it is pcap or pcapng.
+    pub fn new(mut input: R) -> Result<Self, Error> {
+        let mut magic = [0; 4];
+        input.read_exact(&mut magic)?;
+
+        let format = if u32::from_le_bytes(magic) == PCAPNG_SECTION_HEADER {
+            let endian = read_section_header(&mut input)?;
+            Format::PcapNg {
+                endian,
+                interfaces: Vec::new(),
+            }
+        } else {
+            let (endian, nanos) = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+                (PCAP_MAGIC_MICROS, _) => (Endian::Little, false),
+                (PCAP_MAGIC_NANOS, _) => (Endian::Little, true),
+                (_, PCAP_MAGIC_MICROS) => (Endian::Big, false),
+                (_, PCAP_MAGIC_NANOS) => (Endian::Big, true),
+                _ => return Err(invalid("not a pcap or pcapng file").into()),
+            };
+
+            let mut header = [0; 20];
+            input.read_exact(&mut header)?;
+            Format::Pcap {
+                endian,
+                nanos,
+                // the upper bits may carry FCS information
+                link_type: endian.u32(&header[16..20]) & 0x0fff_ffff,
+            }
+        };
+
+        Ok(Reader { input, format })
+    }
+
+    /// Reads the next mDNS datagram, or `None` at the end of the capture.
+    pub fn next_datagram(&mut self) -> Result<Option<Datagram>, Error> {
+        loop {
+            let packet = match self.format {
+                Format::Pcap {
+                    endian,
+                    nanos,
+                    link_type,
+                } => read_pcap_record(&mut self.input, endian, nanos, link_type)?,
+                Format::PcapNg {
+                    ref mut endian,
+                    ref mut interfaces,
+                } => read_pcapng_block(&mut self.input, endian, interfaces)?,
+            };
+
+            match packet {
+                Some(Some((timestamp, link_type, frame))) => {
+                    if let Some(datagram) = decode_frame(timestamp, link_type, &frame) {
+                        return Ok(Some(datagram));
+                    }
+                }
+                // a block or record without a packet in it
+                Some(None) => {}
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+impl<R> Iterator for Reader<R>
+where
+    R: Read,
+{
+    type Item = Result<Datagram, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_datagram().transpose()
+    }
+}
+
+/// Replays every response in a capture, like
+/// [`mDNSListener::listen`](crate::mDNSListener::listen).
+pub fn listen<R>(reader: Reader<R>) -> impl Stream<Item = Result<Response, Error>>
+where
+    R: Read,
+{
+    stream::iter(reader.filter_map(|datagram| match datagram {
+        Ok(datagram) => match dns_parser::Packet::parse(&datagram.payload) {
+            Ok(packet) => Some(Ok(Response::from_packet(&packet))),
+            Err(e) => {
+                let packet = MalformedPacket::new(&datagram.payload, &e);
+                log::warn!("malformed packet from {}: {}", datagram.source, packet);
+                None
+            }
+        },
+        Err(e) => Some(Err(e)),
+    }))
+}
+
+/// Replays the responses to a service name in a capture, like
+/// [`Discovery::listen`](crate::discover::Discovery::listen).
+pub fn discover<R, S>(
+    reader: Reader<R>,
+    service_name: S,
+) -> impl Stream<Item = Result<Response, Error>>
+where
+    R: Read,
+    S: AsRef<str>,
+{
+    // the queries in the capture were already sent, so the interval is irrelevant
+    let querier = Querier::new(service_name, Duration::from_secs(3600));
+    stream::iter(Replay::new(reader, querier).map(|event| event.map(|(_, response)| response)))
+}
+
+/// Drives a [`Querier`] with the datagrams of a capture.
+///
+/// The querier's clock follows the capture timestamps, starting at the
+/// instant the replay was created, so timers fire as they would have during
+/// the capture. The queries the querier would have sent are discarded.
+#[derive(Debug)]
+pub struct Replay<R> {
+    reader: Reader<R>,
+    querier: Querier,
+    /// The first capture timestamp, and the virtual instant matching it.
+    epoch: Option<(Duration, Instant)>,
+    /// The capture timestamp of the last datagram fed to the querier.
+    timestamp: Duration,
+}
+
+impl<R> Replay<R>
+where
+    R: Read,
+{
+    /// Starts replaying a capture through a querier.
+    pub fn new(reader: Reader<R>, querier: Querier) -> Self {
+        Replay {
+            reader,
+            querier,
+            epoch: None,
+            timestamp: Duration::default(),
+        }
+    }
+
+    /// The querier being driven.
+    pub fn querier(&self) -> &Querier {
+        &self.querier
+    }
+}
+
+impl<R> Iterator for Replay<R>
+where
+    R: Read,
+{
+    /// Each response with the capture timestamp of the datagram it came in.
+    type Item = Result<(Duration, Response), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.querier.poll_event() {
+                Some(Event::Response(response)) => return Some(Ok((self.timestamp, response))),
+                Some(Event::Malformed { source, packet }) => {
+                    log::warn!("malformed packet from {}: {}", source, packet);
+                    continue;
+                }
+                None => {}
+            }
+
+            let datagram = match self.reader.next_datagram() {
+                Ok(Some(datagram)) => datagram,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            };
+
+            let (first, start) = *self
+                .epoch
+                .get_or_insert_with(|| (datagram.timestamp, Instant::now()));
+            // captures aren't always in order, but the clock can't go backwards
+            let now = start + datagram.timestamp.checked_sub(first).unwrap_or_default();
+            self.timestamp = datagram.timestamp;
+
+            self.querier
+                .handle_datagram(now, datagram.source, &datagram.payload);
+            while self.querier.poll_transmit().is_some() {}
+        }
+    }
+}
+
+impl Endian {
+    fn u16(self, bytes: &[u8]) -> u16 {
+        let bytes = [bytes[0], bytes[1]];
+        match self {
+            Endian::Little => u16::from_le_bytes(bytes),
+            Endian::Big => u16::from_be_bytes(bytes),
+        }
+    }
+
+    fn u32(self, bytes: &[u8]) -> u32 {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        match self {
+            Endian::Little => u32::from_le_bytes(bytes),
+            Endian::Big => u32::from_be_bytes(bytes),
+        }
+    }
+}
+
+impl Resolution {
+    fn to_duration(self, ticks: u64) -> Duration {
+        match self {
+            Resolution::Decimal(exponent) => {
+                let per_second = 10u64.pow(exponent);
+                let nanos = (ticks % per_second) as u128 * 1_000_000_000 / per_second as u128;
+                Duration::new(ticks / per_second, nanos as u32)
+            }
+            Resolution::Binary(exponent) => {
+                let per_second = 1u64 << exponent;
+                let nanos = (ticks % per_second) as u128 * 1_000_000_000 / per_second as u128;
+                Duration::new(ticks / per_second, nanos as u32)
+            }
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads exactly `buf.len()` bytes, or nothing at the end of the input.
+fn read_or_eof<R: Read>(input: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(count) => filled += count,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+/// Reads the rest of a section header block, whose type has already been read.
+fn read_section_header<R: Read>(input: &mut R) -> Result<Endian, Error> {
+    let mut header = [0; 8];
+    input.read_exact(&mut header)?;
+
+    let endian = match u32::from_le_bytes([header[4], header[5], header[6], header[7]]) {
+        PCAPNG_BYTE_ORDER_MAGIC => Endian::Little,
+        _ if u32::from_be_bytes([header[4], header[5], header[6], header[7]])
+            == PCAPNG_BYTE_ORDER_MAGIC =>
+        {
+            Endian::Big
+        }
+        _ => return Err(invalid("bad pcapng byte-order magic").into()),
+    };
+
+    let total_length = endian.u32(&header[0..4]) as usize;
+    if total_length < 12 || total_length % 4 != 0 || total_length > MAX_RECORD_LEN {
+        return Err(invalid("bad pcapng section header length").into());
+    }
+    let mut rest = vec![0; total_length - 12];
+    input.read_exact(&mut rest)?;
+
+    Ok(endian)
+}
+
+type Packet = (Duration, u32, Vec<u8>);
+
+fn read_pcap_record<R: Read>(
+    input: &mut R,
+    endian: Endian,
+    nanos: bool,
+    link_type: u32,
+) -> Result<Option<Option<Packet>>, Error> {
+    let mut header = [0; 16];
+    if !read_or_eof(input, &mut header)? {
+        return Ok(None);
+    }
+
+    let seconds = endian.u32(&header[0..4]);
+    let fraction = endian.u32(&header[4..8]);
+    let captured = endian.u32(&header[8..12]) as usize;
+    if captured > MAX_RECORD_LEN {
+        return Err(invalid("pcap record too long").into());
+    }
+
+    let mut frame = vec![0; captured];
+    input.read_exact(&mut frame)?;
+
+    let fraction = if nanos {
+        fraction
+    } else {
+        fraction.saturating_mul(1000)
+    };
+    let timestamp = Duration::new(seconds.into(), fraction.min(999_999_999));
+    Ok(Some(Some((timestamp, link_type, frame))))
+}
+
+fn read_pcapng_block<R: Read>(
+    input: &mut R,
+    endian: &mut Endian,
+    interfaces: &mut Vec<NgInterface>,
+) -> Result<Option<Option<Packet>>, Error> {
+    let mut header = [0; 8];
+    if !read_or_eof(input, &mut header)? {
+        return Ok(None);
+    }
+
+    let block_type = endian.u32(&header[0..4]);
+    if block_type == PCAPNG_SECTION_HEADER {
+        // a new section may switch byte order and always resets the interfaces
+        let mut section = io::Cursor::new(header[4..8].to_vec()).chain(input);
+        *endian = read_section_header(&mut section)?;
+        interfaces.clear();
+        return Ok(Some(None));
+    }
+
+    let total_length = endian.u32(&header[4..8]) as usize;
+    if total_length < 12 || total_length % 4 != 0 || total_length > MAX_RECORD_LEN {
+        return Err(invalid("bad pcapng block length").into());
+    }
+    let mut body = vec![0; total_length - 8];
+    input.read_exact(&mut body)?;
+    let body = &body[..body.len() - 4];
+    let endian = *endian;
+
+    let too_short = || Error::from(invalid("truncated pcapng block"));
+
+    match block_type {
+        PCAPNG_INTERFACE_DESCRIPTION => {
+            if body.len() < 8 {
+                return Err(too_short());
+            }
+            let mut resolution = Resolution::Decimal(6);
+            let mut options = &body[8..];
+            while options.len() >= 4 {
+                let code = endian.u16(&options[0..2]);
+                let length = endian.u16(&options[2..4]) as usize;
+                let value = options.get(4..4 + length).ok_or_else(too_short)?;
+                match code {
+                    OPTION_END => break,
+                    OPTION_IF_TSRESOL if length == 1 => {
+                        resolution = if value[0] & 0x80 == 0 {
+                            Resolution::Decimal(u32::from(value[0]).min(19))
+                        } else {
+                            Resolution::Binary(u32::from(value[0] & 0x7f).min(63))
+                        };
+                    }
+                    _ => {}
+                }
+                options = options
+                    .get((4 + length + 3) / 4 * 4..)
+                    .ok_or_else(too_short)?;
+            }
+
+            interfaces.push(NgInterface {
+                link_type: u32::from(endian.u16(&body[0..2])),
+                resolution,
+            });
+            Ok(Some(None))
+        }
+        PCAPNG_ENHANCED_PACKET | PCAPNG_PACKET => {
+            if body.len() < 20 {
+                return Err(too_short());
+            }
+            let interface_id = if block_type == PCAPNG_PACKET {
+                endian.u16(&body[0..2]) as usize
+            } else {
+                endian.u32(&body[0..4]) as usize
+            };
+            let interface = interfaces
+                .get(interface_id)
+                .ok_or_else(|| Error::from(invalid("packet on an undescribed interface")))?;
+
+            let ticks =
+                (u64::from(endian.u32(&body[4..8])) << 32) | u64::from(endian.u32(&body[8..12]));
+            let captured = endian.u32(&body[12..16]) as usize;
+            let frame = body.get(20..20 + captured).ok_or_else(too_short)?;
+
+            Ok(Some(Some((
+                interface.resolution.to_duration(ticks),
+                interface.link_type,
+                frame.to_vec(),
+            ))))
+        }
+        PCAPNG_SIMPLE_PACKET => {
+            let interface = interfaces
+                .first()
+                .ok_or_else(|| Error::from(invalid("packet on an undescribed interface")))?;
+            let original = endian.u32(body.get(0..4).ok_or_else(too_short)?) as usize;
+            let frame = &body[4..];
+            let frame = &frame[..original.min(frame.len())];
+
+            // simple packets carry no timestamp
+            Ok(Some(Some((
+                Duration::default(),
+                interface.link_type,
+                frame.to_vec(),
+            ))))
+        }
+        _ => Ok(Some(None)),
+    }
+}
+
+/// Pulls an mDNS datagram out of a link-layer frame.
+fn decode_frame(timestamp: Duration, link_type: u32, frame: &[u8]) -> Option<Datagram> {
+    let packet = match link_type {
+        LINKTYPE_ETHERNET => {
+            let mut ethertype = u16::from_be_bytes([*frame.get(12)?, *frame.get(13)?]);
+            let mut offset = 14;
+            while ethertype == ETHERTYPE_VLAN || ethertype == ETHERTYPE_QINQ {
+                ethertype = u16::from_be_bytes([*frame.get(offset + 2)?, *frame.get(offset + 3)?]);
+                offset += 4;
+            }
+            match ethertype {
+                ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => frame.get(offset..)?,
+                _ => return None,
+            }
+        }
+        // the address family is in host byte order, but the IP version
+        // nibble tells us all we need
+        LINKTYPE_NULL | LINKTYPE_LOOP => frame.get(4..)?,
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => frame,
+        LINKTYPE_LINUX_SLL => frame.get(16..)?,
+        LINKTYPE_LINUX_SLL2 => frame.get(20..)?,
+        _ => return None,
+    };
+
+    let (source, destination, segment) = match packet.first()? >> 4 {
+        4 => decode_ipv4(packet)?,
+        6 => decode_ipv6(packet)?,
+        _ => return None,
+    };
+
+    let source_port = u16::from_be_bytes([*segment.first()?, *segment.get(1)?]);
+    let destination_port = u16::from_be_bytes([*segment.get(2)?, *segment.get(3)?]);
+    if source_port != MULTICAST_PORT && destination_port != MULTICAST_PORT {
+        return None;
+    }
+
+    let length = u16::from_be_bytes([*segment.get(4)?, *segment.get(5)?]) as usize;
+    let payload = segment.get(8..length.max(8).min(segment.len()))?;
+
+    Some(Datagram {
+        timestamp,
+        source: SocketAddr::new(source, source_port),
+        destination: SocketAddr::new(destination, destination_port),
+        payload: payload.to_vec(),
+    })
+}
+
+fn decode_ipv4(packet: &[u8]) -> Option<(IpAddr, IpAddr, &[u8])> {
+    let header_length = usize::from(packet.first()? & 0x0f) * 4;
+    let total_length = u16::from_be_bytes([*packet.get(2)?, *packet.get(3)?]) as usize;
+    let flags_and_offset = u16::from_be_bytes([*packet.get(6)?, *packet.get(7)?]);
+
+    // fragments other than a complete datagram can't be decoded on their own
+    let more_fragments = flags_and_offset & 0x2000 != 0;
+    if more_fragments || flags_and_offset & 0x1fff != 0 || *packet.get(9)? != IPPROTO_UDP {
+        return None;
+    }
+
+    let address = |offset: usize| -> Option<IpAddr> {
+        let octets: [u8; 4] = packet.get(offset..offset + 4)?.try_into().ok()?;
+        Some(Ipv4Addr::from(octets).into())
+    };
+
+    let end = total_length.max(header_length).min(packet.len());
+    Some((address(12)?, address(16)?, packet.get(header_length..end)?))
+}
+
+fn decode_ipv6(packet: &[u8]) -> Option<(IpAddr, IpAddr, &[u8])> {
+    let payload_length = u16::from_be_bytes([*packet.get(4)?, *packet.get(5)?]) as usize;
+    let mut next_header = *packet.get(6)?;
+
+    let address = |offset: usize| -> Option<IpAddr> {
+        let octets: [u8; 16] = packet.get(offset..offset + 16)?.try_into().ok()?;
+        Some(Ipv6Addr::from(octets).into())
+    };
+
+    let end = (40 + payload_length).min(packet.len());
+    let mut offset = 40;
+    loop {
+        match next_header {
+            IPPROTO_UDP => break,
+            // hop-by-hop, routing and destination options
+            0 | 43 | 60 => {
+                next_header = *packet.get(offset)?;
+                offset += (usize::from(*packet.get(offset + 1)?) + 1) * 8;
+            }
+            // a fragment header; only unfragmented datagrams are decoded
+            44 => {
+                let fragment =
+                    u16::from_be_bytes([*packet.get(offset + 2)?, *packet.get(offset + 3)?]);
+                if fragment != 0 {
+                    return None;
+                }
+                next_header = *packet.get(offset)?;
+                offset += 8;
+            }
+            _ => return None,
+        }
+    }
+
+    Some((address(8)?, address(24)?, packet.get(offset..end)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A little-endian pcap header for Ethernet frames.
+    fn pcap_header() -> Vec<u8> {
+        let mut file = PCAP_MAGIC_MICROS.to_le_bytes().to_vec();
+        file.extend_from_slice(&[2, 0, 4, 0]);
+        file.extend_from_slice(&[0; 8]);
+        file.extend_from_slice(&65535u32.to_le_bytes());
+        file.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        file
+    }
+
+    fn pcap_record(fraction: u32, captured: u32, frame: &[u8]) -> Vec<u8> {
+        let mut record = 1u32.to_le_bytes().to_vec();
+        record.extend_from_slice(&fraction.to_le_bytes());
+        record.extend_from_slice(&captured.to_le_bytes());
+        record.extend_from_slice(&captured.to_le_bytes());
+        record.extend_from_slice(frame);
+        record
+    }
+
+    /// A little-endian pcapng section header block.
+    fn section_header() -> Vec<u8> {
+        let mut block = PCAPNG_SECTION_HEADER.to_le_bytes().to_vec();
+        block.extend_from_slice(&28u32.to_le_bytes());
+        block.extend_from_slice(&PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes());
+        block.extend_from_slice(&[1, 0, 0, 0]);
+        block.extend_from_slice(&u64::MAX.to_le_bytes());
+        block.extend_from_slice(&28u32.to_le_bytes());
+        block
+    }
+
+    fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+        let total_length = (12 + body.len()) as u32;
+        let mut block = block_type.to_le_bytes().to_vec();
+        block.extend_from_slice(&total_length.to_le_bytes());
+        block.extend_from_slice(body);
+        block.extend_from_slice(&total_length.to_le_bytes());
+        block
+    }
+
+    fn read_all(file: &[u8]) -> Result<Vec<Datagram>, Error> {
+        Reader::new(file)?.collect()
+    }
+
+    #[test]
+    fn truncated_pcap_header() {
+        assert!(read_all(&pcap_header()[..10]).is_err());
+    }
+
+    #[test]
+    fn oversized_pcap_record() {
+        let mut file = pcap_header();
+        file.extend(pcap_record(0, u32::MAX, &[]));
+        assert!(read_all(&file).is_err());
+    }
+
+    #[test]
+    fn truncated_pcap_record() {
+        let mut file = pcap_header();
+        file.extend(pcap_record(0, 64, &[0; 10]));
+        assert!(read_all(&file).is_err());
+    }
+
+    #[test]
+    fn out_of_range_pcap_timestamp() {
+        let mut file = pcap_header();
+        file.extend(pcap_record(u32::MAX, 4, &[0; 4]));
+        assert_eq!(read_all(&file).unwrap(), []);
+    }
+
+    #[test]
+    fn truncated_pcapng_block() {
+        let mut file = section_header();
+        file.extend(&block(PCAPNG_INTERFACE_DESCRIPTION, &[1, 0, 0, 0, 0, 0, 0, 0])[..14]);
+        assert!(read_all(&file).is_err());
+    }
+
+    #[test]
+    fn oversized_pcapng_block() {
+        let mut file = section_header();
+        file.extend_from_slice(&PCAPNG_ENHANCED_PACKET.to_le_bytes());
+        file.extend_from_slice(&0xffff_fff0u32.to_le_bytes());
+        assert!(read_all(&file).is_err());
+    }
+
+    #[test]
+    fn pcapng_option_past_the_block() {
+        // an if_tsresol option claiming more bytes than the block holds
+        let mut body = vec![1, 0, 0, 0, 0, 0, 0, 0];
+        body.extend_from_slice(&OPTION_IF_TSRESOL.to_le_bytes());
+        body.extend_from_slice(&8u16.to_le_bytes());
+        let mut file = section_header();
+        file.extend(block(PCAPNG_INTERFACE_DESCRIPTION, &body));
+        assert!(read_all(&file).is_err());
+    }
+
+    #[test]
+    fn pcapng_packet_on_missing_interface() {
+        let mut file = section_header();
+        file.extend(block(PCAPNG_ENHANCED_PACKET, &[0; 20]));
+        assert!(read_all(&file).is_err());
+    }
+}
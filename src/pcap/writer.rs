@@ -0,0 +1,431 @@
+use super::{
+    IPPROTO_UDP, LINKTYPE_RAW, OPTION_END, OPTION_IF_TSRESOL, PCAPNG_BYTE_ORDER_MAGIC,
+    PCAPNG_ENHANCED_PACKET, PCAPNG_INTERFACE_DESCRIPTION, PCAPNG_SECTION_HEADER,
+};
+use crate::proto::{MULTICAST_ADDR, MULTICAST_PORT};
+use crate::Error;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const OPTION_SHB_USERAPPL: u16 = 4;
+const OPTION_IF_NAME: u16 = 2;
+const OPTION_EPB_FLAGS: u16 = 2;
+
+/// How long recorded datagrams may sit in the buffer before being written
+/// out.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Which way a recorded datagram was travelling.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Received from the network.
+    Inbound,
+    /// Sent to the network.
+    Outbound,
+}
+
+/// Writes datagrams to a pcapng file that Wireshark can open.
+///
+/// Every datagram is wrapped in synthesized IP and UDP headers, and tagged
+/// with its direction and the interface it was seen on.
+#[derive(Debug)]
+pub struct Writer<W> {
+    output: W,
+    interfaces: u32,
+}
+
+/// A shareable handle to a pcapng file that live traffic is recorded into.
+///
+/// Pass it to [`Discovery::record`](crate::discover::Discovery::record) to
+/// record every query sent and every datagram received by a discovery, or
+/// to the `record` method of a responder, gateway or reflection.
+///
+/// Datagrams are written out at most a second after they were recorded, on
+/// [`flush`](Self::flush), and once the last handle to the file is dropped.
+#[derive(Clone)]
+pub struct Recorder {
+    output: Arc<Mutex<Output>>,
+}
+
+/// The file behind a recorder.
+struct Output {
+    writer: Writer<Box<dyn Write + Send>>,
+    last_flush: Instant,
+}
+
+/// Records the traffic of a single socket.
+#[derive(Clone, Debug)]
+pub(crate) struct Tap {
+    recorder: Recorder,
+    interface: u32,
+    local_addr: IpAddr,
+    /// The group and port the socket talks to.
+    group: SocketAddr,
+}
+
+/// Where the traffic of a socket is recorded, once a recorder is attached.
+///
+/// The sender and listener of a socket share one, so that a recorder
+/// attached after they were handed to a running task still sees their
+/// traffic.
+#[derive(Clone, Debug)]
+pub(crate) struct TapSlot {
+    local_addr: IpAddr,
+    group: SocketAddr,
+    tap: Arc<Mutex<Option<Tap>>>,
+}
+
+impl<W> Writer<W>
+where
+    W: Write,
+{
+    /// Starts a pcapng file by writing its section header.
+    pub fn new(mut output: W) -> io::Result<Self> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // the section length is unknown
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        write_option(&mut body, OPTION_SHB_USERAPPL, b"mdns-rs");
+        write_option(&mut body, OPTION_END, &[]);
+
+        write_block(&mut output, PCAPNG_SECTION_HEADER, &body)?;
+        Ok(Writer {
+            output,
+            interfaces: 0,
+        })
+    }
+
+    /// Describes a new interface, returning the identifier to write its
+    /// datagrams with.
+    pub fn add_interface(&mut self, name: &str) -> io::Result<u32> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&(LINKTYPE_RAW as u16).to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // no snapshot length limit
+        body.extend_from_slice(&0u32.to_le_bytes());
+        write_option(&mut body, OPTION_IF_NAME, name.as_bytes());
+        // nanosecond timestamps
+        write_option(&mut body, OPTION_IF_TSRESOL, &[9]);
+        write_option(&mut body, OPTION_END, &[]);
+
+        write_block(&mut self.output, PCAPNG_INTERFACE_DESCRIPTION, &body)?;
+        self.interfaces += 1;
+        Ok(self.interfaces - 1)
+    }
+
+    /// Writes a single UDP datagram.
+    pub fn write_datagram(
+        &mut self,
+        interface: u32,
+        direction: Direction,
+        timestamp: SystemTime,
+        source: SocketAddr,
+        destination: SocketAddr,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let packet = ip_packet(source, destination, payload);
+        let nanos = timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+
+        let mut body = Vec::with_capacity(packet.len() + 40);
+        body.extend_from_slice(&interface.to_le_bytes());
+        body.extend_from_slice(&((nanos >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(nanos as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&packet);
+        pad(&mut body);
+
+        let flags: u32 = match direction {
+            Direction::Inbound => 0b01,
+            Direction::Outbound => 0b10,
+        };
+        write_option(&mut body, OPTION_EPB_FLAGS, &flags.to_le_bytes());
+        write_option(&mut body, OPTION_END, &[]);
+
+        write_block(&mut self.output, PCAPNG_ENHANCED_PACKET, &body)
+    }
+
+    /// Flushes the underlying writer.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+impl Recorder {
+    /// Creates (or truncates) a pcapng file to record into.
+    pub fn create<P>(path: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        Recorder::new(BufWriter::new(File::create(path)?))
+    }
+
+    /// Records into an arbitrary writer.
+    pub fn new<W>(output: W) -> Result<Self, Error>
+    where
+        W: Write + Send + 'static,
+    {
+        let output: Box<dyn Write + Send> = Box::new(output);
+        Ok(Recorder {
+            output: Arc::new(Mutex::new(Output {
+                writer: Writer::new(output)?,
+                last_flush: Instant::now(),
+            })),
+        })
+    }
+
+    /// Writes out the datagrams recorded so far.
+    pub fn flush(&self) -> Result<(), Error> {
+        let mut output = self.output.lock().unwrap();
+        output.last_flush = Instant::now();
+        Ok(output.writer.flush()?)
+    }
+
+    /// Describes a socket bound to `local_addr` and talking to `group` as a
+    /// new interface.
+    fn tap(&self, local_addr: IpAddr, group: SocketAddr) -> Result<Tap, Error> {
+        let name = format!("mdns {}", local_addr);
+        let interface = self.output.lock().unwrap().writer.add_interface(&name)?;
+
+        Ok(Tap {
+            recorder: self.clone(),
+            interface,
+            local_addr,
+            group,
+        })
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        if let Err(e) = self.writer.flush() {
+            log::warn!("failed to flush recorded datagrams: {}", e);
+        }
+    }
+}
+
+impl std::fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Recorder").finish()
+    }
+}
+
+impl TapSlot {
+    /// A slot for a socket bound to `local_addr` and talking to `group`.
+    pub(crate) fn new(local_addr: IpAddr, group: SocketAddr) -> Self {
+        TapSlot {
+            local_addr,
+            group,
+            tap: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Starts recording the socket's traffic into `recorder`, as a new
+    /// interface.
+    pub(crate) fn attach(&self, recorder: &Recorder) -> Result<(), Error> {
+        let tap = recorder.tap(self.local_addr, self.group)?;
+        *self.tap.lock().unwrap() = Some(tap);
+        Ok(())
+    }
+
+    /// Records a datagram sent to `destination`, if a recorder is attached.
+    pub(crate) fn outbound(&self, destination: SocketAddr, payload: &[u8]) {
+        if let Some(ref tap) = *self.tap.lock().unwrap() {
+            tap.outbound(destination, payload);
+        }
+    }
+
+    /// Records a datagram received from `source`, if a recorder is attached.
+    pub(crate) fn inbound(&self, source: SocketAddr, payload: &[u8]) {
+        if let Some(ref tap) = *self.tap.lock().unwrap() {
+            tap.inbound(source, payload);
+        }
+    }
+}
+
+impl Default for TapSlot {
+    /// A slot for a socket on the standard mDNS group and port.
+    fn default() -> Self {
+        TapSlot::new(
+            Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::new(MULTICAST_ADDR.into(), MULTICAST_PORT),
+        )
+    }
+}
+
+impl Tap {
+    /// Records a datagram sent to `destination`, from the socket's port.
+    fn outbound(&self, destination: SocketAddr, payload: &[u8]) {
+        let source = SocketAddr::new(self.local_ip(destination.ip()), self.group.port());
+        self.write(Direction::Outbound, source, destination, payload);
+    }
+
+    /// Records a datagram received from `source`.
+    ///
+    /// The destination isn't known, so the group is recorded.
+    fn inbound(&self, source: SocketAddr, payload: &[u8]) {
+        let group = match (source.ip(), self.group.ip()) {
+            (IpAddr::V4(_), IpAddr::V4(group)) => group.into(),
+            (IpAddr::V6(_), IpAddr::V6(group)) => group.into(),
+            (IpAddr::V4(_), _) => MULTICAST_ADDR.into(),
+            (IpAddr::V6(_), _) => Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb).into(),
+        };
+        let destination = SocketAddr::new(group, self.group.port());
+        self.write(Direction::Inbound, source, destination, payload);
+    }
+
+    fn local_ip(&self, remote: IpAddr) -> IpAddr {
+        match (self.local_addr, remote) {
+            (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => self.local_addr,
+            (_, IpAddr::V4(_)) => Ipv4Addr::UNSPECIFIED.into(),
+            (_, IpAddr::V6(_)) => Ipv6Addr::UNSPECIFIED.into(),
+        }
+    }
+
+    fn write(
+        &self,
+        direction: Direction,
+        source: SocketAddr,
+        destination: SocketAddr,
+        payload: &[u8],
+    ) {
+        let mut output = self.recorder.output.lock().unwrap();
+        let mut result = output.writer.write_datagram(
+            self.interface,
+            direction,
+            SystemTime::now(),
+            source,
+            destination,
+            payload,
+        );
+
+        let now = Instant::now();
+        if result.is_ok() && now.saturating_duration_since(output.last_flush) >= FLUSH_INTERVAL {
+            output.last_flush = now;
+            result = output.writer.flush();
+        }
+
+        if let Err(e) = result {
+            log::warn!("failed to record datagram: {}", e);
+        }
+    }
+}
+
+fn write_block<W: Write>(output: &mut W, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let total_length = (body.len() + 12) as u32;
+    output.write_all(&block_type.to_le_bytes())?;
+    output.write_all(&total_length.to_le_bytes())?;
+    output.write_all(body)?;
+    output.write_all(&total_length.to_le_bytes())
+}
+
+fn write_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    pad(body);
+}
+
+fn pad(body: &mut Vec<u8>) {
+    while body.len() % 4 != 0 {
+        body.push(0);
+    }
+}
+
+/// Wraps a UDP payload in UDP and IP headers.
+fn ip_packet(source: SocketAddr, destination: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let udp_length = (payload.len() + 8) as u16;
+    let mut udp = Vec::with_capacity(payload.len() + 8);
+    udp.extend_from_slice(&source.port().to_be_bytes());
+    udp.extend_from_slice(&destination.port().to_be_bytes());
+    udp.extend_from_slice(&udp_length.to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(payload);
+
+    let mut packet = Vec::with_capacity(udp.len() + 40);
+    let pseudo_header = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            let total_length = (udp.len() + 20) as u16;
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&total_length.to_be_bytes());
+            // identification, don't fragment, TTL 255, checksum
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 255, IPPROTO_UDP, 0, 0]);
+            packet.extend_from_slice(&source.octets());
+            packet.extend_from_slice(&destination.octets());
+            let checksum = checksum(&[&packet]);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+            let mut pseudo = Vec::with_capacity(12);
+            pseudo.extend_from_slice(&source.octets());
+            pseudo.extend_from_slice(&destination.octets());
+            pseudo.extend_from_slice(&[0, IPPROTO_UDP]);
+            pseudo.extend_from_slice(&udp_length.to_be_bytes());
+            pseudo
+        }
+        (source, destination) => {
+            let source = to_ipv6(source);
+            let destination = to_ipv6(destination);
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&udp_length.to_be_bytes());
+            // next header, hop limit
+            packet.extend_from_slice(&[IPPROTO_UDP, 255]);
+            packet.extend_from_slice(&source.octets());
+            packet.extend_from_slice(&destination.octets());
+
+            let mut pseudo = Vec::with_capacity(40);
+            pseudo.extend_from_slice(&source.octets());
+            pseudo.extend_from_slice(&destination.octets());
+            pseudo.extend_from_slice(&u32::from(udp_length).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, IPPROTO_UDP]);
+            pseudo
+        }
+    };
+
+    let udp_checksum = match checksum(&[&pseudo_header, &udp]) {
+        // zero means "no checksum"
+        0 => 0xffff,
+        sum => sum,
+    };
+    udp[6..8].copy_from_slice(&udp_checksum.to_be_bytes());
+
+    packet.extend_from_slice(&udp);
+    packet
+}
+
+fn to_ipv6(addr: IpAddr) -> Ipv6Addr {
+    match addr {
+        IpAddr::V4(addr) => addr.to_ipv6_mapped(),
+        IpAddr::V6(addr) => addr,
+    }
+}
+
+/// The internet checksum of the concatenation of `parts`, each of which
+/// but the last must have an even length.
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    for part in parts {
+        for chunk in part.chunks(2) {
+            let word = match *chunk {
+                [high, low] => u16::from_be_bytes([high, low]),
+                [high] => u16::from_be_bytes([high, 0]),
+                _ => unreachable!(),
+            };
+            sum += u32::from(word);
+        }
+    }
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
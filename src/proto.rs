@@ -0,0 +1,268 @@
+//! A sans-IO implementation of the mDNS query protocol.
+//!
+//! Nothing in this module touches a socket, a timer or an executor. The
+//! [`Querier`] state machine is fed incoming datagrams together with the current
+//! time, and in exchange hands back the datagrams it wants sent, the events it
+//! produced and the next instant at which it wants to be woken up.
+//!
+//! This makes it possible to drive the protocol from any event loop, and to
+//! test it deterministically by supplying the clock yourself.
+//!
+//! ```rust
+//! use mdns::proto::{Event, Querier};
+//! use std::time::{Duration, Instant};
+//!
+//! let start = Instant::now();
+//! let mut querier = Querier::new("_googlecast._tcp.local", Duration::from_secs(15));
+//!
+//! // The first query goes out immediately.
+//! querier.handle_timeout(start);
+//! assert!(querier.poll_transmit().is_some());
+//! assert!(querier.poll_transmit().is_none());
+//!
+//! // The next one is due a full interval later.
+//! assert_eq!(querier.poll_timeout(), Some(start + Duration::from_secs(15)));
+//! querier.handle_timeout(start + Duration::from_secs(15));
+//! assert!(querier.poll_transmit().is_some());
+//! # assert!(querier.poll_event().is_none());
+//! ```
+
+use self::filter::Filter;
+use self::reassembly::Reassembler;
+use crate::{MalformedPacket, Response};
+
+pub mod cache;
+pub mod filter;
+pub mod gateway;
+pub mod instance;
+pub mod reassembly;
+pub mod reflector;
+pub mod responder;
+mod splice;
+pub mod watch;
+pub mod wire;
+
+use std::collections::VecDeque;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// The IP address for the mDNS multicast socket.
+pub const MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+/// The UDP port mDNS traffic is sent to and received from.
+pub const MULTICAST_PORT: u16 = 5353;
+/// The flag marking a DNS message as a response, in its third byte.
+const RESPONSE_FLAG: u8 = 0x80;
+
+/// A datagram the state machine wants sent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transmit {
+    /// Where the datagram should be sent to.
+    pub destination: SocketAddr,
+    /// The encoded DNS packet.
+    pub contents: Vec<u8>,
+}
+
+/// Something the state machine wants to report to its user.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// A response that answers the question being asked.
+    Response(Response),
+    /// A datagram that couldn't be parsed. Nothing else is affected by it.
+    Malformed {
+        /// Where the datagram came from.
+        source: SocketAddr,
+        /// What was wrong with it.
+        packet: MalformedPacket,
+    },
+}
+
+/// The state machine behind a discovery of a single service name.
+///
+/// The querier multicasts a question for the service name every query
+/// interval, and reports each received response that answers it. The
+/// question asks for `PTR` records unless told otherwise.
+#[derive(Clone, Debug)]
+pub struct Querier {
+    service_name: String,
+    query_type: dns_parser::QueryType,
+    query_interval: Duration,
+
+    /// Whether we should ignore empty responses.
+    ignore_empty: bool,
+    /// What responses must match, besides answering the question.
+    filters: Vec<Filter>,
+
+    /// When the next query should be sent, or `None` if one should be sent as
+    /// soon as we are given the time.
+    next_query: Option<Instant>,
+
+    /// Joins responses split across several datagrams.
+    reassembler: Reassembler,
+    transmits: VecDeque<Transmit>,
+    events: VecDeque<Event>,
+}
+
+impl Querier {
+    /// Creates a querier for the given service name.
+    pub fn new<S>(service_name: S, query_interval: Duration) -> Self
+    where
+        S: AsRef<str>,
+    {
+        Querier {
+            service_name: service_name.as_ref().to_string(),
+            query_type: dns_parser::QueryType::PTR,
+            query_interval,
+            ignore_empty: true,
+            filters: Vec::new(),
+            next_query: None,
+            reassembler: Reassembler::new(),
+            transmits: VecDeque::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Sets whether or not we should ignore empty responses.
+    ///
+    /// Defaults to `true`.
+    pub fn ignore_empty(mut self, ignore: bool) -> Self {
+        self.ignore_empty = ignore;
+        self
+    }
+
+    /// Sets the type of records to ask for.
+    ///
+    /// Defaults to `PTR`.
+    pub fn query_type(mut self, query_type: dns_parser::QueryType) -> Self {
+        self.query_type = query_type;
+        self
+    }
+
+    /// Only reports responses that match `filter`, on top of answering the
+    /// question. Every filter given must match.
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    /// The service name being queried.
+    pub fn service_name(&self) -> &str {
+        &self.service_name
+    }
+
+    /// Processes a datagram received from `source` at time `now`.
+    ///
+    /// A response with the TC bit set is held back until the rest of it
+    /// arrives, and reported as one.
+    pub fn handle_datagram(&mut self, now: Instant, source: SocketAddr, datagram: &[u8]) {
+        self.handle_timeout(now);
+
+        if datagram.is_empty() {
+            return;
+        }
+
+        self.reassembler.handle_datagram(now, source, datagram);
+        self.handle_reassembled();
+    }
+
+    fn handle_reassembled(&mut self) {
+        while let Some(datagram) = self.reassembler.poll_datagram() {
+            let packet = match dns_parser::Packet::parse(&datagram.contents) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    self.events.push_back(Event::Malformed {
+                        source: datagram.source,
+                        packet: MalformedPacket::new(&datagram.contents, &e),
+                    });
+                    continue;
+                }
+            };
+
+            let source = datagram.source;
+            if !self
+                .filters
+                .iter()
+                .all(|filter| filter.matches(source, &packet))
+            {
+                continue;
+            }
+
+            let response = Response::from_packet(&packet);
+            if self.is_wanted(&response) {
+                self.events.push_back(Event::Response(response));
+            }
+        }
+    }
+
+    /// Advances the state machine's clock to `now`.
+    ///
+    /// This should be called once the instant returned by
+    /// [`poll_timeout`](Self::poll_timeout) is reached, but calling it early
+    /// is harmless.
+    pub fn handle_timeout(&mut self, now: Instant) {
+        self.reassembler.handle_timeout(now);
+        self.handle_reassembled();
+
+        match self.next_query {
+            Some(deadline) if deadline > now => {}
+            _ => {
+                self.transmits.push_back(Transmit {
+                    destination: SocketAddr::new(MULTICAST_ADDR.into(), MULTICAST_PORT),
+                    contents: build_query(&self.service_name, self.query_type),
+                });
+                self.next_query = Some(now + self.query_interval);
+            }
+        }
+    }
+
+    /// Returns the next datagram that should be sent, if any.
+    pub fn poll_transmit(&mut self) -> Option<Transmit> {
+        self.transmits.pop_front()
+    }
+
+    /// Returns the next event produced by the state machine, if any.
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// Returns the instant at which [`handle_timeout`](Self::handle_timeout)
+    /// should next be called.
+    ///
+    /// `None` means the querier has not been started yet, and should be given
+    /// the time straight away.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        let next_query = self.next_query?;
+
+        Some(match self.reassembler.poll_timeout() {
+            Some(deadline) => deadline.min(next_query),
+            None => next_query,
+        })
+    }
+
+    fn is_wanted(&self, response: &Response) -> bool {
+        (!response.is_empty() || !self.ignore_empty)
+            && response
+                .answers
+                .iter()
+                .any(|record| record.name == self.service_name)
+    }
+}
+
+/// Builds a multicast query for the given name.
+pub fn build_query(service_name: &str, query_type: dns_parser::QueryType) -> Vec<u8> {
+    let mut builder = dns_parser::Builder::new_query(0, false);
+    let prefer_unicast = false;
+    builder.add_question(
+        service_name,
+        prefer_unicast,
+        query_type,
+        dns_parser::QueryClass::IN,
+    );
+    builder.build().unwrap()
+}
+
+/// Whether an encoded DNS message is a response, going by its header alone.
+pub(crate) fn is_response(datagram: &[u8]) -> bool {
+    datagram
+        .get(2)
+        .map_or(false, |flags| flags & RESPONSE_FLAG != 0)
+}
@@ -0,0 +1,344 @@
+//! A cache of the records heard on the network.
+//!
+//! Records are kept for as long as their TTLs allow. A record with the
+//! cache-flush bit set replaces the other records of its name and type that
+//! are more than a second old (RFC 6762 section 10.2), and a record with a
+//! TTL of zero says goodbye for the record it matches, which is dropped a
+//! second later.
+//!
+//! ```rust
+//! use mdns::proto::cache::Cache;
+//! use mdns::proto::wire::{Message, Resource};
+//! use mdns::{Record, RecordKind};
+//! use std::net::Ipv4Addr;
+//! use std::time::{Duration, Instant};
+//!
+//! let address = |last: u8| {
+//!     Resource::unique(Record {
+//!         name: "printer.local".to_owned(),
+//!         class: dns_parser::Class::IN,
+//!         ttl: 120,
+//!         kind: RecordKind::A(Ipv4Addr::new(192, 168, 1, last)),
+//!     })
+//! };
+//! let start = Instant::now();
+//! let mut cache = Cache::new();
+//!
+//! let mut response = Message::response(0);
+//! response.answers.push(address(20));
+//! cache.insert(start, &dns_parser::Packet::parse(&response.encode()).unwrap());
+//!
+//! // The printer moved, and flushes its old address.
+//! let mut response = Message::response(0);
+//! response.answers.push(address(30));
+//! let later = start + Duration::from_secs(60);
+//! cache.insert(later, &dns_parser::Packet::parse(&response.encode()).unwrap());
+//!
+//! let records = cache.records(later, "printer.local", dns_parser::QueryType::A);
+//! assert_eq!(records.len(), 1);
+//! assert_eq!(records[0].kind, RecordKind::A(Ipv4Addr::new(192, 168, 1, 30)));
+//!
+//! assert_eq!(cache.expire(later + Duration::from_secs(120)), 1);
+//! assert!(cache.is_empty());
+//! ```
+
+use super::wire;
+use crate::Record;
+
+use dns_parser::QueryType;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// How long records stay cached after a cache-flush record for the same name
+/// and type arrived (RFC 6762 section 10.2).
+const FLUSH_DELAY: Duration = Duration::from_secs(1);
+
+/// How long a record is kept after its goodbye arrived, so that a record
+/// replacing it can arrive first (RFC 6762 section 10.1).
+const GOODBYE_DELAY: Duration = Duration::from_secs(1);
+
+/// How far into their TTLs records are refreshed.
+const REFRESH_PERCENTAGES: [u32; 4] = [80, 85, 90, 95];
+
+/// The record type code of `ANY` questions.
+const ANY: u16 = 255;
+
+/// A lowercased name without its trailing dot, and a record type.
+pub(crate) type Key = (String, u16);
+
+/// Records by name and type.
+#[derive(Clone, Debug, Default)]
+pub struct Cache {
+    entries: HashMap<Key, Vec<Cached>>,
+}
+
+#[derive(Clone, Debug)]
+struct Cached {
+    record: Record,
+    received: Instant,
+    expires: Instant,
+}
+
+impl Cache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Caches the answers and additional records of a response received at
+    /// time `now`.
+    pub fn insert(&mut self, now: Instant, packet: &dns_parser::Packet) {
+        self.insert_names(now, packet, &[]);
+    }
+
+    /// Like [`insert`](Self::insert), but only caches the records owned by
+    /// one of `names`, or every record if `names` is empty.
+    pub fn insert_names(&mut self, now: Instant, packet: &dns_parser::Packet, names: &[&str]) {
+        let names: Vec<_> = names.iter().map(|name| normalize(name)).collect();
+        let mut flushed = HashSet::new();
+        for rr in packet.answers.iter().chain(&packet.additional) {
+            let record = Record::from_resource_record(rr);
+            let record_type = match wire::record_type(&record.kind) {
+                Some(record_type) => record_type,
+                None => continue,
+            };
+            if !names.is_empty() && !names.contains(&normalize(&record.name)) {
+                continue;
+            }
+
+            let key = key_for(&record.name, record_type);
+            let cached = self.entries.entry(key.clone()).or_default();
+            if record.ttl == 0 {
+                for entry in cached.iter_mut() {
+                    if entry.record.kind == record.kind {
+                        entry.expires = entry.expires.min(now + GOODBYE_DELAY);
+                    }
+                }
+                continue;
+            }
+            if rr.multicast_unique && flushed.insert(key) {
+                cached.retain(|entry| now.saturating_duration_since(entry.received) < FLUSH_DELAY);
+            }
+            cached.retain(|entry| entry.record.kind != record.kind);
+            cached.push(Cached {
+                expires: now + Duration::from_secs(record.ttl.into()),
+                received: now,
+                record,
+            });
+        }
+        self.entries.retain(|_, cached| !cached.is_empty());
+    }
+
+    /// Adds the records of another cache, keeping the most recently received
+    /// copy of any record both have.
+    pub fn merge(&mut self, other: Cache) {
+        for (key, entries) in other.entries {
+            let cached = self.entries.entry(key).or_default();
+            for entry in entries {
+                match cached
+                    .iter_mut()
+                    .find(|cached| cached.record.kind == entry.record.kind)
+                {
+                    Some(cached) if cached.received >= entry.received => {}
+                    Some(cached) => *cached = entry,
+                    None => cached.push(entry),
+                }
+            }
+        }
+    }
+
+    /// The records of a name and type that are still fresh at time `now`,
+    /// with their TTLs brought up to date. `ANY` matches every type.
+    pub fn records(&self, now: Instant, name: &str, query_type: QueryType) -> Vec<Record> {
+        let name = normalize(name);
+        let query_type = query_type as u16;
+
+        self.entries
+            .iter()
+            .filter(|((cached_name, record_type), _)| {
+                *cached_name == name && (query_type == ANY || *record_type == query_type)
+            })
+            .flat_map(|(_, cached)| cached)
+            .filter(|entry| entry.expires > now)
+            .map(|entry| {
+                let remaining = entry.expires.saturating_duration_since(now).as_secs();
+                Record {
+                    ttl: (remaining as u32).max(1),
+                    ..entry.record.clone()
+                }
+            })
+            .collect()
+    }
+
+    /// The records of a name and type, with the TTLs they were received
+    /// with.
+    pub fn get(&self, name: &str, query_type: QueryType) -> Vec<Record> {
+        self.entries
+            .get(&key(name, query_type))
+            .map(|cached| cached.iter().map(|entry| entry.record.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    /// When the records of a name and type should be asked for again to keep
+    /// them from expiring: at 80, 85, 90 and 95% of their TTLs (RFC 6762
+    /// section 5.2). Records that said goodbye aren't asked for.
+    pub fn refresh_times(&self, name: &str, query_type: QueryType) -> Vec<Instant> {
+        self.entries
+            .get(&key(name, query_type))
+            .into_iter()
+            .flatten()
+            .flat_map(|entry| {
+                let ttl = Duration::from_secs(entry.record.ttl.into());
+                REFRESH_PERCENTAGES
+                    .iter()
+                    .map(move |&percent| entry.received + ttl * percent / 100)
+                    .filter(move |&at| at < entry.expires)
+            })
+            .collect()
+    }
+
+    /// When the first of the records of a name and type expires.
+    pub fn expires(&self, name: &str, query_type: QueryType) -> Option<Instant> {
+        self.entries
+            .get(&key(name, query_type))?
+            .iter()
+            .map(|entry| entry.expires)
+            .min()
+    }
+
+    /// Whether any record of a name is cached.
+    pub fn contains_name(&self, name: &str) -> bool {
+        let name = normalize(name);
+        self.entries.keys().any(|(cached, _)| *cached == name)
+    }
+
+    /// The records of the given names, of every type, as a cache of their
+    /// own.
+    pub fn of_names(&self, names: &[&str]) -> Cache {
+        let names: Vec<_> = names.iter().map(|name| normalize(name)).collect();
+        Cache {
+            entries: self
+                .entries
+                .iter()
+                .filter(|((name, _), _)| names.contains(name))
+                .map(|(key, cached)| (key.clone(), cached.clone()))
+                .collect(),
+        }
+    }
+
+    /// Whether nothing is cached.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Drops the records whose TTL ran out by time `now`, returning how many
+    /// there were.
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let mut expired = 0;
+        for cached in self.entries.values_mut() {
+            let before = cached.len();
+            cached.retain(|entry| entry.expires > now);
+            expired += before - cached.len();
+        }
+        self.entries.retain(|_, cached| !cached.is_empty());
+        expired
+    }
+}
+
+pub(crate) fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+pub(crate) fn key(name: &str, query_type: QueryType) -> Key {
+    key_for(name, query_type as u16)
+}
+
+fn key_for(name: &str, record_type: u16) -> Key {
+    (normalize(name), record_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{response, shared, unique};
+    use crate::RecordKind;
+    use std::net::Ipv4Addr;
+
+    fn a(last: u8) -> RecordKind {
+        RecordKind::A(Ipv4Addr::new(192, 168, 1, last))
+    }
+
+    fn insert(cache: &mut Cache, now: Instant, answers: Vec<wire::Resource>) {
+        let datagram = response(answers);
+        let packet = dns_parser::Packet::parse(&datagram).unwrap();
+        cache.insert(now, &packet);
+    }
+
+    fn addresses(cache: &Cache, now: Instant) -> Vec<RecordKind> {
+        let mut kinds: Vec<_> = cache
+            .records(now, "kitchen.local", QueryType::A)
+            .into_iter()
+            .map(|record| record.kind)
+            .collect();
+        kinds.sort_by_key(|kind| format!("{:?}", kind));
+        kinds
+    }
+
+    #[test]
+    fn cache_flush_replaces_older_records() {
+        let start = Instant::now();
+        let mut cache = Cache::new();
+        insert(&mut cache, start, vec![unique("kitchen.local", 120, a(20))]);
+
+        // Records received within a second of each other are kept together.
+        let soon = start + Duration::from_millis(500);
+        insert(&mut cache, soon, vec![unique("kitchen.local", 120, a(21))]);
+        assert_eq!(addresses(&cache, soon), [a(20), a(21)]);
+
+        let later = start + Duration::from_secs(5);
+        insert(&mut cache, later, vec![unique("kitchen.local", 120, a(22))]);
+        assert_eq!(addresses(&cache, later), [a(22)]);
+    }
+
+    #[test]
+    fn shared_records_add_up() {
+        let start = Instant::now();
+        let mut cache = Cache::new();
+        insert(&mut cache, start, vec![shared("kitchen.local", 120, a(20))]);
+        let later = start + Duration::from_secs(5);
+        insert(&mut cache, later, vec![shared("kitchen.local", 120, a(21))]);
+        assert_eq!(addresses(&cache, later), [a(20), a(21)]);
+    }
+
+    #[test]
+    fn goodbyes_expire_records_a_second_later() {
+        let start = Instant::now();
+        let mut cache = Cache::new();
+        insert(&mut cache, start, vec![unique("kitchen.local", 120, a(20))]);
+
+        let goodbye = start + Duration::from_secs(10);
+        insert(&mut cache, goodbye, vec![unique("kitchen.local", 0, a(20))]);
+        assert_eq!(
+            cache.expires("kitchen.local", QueryType::A),
+            Some(goodbye + GOODBYE_DELAY)
+        );
+        assert_eq!(addresses(&cache, goodbye), [a(20)]);
+        assert!(cache
+            .refresh_times("kitchen.local", QueryType::A)
+            .is_empty());
+        assert_eq!(cache.expire(goodbye + GOODBYE_DELAY), 1);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn goodbyes_can_be_taken_back() {
+        let start = Instant::now();
+        let mut cache = Cache::new();
+        insert(&mut cache, start, vec![unique("kitchen.local", 120, a(20))]);
+
+        let goodbye = start + Duration::from_secs(10);
+        insert(&mut cache, goodbye, vec![unique("kitchen.local", 0, a(20))]);
+        let back = goodbye + Duration::from_millis(500);
+        insert(&mut cache, back, vec![unique("kitchen.local", 120, a(20))]);
+        assert_eq!(addresses(&cache, goodbye + GOODBYE_DELAY), [a(20)]);
+    }
+}
@@ -0,0 +1,450 @@
+//! Conditions a response must meet to be reported.
+//!
+//! A [`Filter`] is checked against the parsed packet, before a
+//! [`Response`](crate::Response) is built from it, so responses that don't
+//! match are never built. Each condition added to a filter must hold, and
+//! [`Filter::any`] combines filters of which only one needs to match.
+//!
+//! TXT and SRV conditions are about a single instance: they must all be met
+//! by records with the same owner name, so a packet listing one instance
+//! with the right model and another on the right port doesn't match. The
+//! other conditions look at the packet as a whole.
+//!
+//! ```rust
+//! use mdns::proto::filter::{Filter, TxtPredicate};
+//! use mdns::proto::wire::{Message, Resource};
+//! use mdns::proto::{Event, Querier};
+//! use mdns::{Record, RecordKind, TxtRecordValue};
+//! use std::time::{Duration, Instant};
+//!
+//! let chromecast = |model: &str| {
+//!     let mut txt = std::collections::HashMap::new();
+//!     txt.insert("md".to_owned().into(), TxtRecordValue::Value(model.into()));
+//!
+//!     let mut response = Message::response(0);
+//!     response.answers.push(Resource::shared(Record {
+//!         name: "_googlecast._tcp.local".to_owned(),
+//!         class: dns_parser::Class::IN,
+//!         ttl: 120,
+//!         kind: RecordKind::PTR(format!("{}._googlecast._tcp.local", model)),
+//!     }));
+//!     response.additional.push(Resource::unique(Record {
+//!         name: format!("{}._googlecast._tcp.local", model),
+//!         class: dns_parser::Class::IN,
+//!         ttl: 4500,
+//!         kind: RecordKind::TXT(txt),
+//!     }));
+//!     response.encode()
+//! };
+//!
+//! let mut querier = Querier::new("_googlecast._tcp.local", Duration::from_secs(15))
+//!     .filter(Filter::new().txt("md", TxtPredicate::Equals("Chromecast Ultra".into())));
+//! let source = "192.168.1.20:5353".parse().unwrap();
+//! let now = Instant::now();
+//!
+//! querier.handle_datagram(now, source, &chromecast("Chromecast"));
+//! querier.handle_datagram(now, source, &chromecast("Chromecast Ultra"));
+//!
+//! match querier.poll_event() {
+//!     Some(Event::Response(response)) => {
+//!         assert_eq!(response.hostname(), Some("Chromecast Ultra._googlecast._tcp.local"));
+//!     }
+//!     other => panic!("{:?}", other),
+//! }
+//! assert!(querier.poll_event().is_none());
+//! ```
+
+use dns_parser::{Name, Packet, QueryType, RData, ResourceRecord};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+/// Conditions a response must meet, all of them.
+///
+/// An empty filter matches every response.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    conditions: Vec<Condition>,
+}
+
+/// What a TXT attribute must be like.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TxtPredicate {
+    /// The attribute is present, with or without a value.
+    Exists,
+    /// The value is exactly this.
+    Equals(String),
+    /// The value matches this pattern, in which `*` stands for any number of
+    /// bytes and `?` for any one byte.
+    Glob(String),
+    /// The value is a number below this.
+    LessThan(f64),
+    /// The value is a number no greater than this.
+    AtMost(f64),
+    /// The value is a number above this.
+    GreaterThan(f64),
+    /// The value is a number no less than this.
+    AtLeast(f64),
+}
+
+/// A check supplied by the user.
+type Predicate = dyn Fn(SocketAddr, &Packet) -> bool + Send + Sync;
+
+#[derive(Clone)]
+enum Condition {
+    RecordType(QueryType),
+    SrvPort(u16),
+    SourceSubnet {
+        addr: IpAddr,
+        prefix_len: u8,
+    },
+    Txt {
+        key: String,
+        predicate: TxtPredicate,
+    },
+    Any(Vec<Filter>),
+    Custom(Arc<Predicate>),
+}
+
+impl Filter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Matches if any of `filters` does.
+    pub fn any<I>(filters: I) -> Self
+    where
+        I: IntoIterator<Item = Filter>,
+    {
+        Filter::new().with(Condition::Any(filters.into_iter().collect()))
+    }
+
+    /// Requires a record of the given type in any section.
+    pub fn record_type(self, record_type: QueryType) -> Self {
+        self.with(Condition::RecordType(record_type))
+    }
+
+    /// Requires an SRV record for the given port, owned by the same instance
+    /// as the records of any other TXT or SRV condition.
+    pub fn srv_port(self, port: u16) -> Self {
+        self.with(Condition::SrvPort(port))
+    }
+
+    /// Requires the response to come from an address within
+    /// `addr/prefix_len`.
+    pub fn source_subnet<A>(self, addr: A, prefix_len: u8) -> Self
+    where
+        A: Into<IpAddr>,
+    {
+        self.with(Condition::SourceSubnet {
+            addr: addr.into(),
+            prefix_len,
+        })
+    }
+
+    /// Requires a TXT record whose `key` attribute meets `predicate`, owned
+    /// by the same instance as the records of any other TXT or SRV
+    /// condition. Keys are compared without regard to case (RFC 6763
+    /// section 6.4).
+    pub fn txt<S>(self, key: S, predicate: TxtPredicate) -> Self
+    where
+        S: Into<String>,
+    {
+        self.with(Condition::Txt {
+            key: key.into(),
+            predicate,
+        })
+    }
+
+    /// Requires `matches` to return `true` for the source and the packet.
+    pub fn custom<F>(self, matches: F) -> Self
+    where
+        F: Fn(SocketAddr, &Packet) -> bool + Send + Sync + 'static,
+    {
+        self.with(Condition::Custom(Arc::new(matches)))
+    }
+
+    /// Whether a packet received from `source` meets every condition.
+    pub fn matches(&self, source: SocketAddr, packet: &Packet) -> bool {
+        if self.matches_instance(source, packet, None) {
+            return true;
+        }
+        if !self.about_instances() {
+            return false;
+        }
+
+        // Each instance with TXT or SRV records is tried in turn.
+        let mut owners: Vec<_> = records(packet)
+            .filter(|rr| matches!(rr.data, RData::SRV(..) | RData::TXT(..)))
+            .map(|rr| rr.name.to_string().to_ascii_lowercase())
+            .collect();
+        owners.sort();
+        owners.dedup();
+        owners
+            .iter()
+            .any(|owner| self.matches_instance(source, packet, Some(owner)))
+    }
+
+    fn with(mut self, condition: Condition) -> Self {
+        self.conditions.push(condition);
+        self
+    }
+
+    /// Whether the packet meets every condition, with the TXT and SRV ones
+    /// met by the records of `instance`, or failing if there is none.
+    fn matches_instance(
+        &self,
+        source: SocketAddr,
+        packet: &Packet,
+        instance: Option<&str>,
+    ) -> bool {
+        self.conditions
+            .iter()
+            .all(|condition| condition.matches(source, packet, instance))
+    }
+
+    /// Whether there are TXT or SRV conditions.
+    fn about_instances(&self) -> bool {
+        self.conditions.iter().any(|condition| match *condition {
+            Condition::SrvPort(_) | Condition::Txt { .. } => true,
+            Condition::Any(ref filters) => filters.iter().any(Filter::about_instances),
+            _ => false,
+        })
+    }
+}
+
+impl Condition {
+    fn matches(&self, source: SocketAddr, packet: &Packet, instance: Option<&str>) -> bool {
+        let instance_records = || {
+            records(packet)
+                .filter(move |rr| instance.map_or(false, |name| same_name(&rr.name, name)))
+        };
+
+        match *self {
+            Condition::RecordType(record_type) => {
+                records(packet).any(|rr| query_type(&rr.data) == Some(record_type))
+            }
+            Condition::SrvPort(port) => instance_records()
+                .any(|rr| matches!(rr.data, RData::SRV(ref srv) if srv.port == port)),
+            Condition::SourceSubnet { addr, prefix_len } => {
+                in_subnet(source.ip(), addr, prefix_len)
+            }
+            Condition::Txt {
+                ref key,
+                ref predicate,
+            } => instance_records().any(|rr| match rr.data {
+                RData::TXT(ref txt) => {
+                    attribute(txt, key).map_or(false, |value| predicate.matches(value))
+                }
+                _ => false,
+            }),
+            Condition::Any(ref filters) => filters
+                .iter()
+                .any(|filter| filter.matches_instance(source, packet, instance)),
+            Condition::Custom(ref matches) => matches(source, packet),
+        }
+    }
+}
+
+impl fmt::Debug for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Condition::RecordType(record_type) => {
+                f.debug_tuple("RecordType").field(&record_type).finish()
+            }
+            Condition::SrvPort(port) => f.debug_tuple("SrvPort").field(&port).finish(),
+            Condition::SourceSubnet { addr, prefix_len } => f
+                .debug_struct("SourceSubnet")
+                .field("addr", &addr)
+                .field("prefix_len", &prefix_len)
+                .finish(),
+            Condition::Txt {
+                ref key,
+                ref predicate,
+            } => f
+                .debug_struct("Txt")
+                .field("key", key)
+                .field("predicate", predicate)
+                .finish(),
+            Condition::Any(ref filters) => f.debug_tuple("Any").field(filters).finish(),
+            Condition::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+impl TxtPredicate {
+    /// Whether an attribute with `value`, or without one if `None`, meets
+    /// the predicate.
+    fn matches(&self, value: Option<&[u8]>) -> bool {
+        let number = || {
+            let value = std::str::from_utf8(value?).ok()?;
+            value.trim().parse::<f64>().ok()
+        };
+
+        match *self {
+            TxtPredicate::Exists => true,
+            TxtPredicate::Equals(ref expected) => value == Some(expected.as_bytes()),
+            TxtPredicate::Glob(ref pattern) => {
+                value.map_or(false, |value| glob(pattern.as_bytes(), value))
+            }
+            TxtPredicate::LessThan(bound) => number().map_or(false, |n| n < bound),
+            TxtPredicate::AtMost(bound) => number().map_or(false, |n| n <= bound),
+            TxtPredicate::GreaterThan(bound) => number().map_or(false, |n| n > bound),
+            TxtPredicate::AtLeast(bound) => number().map_or(false, |n| n >= bound),
+        }
+    }
+}
+
+fn records<'a>(packet: &'a Packet) -> impl Iterator<Item = &'a ResourceRecord<'a>> {
+    packet
+        .answers
+        .iter()
+        .chain(packet.nameservers.iter())
+        .chain(packet.additional.iter())
+}
+
+/// The type of a record, or `None` for types `dns_parser` doesn't know.
+fn query_type(data: &RData) -> Option<QueryType> {
+    Some(match *data {
+        RData::A(..) => QueryType::A,
+        RData::AAAA(..) => QueryType::AAAA,
+        RData::CNAME(..) => QueryType::CNAME,
+        RData::MX(..) => QueryType::MX,
+        RData::NS(..) => QueryType::NS,
+        RData::PTR(..) => QueryType::PTR,
+        RData::SOA(..) => QueryType::SOA,
+        RData::SRV(..) => QueryType::SRV,
+        RData::TXT(..) => QueryType::TXT,
+        RData::Unknown(..) => return None,
+    })
+}
+
+/// Looks `key` up in a TXT record, returning `Some(None)` for an attribute
+/// without a value. Only the first occurrence of a key counts (RFC 6763
+/// section 6.4).
+fn attribute<'a>(txt: &dns_parser::rdata::txt::Record<'a>, key: &str) -> Option<Option<&'a [u8]>> {
+    txt.iter()
+        .map(|entry| match entry.iter().position(|&b| b == b'=') {
+            Some(equals) => (&entry[..equals], Some(&entry[equals + 1..])),
+            None => (entry, None),
+        })
+        .find(|(name, _)| name.eq_ignore_ascii_case(key.as_bytes()))
+        .map(|(_, value)| value)
+}
+
+/// Whether `name` is `other`, ignoring case, without building a string.
+fn same_name(name: &Name, other: &str) -> bool {
+    struct Compare<'a> {
+        rest: &'a str,
+    }
+
+    impl fmt::Write for Compare<'_> {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            match self.rest.get(..s.len()) {
+                Some(start) if start.eq_ignore_ascii_case(s) => {
+                    self.rest = &self.rest[s.len()..];
+                    Ok(())
+                }
+                _ => Err(fmt::Error),
+            }
+        }
+    }
+
+    let mut compare = Compare { rest: other };
+    fmt::write(&mut compare, format_args!("{}", name)).is_ok() && compare.rest.is_empty()
+}
+
+fn in_subnet(addr: IpAddr, subnet: IpAddr, prefix_len: u8) -> bool {
+    match (addr, subnet) {
+        (IpAddr::V4(addr), IpAddr::V4(subnet)) => {
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(prefix_len.min(32)))
+                .unwrap_or(0);
+            u32::from(addr) & mask == u32::from(subnet) & mask
+        }
+        (IpAddr::V6(addr), IpAddr::V6(subnet)) => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(prefix_len.min(128)))
+                .unwrap_or(0);
+            u128::from(addr) & mask == u128::from(subnet) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Whether `value` matches `pattern`, with `*` and `?` as wildcards.
+fn glob(pattern: &[u8], value: &[u8]) -> bool {
+    let (mut p, mut v) = (0, 0);
+    // Where the last `*` was, and the value position it is tried against.
+    let mut star = None;
+
+    while v < value.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, v));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            _ => match star {
+                // let the `*` take one more byte
+                Some((star_p, star_v)) => {
+                    star = Some((star_p, star_v + 1));
+                    p = star_p + 1;
+                    v = star_v + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{response, srv, txt, unique};
+
+    /// A response listing two Chromecasts, each with its own model and port.
+    fn two_instances() -> Vec<u8> {
+        response(vec![
+            unique(
+                "Lounge._googlecast._tcp.local",
+                4500,
+                txt("md", "Chromecast Ultra"),
+            ),
+            unique(
+                "Lounge._googlecast._tcp.local",
+                120,
+                srv("lounge.local", 8009),
+            ),
+            unique("Den._googlecast._tcp.local", 4500, txt("md", "Chromecast")),
+            unique("Den._googlecast._tcp.local", 120, srv("den.local", 8010)),
+        ])
+    }
+
+    #[test]
+    fn txt_and_srv_conditions_hold_for_one_instance() {
+        let datagram = two_instances();
+        let packet = Packet::parse(&datagram).unwrap();
+        let source = "192.168.1.20:5353".parse().unwrap();
+        let ultra = || Filter::new().txt("md", TxtPredicate::Equals("Chromecast Ultra".into()));
+
+        assert!(ultra().srv_port(8009).matches(source, &packet));
+        assert!(!ultra().srv_port(8010).matches(source, &packet));
+        assert!(
+            Filter::any(vec![ultra().srv_port(8010), Filter::new().srv_port(8010)])
+                .matches(source, &packet)
+        );
+        assert!(!Filter::any(vec![ultra()])
+            .srv_port(8010)
+            .matches(source, &packet));
+        assert!(Filter::new()
+            .record_type(QueryType::SRV)
+            .source_subnet([192, 168, 1, 0], 24)
+            .matches(source, &packet));
+    }
+}
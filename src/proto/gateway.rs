@@ -0,0 +1,455 @@
+//! The state machine behind a unicast DNS gateway to mDNS.
+//!
+//! The [`Gateway`] answers ordinary DNS queries for names under `local`,
+//! including the `PTR`, `SRV` and `TXT` queries of DNS-SD browsers, much like
+//! a discovery proxy (RFC 8766) does. Questions it has no cached answers for
+//! are multicast, and whatever comes back is cached for as long as the
+//! records' TTLs allow.
+//!
+//! ```rust
+//! use mdns::proto::gateway::{Gateway, Protocol};
+//! use mdns::proto::wire::{Message, Question, Resource};
+//! use mdns::{Record, RecordKind};
+//! use std::net::Ipv4Addr;
+//! use std::time::Instant;
+//!
+//! let now = Instant::now();
+//! let mut gateway = Gateway::new();
+//!
+//! let mut query = Message::query(0x1234);
+//! query.questions.push(Question::new("printer.local", dns_parser::QueryType::A));
+//! let id = gateway.handle_query(now, &query.encode(), Protocol::Udp);
+//!
+//! // Nothing is cached yet, so the question is multicast.
+//! let transmit = gateway.poll_transmit().unwrap();
+//! assert!(gateway.poll_reply().is_none());
+//!
+//! let mut response = Message::response(0);
+//! response.answers.push(Resource::unique(Record {
+//!     name: "printer.local".to_owned(),
+//!     class: dns_parser::Class::IN,
+//!     ttl: 120,
+//!     kind: RecordKind::A(Ipv4Addr::new(192, 168, 1, 30)),
+//! }));
+//! gateway.handle_datagram(now, "192.168.1.30:5353".parse().unwrap(), &response.encode());
+//!
+//! let reply = gateway.poll_reply().unwrap();
+//! assert_eq!(reply.query, id);
+//! let packet = dns_parser::Packet::parse(&reply.contents).unwrap();
+//! assert_eq!(packet.header.id, 0x1234);
+//! assert_eq!(packet.answers.len(), 1);
+//! # let _ = transmit;
+//! ```
+
+use super::cache::{key, normalize, Cache, Key};
+use super::wire::{Message, Question, Resource};
+use super::{Transmit, MULTICAST_ADDR, MULTICAST_PORT};
+use crate::metrics::Metrics;
+use crate::{MalformedPacket, Record, RecordKind};
+
+use dns_parser::{QueryClass, QueryType, ResponseCode};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How long to wait for answers to a question that was multicast.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The size of a UDP response to a client that didn't say what it can take.
+const UDP_SIZE: usize = 512;
+/// The largest UDP response we send, whatever the client says.
+const MAX_UDP_SIZE: usize = 4096;
+/// The largest response a TCP message can carry.
+const TCP_SIZE: usize = 65535;
+
+/// Names under these domains are resolved with mDNS (RFC 6762 section 4).
+const LOCAL_DOMAINS: [&str; 6] = [
+    "local",
+    "254.169.in-addr.arpa",
+    "8.e.f.ip6.arpa",
+    "9.e.f.ip6.arpa",
+    "a.e.f.ip6.arpa",
+    "b.e.f.ip6.arpa",
+];
+
+/// Identifies a query handed to a [`Gateway`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct QueryId(u64);
+
+/// How a query reached the gateway, which limits the size of its reply.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Udp,
+    Tcp,
+}
+
+/// A DNS response to send back to the client that asked a query.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reply {
+    /// The query being answered.
+    pub query: QueryId,
+    /// The encoded DNS response, without any TCP length prefix.
+    pub contents: Vec<u8>,
+}
+
+/// Answers unicast DNS queries from mDNS.
+#[derive(Debug)]
+pub struct Gateway {
+    timeout: Duration,
+    cache: Cache,
+    /// Questions answers were waited for in full, until when the cached
+    /// answers to them can be taken as complete.
+    complete: HashMap<Key, Instant>,
+    lookups: Vec<Lookup>,
+    next_query: u64,
+    metrics: Option<Metrics>,
+
+    transmits: VecDeque<Transmit>,
+    replies: VecDeque<Reply>,
+}
+
+/// A query waiting for mDNS answers.
+#[derive(Clone, Debug)]
+struct Lookup {
+    query: QueryId,
+    request: Request,
+    deadline: Instant,
+}
+
+/// The parts of a DNS query that matter to the gateway.
+#[derive(Clone, Debug)]
+struct Request {
+    id: u16,
+    name: String,
+    query_type: QueryType,
+    recursion_desired: bool,
+    max_size: usize,
+}
+
+impl Gateway {
+    pub fn new() -> Self {
+        Gateway {
+            timeout: DEFAULT_TIMEOUT,
+            cache: Cache::new(),
+            complete: HashMap::new(),
+            lookups: Vec::new(),
+            next_query: 0,
+            metrics: None,
+            transmits: VecDeque::new(),
+            replies: VecDeque::new(),
+        }
+    }
+
+    /// Sets how long to wait for answers to a question that was multicast.
+    ///
+    /// Questions with unique answers, such as `A` or `SRV` questions, are
+    /// answered as soon as the first answer arrives. `PTR` and `ANY`
+    /// questions may have any number of answers from different hosts, so
+    /// they are always given the full time. Defaults to one second.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub(crate) fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub(crate) fn set_metrics(&mut self, metrics: Metrics) {
+        self.metrics = Some(metrics);
+    }
+
+    /// Handles a DNS query from a client.
+    ///
+    /// The reply to it comes out of [`poll_reply`](Self::poll_reply), right
+    /// away if the answers are cached, or once they were looked up.
+    pub fn handle_query(&mut self, now: Instant, datagram: &[u8], protocol: Protocol) -> QueryId {
+        let query = QueryId(self.next_query);
+        self.next_query += 1;
+        self.expire(now);
+
+        let request = match parse_request(datagram, protocol) {
+            Ok(request) => request,
+            Err((id, code)) => {
+                let mut message = Message::response(id);
+                message.response_code = code.into();
+                self.replies.push_back(Reply {
+                    query,
+                    contents: message.encode(),
+                });
+                return query;
+            }
+        };
+
+        if !is_local(&request.name) {
+            self.reply(query, &request, ResponseCode::Refused, Vec::new());
+            return query;
+        }
+
+        if self.is_answered(now, &request) {
+            if let Some(ref metrics) = self.metrics {
+                metrics.cache_hit();
+            }
+            self.answer(now, query, &request);
+            return query;
+        }
+
+        let asked = self.lookups.iter().any(|lookup| {
+            key(&lookup.request.name, lookup.request.query_type)
+                == key(&request.name, request.query_type)
+        });
+        if !asked {
+            let mut message = Message::query(0);
+            message
+                .questions
+                .push(Question::new(request.name.clone(), request.query_type));
+            self.transmits.push_back(Transmit {
+                destination: SocketAddr::new(MULTICAST_ADDR.into(), MULTICAST_PORT),
+                contents: message.encode(),
+            });
+        }
+
+        self.lookups.push(Lookup {
+            query,
+            request,
+            deadline: now + self.timeout,
+        });
+        query
+    }
+
+    /// Handles an mDNS datagram, caching the records in it.
+    pub fn handle_datagram(&mut self, now: Instant, source: SocketAddr, datagram: &[u8]) {
+        let packet = match dns_parser::Packet::parse(datagram) {
+            Ok(packet) => packet,
+            Err(e) => {
+                let packet = MalformedPacket::new(datagram, &e);
+                log::warn!("malformed packet from {}: {}", source, packet);
+                return;
+            }
+        };
+        if packet.header.query {
+            return;
+        }
+
+        self.expire(now);
+        self.cache.insert(now, &packet);
+
+        let (ready, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.lookups)
+            .into_iter()
+            .partition(|lookup| {
+                !waits_for_all(lookup.request.query_type)
+                    && !self
+                        .cache
+                        .records(now, &lookup.request.name, lookup.request.query_type)
+                        .is_empty()
+            });
+        self.lookups = waiting;
+        for lookup in ready {
+            self.answer(now, lookup.query, &lookup.request);
+        }
+    }
+
+    /// Answers the queries whose time to wait for mDNS answers is up.
+    pub fn handle_timeout(&mut self, now: Instant) {
+        self.expire(now);
+
+        let (due, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.lookups)
+            .into_iter()
+            .partition(|lookup| lookup.deadline <= now);
+        self.lookups = waiting;
+
+        for lookup in due {
+            let request = &lookup.request;
+            if waits_for_all(request.query_type) {
+                let answers = self.cache.records(now, &request.name, request.query_type);
+                if let Some(ttl) = answers.iter().map(|record| record.ttl).min() {
+                    let key = key(&request.name, request.query_type);
+                    self.complete
+                        .insert(key, now + Duration::from_secs(ttl.into()));
+                }
+            }
+            self.answer(now, lookup.query, request);
+        }
+    }
+
+    /// The next multicast query to send.
+    pub fn poll_transmit(&mut self) -> Option<Transmit> {
+        self.transmits.pop_front()
+    }
+
+    /// The next reply to send to a client.
+    pub fn poll_reply(&mut self) -> Option<Reply> {
+        self.replies.pop_front()
+    }
+
+    /// When [`handle_timeout`](Self::handle_timeout) should be called next.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        self.lookups.iter().map(|lookup| lookup.deadline).min()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let expired = self.cache.expire(now);
+        if let Some(ref metrics) = self.metrics {
+            metrics.cache_expired(expired);
+        }
+        self.complete.retain(|_, until| *until > now);
+    }
+
+    fn is_answered(&self, now: Instant, request: &Request) -> bool {
+        if waits_for_all(request.query_type) {
+            self.complete
+                .contains_key(&key(&request.name, request.query_type))
+        } else {
+            !self
+                .cache
+                .records(now, &request.name, request.query_type)
+                .is_empty()
+        }
+    }
+
+    fn answer(&mut self, now: Instant, query: QueryId, request: &Request) {
+        let answers = self.cache.records(now, &request.name, request.query_type);
+        // Another type of record for the name means it exists after all.
+        let known = self.cache.contains_name(&request.name);
+        let code = if answers.is_empty() && !known {
+            ResponseCode::NameError
+        } else {
+            ResponseCode::NoError
+        };
+
+        self.reply(query, request, code, answers);
+    }
+
+    fn reply(
+        &mut self,
+        query: QueryId,
+        request: &Request,
+        code: ResponseCode,
+        answers: Vec<Record>,
+    ) {
+        let additional = self.additional(&answers);
+
+        let mut message = Message::response(request.id);
+        message.recursion_desired = request.recursion_desired;
+        message.response_code = code.into();
+        message
+            .questions
+            .push(Question::new(request.name.clone(), request.query_type));
+        message.answers = answers.into_iter().map(Resource::shared).collect();
+        message.additional = additional.into_iter().map(Resource::shared).collect();
+
+        let mut contents = message.encode();
+        if contents.len() > request.max_size {
+            message.additional.clear();
+            contents = message.encode();
+        }
+        if contents.len() > request.max_size {
+            message.answers.clear();
+            message.truncated = true;
+            contents = message.encode();
+        }
+
+        self.replies.push_back(Reply { query, contents });
+    }
+
+    /// The cached records a client will want next, such as the `SRV` and
+    /// `TXT` records of the instances in a `PTR` answer.
+    fn additional(&self, answers: &[Record]) -> Vec<Record> {
+        // The TTLs were already brought up to date for the answers; the
+        // additional records are a courtesy, and get theirs as cached.
+        let cached = |name: &str, query_type: QueryType| self.cache.get(name, query_type);
+
+        let mut additional = Vec::new();
+        let mut targets = Vec::new();
+        for answer in answers {
+            match answer.kind {
+                RecordKind::PTR(ref instance) => {
+                    for srv in cached(instance, QueryType::SRV) {
+                        if let RecordKind::SRV { ref target, .. } = srv.kind {
+                            targets.push(target.clone());
+                        }
+                        additional.push(srv);
+                    }
+                    additional.extend(cached(instance, QueryType::TXT));
+                }
+                RecordKind::SRV { ref target, .. } => targets.push(target.clone()),
+                _ => {}
+            }
+        }
+        for target in targets {
+            additional.extend(cached(&target, QueryType::A));
+            additional.extend(cached(&target, QueryType::AAAA));
+        }
+
+        let mut unique = Vec::new();
+        for record in additional {
+            if !answers.contains(&record) && !unique.contains(&record) {
+                unique.push(record);
+            }
+        }
+        unique
+    }
+}
+
+impl Default for Gateway {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Picks the question out of a query, or says what to reply instead.
+fn parse_request(datagram: &[u8], protocol: Protocol) -> Result<Request, (u16, ResponseCode)> {
+    let id = match datagram {
+        [high, low, ..] => u16::from_be_bytes([*high, *low]),
+        _ => 0,
+    };
+    let packet =
+        dns_parser::Packet::parse(datagram).map_err(|_| (id, ResponseCode::FormatError))?;
+
+    if !packet.header.query {
+        return Err((id, ResponseCode::FormatError));
+    }
+    if packet.header.opcode != dns_parser::Opcode::StandardQuery {
+        return Err((id, ResponseCode::NotImplemented));
+    }
+    let question = match packet.questions[..] {
+        [ref question] => question,
+        _ => return Err((id, ResponseCode::FormatError)),
+    };
+    if !matches!(question.qclass, QueryClass::IN | QueryClass::Any) {
+        return Err((id, ResponseCode::Refused));
+    }
+
+    let max_size = match protocol {
+        Protocol::Udp => packet
+            .opt
+            .as_ref()
+            .map_or(UDP_SIZE, |opt| usize::from(opt.udp))
+            .clamp(UDP_SIZE, MAX_UDP_SIZE),
+        Protocol::Tcp => TCP_SIZE,
+    };
+
+    Ok(Request {
+        id,
+        name: question.qname.to_string(),
+        query_type: question.qtype,
+        recursion_desired: packet.header.recursion_desired,
+        max_size,
+    })
+}
+
+/// Whether a question can have answers from any number of hosts, so that
+/// the first answer is no reason to stop waiting.
+fn waits_for_all(query_type: QueryType) -> bool {
+    matches!(query_type, QueryType::PTR | QueryType::All)
+}
+
+fn is_local(name: &str) -> bool {
+    let name = normalize(name);
+    LOCAL_DOMAINS.iter().any(|domain| {
+        name == *domain
+            || name
+                .strip_suffix(domain)
+                .map_or(false, |rest| rest.ends_with('.'))
+    })
+}
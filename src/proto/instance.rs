@@ -0,0 +1,315 @@
+//! The state machine behind resolving a single service instance.
+//!
+//! Browsing a service type to find one instance means waiting for every
+//! instance on the network to answer. An [`InstanceResolver`] asks for the
+//! `SRV` and `TXT` records of the instance by name instead, and then for the
+//! `A` and `AAAA` records of the host the `SRV` record points to. Records
+//! that are already cached, or that come along in the additional section of
+//! an answer, aren't asked for again. Records of other names heard on the
+//! way aren't cached.
+//!
+//! Questions still unanswered are asked again after one second, then two,
+//! four and so on (RFC 6762 section 5.2).
+//!
+//! ```rust
+//! use mdns::proto::cache::Cache;
+//! use mdns::proto::instance::InstanceResolver;
+//! use mdns::proto::wire::{Message, Resource};
+//! use mdns::{Record, RecordKind};
+//! use std::collections::HashMap;
+//! use std::net::Ipv4Addr;
+//! use std::time::Instant;
+//!
+//! let record = |name: &str, kind| {
+//!     Resource::unique(Record {
+//!         name: name.to_owned(),
+//!         class: dns_parser::Class::IN,
+//!         ttl: 120,
+//!         kind,
+//!     })
+//! };
+//! let now = Instant::now();
+//! let mut resolver = InstanceResolver::new("Kitchen._googlecast._tcp.local", Cache::new());
+//!
+//! // The SRV and TXT records are asked for first.
+//! resolver.handle_timeout(now);
+//! let query = resolver.poll_transmit().unwrap();
+//! let packet = dns_parser::Packet::parse(&query.contents).unwrap();
+//! assert_eq!(packet.questions.len(), 2);
+//!
+//! let mut response = Message::response(0);
+//! response.answers.push(record(
+//!     "Kitchen._googlecast._tcp.local",
+//!     RecordKind::SRV { priority: 0, weight: 0, port: 8009, target: "kitchen.local".to_owned() },
+//! ));
+//! response.answers.push(record("Kitchen._googlecast._tcp.local", RecordKind::TXT(HashMap::new())));
+//! resolver.handle_datagram(now, "192.168.1.20:5353".parse().unwrap(), &response.encode());
+//!
+//! // Then the address of the host.
+//! let query = resolver.poll_transmit().unwrap();
+//! let packet = dns_parser::Packet::parse(&query.contents).unwrap();
+//! assert_eq!(packet.questions[0].qname.to_string(), "kitchen.local");
+//! assert!(resolver.instance(now).is_none());
+//!
+//! let mut response = Message::response(0);
+//! response.answers.push(record("kitchen.local", RecordKind::A(Ipv4Addr::new(192, 168, 1, 20))));
+//! resolver.handle_datagram(now, "192.168.1.20:5353".parse().unwrap(), &response.encode());
+//!
+//! let instance = resolver.instance(now).unwrap();
+//! assert_eq!(instance.socket_address(), Some("192.168.1.20:8009".parse().unwrap()));
+//! ```
+
+use super::cache::Cache;
+use super::reassembly::Reassembler;
+use super::wire::{Message, Question};
+use super::{Transmit, MULTICAST_ADDR, MULTICAST_PORT};
+use crate::{MalformedPacket, RecordKind, Response};
+
+use dns_parser::QueryType;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How long to wait before asking unanswered questions again the first time.
+const INITIAL_INTERVAL: Duration = Duration::from_secs(1);
+/// The longest wait between queries.
+const MAX_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Looks up the records of one service instance.
+#[derive(Clone, Debug)]
+pub struct InstanceResolver {
+    instance_name: String,
+    cache: Cache,
+    /// When to ask the questions still unanswered, or `None` to ask any there
+    /// are as soon as we are given the time.
+    next_query: Option<Instant>,
+    interval: Duration,
+    /// The host whose addresses were last asked for.
+    asked_target: Option<String>,
+
+    /// Joins responses split across several datagrams.
+    reassembler: Reassembler,
+    transmits: VecDeque<Transmit>,
+}
+
+impl InstanceResolver {
+    /// Creates a resolver for the instance with the full name
+    /// `instance_name`, starting out with the records in `cache`.
+    pub fn new<S>(instance_name: S, cache: Cache) -> Self
+    where
+        S: Into<String>,
+    {
+        InstanceResolver {
+            instance_name: instance_name.into(),
+            cache,
+            next_query: None,
+            interval: INITIAL_INTERVAL,
+            asked_target: None,
+            reassembler: Reassembler::new(),
+            transmits: VecDeque::new(),
+        }
+    }
+
+    /// Processes a datagram received from `source` at time `now`.
+    pub fn handle_datagram(&mut self, now: Instant, source: SocketAddr, datagram: &[u8]) {
+        self.reassembler.handle_datagram(now, source, datagram);
+        self.handle_reassembled(now);
+
+        // The host's addresses can be asked for as soon as it is known.
+        let target = self.target(now);
+        if target.is_some() && target != self.asked_target {
+            self.next_query = None;
+            self.interval = INITIAL_INTERVAL;
+        }
+        self.handle_timeout(now);
+    }
+
+    /// Advances the state machine's clock to `now`, asking again whatever is
+    /// still unanswered if it is time to.
+    pub fn handle_timeout(&mut self, now: Instant) {
+        self.reassembler.handle_timeout(now);
+        self.handle_reassembled(now);
+        self.cache.expire(now);
+
+        match self.next_query {
+            Some(deadline) if deadline > now => return,
+            _ => {}
+        }
+
+        // Nothing to ask until some record goes missing.
+        let questions = self.questions(now);
+        if questions.is_empty() {
+            self.next_query = None;
+            return;
+        }
+
+        let mut message = Message::query(0);
+        message.questions = questions;
+        self.transmits.push_back(Transmit {
+            destination: SocketAddr::new(MULTICAST_ADDR.into(), MULTICAST_PORT),
+            contents: message.encode(),
+        });
+        self.asked_target = self.target(now);
+        self.next_query = Some(now + self.interval);
+        self.interval = (self.interval * 2).min(MAX_INTERVAL);
+    }
+
+    /// Returns the next datagram that should be sent, if any.
+    pub fn poll_transmit(&mut self) -> Option<Transmit> {
+        self.transmits.pop_front()
+    }
+
+    /// Returns the instant at which [`handle_timeout`](Self::handle_timeout)
+    /// should next be called, or `None` if every record is known and there
+    /// is nothing to wait for.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        match (self.next_query, self.reassembler.poll_timeout()) {
+            (Some(next_query), Some(deadline)) => Some(deadline.min(next_query)),
+            (next_query, deadline) => next_query.or(deadline),
+        }
+    }
+
+    /// The instance's `SRV`, `TXT` and address records, once all of them
+    /// are known.
+    pub fn instance(&self, now: Instant) -> Option<Response> {
+        let response = self.found(now)?;
+        let has = |query_type| {
+            response
+                .answers
+                .iter()
+                .any(|record| super::wire::record_type(&record.kind) == Some(query_type as u16))
+        };
+
+        if has(QueryType::TXT) && (has(QueryType::A) || has(QueryType::AAAA)) {
+            Some(response)
+        } else {
+            None
+        }
+    }
+
+    /// Whatever is known about the instance, as long as its `SRV` record is.
+    pub fn found(&self, now: Instant) -> Option<Response> {
+        let target = self.target(now)?;
+
+        let mut answers = self.cache.records(now, &self.instance_name, QueryType::SRV);
+        answers.extend(self.cache.records(now, &self.instance_name, QueryType::TXT));
+        answers.extend(self.cache.records(now, &target, QueryType::A));
+        answers.extend(self.cache.records(now, &target, QueryType::AAAA));
+
+        Some(Response {
+            answers,
+            ..Response::default()
+        })
+    }
+
+    /// The full name of the instance.
+    pub fn instance_name(&self) -> &str {
+        &self.instance_name
+    }
+
+    /// The records heard so far.
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    /// Hands back the records heard, to be used by the next resolver.
+    pub fn into_cache(self) -> Cache {
+        self.cache
+    }
+
+    fn handle_reassembled(&mut self, now: Instant) {
+        while let Some(datagram) = self.reassembler.poll_datagram() {
+            match dns_parser::Packet::parse(&datagram.contents) {
+                Ok(packet) if !packet.header.query => {
+                    // Only the records asked for are kept: the instance's
+                    // first, which tell whose addresses are wanted.
+                    self.cache
+                        .insert_names(now, &packet, &[&self.instance_name]);
+                    if let Some(target) = self.target(now) {
+                        self.cache.insert_names(now, &packet, &[&target]);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    let packet = MalformedPacket::new(&datagram.contents, &e);
+                    log::warn!("malformed packet from {}: {}", datagram.source, packet);
+                }
+            }
+        }
+    }
+
+    /// The host the instance's `SRV` record points to.
+    pub(crate) fn target(&self, now: Instant) -> Option<String> {
+        self.cache
+            .records(now, &self.instance_name, QueryType::SRV)
+            .into_iter()
+            .find_map(|record| match record.kind {
+                RecordKind::SRV { target, .. } => Some(target),
+                _ => None,
+            })
+    }
+
+    /// The questions whose answers aren't cached yet.
+    fn questions(&self, now: Instant) -> Vec<Question> {
+        let missing = |name: &str, query_type| self.cache.records(now, name, query_type).is_empty();
+        let mut questions = Vec::new();
+
+        for query_type in [QueryType::SRV, QueryType::TXT] {
+            if missing(&self.instance_name, query_type) {
+                questions.push(Question::new(self.instance_name.clone(), query_type));
+            }
+        }
+        if let Some(target) = self.target(now) {
+            if missing(&target, QueryType::A) && missing(&target, QueryType::AAAA) {
+                questions.push(Question::new(target.clone(), QueryType::A));
+                questions.push(Question::new(target, QueryType::AAAA));
+            }
+        }
+
+        questions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{device, response, srv, unique};
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn only_the_names_asked_for_are_cached() {
+        let now = Instant::now();
+        let mut resolver = InstanceResolver::new("Kitchen._googlecast._tcp.local", Cache::new());
+        resolver.handle_timeout(now);
+
+        let response = response(vec![
+            unique(
+                "Kitchen._googlecast._tcp.local",
+                120,
+                srv("kitchen.local", 8009),
+            ),
+            unique(
+                "Lounge._googlecast._tcp.local",
+                120,
+                srv("lounge.local", 8009),
+            ),
+            unique(
+                "kitchen.local",
+                120,
+                RecordKind::A(Ipv4Addr::new(192, 168, 1, 20)),
+            ),
+            unique(
+                "lounge.local",
+                120,
+                RecordKind::A(Ipv4Addr::new(192, 168, 1, 30)),
+            ),
+        ]);
+        resolver.handle_datagram(now, device(), &response);
+
+        let cache = resolver.cache();
+        assert!(cache.contains_name("Kitchen._googlecast._tcp.local"));
+        assert!(cache.contains_name("kitchen.local"));
+        assert!(!cache.contains_name("Lounge._googlecast._tcp.local"));
+        assert!(!cache.contains_name("lounge.local"));
+    }
+}
@@ -0,0 +1,413 @@
+//! Joining messages that were split across several datagrams.
+//!
+//! A query with more known answers than fit in one datagram is sent as
+//! several, with the TC bit set on all but the last (RFC 6762 section 7.2).
+//! Large responses are sometimes split the same way. A [`Reassembler`] holds
+//! on to the datagrams with the TC bit set until the rest of the message
+//! arrives from the same source, and hands back the whole message as one
+//! datagram.
+//!
+//! When no more datagrams come within 500ms of the last one, the message is
+//! handed back as it is. So is a message that would grow past 64 KiB, and
+//! the oldest message waiting when too many sources are sending parts at
+//! once.
+//!
+//! ```rust
+//! use mdns::proto::reassembly::Reassembler;
+//! use mdns::proto::wire::{Message, Resource};
+//! use mdns::{Record, RecordKind};
+//! use std::time::{Duration, Instant};
+//!
+//! let ptr = |instance: &str| {
+//!     Resource::shared(Record {
+//!         name: "_http._tcp.local".to_owned(),
+//!         class: dns_parser::Class::IN,
+//!         ttl: 4500,
+//!         kind: RecordKind::PTR(format!("{}._http._tcp.local", instance)),
+//!     })
+//! };
+//! let source = "192.168.1.10:5353".parse().unwrap();
+//! let now = Instant::now();
+//! let mut reassembler = Reassembler::new();
+//!
+//! let mut first = Message::response(0);
+//! first.truncated = true;
+//! first.answers.push(ptr("Kitchen"));
+//! reassembler.handle_datagram(now, source, &first.encode());
+//! assert!(reassembler.poll_datagram().is_none());
+//!
+//! let mut rest = Message::response(0);
+//! rest.answers.push(ptr("Office"));
+//! reassembler.handle_datagram(now + Duration::from_millis(10), source, &rest.encode());
+//!
+//! let whole = reassembler.poll_datagram().unwrap();
+//! assert_eq!(whole.source, source);
+//! let packet = dns_parser::Packet::parse(&whole.contents).unwrap();
+//! assert!(!packet.header.truncated);
+//! assert_eq!(packet.answers.len(), 2);
+//! ```
+
+use super::splice::{self, Entry, HEADER_LEN};
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How long to wait for the next part of a truncated message.
+const CONTINUATION_WAIT: Duration = Duration::from_millis(500);
+
+/// The longest a joined message may grow, which is as long as a DNS message
+/// can be. It also keeps the number of entries in a section within what the
+/// header can count.
+const MAX_MESSAGE_LEN: usize = 65535;
+/// The most messages waited on at once.
+const MAX_PARTIALS: usize = 16;
+
+/// The TC bit, in the third byte of the header.
+const TRUNCATED: u8 = 0x02;
+
+/// A whole message, ready to be handled.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Datagram {
+    /// Where the message came from.
+    pub source: SocketAddr,
+    /// The encoded DNS packet.
+    pub contents: Vec<u8>,
+}
+
+/// Joins messages split across datagrams by their source.
+#[derive(Clone, Debug, Default)]
+pub struct Reassembler {
+    partial: Vec<Partial>,
+    complete: VecDeque<Datagram>,
+}
+
+/// The parts of a message received so far.
+#[derive(Clone, Debug)]
+struct Partial {
+    source: SocketAddr,
+    /// When to give up waiting for the rest.
+    deadline: Instant,
+    response: bool,
+    /// The header of the first part.
+    header: [u8; HEADER_LEN],
+    /// The length of the message joined so far.
+    len: usize,
+    /// The questions, answers, authority and additional records, copied as
+    /// they were received but for their names, which are decompressed.
+    sections: [Vec<Entry>; 4],
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Processes a datagram received from `source` at time `now`.
+    ///
+    /// Datagrams that aren't part of a split message are handed back as they
+    /// are, as are ones that can't be parsed.
+    pub fn handle_datagram(&mut self, now: Instant, source: SocketAddr, datagram: &[u8]) {
+        self.handle_timeout(now);
+
+        let packet = match dns_parser::Packet::parse(datagram) {
+            Ok(packet) => packet,
+            Err(_) => {
+                self.pass(source, datagram);
+                return;
+            }
+        };
+        let response = !packet.header.query;
+        let parts = splice::sections(datagram);
+        let added: usize = parts.iter().flatten().flatten().map(Entry::len).sum();
+
+        // A query and a response don't make one message, and a message that
+        // would grow too long ends where it is.
+        if let Some(index) = self.position(source) {
+            let partial = &self.partial[index];
+            if partial.response != response || partial.len + added > MAX_MESSAGE_LEN {
+                let partial = self.partial.remove(index);
+                self.finish(partial);
+            }
+        }
+
+        let index = match self.position(source) {
+            Some(index) => index,
+            None if packet.header.truncated => {
+                if self.partial.len() >= MAX_PARTIALS {
+                    self.finish_oldest();
+                }
+                let mut header = [0; HEADER_LEN];
+                header.copy_from_slice(&datagram[..HEADER_LEN]);
+                self.partial.push(Partial {
+                    source,
+                    deadline: now,
+                    response,
+                    header,
+                    len: HEADER_LEN,
+                    sections: Default::default(),
+                });
+                self.partial.len() - 1
+            }
+            None => {
+                self.pass(source, datagram);
+                return;
+            }
+        };
+
+        let partial = &mut self.partial[index];
+        partial.deadline = now + CONTINUATION_WAIT;
+        match parts {
+            Some(sections) => {
+                for (section, part) in partial.sections.iter_mut().zip(sections) {
+                    section.extend(part);
+                }
+                partial.len += added;
+            }
+            None => log::debug!("couldn't copy a part of a message from {}", source),
+        }
+
+        if !packet.header.truncated {
+            let partial = self.partial.remove(index);
+            self.finish(partial);
+        }
+    }
+    /// Advances the reassembler's clock to `now`, handing back the messages
+    /// whose rest didn't arrive in time.
+    pub fn handle_timeout(&mut self, now: Instant) {
+        let mut i = 0;
+        while i < self.partial.len() {
+            if self.partial[i].deadline <= now {
+                let partial = self.partial.remove(i);
+                log::debug!("the rest of a message from {} never came", partial.source);
+                self.finish(partial);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Returns the next whole message, if any.
+    pub fn poll_datagram(&mut self) -> Option<Datagram> {
+        self.complete.pop_front()
+    }
+
+    /// Returns the instant at which [`handle_timeout`](Self::handle_timeout)
+    /// should next be called, or `None` if no message is waiting.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        self.partial.iter().map(|partial| partial.deadline).min()
+    }
+
+    /// The message waiting for more parts from `source`, if any.
+    fn position(&self, source: SocketAddr) -> Option<usize> {
+        self.partial
+            .iter()
+            .position(|partial| partial.source == source)
+    }
+
+    /// Hands back the message that has been waiting the longest, to make
+    /// room for another.
+    fn finish_oldest(&mut self) {
+        let oldest = (0..self.partial.len()).min_by_key(|&i| self.partial[i].deadline);
+        if let Some(oldest) = oldest {
+            let partial = self.partial.remove(oldest);
+            log::debug!(
+                "too many split messages, not waiting for {}",
+                partial.source
+            );
+            self.finish(partial);
+        }
+    }
+
+    fn pass(&mut self, source: SocketAddr, datagram: &[u8]) {
+        self.complete.push_back(Datagram {
+            source,
+            contents: datagram.to_vec(),
+        });
+    }
+
+    fn finish(&mut self, mut partial: Partial) {
+        partial.header[2] &= !TRUNCATED;
+        self.complete.push_back(Datagram {
+            source: partial.source,
+            contents: splice::join(&partial.header, &partial.sections),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::wire::Message;
+    use crate::testing::{device, shared, unique};
+    use crate::RecordKind;
+    use dns_parser::{Packet, RData};
+
+    fn ptr(instance: &str) -> crate::proto::wire::Resource {
+        shared(
+            "_http._tcp.local",
+            4500,
+            RecordKind::PTR(format!("{}._http._tcp.local", instance)),
+        )
+    }
+
+    /// A response holding a TXT record with its strings in a given order and
+    /// an NSEC record, both pointing back at the name of the first answer.
+    fn txt_and_nsec() -> Vec<u8> {
+        let mut message = Message::response(0);
+        message.answers.push(unique(
+            "kitchen.local",
+            120,
+            RecordKind::A([192, 168, 1, 10].into()),
+        ));
+        let mut datagram = message.encode();
+        datagram[7] = 3;
+
+        let name_pointer = [0xc0, 12];
+        // TXT "b=2" "a=1" "a=1"
+        datagram.extend_from_slice(&name_pointer);
+        datagram.extend_from_slice(&[0, 16, 0, 1, 0, 0, 0x11, 0x94, 0, 12]);
+        datagram.extend_from_slice(b"\x03b=2\x03a=1\x03a=1");
+        // NSEC kitchen.local, A
+        datagram.extend_from_slice(&name_pointer);
+        datagram.extend_from_slice(&[0, 47, 0x80, 1, 0, 0, 0, 120, 0, 5]);
+        datagram.extend_from_slice(&name_pointer);
+        datagram.extend_from_slice(&[0, 1, 0x40]);
+        datagram
+    }
+
+    #[test]
+    fn records_are_copied_as_received() {
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new();
+
+        let mut first = Message::response(0);
+        first.truncated = true;
+        first.answers.push(ptr("Kitchen"));
+        reassembler.handle_datagram(now, device(), &first.encode());
+        reassembler.handle_datagram(now, device(), &txt_and_nsec());
+
+        let whole = reassembler.poll_datagram().unwrap();
+        let packet = Packet::parse(&whole.contents).unwrap();
+        assert!(!packet.header.truncated);
+        assert_eq!(packet.answers.len(), 4);
+
+        match packet.answers[0].data {
+            RData::PTR(ref ptr) => assert_eq!(ptr.0.to_string(), "Kitchen._http._tcp.local"),
+            ref other => panic!("{:?}", other),
+        }
+        assert_eq!(packet.answers[2].name.to_string(), "kitchen.local");
+        match packet.answers[2].data {
+            RData::TXT(ref txt) => {
+                let strings: Vec<_> = txt.iter().collect();
+                assert_eq!(strings, [&b"b=2"[..], b"a=1", b"a=1"]);
+            }
+            ref other => panic!("{:?}", other),
+        }
+        assert_eq!(packet.answers[3].name.to_string(), "kitchen.local");
+        assert!(packet.answers[3].multicast_unique);
+        match packet.answers[3].data {
+            RData::Unknown(data) => assert_eq!(data, b"\x07kitchen\x05local\x00\x00\x01\x40"),
+            ref other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn the_rest_is_waited_for_half_a_second() {
+        let start = Instant::now();
+        let mut reassembler = Reassembler::new();
+
+        let mut first = Message::response(0);
+        first.truncated = true;
+        first.answers.push(ptr("Kitchen"));
+        reassembler.handle_datagram(start, device(), &first.encode());
+        assert_eq!(
+            reassembler.poll_timeout(),
+            Some(start + Duration::from_millis(500))
+        );
+
+        // Each part buys the next one more time.
+        let second_at = start + Duration::from_millis(400);
+        let mut second = Message::response(0);
+        second.truncated = true;
+        second.answers.push(ptr("Office"));
+        reassembler.handle_datagram(second_at, device(), &second.encode());
+        reassembler.handle_timeout(start + Duration::from_millis(500));
+        assert!(reassembler.poll_datagram().is_none());
+
+        let deadline = reassembler.poll_timeout().unwrap();
+        assert_eq!(deadline, second_at + Duration::from_millis(500));
+        reassembler.handle_timeout(deadline);
+        let whole = reassembler.poll_datagram().unwrap();
+        let packet = Packet::parse(&whole.contents).unwrap();
+        assert!(!packet.header.truncated);
+        assert_eq!(packet.answers.len(), 2);
+        assert_eq!(reassembler.poll_timeout(), None);
+    }
+
+    #[test]
+    fn other_sources_are_not_held_back() {
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new();
+
+        let mut first = Message::response(0);
+        first.truncated = true;
+        first.answers.push(ptr("Kitchen"));
+        reassembler.handle_datagram(now, device(), &first.encode());
+
+        let mut other = Message::response(0);
+        other.answers.push(ptr("Office"));
+        let laptop = "192.168.1.10:5353".parse().unwrap();
+        reassembler.handle_datagram(now, laptop, &other.encode());
+        assert_eq!(reassembler.poll_datagram().unwrap().source, laptop);
+        assert!(reassembler.poll_datagram().is_none());
+    }
+
+    /// A part of a message, with a PTR record for each of `instances`.
+    fn part(instances: std::ops::Range<usize>, truncated: bool) -> Vec<u8> {
+        let mut message = Message::response(0);
+        message.truncated = truncated;
+        message.answers = instances.map(|i| ptr(&format!("Speaker {}", i))).collect();
+        message.encode()
+    }
+
+    #[test]
+    fn endless_messages_are_cut_off() {
+        let mut now = Instant::now();
+        let mut reassembler = Reassembler::new();
+
+        // Parts keep coming in time, and never say they are the last.
+        for i in 0..2000 {
+            reassembler.handle_datagram(now, device(), &part(i * 10..i * 10 + 10, true));
+            now += Duration::from_millis(100);
+        }
+        reassembler.handle_timeout(now + CONTINUATION_WAIT);
+
+        let mut answers = 0;
+        while let Some(whole) = reassembler.poll_datagram() {
+            assert!(whole.contents.len() <= MAX_MESSAGE_LEN);
+            let packet = Packet::parse(&whole.contents).unwrap();
+            answers += packet.answers.len();
+        }
+        assert_eq!(answers, 20000);
+    }
+
+    #[test]
+    fn few_sources_are_waited_on_at_once() {
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new();
+
+        for port in 0..1000 {
+            let source = SocketAddr::new([192, 168, 1, 20].into(), 10000 + port);
+            reassembler.handle_datagram(now, source, &part(0..1, true));
+            assert!(reassembler.partial.len() <= MAX_PARTIALS);
+        }
+
+        // The messages given up on are handed back as they are.
+        let mut handed_back = 0;
+        while reassembler.poll_datagram().is_some() {
+            handed_back += 1;
+        }
+        assert_eq!(handed_back, 1000 - MAX_PARTIALS);
+    }
+}
@@ -0,0 +1,472 @@
+//! The state machine behind an mDNS reflector.
+//!
+//! Multicast DNS never leaves its link, so devices on one VLAN can't be
+//! discovered from another. A [`Reflector`] sits on several links at once
+//! and re-emits the queries and responses it hears on one [`Interface`] on
+//! all the others.
+//!
+//! Which service types make it across can be limited per direction, and
+//! address records can be rewritten to point at the interface they were
+//! forwarded through. Datagrams from the reflector's own addresses, and
+//! copies of datagrams it has just forwarded, are never reflected, so two
+//! reflectors on the same links don't bounce packets between each other
+//! forever.
+//!
+//! ```rust
+//! use mdns::proto::reflector::{Interface, Reflector};
+//! use mdns::proto::wire::{Message, Question};
+//! use std::net::Ipv4Addr;
+//! use std::time::Instant;
+//!
+//! let mut reflector = Reflector::new()
+//!     .interface(Interface::new("iot", Ipv4Addr::new(10, 0, 20, 1), 24))
+//!     .interface(Interface::new("staff", Ipv4Addr::new(10, 0, 10, 1), 24))
+//!     .allow("staff", "iot", "_googlecast._tcp");
+//!
+//! let mut query = Message::query(0);
+//! query.questions.push(Question::new("_googlecast._tcp.local", dns_parser::QueryType::PTR));
+//! reflector.handle_datagram(Instant::now(), 1, "10.0.10.50:5353".parse().unwrap(), &query.encode());
+//! let forward = reflector.poll_transmit().unwrap();
+//! assert_eq!(forward.interface, 0);
+//!
+//! // Only Chromecasts may be looked for from the staff network.
+//! let mut query = Message::query(0);
+//! query.questions.push(Question::new("_ipp._tcp.local", dns_parser::QueryType::PTR));
+//! reflector.handle_datagram(Instant::now(), 1, "10.0.10.50:5353".parse().unwrap(), &query.encode());
+//! assert!(reflector.poll_transmit().is_none());
+//! ```
+
+use super::responder::SERVICE_TYPES;
+use super::splice::{self, Entry, HEADER_LEN};
+use super::MULTICAST_PORT;
+use crate::MalformedPacket;
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// The record type codes of the records looked into.
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_AAAA: u16 = 28;
+
+/// How long a forwarded datagram is remembered, so that copies of it coming
+/// back are not forwarded again.
+const DUPLICATE_WINDOW: Duration = Duration::from_millis(500);
+
+/// A network interface the reflector listens and forwards on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Interface {
+    name: String,
+    addr: Ipv4Addr,
+    prefix_len: u8,
+}
+
+/// A datagram to multicast on one of the reflector's interfaces.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Forward {
+    /// The index of the interface, in the order they were added.
+    pub interface: usize,
+    /// The encoded DNS packet.
+    pub contents: Vec<u8>,
+}
+
+/// Forwards mDNS traffic between interfaces.
+#[derive(Clone, Debug, Default)]
+pub struct Reflector {
+    interfaces: Vec<Interface>,
+    rules: Vec<Rule>,
+    rewrite_addresses: bool,
+
+    /// The hashes of recently seen and forwarded datagrams.
+    recent: VecDeque<(Instant, u64)>,
+    transmits: VecDeque<Forward>,
+}
+
+/// Lets a service type through in one direction, or keeps it out.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Rule {
+    from: String,
+    to: String,
+    service_type: String,
+    allow: bool,
+}
+
+impl Interface {
+    /// An interface with the given name, address and subnet prefix length,
+    /// e.g. `Interface::new("eth0.20", Ipv4Addr::new(10, 0, 20, 1), 24)`.
+    pub fn new<S>(name: S, addr: Ipv4Addr, prefix_len: u8) -> Self
+    where
+        S: Into<String>,
+    {
+        Interface {
+            name: name.into(),
+            addr,
+            prefix_len: prefix_len.min(32),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn addr(&self) -> Ipv4Addr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Whether `addr` is on this interface's subnet.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V4(addr) => addr,
+            IpAddr::V6(_) => return false,
+        };
+        let mask = u32::MAX
+            .checked_shl(32 - u32::from(self.prefix_len))
+            .unwrap_or(0);
+
+        u32::from(addr) & mask == u32::from(self.addr) & mask
+    }
+}
+
+impl Reflector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an interface to listen and forward on.
+    pub fn interface(mut self, interface: Interface) -> Self {
+        self.interfaces.push(interface);
+        self
+    }
+
+    /// Lets a service type, e.g. `_googlecast._tcp`, through from one
+    /// interface to another.
+    ///
+    /// Once a direction has a service type allowed, every other service type
+    /// is kept out of it. Records that belong to no service type, such as
+    /// the address records of hosts, always make it through.
+    pub fn allow<F, T, S>(self, from: F, to: T, service_type: S) -> Self
+    where
+        F: Into<String>,
+        T: Into<String>,
+        S: AsRef<str>,
+    {
+        self.rule(from.into(), to.into(), service_type.as_ref(), true)
+    }
+
+    /// Keeps a service type from going from one interface to another, even
+    /// if it was allowed.
+    pub fn deny<F, T, S>(self, from: F, to: T, service_type: S) -> Self
+    where
+        F: Into<String>,
+        T: Into<String>,
+        S: AsRef<str>,
+    {
+        self.rule(from.into(), to.into(), service_type.as_ref(), false)
+    }
+
+    /// Whether to rewrite `A` records to the address of the interface they
+    /// are forwarded through, so that clients connect to the reflector's
+    /// host instead of the device itself.
+    ///
+    /// `AAAA` records can't be rewritten, and are left out of forwarded
+    /// responses instead.
+    pub fn rewrite_addresses(mut self, rewrite: bool) -> Self {
+        self.rewrite_addresses = rewrite;
+        self
+    }
+
+    /// The interfaces, in the order they were added.
+    pub fn interfaces(&self) -> &[Interface] {
+        &self.interfaces
+    }
+
+    fn rule(mut self, from: String, to: String, service_type: &str, allow: bool) -> Self {
+        let service_type = service_type_of(service_type)
+            .unwrap_or_else(|| service_type.trim_end_matches('.').to_ascii_lowercase());
+        self.rules.push(Rule {
+            from,
+            to,
+            service_type,
+            allow,
+        });
+        self
+    }
+
+    /// Handles a datagram received on the interface at index `interface`.
+    pub fn handle_datagram(
+        &mut self,
+        now: Instant,
+        interface: usize,
+        source: SocketAddr,
+        datagram: &[u8],
+    ) {
+        let from = match self.interfaces.get(interface) {
+            Some(from) => from,
+            None => return,
+        };
+
+        // Legacy unicast queriers expect the answers to come back to them
+        // directly, which can't happen across links.
+        if source.port() != MULTICAST_PORT {
+            return;
+        }
+        if self
+            .interfaces
+            .iter()
+            .any(|own| IpAddr::V4(own.addr) == source.ip())
+        {
+            return;
+        }
+        if !from.contains(source.ip()) {
+            log::debug!("{} is not on {}, not reflecting", source, from.name);
+            return;
+        }
+
+        while let Some(&(seen, _)) = self.recent.front() {
+            if now.saturating_duration_since(seen) < DUPLICATE_WINDOW {
+                break;
+            }
+            self.recent.pop_front();
+        }
+        let hash = hash(datagram);
+        if self.recent.iter().any(|&(_, recent)| recent == hash) {
+            return;
+        }
+        self.recent.push_back((now, hash));
+
+        let packet = match dns_parser::Packet::parse(datagram) {
+            Ok(packet) => packet,
+            Err(e) => {
+                let packet = MalformedPacket::new(datagram, &e);
+                log::warn!("malformed packet from {}: {}", source, packet);
+                return;
+            }
+        };
+
+        for to in 0..self.interfaces.len() {
+            if to == interface {
+                continue;
+            }
+            if let Some(contents) = self.reflect(&packet, datagram, interface, to) {
+                self.recent.push_back((now, self::hash(&contents)));
+                self.transmits.push_back(Forward {
+                    interface: to,
+                    contents,
+                });
+            }
+        }
+    }
+
+    /// The next datagram to multicast.
+    pub fn poll_transmit(&mut self) -> Option<Forward> {
+        self.transmits.pop_front()
+    }
+
+    /// The datagram to send on interface `to`, if anything is left of it.
+    fn reflect(
+        &self,
+        packet: &dns_parser::Packet,
+        datagram: &[u8],
+        from: usize,
+        to: usize,
+    ) -> Option<Vec<u8>> {
+        let from_name = &self.interfaces[from].name;
+        let to_name = &self.interfaces[to].name;
+        let rules: Vec<_> = self
+            .rules
+            .iter()
+            .filter(|rule| rule.from == *from_name && rule.to == *to_name)
+            .collect();
+        let allowed = |service_type: Option<String>| match service_type {
+            None => true,
+            Some(service_type) => {
+                let matches = |allow| {
+                    rules
+                        .iter()
+                        .any(|rule| rule.allow == allow && rule.service_type == service_type)
+                };
+                !matches(false) && (matches(true) || rules.iter().all(|rule| !rule.allow))
+            }
+        };
+
+        let unicast = packet.questions.iter().any(|q| q.prefer_unicast);
+        if rules.is_empty() && !self.rewrite_addresses && !unicast {
+            return Some(datagram.to_vec());
+        }
+
+        // Records are copied as they came, so that those of types we don't
+        // know, such as NSEC, make it across too.
+        let [questions, answers, authority, additional] = match splice::sections(datagram) {
+            Some(sections) => sections,
+            None => {
+                log::debug!("couldn't take apart a message, not reflecting");
+                return None;
+            }
+        };
+
+        // Unicast answers would come back to the reflector, not the querier.
+        let questions: Vec<_> = questions
+            .into_iter()
+            .filter(|q| {
+                let name = q.name();
+                is_service_types(&name) || allowed(service_type_of(&name))
+            })
+            .map(|mut q| {
+                q.clear_unicast_response();
+                q
+            })
+            .collect();
+
+        let to_addr = self.interfaces[to].addr;
+        let section = |entries: Vec<Entry>| -> Vec<Entry> {
+            let mut kept: Vec<Entry> = Vec::new();
+            for mut entry in entries {
+                if !allowed(record_service_type(&entry)) {
+                    continue;
+                }
+                if self.rewrite_addresses {
+                    match entry.record_type() {
+                        TYPE_A => entry.set_rdata(&to_addr.octets()),
+                        TYPE_AAAA => continue,
+                        _ => {}
+                    }
+                }
+
+                if !kept.contains(&entry) {
+                    kept.push(entry);
+                }
+            }
+            kept
+        };
+        let sections = [
+            questions,
+            section(answers),
+            section(authority),
+            section(additional),
+        ];
+
+        let empty = if packet.header.query {
+            sections[0].is_empty()
+        } else {
+            sections[1].is_empty()
+        };
+        if empty {
+            return None;
+        }
+
+        let mut header = [0; HEADER_LEN];
+        header.copy_from_slice(&datagram[..HEADER_LEN]);
+        Some(splice::join(&header, &sections))
+    }
+}
+
+/// The service type a record belongs to, if any.
+fn record_service_type(record: &Entry) -> Option<String> {
+    let name = record.name();
+    // Service type enumeration points at the type itself.
+    if record.record_type() == TYPE_PTR && is_service_types(&name) {
+        return splice::read_name(record.rdata()).and_then(|target| service_type_of(&target));
+    }
+    service_type_of(&name)
+}
+
+/// The `_service._proto` part of a name, lowercased, e.g. `_ipp._tcp` for
+/// `Office._ipp._tcp.local` or `_universal._sub._ipp._tcp.local`.
+fn service_type_of(name: &str) -> Option<String> {
+    let labels: Vec<_> = name.trim_end_matches('.').split('.').collect();
+
+    (1..labels.len()).rev().find_map(|i| {
+        let proto = labels[i].to_ascii_lowercase();
+        let service = labels[i - 1];
+        if (proto == "_tcp" || proto == "_udp") && service.starts_with('_') {
+            Some(format!("{}.{}", service.to_ascii_lowercase(), proto))
+        } else {
+            None
+        }
+    })
+}
+
+fn is_service_types(name: &str) -> bool {
+    name.trim_end_matches('.')
+        .eq_ignore_ascii_case(SERVICE_TYPES.trim_end_matches('.'))
+}
+
+fn hash(datagram: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    datagram.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::wire::Message;
+    use crate::testing::shared;
+    use crate::RecordKind;
+    use dns_parser::{Packet, RData};
+
+    fn ptr(service_type: &str, instance: &str) -> crate::proto::wire::Resource {
+        shared(
+            service_type,
+            4500,
+            RecordKind::PTR(format!("{}.{}", instance, service_type)),
+        )
+    }
+
+    fn write_name(datagram: &mut Vec<u8>, name: &str) {
+        for label in name.split('.') {
+            datagram.push(label.len() as u8);
+            datagram.extend_from_slice(label.as_bytes());
+        }
+        datagram.push(0);
+    }
+
+    #[test]
+    fn filtered_responses_keep_unknown_records_and_flags() {
+        let mut reflector = Reflector::new()
+            .interface(Interface::new("iot", Ipv4Addr::new(10, 0, 20, 1), 24))
+            .interface(Interface::new("staff", Ipv4Addr::new(10, 0, 10, 1), 24))
+            .allow("iot", "staff", "_googlecast._tcp");
+
+        let mut response = Message::response(0);
+        response.truncated = true;
+        response
+            .answers
+            .push(ptr("_googlecast._tcp.local", "Kitchen"));
+        response.answers.push(ptr("_ipp._tcp.local", "Office"));
+        let mut datagram = response.encode();
+
+        // NSEC Kitchen._googlecast._tcp.local, TXT and SRV
+        datagram[11] = 1;
+        let owner = "Kitchen._googlecast._tcp.local";
+        write_name(&mut datagram, owner);
+        datagram.extend_from_slice(&[0, 47, 0x80, 1, 0, 0, 0x11, 0x94, 0, 40]);
+        write_name(&mut datagram, owner);
+        datagram.extend_from_slice(&[0, 6, 0, 0, 0x80, 0, 0x40, 0]);
+
+        let source = "10.0.20.50:5353".parse().unwrap();
+        reflector.handle_datagram(Instant::now(), 0, source, &datagram);
+        let forward = reflector.poll_transmit().unwrap();
+        assert_eq!(forward.interface, 1);
+
+        let packet = Packet::parse(&forward.contents).unwrap();
+        assert!(packet.header.truncated);
+        assert!(packet.header.authoritative);
+        assert_eq!(packet.answers.len(), 1);
+        assert_eq!(packet.answers[0].name.to_string(), "_googlecast._tcp.local");
+        assert_eq!(packet.additional.len(), 1);
+        assert_eq!(packet.additional[0].name.to_string(), owner);
+        assert!(packet.additional[0].multicast_unique);
+        match packet.additional[0].data {
+            RData::Unknown(data) => assert_eq!(data.len(), 40),
+            ref other => panic!("{:?}", other),
+        }
+    }
+}
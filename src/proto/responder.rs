@@ -0,0 +1,1633 @@
+//! A sans-IO mDNS responder, which claims names and answers for them.
+//!
+//! Two kinds of names can be claimed: the names of [`Service`] instances, and
+//! the names of [`Host`]s along with their addresses.
+//!
+//! Before a name is announced, it is probed for as described in
+//! RFC 6762 sections 8 and 9. The probe is sent three times, 250ms apart, and
+//! the name is only announced once none of them drew an answer. Probes sent
+//! by another host at the same time are resolved by comparing the proposed
+//! records, and a name another host turns out to own is given up or renamed
+//! according to the [`ConflictPolicy`].
+//!
+//! Queries are answered as RFC 6762 section 6 asks: queries from a port other
+//! than 5353 come from simple resolvers, and get a unicast reply that looks
+//! like a conventional DNS response, and questions asking for a unicast
+//! response (the "QU" bit) get one for records that were multicast recently.
+//!
+//! Multicast answers are timed so that a busy network isn't flooded. Answers
+//! made up of unique records go out straight away, while answers that other
+//! hosts may give too wait 20-120ms, and are sent together with any other
+//! answers that become due in the meantime. Records the asker listed as
+//! already known, and records another host multicasts while ours are
+//! waiting, are left out, and no record is multicast more than once a
+//! second. A query whose known answers didn't fit in one datagram is only
+//! answered once the rest of them arrive.
+//!
+//! ```rust
+//! use mdns::proto::responder::{Event, Responder, Service};
+//! use std::time::{Duration, Instant};
+//!
+//! let start = Instant::now();
+//! let mut responder = Responder::new();
+//! let id = responder.register(start, Service::new("Kitchen", "_http._tcp.local", "kitchen.local", 80));
+//!
+//! // Three probes go out, a quarter of a second apart.
+//! let mut now = start;
+//! let mut probes = 0;
+//! while probes < 3 {
+//!     now = responder.poll_timeout().unwrap();
+//!     responder.handle_timeout(now);
+//!     while responder.poll_transmit().is_some() {
+//!         probes += 1;
+//!     }
+//! }
+//!
+//! // Nobody objected, so the name is ours.
+//! responder.handle_timeout(now + Duration::from_millis(250));
+//! assert_eq!(
+//!     responder.poll_event(),
+//!     Some(Event::Registered { id, name: "Kitchen._http._tcp.local".to_owned() }),
+//! );
+//! ```
+
+use super::reassembly::Reassembler;
+use super::wire::{self, Message, Question, Resource};
+use super::{Transmit, MULTICAST_ADDR, MULTICAST_PORT};
+use crate::{MalformedPacket, Record, RecordKind, TxtRecordValue};
+
+use std::cmp::Ordering;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use unicase::UniCase;
+
+/// The name DNS-SD browsers query to list the service types on a network.
+pub const SERVICE_TYPES: &str = "_services._dns-sd._udp.local";
+
+/// How many probes are sent before a name is considered ours.
+const PROBE_COUNT: u8 = 3;
+/// The time between probes, and after the last probe.
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+/// How many times a newly claimed name is announced.
+const ANNOUNCE_COUNT: u8 = 2;
+/// The time between announcements.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to wait after losing a simultaneous probe.
+const PROBE_DEFER: Duration = Duration::from_secs(1);
+
+/// Too many conflicts within this window slow probing down.
+const CONFLICT_WINDOW: Duration = Duration::from_secs(10);
+/// The number of conflicts in the window that counts as too many.
+const CONFLICT_LIMIT: usize = 15;
+/// How long to wait before probing after too many conflicts.
+const CONFLICT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// The TTL of records whose data refers to a host name (RFC 6762 section 10).
+const HOST_TTL: u32 = 120;
+/// The TTL of every other record.
+const OTHER_TTL: u32 = 4500;
+/// The highest TTL given in replies to legacy unicast queries.
+const LEGACY_TTL: u32 = 10;
+
+/// The least time shared answers are held back for.
+const SHARED_DELAY_MIN: Duration = Duration::from_millis(20);
+/// How much longer than the least time shared answers may be held back for.
+const SHARED_DELAY_SPREAD: Duration = Duration::from_millis(100);
+/// The least time between multicasts of the same record.
+const MULTICAST_INTERVAL: Duration = Duration::from_secs(1);
+/// The largest goodbye sent in one datagram, which fits in an Ethernet frame
+/// even over IPv6 (RFC 6762 section 17).
+const MAX_GOODBYE_SIZE: usize = 1452;
+/// The least time between multicasts of the same record in answer to
+/// probes, which must be defended quickly.
+const PROBE_DEFENSE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Identifies a service registered with a [`Responder`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Id(u64);
+
+/// A service instance to advertise.
+///
+/// Instance names are taken literally, and must not contain dots.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Service {
+    instance: String,
+    service_type: String,
+    host: String,
+    port: u16,
+    txt: HashMap<UniCase<String>, TxtRecordValue>,
+    subtypes: Vec<String>,
+}
+
+/// A host name, and the addresses it resolves to.
+///
+/// Besides the `A` and `AAAA` records for its name, a host answers reverse
+/// lookups of its addresses under `in-addr.arpa` and `ip6.arpa`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Host {
+    name: String,
+    addresses: Vec<IpAddr>,
+}
+
+/// What to do when another host turns out to own a name we claimed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Picks the next free-looking name and probes for that instead: services
+    /// go from "Kitchen" to "Kitchen (2)", hosts from `kitchen.local` to
+    /// `kitchen-2.local`, taking the services on them along.
+    #[default]
+    Rename,
+    /// Gives up the name altogether.
+    Abandon,
+}
+
+/// Something the responder wants to report to its user.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// Probing found nobody else using the name, which is now being
+    /// announced.
+    Registered { id: Id, name: String },
+    /// Another host is using a name we probed for or announced.
+    ///
+    /// `renamed_to` holds the name probed for next, or `None` if the service
+    /// was abandoned.
+    NameConflict {
+        id: Id,
+        name: String,
+        renamed_to: Option<String>,
+    },
+}
+
+/// The state machine behind advertising services and host names.
+#[derive(Clone, Debug)]
+pub struct Responder {
+    entries: Vec<Entry>,
+    next_id: u64,
+    conflict_policy: ConflictPolicy,
+    /// When the recent conflicts happened, oldest first.
+    conflicts: VecDeque<Instant>,
+    /// When each of our records was last multicast.
+    multicast_at: HashMap<RecordKey, Instant>,
+    /// Shared answers waiting to be multicast.
+    pending: Option<Pending>,
+    /// Holds back queries with the TC bit set until the rest of their known
+    /// answers arrive (RFC 6762 section 7.2).
+    reassembler: Reassembler,
+    rng: u64,
+
+    transmits: VecDeque<Transmit>,
+    events: VecDeque<Event>,
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    id: Id,
+    claim: Claim,
+    /// The name the claim was registered with, before any renaming.
+    original: String,
+    /// How many names have been tried so far.
+    attempt: u32,
+    state: State,
+}
+
+/// What an entry claims.
+#[derive(Clone, Debug)]
+enum Claim {
+    Service(Service),
+    Host(Host),
+}
+
+/// A multicast response waiting for its random delay to pass.
+#[derive(Clone, Debug)]
+struct Pending {
+    at: Instant,
+    answers: Vec<Resource>,
+}
+
+/// Identifies a record regardless of its TTL: the lowercased name, the
+/// type and the data.
+type RecordKey = (String, u16, Vec<u8>);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Probing { sent: u8, next: Instant },
+    Announcing { sent: u8, next: Instant },
+    Established,
+}
+
+impl Service {
+    /// Creates a service of the given type, e.g. `_http._tcp.local`, running
+    /// on the given host and port.
+    pub fn new<I, T, H>(instance: I, service_type: T, host: H, port: u16) -> Self
+    where
+        I: Into<String>,
+        T: Into<String>,
+        H: Into<String>,
+    {
+        Service {
+            instance: instance.into(),
+            service_type: service_type.into(),
+            host: host.into(),
+            port,
+            txt: HashMap::new(),
+            subtypes: Vec::new(),
+        }
+    }
+
+    /// Adds a subtype the service can be browsed by, e.g.
+    /// `_printer._sub._http._tcp.local` (RFC 6763 section 7.1).
+    pub fn subtype<S>(mut self, subtype: S) -> Self
+    where
+        S: Into<String>,
+    {
+        let subtype = subtype.into();
+        if !self.subtypes.contains(&subtype) {
+            self.subtypes.push(subtype);
+        }
+        self
+    }
+
+    /// Adds an entry to the service's TXT record.
+    pub fn txt<K>(mut self, key: K, value: TxtRecordValue) -> Self
+    where
+        K: Into<String>,
+    {
+        self.txt.insert(UniCase::new(key.into()), value);
+        self
+    }
+
+    /// The instance name, e.g. `Kitchen`.
+    pub fn instance(&self) -> &str {
+        &self.instance
+    }
+
+    /// The service type, e.g. `_http._tcp.local`.
+    pub fn service_type(&self) -> &str {
+        &self.service_type
+    }
+
+    /// The host the service runs on.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// The port the service listens on.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// The entries of the service's TXT record.
+    pub fn txt_records(&self) -> impl Iterator<Item = (&str, &TxtRecordValue)> {
+        self.txt.iter().map(|(key, value)| (key.as_str(), value))
+    }
+
+    /// The full name of the instance, e.g. `Kitchen._http._tcp.local`.
+    pub fn full_name(&self) -> String {
+        format!("{}.{}", self.instance, self.service_type)
+    }
+
+    /// The records only this service may hold.
+    fn unique_records(&self) -> Vec<Record> {
+        let name = self.full_name();
+        vec![
+            record(
+                &name,
+                HOST_TTL,
+                RecordKind::SRV {
+                    priority: 0,
+                    weight: 0,
+                    port: self.port,
+                    target: self.host.clone(),
+                },
+            ),
+            record(&name, OTHER_TTL, RecordKind::TXT(self.txt.clone())),
+        ]
+    }
+
+    /// The subtypes the service can be browsed by.
+    pub fn subtypes(&self) -> &[String] {
+        &self.subtypes
+    }
+
+    /// The records other instances of the service type hold too.
+    fn shared_records(&self) -> Vec<Record> {
+        let mut records = vec![
+            record(
+                &self.service_type,
+                OTHER_TTL,
+                RecordKind::PTR(self.full_name()),
+            ),
+            record(
+                SERVICE_TYPES,
+                OTHER_TTL,
+                RecordKind::PTR(self.service_type.clone()),
+            ),
+        ];
+        records.extend(
+            self.subtypes
+                .iter()
+                .map(|subtype| record(subtype, OTHER_TTL, RecordKind::PTR(self.full_name()))),
+        );
+        records
+    }
+}
+
+impl Host {
+    /// Creates a host name, e.g. `appliance-1234.local`, with no addresses.
+    pub fn new<N>(name: N) -> Self
+    where
+        N: Into<String>,
+    {
+        Host {
+            name: name.into(),
+            addresses: Vec::new(),
+        }
+    }
+
+    /// Adds an address the host name resolves to.
+    pub fn address<A>(mut self, address: A) -> Self
+    where
+        A: Into<IpAddr>,
+    {
+        let address = address.into();
+        if !self.addresses.contains(&address) {
+            self.addresses.push(address);
+        }
+        self
+    }
+
+    /// The host name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The addresses the host name resolves to.
+    pub fn addresses(&self) -> &[IpAddr] {
+        &self.addresses
+    }
+
+    fn unique_records(&self) -> Vec<Record> {
+        let forward = self.addresses.iter().map(|address| {
+            let kind = match *address {
+                IpAddr::V4(address) => RecordKind::A(address),
+                IpAddr::V6(address) => RecordKind::AAAA(address),
+            };
+            record(&self.name, HOST_TTL, kind)
+        });
+        let reverse = self.addresses.iter().map(|address| {
+            record(
+                &reverse_name(address),
+                HOST_TTL,
+                RecordKind::PTR(self.name.clone()),
+            )
+        });
+
+        forward.chain(reverse).collect()
+    }
+}
+
+impl Claim {
+    /// The name probed for.
+    fn name(&self) -> String {
+        match *self {
+            Claim::Service(ref service) => service.full_name(),
+            Claim::Host(ref host) => host.name.clone(),
+        }
+    }
+
+    /// The records only we may hold, which are announced with the
+    /// cache-flush bit.
+    fn unique_records(&self) -> Vec<Record> {
+        match *self {
+            Claim::Service(ref service) => service.unique_records(),
+            Claim::Host(ref host) => host.unique_records(),
+        }
+    }
+
+    /// The unique records under the name probed for, which are what
+    /// simultaneous probes are compared by.
+    fn proposed_records(&self) -> Vec<Record> {
+        let name = self.name();
+        self.unique_records()
+            .into_iter()
+            .filter(|record| record.name.eq_ignore_ascii_case(&name))
+            .collect()
+    }
+
+    fn resources(&self) -> Vec<Resource> {
+        let shared = match *self {
+            Claim::Service(ref service) => service.shared_records(),
+            Claim::Host(..) => Vec::new(),
+        };
+
+        self.unique_records()
+            .into_iter()
+            .map(Resource::unique)
+            .chain(shared.into_iter().map(Resource::shared))
+            .collect()
+    }
+
+    /// Switches to the name to try after `attempt` names were taken.
+    fn rename(&mut self, original: &str, attempt: u32) {
+        match *self {
+            Claim::Service(ref mut service) => {
+                service.instance = format!("{} ({})", original, attempt + 1);
+            }
+            Claim::Host(ref mut host) => {
+                let (label, domain) = original.split_once('.').unwrap_or((original, "local"));
+                host.name = format!("{}-{}.{}", label, attempt + 1, domain);
+            }
+        }
+    }
+}
+
+impl Responder {
+    /// Creates a responder with nothing registered.
+    pub fn new() -> Self {
+        let seed = RandomState::new().build_hasher().finish();
+
+        Responder {
+            entries: Vec::new(),
+            next_id: 0,
+            conflict_policy: ConflictPolicy::default(),
+            conflicts: VecDeque::new(),
+            multicast_at: HashMap::new(),
+            pending: None,
+            reassembler: Reassembler::new(),
+            // xorshift gets stuck on zero
+            rng: seed.max(1),
+            transmits: VecDeque::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Sets what to do when another host owns a name we claimed.
+    ///
+    /// Defaults to [`ConflictPolicy::Rename`].
+    pub fn conflict_policy(mut self, policy: ConflictPolicy) -> Self {
+        self.conflict_policy = policy;
+        self
+    }
+
+    /// Seeds the generator behind the random delays, making them repeatable.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.rng = seed.max(1);
+        self
+    }
+
+    pub(crate) fn set_conflict_policy(&mut self, policy: ConflictPolicy) {
+        self.conflict_policy = policy;
+    }
+
+    /// Starts probing for a service's name at time `now`.
+    ///
+    /// The first probe goes out after a random delay of up to 250ms, so that
+    /// hosts powered on together don't all probe at once.
+    pub fn register(&mut self, now: Instant, service: Service) -> Id {
+        let original = service.instance.clone();
+        self.claim(now, Claim::Service(service), original)
+    }
+
+    /// Starts probing for a host name at time `now`.
+    ///
+    /// Like services, the first probe goes out after a random delay.
+    pub fn register_host(&mut self, now: Instant, host: Host) -> Id {
+        let original = host.name.clone();
+        self.claim(now, Claim::Host(host), original)
+    }
+
+    /// The service registered under `id`, with its current name.
+    pub fn service(&self, id: Id) -> Option<&Service> {
+        match self.entry(id)?.claim {
+            Claim::Service(ref service) => Some(service),
+            Claim::Host(..) => None,
+        }
+    }
+
+    /// The host registered under `id`, with its current name.
+    pub fn host(&self, id: Id) -> Option<&Host> {
+        match self.entry(id)?.claim {
+            Claim::Host(ref host) => Some(host),
+            Claim::Service(..) => None,
+        }
+    }
+
+    /// Replaces the addresses of the host registered under `id`.
+    ///
+    /// Records for addresses that went away are withdrawn, and the new set is
+    /// announced. Nothing happens if the addresses didn't change.
+    pub fn set_host_addresses<I>(&mut self, now: Instant, id: Id, addresses: I)
+    where
+        I: IntoIterator<Item = IpAddr>,
+    {
+        self.update(now, id, |claim| {
+            if let Claim::Host(ref mut host) = *claim {
+                host.addresses = addresses
+                    .into_iter()
+                    .fold(Host::new(host.name.clone()), Host::address)
+                    .addresses;
+            }
+        });
+    }
+
+    /// Replaces the TXT record of the service registered under `id`.
+    ///
+    /// The new record is announced with the cache-flush bit set, so that it
+    /// replaces the old one in every cache.
+    pub fn set_service_txt<I, K>(&mut self, now: Instant, id: Id, txt: I)
+    where
+        I: IntoIterator<Item = (K, TxtRecordValue)>,
+        K: Into<String>,
+    {
+        self.update(now, id, |claim| {
+            if let Claim::Service(ref mut service) = *claim {
+                service.txt = txt
+                    .into_iter()
+                    .map(|(key, value)| (UniCase::new(key.into()), value))
+                    .collect();
+            }
+        });
+    }
+
+    /// Changes the port of the service registered under `id`, announcing
+    /// its new `SRV` record.
+    pub fn set_service_port(&mut self, now: Instant, id: Id, port: u16) {
+        self.update(now, id, |claim| {
+            if let Claim::Service(ref mut service) = *claim {
+                service.port = port;
+            }
+        });
+    }
+
+    /// Stops advertising whatever is registered under `id`, sending goodbyes
+    /// for its records if they were announced (RFC 6762 section 10.1).
+    ///
+    /// Shared records another registration still holds, such as the `PTR`
+    /// record listing a service type, are kept.
+    pub fn unregister(&mut self, now: Instant, id: Id) {
+        let index = match self.entries.iter().position(|entry| entry.id == id) {
+            Some(index) => index,
+            None => return,
+        };
+        let entry = self.entries.remove(index);
+        let remaining = self.prune_pending();
+
+        if let State::Probing { .. } = entry.state {
+            return;
+        }
+
+        let withdrawn: Vec<_> = entry
+            .claim
+            .resources()
+            .into_iter()
+            .filter(|resource| !remaining.contains(resource))
+            .collect();
+        if !withdrawn.is_empty() {
+            for goodbye in goodbyes(withdrawn) {
+                note_multicast(&mut self.multicast_at, now, &goodbye);
+                self.transmits.push_back(multicast(goodbye));
+            }
+        }
+    }
+
+    /// Withdraws everything registered, sending goodbyes for every record
+    /// that was announced.
+    ///
+    /// Shared records several registrations hold are said goodbye to once.
+    pub fn shutdown(&mut self) {
+        let mut announced = Vec::new();
+        for resource in self
+            .entries
+            .drain(..)
+            .filter(|entry| !matches!(entry.state, State::Probing { .. }))
+            .flat_map(|entry| entry.claim.resources())
+        {
+            if !announced.contains(&resource) {
+                announced.push(resource);
+            }
+        }
+
+        if !announced.is_empty() {
+            self.transmits
+                .extend(goodbyes(announced).into_iter().map(multicast));
+        }
+        self.multicast_at.clear();
+        self.pending = None;
+    }
+
+    /// Processes a datagram received from `source` at time `now`.
+    ///
+    /// A query coming from a port other than 5353 gets a conventional
+    /// unicast DNS reply:
+    ///
+    /// ```rust
+    /// use mdns::proto::responder::{Host, Responder};
+    /// use std::net::Ipv4Addr;
+    /// use std::time::Instant;
+    ///
+    /// let mut now = Instant::now();
+    /// let mut responder = Responder::new();
+    /// responder.register_host(now, Host::new("printer.local").address(Ipv4Addr::new(192, 168, 1, 20)));
+    /// while let Some(deadline) = responder.poll_timeout() {
+    ///     now = deadline;
+    ///     responder.handle_timeout(now);
+    /// }
+    /// while responder.poll_transmit().is_some() {}
+    ///
+    /// let mut query = dns_parser::Builder::new_query(0x1234, false);
+    /// query.add_question("printer.local", false, dns_parser::QueryType::A, dns_parser::QueryClass::IN);
+    /// let resolver = "192.168.1.10:49152".parse().unwrap();
+    /// responder.handle_datagram(now, resolver, &query.build().unwrap());
+    ///
+    /// let reply = responder.poll_transmit().unwrap();
+    /// assert_eq!(reply.destination, resolver);
+    /// let reply = dns_parser::Packet::parse(&reply.contents).unwrap();
+    /// assert_eq!(reply.header.id, 0x1234);
+    /// assert_eq!(reply.questions.len(), 1);
+    /// assert_eq!(reply.answers[0].ttl, 10);
+    /// ```
+    pub fn handle_datagram(&mut self, now: Instant, source: SocketAddr, datagram: &[u8]) {
+        self.handle_timeout(now);
+
+        self.reassembler.handle_datagram(now, source, datagram);
+        self.handle_reassembled(now);
+    }
+
+    fn handle_reassembled(&mut self, now: Instant) {
+        while let Some(datagram) = self.reassembler.poll_datagram() {
+            let packet = match dns_parser::Packet::parse(&datagram.contents) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    let packet = MalformedPacket::new(&datagram.contents, &e);
+                    log::warn!("malformed packet from {}: {}", datagram.source, packet);
+                    continue;
+                }
+            };
+
+            // Messages with other opcodes must be silently ignored (RFC 6762
+            // section 18.3).
+            if packet.header.opcode != dns_parser::Opcode::StandardQuery {
+                continue;
+            }
+
+            if packet.header.query {
+                self.handle_query(now, datagram.source, &packet);
+            } else {
+                self.handle_response(now, &packet);
+            }
+        }
+    }
+
+    /// Advances the state machine's clock to `now`, sending any probes,
+    /// announcements and delayed answers that are due.
+    ///
+    /// ```rust
+    /// use mdns::proto::responder::{Responder, Service};
+    /// use std::time::{Duration, Instant};
+    ///
+    /// let mut now = Instant::now();
+    /// let mut responder = Responder::new();
+    /// responder.register(now, Service::new("Kitchen", "_http._tcp.local", "kitchen.local", 80));
+    /// while let Some(deadline) = responder.poll_timeout() {
+    ///     now = deadline;
+    ///     responder.handle_timeout(now);
+    /// }
+    /// while responder.poll_transmit().is_some() {}
+    /// now += Duration::from_secs(5);
+    ///
+    /// // Other hosts may have `_http._tcp.local` instances too, so the answer
+    /// // waits a little to avoid colliding with theirs.
+    /// let mut browse = dns_parser::Builder::new_query(0, false);
+    /// browse.add_question("_http._tcp.local", false, dns_parser::QueryType::PTR, dns_parser::QueryClass::IN);
+    /// responder.handle_datagram(now, "192.168.1.10:5353".parse().unwrap(), &browse.build().unwrap());
+    /// assert!(responder.poll_transmit().is_none());
+    ///
+    /// let deadline = responder.poll_timeout().unwrap();
+    /// assert!(deadline >= now + Duration::from_millis(20));
+    /// assert!(deadline <= now + Duration::from_millis(120));
+    /// responder.handle_timeout(deadline);
+    /// assert!(responder.poll_transmit().is_some());
+    /// ```
+    pub fn handle_timeout(&mut self, now: Instant) {
+        for entry in &mut self.entries {
+            loop {
+                match entry.state {
+                    State::Probing { sent, next } if next <= now => {
+                        if sent == PROBE_COUNT {
+                            self.events.push_back(Event::Registered {
+                                id: entry.id,
+                                name: entry.claim.name(),
+                            });
+                            entry.state = State::Announcing { sent: 0, next: now };
+                            continue;
+                        }
+
+                        self.transmits
+                            .push_back(multicast(probe(&entry.claim, sent == 0)));
+                        entry.state = State::Probing {
+                            sent: sent + 1,
+                            next: now + PROBE_INTERVAL,
+                        };
+                    }
+                    State::Announcing { sent, next } if next <= now => {
+                        let mut announcement = Message::response(0);
+                        announcement.answers = entry.claim.resources();
+                        note_multicast(&mut self.multicast_at, now, &announcement);
+                        self.transmits.push_back(multicast(announcement));
+
+                        entry.state = if sent + 1 == ANNOUNCE_COUNT {
+                            State::Established
+                        } else {
+                            State::Announcing {
+                                sent: sent + 1,
+                                next: now + ANNOUNCE_INTERVAL,
+                            }
+                        };
+                    }
+                    _ => {}
+                }
+                break;
+            }
+        }
+
+        self.reassembler.handle_timeout(now);
+        self.handle_reassembled(now);
+
+        if let Some(pending) = self.pending.take() {
+            if pending.at <= now {
+                self.send_multicast(now, pending.answers, MULTICAST_INTERVAL);
+            } else {
+                self.pending = Some(pending);
+            }
+        }
+    }
+
+    fn claim(&mut self, now: Instant, claim: Claim, original: String) -> Id {
+        let id = Id(self.next_id);
+        self.next_id += 1;
+
+        let delay = self.random_delay(PROBE_INTERVAL);
+        self.entries.push(Entry {
+            id,
+            claim,
+            original,
+            attempt: 1,
+            state: State::Probing {
+                sent: 0,
+                next: now + delay,
+            },
+        });
+
+        id
+    }
+
+    fn entry(&self, id: Id) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.id == id)
+    }
+
+    /// Changes the records of a claimed name, withdrawing the records that
+    /// went away and announcing the rest again (RFC 6762 section 8.4).
+    ///
+    /// The name is already ours, so there is no need to probe for it again.
+    fn update<F>(&mut self, now: Instant, id: Id, update: F)
+    where
+        F: FnOnce(&mut Claim),
+    {
+        let entry = match self.entries.iter_mut().find(|entry| entry.id == id) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        let before = entry.claim.resources();
+        update(&mut entry.claim);
+        let after = entry.claim.resources();
+        if before == after {
+            return;
+        }
+
+        // Names still being probed for haven't been announced yet, and the
+        // remaining probes propose the new records.
+        if let State::Probing { .. } = entry.state {
+            return;
+        }
+
+        let withdrawn: Vec<_> = before
+            .into_iter()
+            .filter(|resource| !after.contains(resource))
+            .collect();
+        if !withdrawn.is_empty() {
+            for goodbye in goodbyes(withdrawn) {
+                note_multicast(&mut self.multicast_at, now, &goodbye);
+                self.transmits.push_back(multicast(goodbye));
+            }
+        }
+        entry.state = State::Announcing { sent: 0, next: now };
+        self.prune_pending();
+        self.handle_timeout(now);
+    }
+
+    /// Drops waiting answers we no longer hold, returning the records we do.
+    fn prune_pending(&mut self) -> Vec<Resource> {
+        let held: Vec<_> = self
+            .entries
+            .iter()
+            .flat_map(|entry| entry.claim.resources())
+            .collect();
+
+        if let Some(ref mut pending) = self.pending {
+            pending.answers.retain(|resource| held.contains(resource));
+            if pending.answers.is_empty() {
+                self.pending = None;
+            }
+        }
+
+        held
+    }
+
+    /// Returns the next datagram that should be sent, if any.
+    pub fn poll_transmit(&mut self) -> Option<Transmit> {
+        self.transmits.pop_front()
+    }
+
+    /// Returns the next event produced by the state machine, if any.
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// Returns the instant at which [`handle_timeout`](Self::handle_timeout)
+    /// should next be called, or `None` if nothing is scheduled.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        self.entries
+            .iter()
+            .filter_map(|entry| match entry.state {
+                State::Probing { next, .. } | State::Announcing { next, .. } => Some(next),
+                State::Established => None,
+            })
+            .chain(self.pending.as_ref().map(|pending| pending.at))
+            .chain(self.reassembler.poll_timeout())
+            .min()
+    }
+
+    fn handle_query(&mut self, now: Instant, source: SocketAddr, packet: &dns_parser::Packet) {
+        // Simultaneous probes for the same name are won by the host proposing
+        // the lexicographically later records (RFC 6762 section 8.2).
+        for entry in &mut self.entries {
+            if let State::Probing { .. } = entry.state {
+                let name = entry.claim.name();
+                let theirs: Vec<_> = packet
+                    .nameservers
+                    .iter()
+                    .filter(|rr| rr.name.to_string().eq_ignore_ascii_case(&name))
+                    .map(|rr| {
+                        let record = Record::from_resource_record(rr);
+                        sort_key(&record)
+                    })
+                    .collect();
+
+                if theirs.is_empty() {
+                    continue;
+                }
+
+                if tiebreak(&entry.claim.proposed_records(), theirs) == Ordering::Less {
+                    log::debug!("lost simultaneous probe for {}", name);
+                    entry.state = State::Probing {
+                        sent: 0,
+                        next: now + PROBE_DEFER,
+                    };
+                }
+            }
+        }
+
+        if source.port() != MULTICAST_PORT {
+            self.answer_legacy(source, packet);
+            return;
+        }
+
+        // Answers the asker already has with at least half their TTL left
+        // needn't be repeated (RFC 6762 section 7.1).
+        let known_answers: Vec<_> = packet
+            .answers
+            .iter()
+            .map(|rr| (record_key(&Record::from_resource_record(rr)), rr.ttl))
+            .collect();
+        let is_known = |resource: &Resource| {
+            let key = record_key(&resource.record);
+            known_answers
+                .iter()
+                .any(|(known, ttl)| *known == key && *ttl >= resource.record.ttl / 2)
+        };
+
+        let mut multicast_answers = Vec::new();
+        let mut unicast_response = Message::response(0);
+        for question in &packet.questions {
+            if !is_internet(question.qclass) {
+                continue;
+            }
+
+            let name = question.qname.to_string();
+            for resource in self.answers(&name, question.qtype) {
+                if is_known(&resource) {
+                    continue;
+                }
+
+                // Records multicast within the last quarter of their TTL are
+                // in everyone's cache already, so only the asker needs them
+                // (RFC 6762 section 5.4).
+                let quarter = Duration::from_secs(u64::from(resource.record.ttl) / 4);
+                let answers =
+                    if question.prefer_unicast && self.multicast_within(now, &resource, quarter) {
+                        &mut unicast_response.answers
+                    } else {
+                        &mut multicast_answers
+                    };
+                if !answers.contains(&resource) {
+                    answers.push(resource);
+                }
+            }
+        }
+
+        if !unicast_response.answers.is_empty() {
+            unicast_response.additional = self.additional(&unicast_response.answers);
+            self.transmits.push_back(Transmit {
+                destination: source,
+                contents: unicast_response.encode(),
+            });
+        }
+
+        if multicast_answers.is_empty() {
+            return;
+        }
+
+        // Probes are answered straight away, so that the prober hears about
+        // the conflict before it is done probing. So are answers only we can
+        // give, as nobody else's answer is going to collide with them
+        // (RFC 6762 section 6).
+        let probe = !packet.nameservers.is_empty();
+        if probe {
+            self.send_multicast(now, multicast_answers, PROBE_DEFENSE_INTERVAL);
+        } else if multicast_answers
+            .iter()
+            .all(|resource| resource.cache_flush)
+        {
+            self.send_multicast(now, multicast_answers, MULTICAST_INTERVAL);
+        } else {
+            let at = now + SHARED_DELAY_MIN + self.random_delay(SHARED_DELAY_SPREAD);
+            let pending = self.pending.get_or_insert(Pending {
+                at,
+                answers: Vec::new(),
+            });
+            for resource in multicast_answers {
+                if !pending.answers.contains(&resource) {
+                    pending.answers.push(resource);
+                }
+            }
+        }
+    }
+
+    /// Multicasts some answers, leaving out any sent less than
+    /// `min_interval` ago.
+    fn send_multicast(&mut self, now: Instant, answers: Vec<Resource>, min_interval: Duration) {
+        let answers: Vec<_> = answers
+            .into_iter()
+            .filter(|resource| !self.multicast_within(now, resource, min_interval))
+            .collect();
+        if answers.is_empty() {
+            return;
+        }
+
+        let mut response = Message::response(0);
+        response.additional = self.additional(&answers);
+        response.answers = answers;
+        note_multicast(&mut self.multicast_at, now, &response);
+        self.transmits.push_back(multicast(response));
+    }
+
+    /// Answers a query from a resolver that isn't taking part in mDNS
+    /// (RFC 6762 section 6.7).
+    ///
+    /// The reply goes straight back to the resolver, echoes its query ID and
+    /// questions, and caps TTLs so that it doesn't hold on to records it
+    /// won't see updates for. The cache-flush bit means nothing to such a
+    /// resolver, so it is left out (RFC 6762 section 10.2).
+    fn answer_legacy(&mut self, source: SocketAddr, packet: &dns_parser::Packet) {
+        let mut response = Message::response(packet.header.id);
+        for question in &packet.questions {
+            if !is_internet(question.qclass) {
+                continue;
+            }
+
+            let name = question.qname.to_string();
+            response
+                .questions
+                .push(Question::new(name.clone(), question.qtype));
+            for resource in self.answers(&name, question.qtype) {
+                if !response.answers.contains(&resource) {
+                    response.answers.push(resource);
+                }
+            }
+        }
+
+        if response.answers.is_empty() {
+            return;
+        }
+
+        response.additional = self.additional(&response.answers);
+        for resource in response
+            .answers
+            .iter_mut()
+            .chain(response.additional.iter_mut())
+        {
+            resource.record.ttl = resource.record.ttl.min(LEGACY_TTL);
+            resource.cache_flush = false;
+        }
+
+        self.transmits.push_back(Transmit {
+            destination: source,
+            contents: response.encode(),
+        });
+    }
+
+    /// Whether a record was multicast less than `window` ago.
+    fn multicast_within(&self, now: Instant, resource: &Resource, window: Duration) -> bool {
+        self.multicast_at
+            .get(&record_key(&resource.record))
+            .map_or(false, |&at| now.saturating_duration_since(at) < window)
+    }
+
+    fn handle_response(&mut self, now: Instant, packet: &dns_parser::Packet) {
+        // Another host multicasting an answer we were about to give saves us
+        // the trouble, as long as it doesn't have a much lower TTL than ours
+        // (RFC 6762 section 7.4).
+        if let Some(ref mut pending) = self.pending {
+            for rr in packet.answers.iter().chain(&packet.additional) {
+                let key = record_key(&Record::from_resource_record(rr));
+                pending.answers.retain(|resource| {
+                    record_key(&resource.record) != key || rr.ttl < resource.record.ttl / 2
+                });
+            }
+            if pending.answers.is_empty() {
+                self.pending = None;
+            }
+        }
+
+        let mut conflicting = Vec::new();
+
+        for rr in packet.answers.iter().chain(&packet.additional) {
+            let theirs = Record::from_resource_record(rr);
+
+            for entry in &self.entries {
+                if conflicting.contains(&entry.id)
+                    || !theirs.name.eq_ignore_ascii_case(&entry.claim.name())
+                {
+                    continue;
+                }
+
+                let conflict = match entry.state {
+                    // Any answer for a name we're probing for means it's taken.
+                    State::Probing { .. } => true,
+                    // Once the name is ours, only records of a type we hold
+                    // that match none of ours do (RFC 6762 section 9).
+                    State::Announcing { .. } | State::Established => {
+                        let ours: Vec<_> = entry
+                            .claim
+                            .proposed_records()
+                            .into_iter()
+                            .filter(|ours| {
+                                wire::record_type(&ours.kind) == wire::record_type(&theirs.kind)
+                                    && ours.class == theirs.class
+                            })
+                            .collect();
+                        !ours.is_empty()
+                            && ours
+                                .iter()
+                                .all(|ours| wire::rdata(&ours.kind) != wire::rdata(&theirs.kind))
+                    }
+                };
+
+                if conflict {
+                    conflicting.push(entry.id);
+                }
+            }
+        }
+
+        for id in conflicting {
+            self.handle_conflict(now, id);
+        }
+    }
+
+    fn handle_conflict(&mut self, now: Instant, id: Id) {
+        self.conflicts.push_back(now);
+        while let Some(&oldest) = self.conflicts.front() {
+            if now.saturating_duration_since(oldest) < CONFLICT_WINDOW {
+                break;
+            }
+            self.conflicts.pop_front();
+        }
+
+        // Hosts that keep running into conflicts must slow down, so that two
+        // misbehaving hosts don't flood the network (RFC 6762 section 8.1).
+        let delay = if self.conflicts.len() >= CONFLICT_LIMIT {
+            CONFLICT_BACKOFF
+        } else {
+            self.random_delay(PROBE_INTERVAL)
+        };
+
+        let index = match self.entries.iter().position(|entry| entry.id == id) {
+            Some(index) => index,
+            None => return,
+        };
+        let entry = &mut self.entries[index];
+        let name = entry.claim.name();
+        log::info!("name conflict for {}", name);
+
+        match self.conflict_policy {
+            ConflictPolicy::Rename => {
+                entry.claim.rename(&entry.original, entry.attempt);
+                entry.attempt += 1;
+                entry.state = State::Probing {
+                    sent: 0,
+                    next: now + delay,
+                };
+                let renamed_to = entry.claim.name();
+                let is_host = matches!(entry.claim, Claim::Host(..));
+                self.events.push_back(Event::NameConflict {
+                    id,
+                    name: name.clone(),
+                    renamed_to: Some(renamed_to.clone()),
+                });
+
+                // Nobody answers for the old host name anymore.
+                if is_host {
+                    self.move_services(now, &name, &renamed_to);
+                }
+            }
+            ConflictPolicy::Abandon => {
+                self.entries.remove(index);
+                self.events.push_back(Event::NameConflict {
+                    id,
+                    name,
+                    renamed_to: None,
+                });
+            }
+        }
+    }
+
+    /// Points the `SRV` records of the services on host `from` at host `to`,
+    /// announcing the new records of those already announced.
+    fn move_services(&mut self, now: Instant, from: &str, to: &str) {
+        let moved: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|entry| match entry.claim {
+                Claim::Service(ref service) => service.host.eq_ignore_ascii_case(from),
+                Claim::Host(..) => false,
+            })
+            .map(|entry| entry.id)
+            .collect();
+
+        for id in moved {
+            self.update(now, id, |claim| {
+                if let Claim::Service(ref mut service) = *claim {
+                    service.host = to.to_owned();
+                }
+            });
+        }
+    }
+
+    /// Our records that answer a question, if we are allowed to give them.
+    fn answers(&self, name: &str, query_type: dns_parser::QueryType) -> Vec<Resource> {
+        self.entries
+            .iter()
+            .filter(|entry| !matches!(entry.state, State::Probing { .. }))
+            .flat_map(|entry| entry.claim.resources())
+            .filter(|resource| {
+                resource.record.name.eq_ignore_ascii_case(name)
+                    && (query_type == dns_parser::QueryType::All
+                        || wire::record_type(&resource.record.kind) == Some(query_type as u16))
+            })
+            .collect()
+    }
+
+    /// The records that go along with some answers (RFC 6763 section 12):
+    /// the `SRV` and `TXT` records of the instances in `PTR` answers, and the
+    /// addresses of the hosts in `SRV` records.
+    fn additional(&self, answers: &[Resource]) -> Vec<Resource> {
+        let mut additional: Vec<Resource> = Vec::new();
+        let add = |resources: Vec<Resource>, additional: &mut Vec<Resource>| {
+            for resource in resources {
+                if !answers.contains(&resource) && !additional.contains(&resource) {
+                    additional.push(resource);
+                }
+            }
+        };
+
+        for answer in answers {
+            if let RecordKind::PTR(ref target) = answer.record.kind {
+                if !answer.record.name.ends_with(".arpa") {
+                    add(
+                        self.answers(target, dns_parser::QueryType::All),
+                        &mut additional,
+                    );
+                }
+            }
+        }
+
+        let targets: Vec<String> = answers
+            .iter()
+            .chain(&additional)
+            .filter_map(|resource| match resource.record.kind {
+                RecordKind::SRV { ref target, .. } => Some(target.clone()),
+                _ => None,
+            })
+            .collect();
+        for target in targets {
+            add(
+                self.answers(&target, dns_parser::QueryType::A),
+                &mut additional,
+            );
+            add(
+                self.answers(&target, dns_parser::QueryType::AAAA),
+                &mut additional,
+            );
+        }
+
+        additional
+    }
+
+    /// A random delay of up to `max`.
+    fn random_delay(&mut self, max: Duration) -> Duration {
+        // xorshift64*
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let bits = self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        let fraction = (bits >> 11) as f64 / (1u64 << 53) as f64;
+        max.mul_f64(fraction)
+    }
+}
+
+impl Default for Responder {
+    fn default() -> Self {
+        Responder::new()
+    }
+}
+
+fn record(name: &str, ttl: u32, kind: RecordKind) -> Record {
+    Record {
+        name: name.to_owned(),
+        class: dns_parser::Class::IN,
+        ttl,
+        kind,
+    }
+}
+
+fn is_internet(class: dns_parser::QueryClass) -> bool {
+    matches!(
+        class,
+        dns_parser::QueryClass::IN | dns_parser::QueryClass::Any
+    )
+}
+
+fn record_key(record: &Record) -> RecordKey {
+    (
+        record.name.to_ascii_lowercase(),
+        wire::record_type(&record.kind).unwrap_or(0),
+        wire::rdata(&record.kind),
+    )
+}
+
+/// Remembers when the records in a multicast response went out.
+fn note_multicast(multicast_at: &mut HashMap<RecordKey, Instant>, now: Instant, message: &Message) {
+    // Nothing is ever asked about records multicast longer than a quarter of
+    // the longest TTL ago.
+    let forget = Duration::from_secs(u64::from(OTHER_TTL) / 4);
+    multicast_at.retain(|_, &mut at| now.saturating_duration_since(at) < forget);
+
+    for resource in message.answers.iter().chain(&message.additional) {
+        if resource.record.ttl == 0 {
+            multicast_at.remove(&record_key(&resource.record));
+        } else {
+            multicast_at.insert(record_key(&resource.record), now);
+        }
+    }
+}
+
+fn multicast(message: Message) -> Transmit {
+    Transmit {
+        destination: SocketAddr::new(MULTICAST_ADDR.into(), MULTICAST_PORT),
+        contents: message.encode(),
+    }
+}
+
+/// Responses telling caches to forget some records, as many as it takes to
+/// keep each one within [`MAX_GOODBYE_SIZE`].
+fn goodbyes(resources: Vec<Resource>) -> Vec<Message> {
+    let mut goodbyes = vec![Message::response(0)];
+    for mut resource in resources {
+        resource.record.ttl = 0;
+
+        let goodbye = goodbyes.last_mut().unwrap();
+        goodbye.answers.push(resource);
+        if goodbye.answers.len() > 1 && goodbye.encode().len() > MAX_GOODBYE_SIZE {
+            let mut next = Message::response(0);
+            next.answers.extend(goodbye.answers.pop());
+            goodbyes.push(next);
+        }
+    }
+    goodbyes
+}
+
+/// The name reverse lookups of an address ask for, e.g.
+/// `20.1.168.192.in-addr.arpa`.
+fn reverse_name(address: &IpAddr) -> String {
+    match *address {
+        IpAddr::V4(address) => {
+            let [a, b, c, d] = address.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+        }
+        IpAddr::V6(address) => {
+            let mut name = String::with_capacity(72);
+            for byte in address.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0xf, byte >> 4));
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    }
+}
+
+/// A probe for a claimed name, proposing its unique records.
+fn probe(claim: &Claim, first: bool) -> Message {
+    let mut probe = Message::query(0);
+    probe.questions.push(Question {
+        name: claim.name(),
+        query_type: dns_parser::QueryType::All,
+        // Asking for a unicast answer to the first probe spares the network
+        // a multicast response if the name is taken (RFC 6762 section 8.1).
+        unicast_response: first,
+    });
+    probe.authority = claim
+        .proposed_records()
+        .into_iter()
+        .map(Resource::unique)
+        .collect();
+    probe
+}
+
+/// What records are ordered by when breaking ties between probes.
+fn sort_key(record: &Record) -> (u16, u16, Vec<u8>) {
+    (
+        record.class as u16,
+        wire::record_type(&record.kind).unwrap_or(0),
+        wire::rdata(&record.kind),
+    )
+}
+
+/// Compares our proposed records with another host's.
+fn tiebreak(ours: &[Record], mut theirs: Vec<(u16, u16, Vec<u8>)>) -> Ordering {
+    let mut ours: Vec<_> = ours.iter().map(sort_key).collect();
+    ours.sort();
+    theirs.sort();
+    ours.cmp(&theirs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{response, shared, unique};
+    use dns_parser::{Packet, QueryType};
+    use std::net::Ipv4Addr;
+
+    fn kitchen() -> Host {
+        Host::new("kitchen.local").address(Ipv4Addr::new(192, 168, 1, 20))
+    }
+
+    /// Runs the responder's timers up to `until`, returning when each probe
+    /// and each announcement was sent.
+    fn run_until(responder: &mut Responder, until: Instant) -> (Vec<Instant>, Vec<Instant>) {
+        let (mut probes, mut announcements) = (Vec::new(), Vec::new());
+        while let Some(at) = responder.poll_timeout().filter(|&at| at <= until) {
+            responder.handle_timeout(at);
+            while let Some(transmit) = responder.poll_transmit() {
+                let packet = Packet::parse(&transmit.contents).unwrap();
+                if packet.header.query {
+                    assert_eq!(packet.questions[0].qtype, QueryType::All);
+                    assert!(!packet.nameservers.is_empty());
+                    probes.push(at);
+                } else {
+                    announcements.push(at);
+                }
+            }
+        }
+        (probes, announcements)
+    }
+
+    /// A probe from another host for the name, proposing its own address.
+    fn their_probe(address: Ipv4Addr) -> Vec<u8> {
+        let mut probe = Message::query(0);
+        probe
+            .questions
+            .push(Question::new("kitchen.local", QueryType::All));
+        probe
+            .authority
+            .push(shared("kitchen.local", 120, RecordKind::A(address)));
+        probe.encode()
+    }
+
+    fn other_host() -> SocketAddr {
+        "192.168.1.30:5353".parse().unwrap()
+    }
+
+    #[test]
+    fn names_are_probed_for_three_times() {
+        let start = Instant::now();
+        let mut responder = Responder::new().seed(1);
+        let id = responder.register_host(start, kitchen());
+
+        let (probes, announcements) = run_until(&mut responder, start + Duration::from_secs(5));
+        assert_eq!(probes.len(), 3);
+        assert!(probes[0] <= start + PROBE_INTERVAL);
+        assert_eq!(probes[1] - probes[0], PROBE_INTERVAL);
+        assert_eq!(probes[2] - probes[1], PROBE_INTERVAL);
+
+        assert_eq!(
+            responder.poll_event(),
+            Some(Event::Registered {
+                id,
+                name: "kitchen.local".to_owned()
+            })
+        );
+        assert_eq!(announcements.len(), 2);
+        assert_eq!(announcements[0], probes[2] + PROBE_INTERVAL);
+        assert_eq!(announcements[1] - announcements[0], ANNOUNCE_INTERVAL);
+    }
+
+    #[test]
+    fn simultaneous_probes_with_later_records_win() {
+        let start = Instant::now();
+        let mut responder = Responder::new().seed(1);
+        responder.register_host(start, kitchen());
+        let (probes, _) = run_until(&mut responder, start + PROBE_INTERVAL);
+        let first = probes[0];
+
+        // 192.168.1.30 sorts after our 192.168.1.20, so we back off.
+        responder.handle_datagram(
+            first,
+            other_host(),
+            &their_probe(Ipv4Addr::new(192, 168, 1, 30)),
+        );
+        let (probes, _) = run_until(
+            &mut responder,
+            first + PROBE_DEFER - Duration::from_millis(1),
+        );
+        assert!(probes.is_empty());
+
+        // And start over after a second, without calling it a conflict.
+        let (probes, announcements) = run_until(&mut responder, first + Duration::from_secs(5));
+        assert_eq!(probes.len(), 3);
+        assert_eq!(probes[0], first + PROBE_DEFER);
+        assert!(!announcements.is_empty());
+        assert!(matches!(
+            responder.poll_event(),
+            Some(Event::Registered { .. })
+        ));
+    }
+
+    #[test]
+    fn simultaneous_probes_with_earlier_records_lose() {
+        let start = Instant::now();
+        let mut responder = Responder::new().seed(1);
+        responder.register_host(start, kitchen());
+        let (probes, _) = run_until(&mut responder, start + PROBE_INTERVAL);
+        let first = probes[0];
+
+        responder.handle_datagram(
+            first,
+            other_host(),
+            &their_probe(Ipv4Addr::new(192, 168, 1, 10)),
+        );
+        let (probes, _) = run_until(&mut responder, first + PROBE_INTERVAL);
+        assert_eq!(probes, [first + PROBE_INTERVAL]);
+    }
+
+    #[test]
+    fn an_answer_while_probing_renames() {
+        let start = Instant::now();
+        let mut responder = Responder::new().seed(1);
+        let id = responder.register(
+            start,
+            Service::new("Kitchen", "_http._tcp.local", "kitchen.local", 80),
+        );
+        let (probes, _) = run_until(&mut responder, start + PROBE_INTERVAL);
+
+        let mut answer = Message::response(0);
+        answer.answers.push(unique(
+            "Kitchen._http._tcp.local",
+            120,
+            RecordKind::SRV {
+                priority: 0,
+                weight: 0,
+                port: 8080,
+                target: "office.local".to_owned(),
+            },
+        ));
+        responder.handle_datagram(probes[0], other_host(), &answer.encode());
+        assert_eq!(
+            responder.poll_event(),
+            Some(Event::NameConflict {
+                id,
+                name: "Kitchen._http._tcp.local".to_owned(),
+                renamed_to: Some("Kitchen (2)._http._tcp.local".to_owned()),
+            })
+        );
+    }
+
+    #[test]
+    fn renamed_hosts_take_their_services_along() {
+        let start = Instant::now();
+        let mut responder = Responder::new().seed(1);
+        let service = responder.register(
+            start,
+            Service::new("Kitchen", "_http._tcp.local", "kitchen.local", 80),
+        );
+        responder.register_host(start, kitchen());
+        run_until(&mut responder, start + Duration::from_secs(5));
+        while responder.poll_event().is_some() {}
+
+        // Another host turns out to be called kitchen.local too.
+        let now = start + Duration::from_secs(10);
+        let theirs = response(vec![unique(
+            "kitchen.local",
+            120,
+            RecordKind::A(Ipv4Addr::new(192, 168, 1, 30)),
+        )]);
+        responder.handle_datagram(now, other_host(), &theirs);
+        assert!(matches!(
+            responder.poll_event(),
+            Some(Event::NameConflict { renamed_to: Some(ref name), .. }) if name == "kitchen-2.local"
+        ));
+        assert_eq!(
+            responder.service(service).unwrap().host(),
+            "kitchen-2.local"
+        );
+
+        // The old SRV record says goodbye, and the new one is announced.
+        let mut targets = Vec::new();
+        while let Some(transmit) = responder.poll_transmit() {
+            let packet = Packet::parse(&transmit.contents).unwrap();
+            for rr in &packet.answers {
+                if let RecordKind::SRV { ref target, .. } = Record::from_resource_record(rr).kind {
+                    targets.push((target.clone(), rr.ttl));
+                }
+            }
+        }
+        assert_eq!(
+            targets,
+            [
+                ("kitchen.local".to_owned(), 0),
+                ("kitchen-2.local".to_owned(), HOST_TTL)
+            ]
+        );
+    }
+
+    #[test]
+    fn shutdown_says_goodbye_to_shared_records_once() {
+        let start = Instant::now();
+        let mut responder = Responder::new().seed(1);
+        for speaker in 0..40 {
+            responder.register(
+                start,
+                Service::new(
+                    format!("Speaker {}", speaker),
+                    "_raop._tcp.local",
+                    "kitchen.local",
+                    7000,
+                ),
+            );
+        }
+        run_until(&mut responder, start + Duration::from_secs(5));
+
+        responder.shutdown();
+        let (mut datagrams, mut answers) = (0, Vec::new());
+        while let Some(transmit) = responder.poll_transmit() {
+            datagrams += 1;
+            assert!(transmit.contents.len() <= MAX_GOODBYE_SIZE);
+            let packet = Packet::parse(&transmit.contents).unwrap();
+            assert!(packet.answers.iter().all(|rr| rr.ttl == 0));
+            answers.extend(packet.answers.iter().map(Record::from_resource_record));
+        }
+
+        // A PTR, SRV and TXT record for each speaker, and one listing the
+        // service type.
+        assert!(datagrams > 1);
+        assert_eq!(answers.len(), 40 * 3 + 1);
+        let service_types = answers
+            .iter()
+            .filter(|record| record.name == SERVICE_TYPES)
+            .count();
+        assert_eq!(service_types, 1);
+    }
+}
@@ -0,0 +1,195 @@
+//! Copying questions and records out of received datagrams as they are.
+//!
+//! Going through [`Record`](crate::Record) loses whatever `dns_parser`
+//! doesn't understand: records of unknown types such as NSEC, the order of
+//! TXT strings, the flags in the header. Messages built from the parts of
+//! other messages, such as joined or reflected ones, are spliced together
+//! from the [`Entry`]s of the originals instead.
+
+/// The length of a DNS header.
+pub(crate) const HEADER_LEN: usize = 12;
+/// The most compression pointers followed in one name, to stop at loops.
+const MAX_POINTERS: usize = 64;
+/// The top bit of a question's class asks for a unicast response.
+const UNICAST_RESPONSE: u8 = 0x80;
+
+/// A question or record, with its names written out in full so that it can
+/// be put in another message as it is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Entry {
+    bytes: Vec<u8>,
+    /// The length of the owner name at the start of `bytes`.
+    name_len: usize,
+}
+
+impl Entry {
+    /// The owner name.
+    pub(crate) fn name(&self) -> String {
+        read_name(&self.bytes).unwrap_or_default()
+    }
+
+    /// The type of the question or record.
+    pub(crate) fn record_type(&self) -> u16 {
+        u16::from_be_bytes([self.bytes[self.name_len], self.bytes[self.name_len + 1]])
+    }
+
+    /// The data of a record.
+    pub(crate) fn rdata(&self) -> &[u8] {
+        self.bytes.get(self.name_len + 10..).unwrap_or_default()
+    }
+
+    /// Replaces the data of a record.
+    pub(crate) fn set_rdata(&mut self, rdata: &[u8]) {
+        self.bytes.truncate(self.name_len + 8);
+        self.bytes
+            .extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        self.bytes.extend_from_slice(rdata);
+    }
+
+    /// The encoded length of the entry.
+    pub(crate) fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Clears the "QU" bit of a question.
+    pub(crate) fn clear_unicast_response(&mut self) {
+        self.bytes[self.name_len + 2] &= !UNICAST_RESPONSE;
+    }
+}
+
+/// The questions, answers, authority and additional records of `datagram`,
+/// or `None` if it can't be taken apart.
+pub(crate) fn sections(datagram: &[u8]) -> Option<[Vec<Entry>; 4]> {
+    let mut sections: [Vec<Entry>; 4] = Default::default();
+    let header = datagram.get(..HEADER_LEN)?;
+    let count = |i: usize| u16::from_be_bytes([header[4 + 2 * i], header[5 + 2 * i]]);
+    let mut offset = HEADER_LEN;
+
+    for _ in 0..count(0) {
+        let mut bytes = Vec::new();
+        offset = copy_name(datagram, offset, &mut bytes)?;
+        let name_len = bytes.len();
+        bytes.extend_from_slice(datagram.get(offset..offset + 4)?);
+        offset += 4;
+        sections[0].push(Entry { bytes, name_len });
+    }
+
+    for (i, section) in sections.iter_mut().enumerate().skip(1) {
+        for _ in 0..count(i) {
+            let (entry, next) = copy_record(datagram, offset)?;
+            section.push(entry);
+            offset = next;
+        }
+    }
+    Some(sections)
+}
+
+/// A message with the flags and ID of `header` and the given sections, each
+/// of which must hold at most 65535 entries.
+pub(crate) fn join(header: &[u8; HEADER_LEN], sections: &[Vec<Entry>; 4]) -> Vec<u8> {
+    let mut contents = header.to_vec();
+    for (i, section) in sections.iter().enumerate() {
+        debug_assert!(section.len() <= usize::from(u16::MAX));
+        contents[4 + 2 * i..6 + 2 * i].copy_from_slice(&(section.len() as u16).to_be_bytes());
+    }
+    for entry in sections.iter().flatten() {
+        contents.extend_from_slice(&entry.bytes);
+    }
+    contents
+}
+
+/// Copies the resource record at `offset`, returning it and where the next
+/// one starts.
+fn copy_record(datagram: &[u8], offset: usize) -> Option<(Entry, usize)> {
+    let mut bytes = Vec::new();
+    let offset = copy_name(datagram, offset, &mut bytes)?;
+    let name_len = bytes.len();
+    let fixed = datagram.get(offset..offset + 10)?;
+    let record_type = u16::from_be_bytes([fixed[0], fixed[1]]);
+    let rdlength = usize::from(u16::from_be_bytes([fixed[8], fixed[9]]));
+    let start = offset + 10;
+    let end = start + rdlength;
+    let rdata = datagram.get(start..end)?;
+
+    let mut data = Vec::with_capacity(rdlength);
+    match record_type {
+        // NS, CNAME, PTR
+        2 | 5 | 12 => {
+            copy_name(datagram, start, &mut data)?;
+        }
+        // SOA: two names, then five numbers
+        6 => {
+            let rest = copy_name(datagram, start, &mut data)?;
+            let rest = copy_name(datagram, rest, &mut data)?;
+            data.extend_from_slice(datagram.get(rest..end)?);
+        }
+        // MX: a preference, then a name
+        15 => {
+            data.extend_from_slice(rdata.get(..2)?);
+            copy_name(datagram, start + 2, &mut data)?;
+        }
+        // SRV: priority, weight and port, then a name
+        33 => {
+            data.extend_from_slice(rdata.get(..6)?);
+            copy_name(datagram, start + 6, &mut data)?;
+        }
+        // NSEC: a name, then the type bitmap
+        47 => {
+            let rest = copy_name(datagram, start, &mut data)?;
+            data.extend_from_slice(datagram.get(rest..end)?);
+        }
+        _ => data.extend_from_slice(rdata),
+    }
+
+    bytes.extend_from_slice(&fixed[..8]);
+    let mut entry = Entry { bytes, name_len };
+    entry.set_rdata(&data);
+    Some((entry, end))
+}
+
+/// Writes out the name at `offset` without compression, returning where
+/// the name ends in `datagram`.
+fn copy_name(datagram: &[u8], mut offset: usize, output: &mut Vec<u8>) -> Option<usize> {
+    let mut end = None;
+    let mut pointers = 0;
+
+    loop {
+        let length = *datagram.get(offset)?;
+        match length {
+            0 => {
+                output.push(0);
+                return Some(end.unwrap_or(offset + 1));
+            }
+            length if length & 0xc0 == 0xc0 => {
+                let low = *datagram.get(offset + 1)?;
+                end = end.or(Some(offset + 2));
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return None;
+                }
+                offset = usize::from(u16::from_be_bytes([length & 0x3f, low]));
+            }
+            length if length & 0xc0 == 0 => {
+                let label = datagram.get(offset..offset + 1 + usize::from(length))?;
+                output.extend_from_slice(label);
+                offset += label.len();
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Reads an uncompressed name, such as one written out by [`copy_name`].
+pub(crate) fn read_name(bytes: &[u8]) -> Option<String> {
+    let mut labels = Vec::new();
+    let mut offset = 0;
+    loop {
+        let length = usize::from(*bytes.get(offset)?);
+        if length == 0 {
+            return Some(labels.join("."));
+        }
+        let label = bytes.get(offset + 1..offset + 1 + length)?;
+        labels.push(String::from_utf8_lossy(label));
+        offset += 1 + length;
+    }
+}
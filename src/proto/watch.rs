@@ -0,0 +1,448 @@
+//! The state machine behind watching a single service instance.
+//!
+//! A [`Watcher`] first resolves the instance like an
+//! [`InstanceResolver`](super::instance::InstanceResolver) does, and then
+//! keeps its records fresh by asking for them again at 80, 85, 90 and 95% of
+//! their TTLs (RFC 6762 section 5.2), rather than browsing the whole service
+//! type. Every change to the instance's host, port, addresses or TXT
+//! attributes is reported as a [`Change`], as is the instance going away.
+//!
+//! ```rust
+//! use mdns::proto::cache::Cache;
+//! use mdns::proto::watch::{Change, Watcher};
+//! use mdns::proto::wire::{Message, Resource};
+//! use mdns::{Record, RecordKind};
+//! use std::collections::HashMap;
+//! use std::net::Ipv4Addr;
+//! use std::time::Instant;
+//!
+//! let record = |name: &str, kind| {
+//!     Resource::unique(Record { name: name.to_owned(), class: dns_parser::Class::IN, ttl: 120, kind })
+//! };
+//! let now = Instant::now();
+//! let mut watcher = Watcher::new("Kitchen._googlecast._tcp.local", Cache::new());
+//! watcher.handle_timeout(now);
+//!
+//! let mut response = Message::response(0);
+//! response.answers.push(record(
+//!     "Kitchen._googlecast._tcp.local",
+//!     RecordKind::SRV { priority: 0, weight: 0, port: 8009, target: "kitchen.local".to_owned() },
+//! ));
+//! response.answers.push(record("Kitchen._googlecast._tcp.local", RecordKind::TXT(HashMap::new())));
+//! response.answers.push(record("kitchen.local", RecordKind::A(Ipv4Addr::new(192, 168, 1, 20))));
+//! watcher.handle_datagram(now, "192.168.1.20:5353".parse().unwrap(), &response.encode());
+//! assert!(matches!(watcher.poll_event(), Some(Change::Found(_))));
+//!
+//! // The records are asked for again before they expire.
+//! assert!(watcher.poll_timeout().is_some());
+//! ```
+
+use super::cache::Cache;
+use super::instance::InstanceResolver;
+use super::wire::{Message, Question};
+use super::{Transmit, MULTICAST_ADDR, MULTICAST_PORT};
+use crate::{RecordKind, Response, TxtRecordValue};
+
+use dns_parser::QueryType;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+use unicase::UniCase;
+
+/// Something that happened to a watched instance.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// The instance was found, or came back after it was gone. The response
+    /// holds its `SRV`, `TXT` and address records as answers.
+    Found(Response),
+    /// The instance moved to another host or port.
+    Moved { target: String, port: u16 },
+    /// The addresses of the instance's host changed. All of its current
+    /// addresses are listed, and there is always at least one: while they
+    /// are being asked for again, as after the instance moved to another
+    /// host, nothing is reported.
+    Addresses(Vec<IpAddr>),
+    /// The instance's TXT attributes changed. A TXT record that expired
+    /// isn't reported until it is heard again.
+    Txt(HashMap<UniCase<String>, TxtRecordValue>),
+    /// The instance said goodbye, or its records expired without being
+    /// refreshed.
+    Gone,
+}
+
+/// Follows the records of one service instance.
+#[derive(Clone, Debug)]
+pub struct Watcher {
+    /// Finds the instance, and asks for any of its records that go missing.
+    resolver: InstanceResolver,
+    /// What the instance was last reported to be like, while it is there.
+    known: Option<Snapshot>,
+    /// When records were last asked for to refresh them.
+    last_refresh: Option<Instant>,
+
+    transmits: VecDeque<Transmit>,
+    events: VecDeque<Change>,
+}
+
+/// The parts of an instance whose changes are reported.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Snapshot {
+    target: String,
+    port: u16,
+    /// Sorted, so that the order they were heard in doesn't matter.
+    addresses: Vec<IpAddr>,
+    txt: Option<HashMap<UniCase<String>, TxtRecordValue>>,
+}
+
+impl Watcher {
+    /// Creates a watcher for the instance with the full name
+    /// `instance_name`, starting out with the records in `cache`.
+    pub fn new<S>(instance_name: S, cache: Cache) -> Self
+    where
+        S: Into<String>,
+    {
+        Watcher {
+            resolver: InstanceResolver::new(instance_name, cache),
+            known: None,
+            last_refresh: None,
+            transmits: VecDeque::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Processes a datagram received from `source` at time `now`.
+    pub fn handle_datagram(&mut self, now: Instant, source: SocketAddr, datagram: &[u8]) {
+        self.resolver.handle_datagram(now, source, datagram);
+        self.update(now);
+    }
+
+    /// Advances the state machine's clock to `now`, asking for records that
+    /// are missing or about to expire.
+    pub fn handle_timeout(&mut self, now: Instant) {
+        self.resolver.handle_timeout(now);
+        self.refresh(now);
+        self.update(now);
+    }
+
+    /// Returns the next datagram that should be sent, if any.
+    pub fn poll_transmit(&mut self) -> Option<Transmit> {
+        self.resolver
+            .poll_transmit()
+            .or_else(|| self.transmits.pop_front())
+    }
+
+    /// Returns the next change to the instance, if any.
+    pub fn poll_event(&mut self) -> Option<Change> {
+        self.events.pop_front()
+    }
+
+    /// Returns the instant at which [`handle_timeout`](Self::handle_timeout)
+    /// should next be called.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        let cache = self.resolver.cache();
+        let refreshes = self.tracked().into_iter().flat_map(|(name, query_type)| {
+            let expires = cache.expires(&name, query_type);
+            cache
+                .refresh_times(&name, query_type)
+                .into_iter()
+                .filter(|&at| self.last_refresh.map_or(true, |last| at > last))
+                .chain(expires)
+        });
+
+        self.resolver
+            .poll_timeout()
+            .into_iter()
+            .chain(refreshes)
+            .min()
+    }
+
+    /// The records heard so far.
+    pub fn cache(&self) -> &Cache {
+        self.resolver.cache()
+    }
+
+    /// Hands back the records heard, to be used by the next resolver or
+    /// watcher.
+    pub fn into_cache(self) -> Cache {
+        self.resolver.into_cache()
+    }
+
+    /// The records of the instance and of the host it is on, without those
+    /// of hosts it moved away from.
+    pub fn instance_records(&self) -> Cache {
+        let names: Vec<_> = self.tracked().into_iter().map(|(name, _)| name).collect();
+        let names: Vec<_> = names.iter().map(String::as_str).collect();
+        self.resolver.cache().of_names(&names)
+    }
+
+    /// Asks again for the records that reached a refresh point since the
+    /// last time.
+    fn refresh(&mut self, now: Instant) {
+        let cache = self.resolver.cache();
+        let last_refresh = self.last_refresh;
+        let questions: Vec<_> = self
+            .tracked()
+            .into_iter()
+            .filter(|&(ref name, query_type)| {
+                cache
+                    .refresh_times(name, query_type)
+                    .into_iter()
+                    .any(|at| at <= now && last_refresh.map_or(true, |last| at > last))
+            })
+            .map(|(name, query_type)| Question::new(name, query_type))
+            .collect();
+        if questions.is_empty() {
+            return;
+        }
+
+        let mut message = Message::query(0);
+        message.questions = questions;
+        self.transmits.push_back(Transmit {
+            destination: SocketAddr::new(MULTICAST_ADDR.into(), MULTICAST_PORT),
+            contents: message.encode(),
+        });
+        self.last_refresh = Some(now);
+    }
+
+    /// Reports how the instance changed since it was last looked at.
+    fn update(&mut self, now: Instant) {
+        let known = match self.known {
+            Some(ref known) => known,
+            None => {
+                if let Some(response) = self.resolver.instance(now) {
+                    self.known = Snapshot::of(&response);
+                    self.events.push_back(Change::Found(response));
+                }
+                return;
+            }
+        };
+
+        let mut current = match self.resolver.found(now).and_then(|r| Snapshot::of(&r)) {
+            Some(current) => current,
+            None => {
+                self.known = None;
+                self.events.push_back(Change::Gone);
+                return;
+            }
+        };
+
+        if (&current.target, current.port) != (&known.target, known.port) {
+            self.events.push_back(Change::Moved {
+                target: current.target.clone(),
+                port: current.port,
+            });
+        }
+        // Addresses and TXT attributes that went missing are being asked
+        // for again, so the last ones known stand in until they are back.
+        if current.addresses.is_empty() {
+            current.addresses = known.addresses.clone();
+        } else if current.addresses != known.addresses {
+            self.events
+                .push_back(Change::Addresses(current.addresses.clone()));
+        }
+        match current.txt {
+            Some(ref txt) if current.txt != known.txt => {
+                self.events.push_back(Change::Txt(txt.clone()));
+            }
+            Some(_) => {}
+            None => current.txt = known.txt.clone(),
+        }
+        self.known = Some(current);
+    }
+
+    /// The names and types of the records kept fresh: those of the instance
+    /// and its host, once it was found.
+    fn tracked(&self) -> Vec<(String, QueryType)> {
+        let known = match self.known {
+            Some(ref known) => known,
+            None => return Vec::new(),
+        };
+
+        let instance_name = self.resolver.instance_name();
+        vec![
+            (instance_name.to_owned(), QueryType::SRV),
+            (instance_name.to_owned(), QueryType::TXT),
+            (known.target.clone(), QueryType::A),
+            (known.target.clone(), QueryType::AAAA),
+        ]
+    }
+}
+
+impl Snapshot {
+    /// The state of an instance, from a response holding its `SRV` record.
+    fn of(response: &Response) -> Option<Self> {
+        let (target, port) = response.records().find_map(|record| match record.kind {
+            RecordKind::SRV {
+                ref target, port, ..
+            } => Some((target.clone(), port)),
+            _ => None,
+        })?;
+
+        let mut addresses: Vec<_> = response
+            .records()
+            .filter_map(|record| match record.kind {
+                RecordKind::A(addr) => Some(IpAddr::V4(addr)),
+                RecordKind::AAAA(addr) => Some(IpAddr::V6(addr)),
+                _ => None,
+            })
+            .collect();
+        addresses.sort();
+
+        let txt = response.records().find_map(|record| match record.kind {
+            RecordKind::TXT(ref txt) => Some(txt.clone()),
+            _ => None,
+        });
+
+        Some(Snapshot {
+            target,
+            port,
+            addresses,
+            txt,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{device, response, srv, txt, unique};
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    const INSTANCE: &str = "Kitchen._googlecast._tcp.local";
+
+    /// A watcher that found the instance at `start`, with the given TTLs for
+    /// its TXT and address records.
+    fn found(start: Instant, txt_ttl: u32, address_ttl: u32) -> Watcher {
+        let mut watcher = Watcher::new(INSTANCE, Cache::new());
+        watcher.handle_timeout(start);
+        let response = response(vec![
+            unique(INSTANCE, 4500, srv("kitchen.local", 8009)),
+            unique(INSTANCE, txt_ttl, txt("st", "idle")),
+            unique(
+                "kitchen.local",
+                address_ttl,
+                RecordKind::A(Ipv4Addr::new(192, 168, 1, 20)),
+            ),
+        ]);
+        watcher.handle_datagram(start, device(), &response);
+        assert!(matches!(watcher.poll_event(), Some(Change::Found(_))));
+        while watcher.poll_transmit().is_some() {}
+        watcher
+    }
+
+    /// The names asked for by the next query sent.
+    fn asked(watcher: &mut Watcher) -> Vec<String> {
+        let query = watcher.poll_transmit().expect("no query sent");
+        let packet = dns_parser::Packet::parse(&query.contents).unwrap();
+        packet
+            .questions
+            .iter()
+            .map(|question| question.qname.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn records_are_refreshed_before_they_expire() {
+        let start = Instant::now();
+        let mut watcher = found(start, 4500, 120);
+
+        for &percent in &[80, 85, 90, 95] {
+            let refresh = watcher.poll_timeout().unwrap();
+            assert_eq!(refresh, start + Duration::from_millis(1200 * percent));
+            watcher.handle_timeout(refresh);
+            assert_eq!(asked(&mut watcher), ["kitchen.local"]);
+            assert!(watcher.poll_transmit().is_none());
+        }
+
+        // An answer starts the refreshes over from its own TTL.
+        let answered = start + Duration::from_secs(115);
+        let response = response(vec![unique(
+            "kitchen.local",
+            120,
+            RecordKind::A(Ipv4Addr::new(192, 168, 1, 20)),
+        )]);
+        watcher.handle_datagram(answered, device(), &response);
+        assert_eq!(watcher.poll_event(), None);
+        assert_eq!(
+            watcher.poll_timeout(),
+            Some(answered + Duration::from_secs(96))
+        );
+    }
+
+    #[test]
+    fn goodbyes_take_a_second() {
+        let start = Instant::now();
+        let mut watcher = found(start, 4500, 4500);
+
+        let later = start + Duration::from_secs(10);
+        let goodbye = response(vec![unique(INSTANCE, 0, srv("kitchen.local", 8009))]);
+        watcher.handle_datagram(later, device(), &goodbye);
+        assert_eq!(watcher.poll_event(), None);
+
+        let gone = watcher.poll_timeout().unwrap();
+        assert_eq!(gone, later + Duration::from_secs(1));
+        watcher.handle_timeout(gone);
+        assert_eq!(watcher.poll_event(), Some(Change::Gone));
+    }
+
+    #[test]
+    fn expired_txt_is_held_back() {
+        let start = Instant::now();
+        let mut watcher = found(start, 120, 4500);
+
+        watcher.handle_timeout(start + Duration::from_secs(120));
+        assert_eq!(watcher.poll_event(), None);
+
+        let same = response(vec![unique(INSTANCE, 4500, txt("st", "idle"))]);
+        watcher.handle_datagram(start + Duration::from_secs(121), device(), &same);
+        assert_eq!(watcher.poll_event(), None);
+
+        let changed = response(vec![unique(INSTANCE, 4500, txt("st", "casting"))]);
+        watcher.handle_datagram(start + Duration::from_secs(122), device(), &changed);
+        assert!(matches!(watcher.poll_event(), Some(Change::Txt(_))));
+    }
+
+    #[test]
+    fn expired_addresses_are_held_back() {
+        let start = Instant::now();
+        let mut watcher = found(start, 4500, 120);
+
+        watcher.handle_timeout(start + Duration::from_secs(120));
+        assert_eq!(watcher.poll_event(), None);
+    }
+
+    #[test]
+    fn addresses_follow_a_move() {
+        let start = Instant::now();
+        let mut watcher = found(start, 4500, 4500);
+
+        let moved = start + Duration::from_secs(10);
+        let response_moved = response(vec![unique(INSTANCE, 4500, srv("den.local", 8009))]);
+        watcher.handle_datagram(moved, device(), &response_moved);
+        assert_eq!(
+            watcher.poll_event(),
+            Some(Change::Moved {
+                target: "den.local".to_owned(),
+                port: 8009,
+            })
+        );
+        assert_eq!(watcher.poll_event(), None);
+
+        // The new host's addresses are asked for.
+        assert_eq!(asked(&mut watcher), ["den.local", "den.local"]);
+
+        let address = Ipv4Addr::new(192, 168, 1, 30);
+        let addresses = response(vec![unique("den.local", 120, RecordKind::A(address))]);
+        watcher.handle_datagram(moved, device(), &addresses);
+        assert_eq!(
+            watcher.poll_event(),
+            Some(Change::Addresses(vec![address.into()]))
+        );
+
+        // The old host's records aren't shared any more.
+        let records = watcher.instance_records();
+        assert!(records.contains_name("den.local"));
+        assert!(!records.contains_name("kitchen.local"));
+    }
+}
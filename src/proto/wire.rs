@@ -0,0 +1,378 @@
+//! Encoding of outgoing DNS messages.
+//!
+//! `dns_parser` can only build queries with questions in them. Responding,
+//! probing and announcing all need records in the answer, authority and
+//! additional sections, along with the mDNS cache-flush bit, so this module
+//! encodes those messages itself.
+//!
+//! ```rust
+//! use mdns::proto::wire::{Message, Resource};
+//! use mdns::{Record, RecordKind};
+//!
+//! let mut message = Message::response(0);
+//! message.answers.push(Resource::shared(Record {
+//!     name: "_http._tcp.local".to_owned(),
+//!     class: dns_parser::Class::IN,
+//!     ttl: 4500,
+//!     kind: RecordKind::PTR("Kitchen._http._tcp.local".to_owned()),
+//! }));
+//!
+//! let encoded = message.encode();
+//! let packet = dns_parser::Packet::parse(&encoded).unwrap();
+//! assert!(!packet.header.query);
+//! assert_eq!(packet.answers.len(), 1);
+//! ```
+
+use crate::{Record, RecordKind, TxtRecordValue};
+
+use std::collections::HashMap;
+
+/// The flag marking a message as a response.
+const FLAG_RESPONSE: u16 = 0x8000;
+/// The flag marking a response as authoritative.
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+/// The flag marking a message as cut short.
+const FLAG_TRUNCATED: u16 = 0x0200;
+/// The flag asking a unicast DNS server to recurse.
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+/// The mask of the response code in the flags.
+const RESPONSE_CODE_MASK: u16 = 0x000f;
+/// The top bit of a question's class asks for a unicast response.
+const UNICAST_RESPONSE: u16 = 0x8000;
+/// The top bit of a record's class tells caches to flush older records.
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// The largest offset a compression pointer can refer to.
+const MAX_POINTER: usize = 0x3fff;
+
+/// A question in an outgoing message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Question {
+    pub name: String,
+    pub query_type: dns_parser::QueryType,
+    /// Whether a unicast response is preferred (the "QU" bit).
+    pub unicast_response: bool,
+}
+
+/// A record in an outgoing message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Resource {
+    pub record: Record,
+    /// Whether the record is unique to this host, in which case receivers
+    /// should flush any other records they have cached for its name and type.
+    pub cache_flush: bool,
+}
+
+/// An outgoing DNS message.
+///
+/// Records of an [`Unimplemented`](RecordKind::Unimplemented) kind carry no
+/// record type, and are left out when encoding.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Message {
+    pub id: u16,
+    /// Whether this is a response rather than a query.
+    pub response: bool,
+    /// Whether records were left out for lack of space.
+    pub truncated: bool,
+    /// Whether recursion was asked for. Only meaningful in unicast DNS,
+    /// where responses echo it from the query.
+    pub recursion_desired: bool,
+    /// The response code, e.g. `3` for a name that doesn't exist.
+    pub response_code: u8,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Resource>,
+    pub authority: Vec<Resource>,
+    pub additional: Vec<Resource>,
+}
+
+impl Question {
+    /// Creates a question that asks for a multicast response.
+    pub fn new<S>(name: S, query_type: dns_parser::QueryType) -> Self
+    where
+        S: Into<String>,
+    {
+        Question {
+            name: name.into(),
+            query_type,
+            unicast_response: false,
+        }
+    }
+}
+
+impl Resource {
+    /// A record other hosts may hold too, such as a `PTR` record for a
+    /// service type.
+    pub fn shared(record: Record) -> Self {
+        Resource {
+            record,
+            cache_flush: false,
+        }
+    }
+
+    /// A record only this host holds, such as the `SRV` record of a service
+    /// instance.
+    pub fn unique(record: Record) -> Self {
+        Resource {
+            record,
+            cache_flush: true,
+        }
+    }
+}
+
+impl Message {
+    /// Creates an empty query.
+    pub fn query(id: u16) -> Self {
+        Message {
+            id,
+            ..Message::default()
+        }
+    }
+
+    /// Creates an empty response.
+    pub fn response(id: u16) -> Self {
+        Message {
+            id,
+            response: true,
+            ..Message::default()
+        }
+    }
+
+    /// Whether the message has nothing in any of its sections.
+    pub fn is_empty(&self) -> bool {
+        self.questions.is_empty()
+            && self.answers.is_empty()
+            && self.authority.is_empty()
+            && self.additional.is_empty()
+    }
+
+    /// Encodes the message, compressing names wherever possible.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder {
+            buffer: Vec::with_capacity(512),
+            names: HashMap::new(),
+        };
+
+        let mut flags = if self.response {
+            FLAG_RESPONSE | FLAG_AUTHORITATIVE
+        } else {
+            0
+        };
+        if self.truncated {
+            flags |= FLAG_TRUNCATED;
+        }
+        if self.recursion_desired {
+            flags |= FLAG_RECURSION_DESIRED;
+        }
+        flags |= u16::from(self.response_code) & RESPONSE_CODE_MASK;
+        encoder.u16(self.id);
+        encoder.u16(flags);
+        encoder.u16(self.questions.len() as u16);
+        for section in [&self.answers, &self.authority, &self.additional] {
+            encoder.u16(section.iter().filter(|r| is_encodable(&r.record)).count() as u16);
+        }
+
+        for question in &self.questions {
+            encoder.name(&question.name);
+            encoder.u16(question.query_type as u16);
+            let unicast = if question.unicast_response {
+                UNICAST_RESPONSE
+            } else {
+                0
+            };
+            encoder.u16(dns_parser::QueryClass::IN as u16 | unicast);
+        }
+
+        for resource in self
+            .answers
+            .iter()
+            .chain(&self.authority)
+            .chain(&self.additional)
+        {
+            encoder.resource(resource);
+        }
+
+        encoder.buffer
+    }
+}
+
+/// The type code of a record, or `None` for unimplemented kinds.
+pub(crate) fn record_type(kind: &RecordKind) -> Option<u16> {
+    let query_type = match *kind {
+        RecordKind::A(..) => dns_parser::QueryType::A,
+        RecordKind::AAAA(..) => dns_parser::QueryType::AAAA,
+        RecordKind::CNAME(..) => dns_parser::QueryType::CNAME,
+        RecordKind::MX { .. } => dns_parser::QueryType::MX,
+        RecordKind::NS(..) => dns_parser::QueryType::NS,
+        RecordKind::SRV { .. } => dns_parser::QueryType::SRV,
+        RecordKind::TXT(..) => dns_parser::QueryType::TXT,
+        RecordKind::PTR(..) => dns_parser::QueryType::PTR,
+        RecordKind::Unimplemented(..) => return None,
+    };
+    Some(query_type as u16)
+}
+
+/// The record data without any name compression, as used when comparing
+/// records lexicographically (RFC 6762 section 8.2).
+pub(crate) fn rdata(kind: &RecordKind) -> Vec<u8> {
+    let mut encoder = Encoder {
+        buffer: Vec::new(),
+        names: HashMap::new(),
+    };
+    encoder.rdata(kind, false);
+    encoder.buffer
+}
+
+fn is_encodable(record: &Record) -> bool {
+    record_type(&record.kind).is_some()
+}
+
+struct Encoder {
+    buffer: Vec<u8>,
+    /// The offsets of the names written so far, keyed by their lowercased text.
+    names: HashMap<String, u16>,
+}
+
+impl Encoder {
+    fn u16(&mut self, value: u16) {
+        self.buffer.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.buffer.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn name(&mut self, name: &str) {
+        self.name_with(name, true)
+    }
+
+    fn name_with(&mut self, name: &str, compress: bool) {
+        let mut rest = name.trim_end_matches('.');
+
+        loop {
+            // An empty label would end the name early, so stray dots are
+            // skipped.
+            rest = rest.trim_start_matches('.');
+            if rest.is_empty() {
+                break;
+            }
+
+            if compress {
+                let key = rest.to_ascii_lowercase();
+                if let Some(&offset) = self.names.get(&key) {
+                    self.u16(0xc000 | offset);
+                    return;
+                }
+                if self.buffer.len() <= MAX_POINTER {
+                    self.names.insert(key, self.buffer.len() as u16);
+                }
+            }
+
+            let (label, remainder) = match rest.find('.') {
+                Some(dot) => (&rest[..dot], &rest[dot + 1..]),
+                None => (rest, ""),
+            };
+            let label = &label.as_bytes()[..label.len().min(63)];
+            self.buffer.push(label.len() as u8);
+            self.buffer.extend_from_slice(label);
+            rest = remainder;
+        }
+
+        self.buffer.push(0);
+    }
+
+    fn resource(&mut self, resource: &Resource) {
+        let record = &resource.record;
+        let record_type = match record_type(&record.kind) {
+            Some(record_type) => record_type,
+            None => return,
+        };
+
+        self.name(&record.name);
+        self.u16(record_type);
+        let flush = if resource.cache_flush { CACHE_FLUSH } else { 0 };
+        self.u16(record.class as u16 | flush);
+        self.u32(record.ttl);
+
+        let length_at = self.buffer.len();
+        self.u16(0);
+        self.rdata(&record.kind, true);
+        let length = (self.buffer.len() - length_at - 2) as u16;
+        self.buffer[length_at..length_at + 2].copy_from_slice(&length.to_be_bytes());
+    }
+
+    fn rdata(&mut self, kind: &RecordKind, compress: bool) {
+        match *kind {
+            RecordKind::A(addr) => self.buffer.extend_from_slice(&addr.octets()),
+            RecordKind::AAAA(addr) => self.buffer.extend_from_slice(&addr.octets()),
+            RecordKind::CNAME(ref name) | RecordKind::NS(ref name) | RecordKind::PTR(ref name) => {
+                self.name_with(name, compress)
+            }
+            RecordKind::MX {
+                preference,
+                ref exchange,
+            } => {
+                self.u16(preference);
+                self.name_with(exchange, compress);
+            }
+            RecordKind::SRV {
+                priority,
+                weight,
+                port,
+                ref target,
+            } => {
+                self.u16(priority);
+                self.u16(weight);
+                self.u16(port);
+                self.name_with(target, compress);
+            }
+            RecordKind::TXT(ref entries) => {
+                // Sorted, so the same entries always encode to the same bytes.
+                let mut entries: Vec<_> = entries.iter().collect();
+                entries.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+
+                for (key, value) in &entries {
+                    let mut entry = key.as_bytes().to_vec();
+                    match value {
+                        TxtRecordValue::None => {}
+                        TxtRecordValue::Empty => entry.push(b'='),
+                        TxtRecordValue::Value(value) => {
+                            entry.push(b'=');
+                            entry.extend_from_slice(value);
+                        }
+                    }
+                    entry.truncate(255);
+                    self.buffer.push(entry.len() as u8);
+                    self.buffer.extend_from_slice(&entry);
+                }
+
+                // A TXT record always holds at least one string (RFC 6763 section 6.1).
+                if entries.is_empty() {
+                    self.buffer.push(0);
+                }
+            }
+            RecordKind::Unimplemented(ref data) => self.buffer.extend_from_slice(data),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dns_parser::{Packet, QueryType};
+
+    #[test]
+    fn empty_labels_are_skipped() {
+        let mut message = Message::query(0);
+        message
+            .questions
+            .push(Question::new("a..b.local", QueryType::A));
+        message
+            .questions
+            .push(Question::new(".b.local.", QueryType::A));
+
+        let encoded = message.encode();
+        let packet = Packet::parse(&encoded).unwrap();
+        assert_eq!(packet.questions[0].qname.to_string(), "a.b.local");
+        assert_eq!(packet.questions[1].qname.to_string(), "b.local");
+    }
+}
@@ -0,0 +1,251 @@
+//! Reflecting mDNS traffic between network interfaces.
+//!
+//! Runs the state machine in [`proto::reflector`](crate::proto::reflector)
+//! with an mDNS socket on each of its interfaces, so that services on one
+//! link can be discovered from the others.
+//!
+//! ```rust
+//! use futures_util::{pin_mut, StreamExt};
+//! use mdns::proto::reflector::{Interface, Reflector};
+//! use mdns::register::{self, Service};
+//! use mdns::transport::sim::Network;
+//! use std::net::Ipv4Addr;
+//! use std::time::Duration;
+//!
+//! let network = Network::new();
+//! let iot = network.link();
+//! let staff = network.link();
+//! let chromecast = network.host("chromecast");
+//! chromecast.interface(&iot, [10, 0, 20, 30]);
+//! let laptop = network.host("laptop");
+//! laptop.interface(&staff, [10, 0, 10, 50]);
+//! let router = network.host("router");
+//! let router_iot = router.interface(&iot, [10, 0, 20, 1]);
+//! let router_staff = router.interface(&staff, [10, 0, 10, 1]);
+//!
+//! let responder = register::with_transport(network.runtime(), chromecast.bind(5353));
+//! responder.register(Service::new("Lounge", "_googlecast._tcp.local", "chromecast.local", 8009));
+//!
+//! let reflector = Reflector::new()
+//!     .interface(Interface::new("iot", Ipv4Addr::new(10, 0, 20, 1), 24))
+//!     .interface(Interface::new("staff", Ipv4Addr::new(10, 0, 10, 1), 24));
+//! let _reflection = mdns::reflect::with_transports(
+//!     network.runtime(),
+//!     reflector,
+//!     vec![router_iot.bind(5353), router_staff.bind(5353)],
+//! );
+//!
+//! let stream = mdns::discover::with_transport(
+//!     network.runtime(),
+//!     laptop.bind(5353),
+//!     "_googlecast._tcp.local",
+//!     Duration::from_secs(1),
+//! )
+//! .listen();
+//! pin_mut!(stream);
+//! let response = network.block_on(stream.next()).unwrap().unwrap();
+//! assert_eq!(response.port(), Some(8009));
+//! ```
+
+use crate::proto::reflector::{Forward, Reflector};
+use crate::proto::{MULTICAST_ADDR, MULTICAST_PORT};
+use crate::runtime::{self, Runtime};
+use crate::socket::SocketConfig;
+use crate::transport::{Resilient, Transport};
+use crate::Error;
+
+use futures_util::future::{select, select_all, Either};
+use futures_util::pin_mut;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// The largest datagram reflected.
+const MAX_DATAGRAM_SIZE: usize = 9000;
+
+/// A reflector running in the background, until it is dropped.
+pub struct Reflection {
+    /// Closed when dropped, which stops the task.
+    _stop: async_channel::Sender<()>,
+    /// Where the traffic of each interface is recorded.
+    #[cfg(feature = "pcap")]
+    taps: Vec<crate::pcap::TapSlot>,
+}
+
+/// Reflects between the reflector's interfaces, opening an mDNS socket on
+/// each of them.
+pub fn interfaces(reflector: Reflector) -> Result<Reflection, Error> {
+    interfaces_with_runtime(runtime::default(), reflector)
+}
+
+/// Reflects between the reflector's interfaces, driven by the given runtime.
+pub fn interfaces_with_runtime(
+    runtime: Arc<dyn Runtime>,
+    reflector: Reflector,
+) -> Result<Reflection, Error> {
+    let mut transports = Vec::new();
+    for interface in reflector.interfaces() {
+        let config = SocketConfig::new().interface(interface.addr());
+        let open_runtime = runtime.clone();
+        let transport = Resilient::new(runtime.clone(), move || {
+            let socket = config.bind()?;
+            own_memberships_only(&socket)?;
+            Ok(open_runtime.udp_socket(socket)?)
+        })?;
+        transports.push(Arc::new(transport) as Arc<dyn Transport>);
+    }
+
+    Ok(with_transports(runtime, reflector, transports))
+}
+
+/// Reflects between the given transports, one for each of the reflector's
+/// interfaces, in the same order.
+///
+/// This is mostly useful with the simulated network in
+/// [`transport::sim`](crate::transport::sim).
+///
+/// # Panics
+///
+/// Panics if there isn't exactly one transport for each interface.
+pub fn with_transports(
+    runtime: Arc<dyn Runtime>,
+    reflector: Reflector,
+    transports: Vec<Arc<dyn Transport>>,
+) -> Reflection {
+    assert_eq!(
+        transports.len(),
+        reflector.interfaces().len(),
+        "one transport is needed for each interface"
+    );
+
+    #[cfg(feature = "pcap")]
+    let taps: Vec<_> = reflector
+        .interfaces()
+        .iter()
+        .map(|interface| {
+            let group = SocketAddr::new(MULTICAST_ADDR.into(), MULTICAST_PORT);
+            crate::pcap::TapSlot::new(interface.addr().into(), group)
+        })
+        .collect();
+
+    let (stop, stopped) = async_channel::bounded(1);
+    let task = run(
+        runtime.clone(),
+        reflector,
+        transports,
+        #[cfg(feature = "pcap")]
+        taps.clone(),
+        stopped,
+    );
+    runtime.spawn(Box::pin(task));
+
+    Reflection {
+        _stop: stop,
+        #[cfg(feature = "pcap")]
+        taps,
+    }
+}
+
+impl Reflection {
+    /// Records every datagram received and reflected, with each of the
+    /// reflector's interfaces as its own interface in the capture.
+    #[cfg(feature = "pcap")]
+    pub fn record(self, recorder: &crate::pcap::Recorder) -> Result<Self, Error> {
+        for tap in &self.taps {
+            tap.attach(recorder)?;
+        }
+        Ok(self)
+    }
+}
+
+async fn run(
+    runtime: Arc<dyn Runtime>,
+    mut reflector: Reflector,
+    transports: Vec<Arc<dyn Transport>>,
+    #[cfg(feature = "pcap")] taps: Vec<crate::pcap::TapSlot>,
+    stopped: async_channel::Receiver<()>,
+) {
+    let mut buffers = vec![vec![0; MAX_DATAGRAM_SIZE]; transports.len()];
+    let destination = SocketAddr::new(MULTICAST_ADDR.into(), MULTICAST_PORT);
+
+    loop {
+        let (received, interface) = {
+            let receives = transports
+                .iter()
+                .zip(buffers.iter_mut())
+                .map(|(transport, buffer)| transport.recv_from(buffer));
+            let receive = select_all(receives);
+            let stop = stopped.recv();
+            pin_mut!(stop);
+
+            match select(receive, stop).await {
+                Either::Left(((received, interface, _), _)) => (received, interface),
+                Either::Right(_) => break,
+            }
+        };
+
+        match received {
+            Ok((count, source)) => {
+                let datagram = &buffers[interface][..count];
+                #[cfg(feature = "pcap")]
+                taps[interface].inbound(source, datagram);
+                reflector.handle_datagram(runtime.now(), interface, source, datagram);
+            }
+            Err(e) => {
+                log::warn!(
+                    "failed to receive on {}: {}",
+                    reflector.interfaces()[interface].name(),
+                    e
+                );
+                continue;
+            }
+        }
+
+        while let Some(Forward {
+            interface,
+            contents,
+        }) = reflector.poll_transmit()
+        {
+            #[cfg(feature = "pcap")]
+            taps[interface].outbound(destination, &contents);
+            if let Err(e) = transports[interface].send_to(&contents, destination).await {
+                log::warn!(
+                    "failed to reflect to {}: {}",
+                    reflector.interfaces()[interface].name(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Linux hands multicast datagrams to every socket bound to the port by
+/// default, whichever interface it joined the group on. This limits the
+/// socket to datagrams that arrived on its own interface.
+#[cfg(target_os = "linux")]
+fn own_memberships_only(socket: &std::net::UdpSocket) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let off: i32 = 0;
+    // Safety: the option value is an int that lives across the call.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_MULTICAST_ALL,
+            &off as *const i32 as *const libc::c_void,
+            std::mem::size_of::<i32>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Elsewhere, the reflector's subnet check keeps datagrams from being
+/// taken for ones that arrived on another interface.
+#[cfg(not(target_os = "linux"))]
+fn own_memberships_only(_: &std::net::UdpSocket) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,570 @@
+//! Utilities for advertising services and host names on the LAN.
+//!
+//! A [`Responder`] runs the protocol in [`proto::responder`](crate::proto::responder)
+//! on a task of its own: it probes for every name registered with it,
+//! announces the ones nobody else owns, and answers queries for them from then
+//! on. When the responder shuts down, or every handle to it is dropped,
+//! goodbyes are sent so that other hosts forget the records straight away.
+//!
+//! When two hosts want the same name, the second one to probe loses and, by
+//! default, picks another.
+//!
+//! ```rust
+//! use futures_util::{pin_mut, StreamExt};
+//! use mdns::register::{self, Event, Service};
+//! use mdns::transport::sim::Network;
+//!
+//! let network = Network::new();
+//! let lan = network.link();
+//! let kitchen = network.host("kitchen");
+//! kitchen.interface(&lan, [192, 168, 1, 10]);
+//! let office = network.host("office");
+//! office.interface(&lan, [192, 168, 1, 20]);
+//!
+//! let first = register::with_transport(network.runtime(), kitchen.bind(5353));
+//! first.register(Service::new("Speaker", "_raop._tcp.local", "kitchen.local", 7000));
+//! let events = first.events();
+//! pin_mut!(events);
+//! let event = network.block_on(events.next()).unwrap();
+//! assert!(matches!(event, Event::Registered { name, .. } if name == "Speaker._raop._tcp.local"));
+//!
+//! let second = register::with_transport(network.runtime(), office.bind(5353));
+//! let speaker = second.register(Service::new("Speaker", "_raop._tcp.local", "office.local", 7000));
+//! let events = second.events();
+//! pin_mut!(events);
+//! let event = network.block_on(events.next()).unwrap();
+//! assert!(matches!(event, Event::NameConflict { renamed_to: Some(name), .. } if name == "Speaker (2)._raop._tcp.local"));
+//! let event = network.block_on(events.next()).unwrap();
+//! assert!(matches!(event, Event::Registered { .. }));
+//! assert_eq!(speaker.name().as_deref(), Some("Speaker (2)._raop._tcp.local"));
+//! ```
+
+use crate::mdns::{mDNSSender, mdns_interface, mdns_transport};
+use crate::proto::responder;
+use crate::runtime::{self, Runtime};
+use crate::socket::SocketConfig;
+use crate::transport::Transport;
+use crate::{mDNSListener, Error, TxtRecordValue};
+
+pub use self::addresses::{local_addresses, local_host_name};
+pub use crate::proto::responder::{ConflictPolicy, Event, Host, Id, Service};
+
+use futures_core::Stream;
+use futures_util::future::{select, Either};
+use futures_util::pin_mut;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+mod addresses;
+#[cfg(feature = "avahi")]
+pub mod avahi;
+
+/// Advertises services and host names, for as long as it or any of its
+/// registrations are alive.
+pub struct Responder {
+    handle: Handle,
+    events: async_channel::Receiver<Event>,
+    /// Where the traffic of the responder's socket is recorded.
+    #[cfg(feature = "pcap")]
+    tap: crate::pcap::TapSlot,
+}
+
+/// A service registered with a [`Responder`].
+///
+/// Dropping a registration leaves the service advertised for as long as the
+/// responder runs; [`unregister`](Self::unregister) withdraws it.
+pub struct Registration {
+    id: Id,
+    handle: Handle,
+}
+
+/// A host name registered with a [`Responder`].
+pub struct HostRegistration {
+    id: Id,
+    handle: Handle,
+    /// Lets the task following the interface addresses know when to stop.
+    alive: Arc<()>,
+}
+
+/// What the handles share with the task running the responder.
+#[derive(Clone)]
+struct Handle {
+    responder: Arc<Mutex<responder::Responder>>,
+    runtime: Arc<dyn Runtime>,
+    /// Wakes the task up after the responder was changed. The task stops
+    /// once every handle is gone, or the channel is closed.
+    wake: async_channel::Sender<()>,
+    /// Closed once the task has sent its goodbyes and stopped.
+    stopped: async_channel::Receiver<()>,
+}
+
+/// Creates a responder on all interfaces.
+pub fn all() -> Result<Responder, Error> {
+    interface(Ipv4Addr::new(0, 0, 0, 0))
+}
+
+/// Creates a responder on a given interface.
+pub fn interface(interface_addr: Ipv4Addr) -> Result<Responder, Error> {
+    interface_with_runtime(runtime::default(), interface_addr)
+}
+
+/// Creates a responder on a given interface, driven by the given runtime.
+pub fn interface_with_runtime(
+    runtime: Arc<dyn Runtime>,
+    interface_addr: Ipv4Addr,
+) -> Result<Responder, Error> {
+    with_config(runtime, &SocketConfig::new().interface(interface_addr))
+}
+
+/// Creates a responder on a socket set up as `config` describes, driven by
+/// the given runtime.
+pub fn with_config(runtime: Arc<dyn Runtime>, config: &SocketConfig) -> Result<Responder, Error> {
+    let (mdns_listener, mdns_sender) = mdns_interface(&runtime, config)?;
+
+    Ok(Responder::spawn(runtime, mdns_sender, mdns_listener))
+}
+
+/// Creates a responder on the given transport, driven by the given runtime.
+///
+/// This is mostly useful with the simulated network in
+/// [`transport::sim`](crate::transport::sim).
+pub fn with_transport(runtime: Arc<dyn Runtime>, transport: Arc<dyn Transport>) -> Responder {
+    let (mdns_listener, mdns_sender) = mdns_transport(transport);
+
+    Responder::spawn(runtime, mdns_sender, mdns_listener)
+}
+
+impl Responder {
+    fn spawn(
+        runtime: Arc<dyn Runtime>,
+        mdns_sender: mDNSSender,
+        mdns_listener: mDNSListener,
+    ) -> Self {
+        let responder = Arc::new(Mutex::new(responder::Responder::new()));
+        let (wake, woken) = async_channel::bounded(1);
+        let responder_task = responder.clone();
+        let runtime_task = runtime.clone();
+        let (events_sender, events) = async_channel::unbounded();
+        let (stopping, stopped) = async_channel::bounded(1);
+        #[cfg(feature = "pcap")]
+        let tap = mdns_sender.tap.clone();
+
+        runtime.spawn(Box::pin(async move {
+            run(
+                responder_task,
+                runtime_task,
+                mdns_sender,
+                mdns_listener,
+                woken,
+                events_sender,
+            )
+            .await;
+            drop(stopping);
+        }));
+
+        Responder {
+            handle: Handle {
+                responder,
+                runtime,
+                wake,
+                stopped,
+            },
+            events,
+            #[cfg(feature = "pcap")]
+            tap,
+        }
+    }
+
+    /// Sets what to do when another host owns a name we claimed.
+    ///
+    /// Defaults to [`ConflictPolicy::Rename`].
+    pub fn conflict_policy(self, policy: ConflictPolicy) -> Self {
+        self.handle.lock().set_conflict_policy(policy);
+        self
+    }
+
+    /// Records every datagram the responder sends and receives.
+    ///
+    /// Whatever was sent before the recorder was attached, such as probes
+    /// for names registered earlier, is missing from the capture.
+    #[cfg(feature = "pcap")]
+    pub fn record(self, recorder: &crate::pcap::Recorder) -> Result<Self, Error> {
+        self.tap.attach(recorder)?;
+        Ok(self)
+    }
+
+    /// Starts advertising a service.
+    ///
+    /// Its name is probed for first, so it only becomes visible once the
+    /// [`Event::Registered`] event for it comes out of [`events`](Self::events).
+    pub fn register(&self, service: Service) -> Registration {
+        self.handle.register(service)
+    }
+
+    /// Starts advertising a host name and its addresses.
+    ///
+    /// Like services, the name only becomes visible once it has been probed
+    /// for.
+    ///
+    /// ```rust,no_run
+    /// use mdns::register::Host;
+    /// use std::net::Ipv4Addr;
+    ///
+    /// # fn main() -> Result<(), mdns::Error> {
+    /// let responder = mdns::register::all()?;
+    /// let host = responder.register_host(
+    ///     Host::new("appliance-1234.local").address(Ipv4Addr::new(192, 168, 1, 10)),
+    /// );
+    ///
+    /// // Withdrawing the address tells everyone to forget it.
+    /// host.set_addresses(vec![]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn register_host(&self, host: Host) -> HostRegistration {
+        let now = self.handle.runtime.now();
+        let id = self.handle.lock().register_host(now, host);
+        self.handle.wake();
+
+        HostRegistration {
+            id,
+            handle: self.handle.clone(),
+            alive: Arc::new(()),
+        }
+    }
+
+    /// Withdraws everything registered and stops answering queries.
+    ///
+    /// Completes once the goodbyes have been sent.
+    pub async fn shutdown(self) {
+        self.handle.wake.close();
+        // Errors once the task is gone, which is what we're waiting for.
+        let _ = self.handle.stopped.recv().await;
+    }
+
+    /// The events of all registrations.
+    ///
+    /// Each event is delivered to only one of the streams returned.
+    pub fn events(&self) -> impl Stream<Item = Event> {
+        self.events.clone()
+    }
+}
+
+impl Registration {
+    /// The identifier of this registration in the responder's events.
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    /// The full name the service is currently advertised or probed under,
+    /// or `None` if it was abandoned.
+    pub fn name(&self) -> Option<String> {
+        self.handle
+            .lock()
+            .service(self.id)
+            .map(|service| service.full_name())
+    }
+
+    /// Replaces the entries of the service's TXT record, and announces the
+    /// new record.
+    ///
+    /// ```rust,no_run
+    /// use mdns::register::Service;
+    /// use mdns::TxtRecordValue;
+    ///
+    /// # fn main() -> Result<(), mdns::Error> {
+    /// let responder = mdns::register::all()?;
+    /// let registration = responder.register(
+    ///     Service::new("Porch", "_camera._tcp.local", "camera.local", 554)
+    ///         .txt("paired", TxtRecordValue::Value("no".into())),
+    /// );
+    ///
+    /// registration.update_txt(vec![("paired", TxtRecordValue::Value("yes".into()))]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn update_txt<I, K>(&self, entries: I)
+    where
+        I: IntoIterator<Item = (K, TxtRecordValue)>,
+        K: Into<String>,
+    {
+        let now = self.handle.runtime.now();
+        self.handle.lock().set_service_txt(now, self.id, entries);
+        self.handle.wake();
+    }
+
+    /// Moves the service to another port, and announces its new `SRV`
+    /// record.
+    pub fn update_port(&self, port: u16) {
+        let now = self.handle.runtime.now();
+        self.handle.lock().set_service_port(now, self.id, port);
+        self.handle.wake();
+    }
+
+    /// Stops advertising the service, sending goodbyes for its records.
+    pub fn unregister(self) {
+        self.handle.unregister(self.id);
+    }
+}
+
+impl HostRegistration {
+    /// The identifier of this registration in the responder's events.
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    /// The host name currently advertised or probed for, or `None` if it was
+    /// abandoned.
+    pub fn name(&self) -> Option<String> {
+        self.handle
+            .lock()
+            .host(self.id)
+            .map(|host| host.name().to_owned())
+    }
+
+    /// The addresses currently advertised.
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        self.handle
+            .lock()
+            .host(self.id)
+            .map(|host| host.addresses().to_vec())
+            .unwrap_or_default()
+    }
+
+    /// Replaces the advertised addresses, withdrawing the records of the
+    /// ones that went away and announcing the new set.
+    pub fn set_addresses<I>(&self, addresses: I)
+    where
+        I: IntoIterator<Item = IpAddr>,
+    {
+        let now = self.handle.runtime.now();
+        self.handle
+            .lock()
+            .set_host_addresses(now, self.id, addresses);
+        self.handle.wake();
+    }
+
+    /// Keeps the advertised addresses in line with [`local_addresses`],
+    /// checking every `interval`, for as long as this registration is alive.
+    pub fn follow_interfaces(&self, interval: Duration) {
+        let follow = follow_interfaces(
+            self.handle.clone(),
+            self.id,
+            Arc::downgrade(&self.alive),
+            interval,
+        );
+        self.handle.runtime.spawn(Box::pin(follow));
+    }
+
+    /// Stops advertising the host name, sending goodbyes for its records.
+    pub fn unregister(self) {
+        self.handle.unregister(self.id);
+    }
+}
+
+impl Handle {
+    fn lock(&self) -> std::sync::MutexGuard<'_, responder::Responder> {
+        self.responder.lock().unwrap()
+    }
+
+    fn register(&self, service: Service) -> Registration {
+        let now = self.runtime.now();
+        let id = self.lock().register(now, service);
+        self.wake();
+
+        Registration {
+            id,
+            handle: self.clone(),
+        }
+    }
+
+    fn unregister(&self, id: Id) {
+        let now = self.runtime.now();
+        self.lock().unregister(now, id);
+        self.wake();
+    }
+
+    fn wake(&self) {
+        // A full channel means the task is going to wake up anyway.
+        let _ = self.wake.try_send(());
+    }
+}
+
+async fn follow_interfaces(handle: Handle, id: Id, alive: Weak<()>, interval: Duration) {
+    loop {
+        match local_addresses() {
+            Ok(addresses) => {
+                let now = handle.runtime.now();
+                handle.lock().set_host_addresses(now, id, addresses);
+                handle.wake();
+            }
+            Err(e) => log::warn!("failed to list interface addresses: {}", e),
+        }
+
+        handle.runtime.sleep(interval).await;
+        if alive.upgrade().is_none() || handle.wake.is_closed() {
+            break;
+        }
+    }
+}
+
+/// Feeds the responder with datagrams and timeouts until every handle is
+/// dropped.
+async fn run(
+    responder: Arc<Mutex<responder::Responder>>,
+    runtime: Arc<dyn Runtime>,
+    mdns_sender: mDNSSender,
+    mut mdns_listener: mDNSListener,
+    woken: async_channel::Receiver<()>,
+    events: async_channel::Sender<Event>,
+) {
+    loop {
+        let (transmits, deadline) = {
+            let mut responder = responder.lock().unwrap();
+            while let Some(event) = responder.poll_event() {
+                // Nobody listening for events is fine.
+                let _ = events.try_send(event);
+            }
+            let transmits: Vec<_> = std::iter::from_fn(|| responder.poll_transmit()).collect();
+            (transmits, responder.poll_timeout())
+        };
+
+        for transmit in transmits {
+            if let Err(e) = mdns_sender.send(&transmit).await {
+                log::warn!("{}", e);
+            }
+        }
+
+        let received = {
+            let receive = async {
+                match deadline {
+                    Some(deadline) => {
+                        let wait = deadline.saturating_duration_since(runtime.now());
+                        runtime::timeout(&*runtime, wait, mdns_listener.recv())
+                            .await
+                            .ok()
+                    }
+                    None => Some(mdns_listener.recv().await),
+                }
+            };
+            let woken = woken.recv();
+            pin_mut!(receive, woken);
+
+            match select(receive, woken).await {
+                Either::Left((received, _)) => received,
+                Either::Right((Ok(()), _)) => None,
+                Either::Right((Err(_), _)) => {
+                    shutdown(&responder, &mdns_sender).await;
+                    break;
+                }
+            }
+        };
+
+        let now = runtime.now();
+        match received {
+            Some(Ok((count, source))) => {
+                let datagram = &mdns_listener.recv_buffer[..count];
+                responder
+                    .lock()
+                    .unwrap()
+                    .handle_datagram(now, source, datagram);
+            }
+            Some(Err(e)) => {
+                log::error!("responder stopped: {}", e);
+                break;
+            }
+            None => responder.lock().unwrap().handle_timeout(now),
+        }
+    }
+}
+
+/// Sends goodbyes for everything the responder announced.
+async fn shutdown(responder: &Mutex<responder::Responder>, mdns_sender: &mDNSSender) {
+    let transmits: Vec<_> = {
+        let mut responder = responder.lock().unwrap();
+        responder.shutdown();
+        std::iter::from_fn(|| responder.poll_transmit()).collect()
+    };
+
+    for transmit in transmits {
+        if let Err(e) = mdns_sender.send(&transmit).await {
+            log::warn!("goodbye not sent: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Lan;
+    use futures_util::{pin_mut, StreamExt};
+    use std::time::Duration;
+
+    #[test]
+    fn withdrawn_addresses_say_goodbye() {
+        let lan = Lan::new();
+        let responder = with_transport(lan.network.runtime(), lan.device.bind(5353));
+        let host = responder
+            .register_host(Host::new("device.local").address(Ipv4Addr::new(192, 168, 1, 20)));
+
+        let lookup = crate::discover::with_transport(
+            lan.network.runtime(),
+            lan.laptop.bind(5353),
+            "device.local",
+            Duration::from_secs(1),
+        )
+        .query_type(dns_parser::QueryType::A)
+        .listen();
+        pin_mut!(lookup);
+
+        let response = lan.network.block_on(lookup.next()).unwrap().unwrap();
+        assert_eq!(
+            response.ip_addr(),
+            Some(Ipv4Addr::new(192, 168, 1, 20).into())
+        );
+
+        host.set_addresses(vec![]);
+        let response = lan.network.block_on(lookup.next()).unwrap().unwrap();
+        assert_eq!(response.answers[0].ttl, 0);
+    }
+
+    #[test]
+    fn txt_updates_and_unregistering_are_announced() {
+        let lan = Lan::new();
+        let responder = with_transport(lan.network.runtime(), lan.device.bind(5353));
+        let registration = responder.register(
+            Service::new("Porch", "_camera._tcp.local", "device.local", 554)
+                .txt("paired", TxtRecordValue::Value("no".into())),
+        );
+        let events = responder.events();
+        pin_mut!(events);
+        lan.network.block_on(events.next());
+
+        let browse = crate::discover::with_transport(
+            lan.network.runtime(),
+            lan.laptop.bind(5353),
+            "_camera._tcp.local",
+            Duration::from_secs(60),
+        )
+        .listen();
+        pin_mut!(browse);
+        let paired = |response: &crate::Response| {
+            response
+                .txt_records()
+                .find(|(key, _)| *key == "paired")
+                .map(|(_, value)| value.clone())
+        };
+
+        let response = lan.network.block_on(browse.next()).unwrap().unwrap();
+        assert_eq!(paired(&response), Some(TxtRecordValue::Value("no".into())));
+
+        registration.update_txt(vec![("paired", TxtRecordValue::Value("yes".into()))]);
+        let response = lan.network.block_on(browse.next()).unwrap().unwrap();
+        assert_eq!(paired(&response), Some(TxtRecordValue::Value("yes".into())));
+
+        registration.unregister();
+        let response = lan.network.block_on(browse.next()).unwrap().unwrap();
+        assert!(response.answers.iter().all(|record| record.ttl == 0));
+    }
+}
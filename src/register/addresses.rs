@@ -0,0 +1,45 @@
+use crate::interfaces::interface_addrs;
+
+use std::io;
+use std::net::IpAddr;
+
+/// The addresses of this machine's interfaces that are up, leaving out
+/// loopback interfaces.
+///
+/// Only supported on Unix for now.
+pub fn local_addresses() -> io::Result<Vec<IpAddr>> {
+    let mut addresses = Vec::new();
+    for interface in interface_addrs()? {
+        if !interface.loopback && !addresses.contains(&interface.addr) {
+            addresses.push(interface.addr);
+        }
+    }
+    Ok(addresses)
+}
+
+/// This machine's host name, without any domain, e.g. `appliance-1234`.
+#[cfg(unix)]
+pub fn local_host_name() -> io::Result<String> {
+    let mut buffer = [0u8; 256];
+
+    // Safety: the buffer is writable for its whole length, and one byte is
+    // kept back so the name is always terminated.
+    if unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len() - 1) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let end = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    let name = String::from_utf8_lossy(&buffer[..end]);
+    Ok(name.split('.').next().unwrap_or_default().to_owned())
+}
+
+/// This machine's host name, without any domain, e.g. `appliance-1234`.
+///
+/// Only supported on Unix for now.
+#[cfg(not(unix))]
+pub fn local_host_name() -> io::Result<String> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "reading the host name is not supported on this platform",
+    ))
+}
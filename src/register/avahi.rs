@@ -0,0 +1,504 @@
+//! Static service definitions in the format of Avahi's `.service` files.
+//!
+//! Avahi reads service groups from `/etc/avahi/services`, one XML file per
+//! group. The same files can be loaded here, either one at a time with
+//! [`ServiceGroup::load`], or by [`watch`]ing a directory of them.
+//!
+//! ```rust
+//! use mdns::register::avahi::ServiceGroup;
+//!
+//! let group = ServiceGroup::parse(r#"<?xml version="1.0" standalone="no"?>
+//! <!DOCTYPE service-group SYSTEM "avahi-service.dtd">
+//! <service-group>
+//!   <name replace-wildcards="yes">Printer on %h</name>
+//!   <service>
+//!     <type>_ipp._tcp</type>
+//!     <subtype>_universal._sub._ipp._tcp</subtype>
+//!     <port>631</port>
+//!     <txt-record>rp=printers/office</txt-record>
+//!     <txt-record>color</txt-record>
+//!   </service>
+//! </service-group>"#).unwrap();
+//!
+//! let services = group.services("office");
+//! assert_eq!(services[0].full_name(), "Printer on office._ipp._tcp.local");
+//! assert_eq!(services[0].host(), "office.local");
+//! assert_eq!(services[0].port(), 631);
+//! assert_eq!(services[0].subtypes(), ["_universal._sub._ipp._tcp.local"]);
+//! ```
+
+use super::{Handle, Registration, Responder, Service};
+use crate::{Error, TxtRecordValue};
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+mod xml;
+
+/// The services of one Avahi `.service` file, all advertised under the same
+/// instance name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServiceGroup {
+    name: String,
+    replace_wildcards: bool,
+    services: Vec<Definition>,
+}
+
+/// Keeps a responder's services in line with a directory of `.service`
+/// files.
+///
+/// Dropping it stops watching; the services loaded are withdrawn at the
+/// next check.
+///
+/// ```rust
+/// use futures_util::{pin_mut, StreamExt};
+/// use mdns::register::{self, avahi};
+/// use mdns::transport::sim::Network;
+/// use std::time::Duration;
+///
+/// let directory = std::env::temp_dir().join(format!("mdns-avahi-{}", std::process::id()));
+/// std::fs::create_dir_all(&directory).unwrap();
+/// let file = directory.join("ssh.service");
+/// std::fs::write(&file, r#"<service-group>
+///   <name replace-wildcards="yes">%h</name>
+///   <service><type>_ssh._tcp</type><port>22</port></service>
+/// </service-group>"#).unwrap();
+///
+/// let network = Network::new();
+/// let lan = network.link();
+/// let server = network.host("server");
+/// server.interface(&lan, [192, 168, 1, 10]);
+/// let laptop = network.host("laptop");
+/// laptop.interface(&lan, [192, 168, 1, 20]);
+///
+/// let responder = register::with_transport(network.runtime(), server.bind(5353));
+/// let watch = avahi::watch(&responder, &directory, "server", Duration::from_secs(1)).unwrap();
+///
+/// let browse = mdns::discover::with_transport(
+///     network.runtime(),
+///     laptop.bind(5353),
+///     "_ssh._tcp.local",
+///     Duration::from_secs(60),
+/// )
+/// .listen();
+/// pin_mut!(browse);
+/// let response = network.block_on(browse.next()).unwrap().unwrap();
+/// assert_eq!(response.port(), Some(22));
+///
+/// // Removing the file withdraws the service at the next check.
+/// std::fs::remove_file(&file).unwrap();
+/// let response = network.block_on(browse.next()).unwrap().unwrap();
+/// assert!(response.answers.iter().all(|record| record.ttl == 0));
+/// # drop(watch);
+/// # std::fs::remove_dir_all(&directory).unwrap();
+/// ```
+pub struct Watch {
+    /// Lets the task polling the directory know when to stop.
+    _alive: Arc<()>,
+}
+
+/// A `<service>` element.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Definition {
+    service_type: String,
+    subtypes: Vec<String>,
+    domain: String,
+    host: Option<String>,
+    port: u16,
+    txt: Vec<(String, TxtRecordValue)>,
+}
+
+/// The services registered for the files in a watched directory.
+struct Directory {
+    path: PathBuf,
+    host_name: String,
+    files: HashMap<PathBuf, File>,
+}
+
+/// A file in a watched directory, and what was registered for it.
+struct File {
+    contents: String,
+    registrations: Vec<(Service, Registration)>,
+}
+
+impl ServiceGroup {
+    /// Parses the contents of a `.service` file.
+    pub fn parse(xml: &str) -> Result<Self, Error> {
+        let root = xml::parse(xml).map_err(invalid)?;
+        if root.name != "service-group" {
+            return Err(invalid(format!(
+                "expected <service-group>, found <{}>",
+                root.name
+            )));
+        }
+
+        let name = root
+            .child("name")
+            .ok_or_else(|| invalid("missing <name>"))?;
+        let services = root
+            .children("service")
+            .map(Definition::parse)
+            .collect::<Result<_, _>>()?;
+
+        Ok(ServiceGroup {
+            name: name.text.trim().to_owned(),
+            replace_wildcards: name.attribute("replace-wildcards") == Some("yes"),
+            services,
+        })
+    }
+
+    /// Reads and parses a `.service` file.
+    pub fn load<P>(path: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// The instance name, with `%h` replaced by `host_name` if the file asks
+    /// for it.
+    pub fn name(&self, host_name: &str) -> String {
+        if self.replace_wildcards {
+            self.name.replace("%h", host_name)
+        } else {
+            self.name.clone()
+        }
+    }
+
+    /// The services to register, for a machine called `host_name`.
+    ///
+    /// Services without a `<host-name>` of their own point at
+    /// `host_name` in their domain, e.g. `office.local`.
+    pub fn services(&self, host_name: &str) -> Vec<Service> {
+        let instance = self.name(host_name);
+
+        self.services
+            .iter()
+            .map(|definition| {
+                let host = match definition.host {
+                    Some(ref host) => host.clone(),
+                    None => format!("{}.{}", host_name, definition.domain),
+                };
+                let service_type = format!("{}.{}", definition.service_type, definition.domain);
+
+                let mut service =
+                    Service::new(instance.clone(), service_type, host, definition.port);
+                for subtype in &definition.subtypes {
+                    service = service.subtype(format!("{}.{}", subtype, definition.domain));
+                }
+                for (key, value) in &definition.txt {
+                    service = service.txt(key.clone(), value.clone());
+                }
+                service
+            })
+            .collect()
+    }
+}
+
+impl Definition {
+    fn parse(element: &xml::Element) -> Result<Self, Error> {
+        let text = |name| element.child(name).map(|child| child.text.trim());
+
+        let service_type = text("type").ok_or_else(|| invalid("missing <type> in <service>"))?;
+        let port = text("port").ok_or_else(|| invalid("missing <port> in <service>"))?;
+        let port = port
+            .parse()
+            .map_err(|_| invalid(format!("invalid port `{}`", port)))?;
+        let domain = text("domain-name")
+            .filter(|domain| !domain.is_empty())
+            .unwrap_or("local");
+
+        let txt = element
+            .children("txt-record")
+            .map(txt_entry)
+            .collect::<Result<_, _>>()?;
+
+        Ok(Definition {
+            service_type: service_type.trim_end_matches('.').to_owned(),
+            subtypes: element
+                .children("subtype")
+                .map(|subtype| subtype.text.trim().trim_end_matches('.').to_owned())
+                .collect(),
+            domain: domain.trim_matches('.').to_owned(),
+            host: text("host-name").map(str::to_owned),
+            port,
+            txt,
+        })
+    }
+}
+
+/// Splits a `<txt-record>` into its key and value.
+fn txt_entry(element: &xml::Element) -> Result<(String, TxtRecordValue), Error> {
+    let bytes = match element.attribute("value-format") {
+        None | Some("text") => element.text.as_bytes().to_vec(),
+        Some("binary-hex") => {
+            decode_hex(element.text.trim()).ok_or_else(|| invalid("invalid hex in <txt-record>"))?
+        }
+        Some("binary-base64") => decode_base64(element.text.trim())
+            .ok_or_else(|| invalid("invalid base64 in <txt-record>"))?,
+        Some(format) => return Err(invalid(format!("unknown value-format `{}`", format))),
+    };
+
+    Ok(match bytes.iter().position(|&b| b == b'=') {
+        None => (
+            String::from_utf8_lossy(&bytes).into_owned(),
+            TxtRecordValue::None,
+        ),
+        Some(equals) => {
+            let key = String::from_utf8_lossy(&bytes[..equals]).into_owned();
+            let value = &bytes[equals + 1..];
+            if value.is_empty() {
+                (key, TxtRecordValue::Empty)
+            } else {
+                (key, TxtRecordValue::Value(value.to_vec().into()))
+            }
+        }
+    })
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = text.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    if digits.len() % 2 != 0 {
+        return None;
+    }
+
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).ok()?;
+            u8::from_str_radix(pair, 16).ok()
+        })
+        .collect()
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut bits = 0u32;
+    let mut count = 0;
+
+    for b in text
+        .bytes()
+        .filter(|b| !b.is_ascii_whitespace() && *b != b'=')
+    {
+        let value = match b {
+            b'A'..=b'Z' => b - b'A',
+            b'a'..=b'z' => b - b'a' + 26,
+            b'0'..=b'9' => b - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = bits << 6 | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+        }
+    }
+
+    Some(bytes)
+}
+
+fn invalid<E>(error: E) -> Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error).into()
+}
+
+/// Registers the services of every `.service` file in `directory`, then
+/// checks it every `interval` for files that were added, changed or
+/// removed.
+///
+/// `%h` in the files' names stands for `host_name`, which is usually
+/// [`local_host_name`](super::local_host_name). Files that fail to parse are
+/// logged and skipped; an error is only returned if the directory can't be
+/// read at all.
+///
+/// ```rust,no_run
+/// use mdns::register::{self, avahi};
+/// use std::time::Duration;
+///
+/// # fn main() -> Result<(), mdns::Error> {
+/// let responder = register::all()?;
+/// let host_name = register::local_host_name()?;
+/// let _watch = avahi::watch(
+///     &responder,
+///     "/etc/avahi/services",
+///     &host_name,
+///     Duration::from_secs(5),
+/// )?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn watch<P>(
+    responder: &Responder,
+    directory: P,
+    host_name: &str,
+    interval: Duration,
+) -> Result<Watch, Error>
+where
+    P: Into<PathBuf>,
+{
+    let mut directory = Directory {
+        path: directory.into(),
+        host_name: host_name.to_owned(),
+        files: HashMap::new(),
+    };
+    directory.scan(&responder.handle)?;
+
+    let alive = Arc::new(());
+    let poll = poll(
+        responder.handle.clone(),
+        directory,
+        Arc::downgrade(&alive),
+        interval,
+    );
+    responder.handle.runtime.spawn(Box::pin(poll));
+
+    Ok(Watch { _alive: alive })
+}
+
+async fn poll(handle: Handle, mut directory: Directory, alive: Weak<()>, interval: Duration) {
+    loop {
+        handle.runtime.sleep(interval).await;
+        if handle.wake.is_closed() {
+            break;
+        }
+        if alive.upgrade().is_none() {
+            directory.clear();
+            break;
+        }
+
+        if let Err(e) = directory.scan(&handle) {
+            log::warn!("failed to read {}: {}", directory.path.display(), e);
+        }
+    }
+}
+
+impl Directory {
+    /// Applies the files added, changed or removed since the last scan.
+    fn scan(&mut self, handle: &Handle) -> io::Result<()> {
+        let mut present = HashSet::new();
+
+        for entry in fs::read_dir(&self.path)? {
+            let path = entry?.path();
+            if path.extension() != Some(OsStr::new("service")) {
+                continue;
+            }
+
+            // A file that can't be read right now is left as it was.
+            present.insert(path.clone());
+            let contents = match fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    log::warn!("failed to read {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+
+            let registered = match self.files.remove(&path) {
+                Some(file) if file.contents == contents => {
+                    self.files.insert(path, file);
+                    continue;
+                }
+                Some(file) => file.registrations,
+                None => Vec::new(),
+            };
+
+            let services = match ServiceGroup::parse(&contents) {
+                Ok(group) => group.services(&self.host_name),
+                Err(e) => {
+                    log::warn!("ignoring {}: {}", path.display(), e);
+                    Vec::new()
+                }
+            };
+
+            let registrations = apply(handle, registered, services);
+            self.files.insert(
+                path,
+                File {
+                    contents,
+                    registrations,
+                },
+            );
+        }
+
+        let removed: Vec<_> = self
+            .files
+            .keys()
+            .filter(|path| !present.contains(*path))
+            .cloned()
+            .collect();
+        for path in removed {
+            if let Some(file) = self.files.remove(&path) {
+                apply(handle, file.registrations, Vec::new());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Withdraws everything registered for the directory.
+    fn clear(&mut self) {
+        for (_, file) in self.files.drain() {
+            for (_, registration) in file.registrations {
+                registration.unregister();
+            }
+        }
+    }
+}
+
+/// Brings the registrations for a file in line with its new services.
+///
+/// Services that only differ in their port or TXT record are updated in
+/// place, so they keep their names; the rest are withdrawn and registered
+/// afresh.
+fn apply(
+    handle: &Handle,
+    registered: Vec<(Service, Registration)>,
+    services: Vec<Service>,
+) -> Vec<(Service, Registration)> {
+    let mut services: Vec<Option<Service>> = services.into_iter().map(Some).collect();
+    let mut registrations = Vec::new();
+
+    for (old, registration) in registered {
+        let same_name = services.iter_mut().find(|service| {
+            service.as_ref().map_or(false, |service| {
+                service.instance() == old.instance()
+                    && service.service_type() == old.service_type()
+                    && service.host() == old.host()
+                    && service.subtypes() == old.subtypes()
+            })
+        });
+
+        match same_name.and_then(Option::take) {
+            Some(new) => {
+                if new.port() != old.port() {
+                    registration.update_port(new.port());
+                }
+                let txt: HashMap<_, _> = new.txt_records().collect();
+                if txt != old.txt_records().collect() {
+                    registration.update_txt(
+                        txt.into_iter()
+                            .map(|(key, value)| (key.to_owned(), value.clone())),
+                    );
+                }
+                registrations.push((new, registration));
+            }
+            None => registration.unregister(),
+        }
+    }
+
+    for service in services.into_iter().flatten() {
+        let registration = handle.register(service.clone());
+        registrations.push((service, registration));
+    }
+
+    registrations
+}
@@ -0,0 +1,234 @@
+//! Just enough XML to read Avahi service files: elements, attributes, text,
+//! comments, CDATA sections and character references. Document type
+//! declarations and processing instructions are skipped.
+
+/// An element, with the text directly inside it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(super) struct Element {
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<Element>,
+    pub text: String,
+}
+
+impl Element {
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> {
+        self.children.iter().filter(move |child| child.name == name)
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.name == name)
+    }
+}
+
+/// Parses a document, returning its root element.
+pub(super) fn parse(input: &str) -> Result<Element, String> {
+    let mut parser = Parser { input, position: 0 };
+
+    parser.skip_misc()?;
+    let root = parser.element()?;
+    parser.skip_misc()?;
+    if parser.position != input.len() {
+        return Err(parser.error("content after the root element"));
+    }
+
+    Ok(root)
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.position..]
+    }
+
+    fn error(&self, message: &str) -> String {
+        let line = self.input[..self.position].matches('\n').count() + 1;
+        format!("line {}: {}", line, message)
+    }
+
+    fn eat(&mut self, prefix: &str) -> bool {
+        if self.rest().starts_with(prefix) {
+            self.position += prefix.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, prefix: &str) -> Result<(), String> {
+        if self.eat(prefix) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected `{}`", prefix)))
+        }
+    }
+
+    /// Skips past the next occurrence of `end`.
+    fn skip_past(&mut self, end: &str) -> Result<&'a str, String> {
+        match self.rest().find(end) {
+            Some(offset) => {
+                let skipped = &self.rest()[..offset];
+                self.position += offset + end.len();
+                Ok(skipped)
+            }
+            None => Err(self.error(&format!("missing `{}`", end))),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    /// Skips whitespace, comments, processing instructions and document type
+    /// declarations.
+    fn skip_misc(&mut self) -> Result<(), String> {
+        loop {
+            self.skip_whitespace();
+            if self.eat("<?") {
+                self.skip_past("?>")?;
+            } else if self.eat("<!--") {
+                self.skip_past("-->")?;
+            } else if self.eat("<!DOCTYPE") {
+                let declaration = self.rest();
+                let internal_subset = declaration.find('[');
+                match internal_subset {
+                    Some(open) if open < declaration.find('>').unwrap_or(usize::MAX) => {
+                        self.skip_past("]")?;
+                        self.skip_past(">")?;
+                    }
+                    _ => {
+                        self.skip_past(">")?;
+                    }
+                }
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let rest = self.rest();
+        let end = rest
+            .find(|c: char| c.is_whitespace() || matches!(c, '/' | '>' | '=' | '<'))
+            .unwrap_or(rest.len());
+        if end == 0 {
+            return Err(self.error("expected a name"));
+        }
+
+        self.position += end;
+        Ok(rest[..end].to_owned())
+    }
+
+    fn element(&mut self) -> Result<Element, String> {
+        self.expect("<")?;
+        let mut element = Element {
+            name: self.name()?,
+            ..Element::default()
+        };
+
+        loop {
+            self.skip_whitespace();
+            if self.eat("/>") {
+                return Ok(element);
+            }
+            if self.eat(">") {
+                break;
+            }
+
+            let key = self.name()?;
+            self.skip_whitespace();
+            self.expect("=")?;
+            self.skip_whitespace();
+            let quote = if self.eat("\"") {
+                "\""
+            } else if self.eat("'") {
+                "'"
+            } else {
+                return Err(self.error("expected a quoted attribute value"));
+            };
+            let value = self.skip_past(quote)?;
+            element.attributes.push((key, self.unescape(value)?));
+        }
+
+        loop {
+            if self.eat("</") {
+                let name = self.name()?;
+                if name != element.name {
+                    return Err(self.error(&format!("`</{}>` closes `<{}>`", name, element.name)));
+                }
+                self.skip_whitespace();
+                self.expect(">")?;
+                return Ok(element);
+            } else if self.eat("<!--") {
+                self.skip_past("-->")?;
+            } else if self.eat("<![CDATA[") {
+                let text = self.skip_past("]]>")?;
+                element.text.push_str(text);
+            } else if self.rest().starts_with('<') {
+                let child = self.element()?;
+                element.children.push(child);
+            } else if self.rest().is_empty() {
+                return Err(self.error(&format!("`<{}>` is never closed", element.name)));
+            } else {
+                let end = self.rest().find('<').unwrap_or(self.rest().len());
+                let text = &self.rest()[..end];
+                let text = self.unescape(text)?;
+                element.text.push_str(&text);
+                self.position += end;
+            }
+        }
+    }
+
+    /// Replaces entity and character references.
+    fn unescape(&self, text: &str) -> Result<String, String> {
+        let mut unescaped = String::with_capacity(text.len());
+        let mut rest = text;
+
+        while let Some(start) = rest.find('&') {
+            unescaped.push_str(&rest[..start]);
+            rest = &rest[start + 1..];
+
+            let end = rest
+                .find(';')
+                .ok_or_else(|| self.error("unterminated reference"))?;
+            let reference = &rest[..end];
+            rest = &rest[end + 1..];
+
+            let c = match reference {
+                "lt" => '<',
+                "gt" => '>',
+                "amp" => '&',
+                "quot" => '"',
+                "apos" => '\'',
+                _ => {
+                    let code = if let Some(hex) = reference.strip_prefix("#x") {
+                        u32::from_str_radix(hex, 16).ok()
+                    } else if let Some(decimal) = reference.strip_prefix('#') {
+                        decimal.parse().ok()
+                    } else {
+                        None
+                    };
+                    code.and_then(char::from_u32).ok_or_else(|| {
+                        self.error(&format!("unknown reference `&{};`", reference))
+                    })?
+                }
+            };
+            unescaped.push(c);
+        }
+
+        unescaped.push_str(rest);
+        Ok(unescaped)
+    }
+}
@@ -9,8 +9,9 @@
 //! const SERVICE_NAME: &'static str = "_googlecast._tcp.local";
 //! const HOST: &'static str = "mycast._googlecast._tcp.local";
 //!
+//! # #[cfg(any(feature = "runtime-async-std", feature = "runtime-tokio"))]
 //! #[cfg_attr(feature = "runtime-async-std", async_std::main)]
-//! #[cfg_attr(feature = "runtime-tokio", tokio::main)]
+//! #[cfg_attr(all(feature = "runtime-tokio", not(feature = "runtime-async-std")), tokio::main)]
 //! async fn main() -> Result<(), Error> {
 //!     if let Some(response) = mdns::resolve::one(SERVICE_NAME, HOST, Duration::from_secs(15)).await? {
 //!         println!("{:?}", response);
@@ -18,13 +19,309 @@
 //!
 //!     Ok(())
 //! }
+//! # #[cfg(not(any(feature = "runtime-async-std", feature = "runtime-tokio")))]
+//! # fn main() {}
 //! ```
+//!
+//! A [`Resolution`] gives more control: each host can have a deadline of its
+//! own, and the resolution can end as soon as enough hosts were found. Its
+//! [`Report`] tells which hosts resolved and which are still missing, so one
+//! device that is offline doesn't hide the others.
+//!
+//! ```rust
+//! use mdns::register::{self, Service};
+//! use mdns::resolve::{EarlyExit, Resolution};
+//! use mdns::transport::sim::Network;
+//! use std::time::Duration;
+//!
+//! let network = Network::new();
+//! let lan = network.link();
+//! let laptop = network.host("laptop");
+//! laptop.interface(&lan, [192, 168, 1, 10]);
+//! let printer = network.host("printer");
+//! printer.interface(&lan, [192, 168, 1, 20]);
+//!
+//! let responder = register::with_transport(network.runtime(), printer.bind(5353));
+//! responder.register(Service::new("Office", "_ipp._tcp.local", "printer.local", 631));
+//!
+//! let resolution = Resolution::new("_ipp._tcp.local", Duration::from_secs(10))
+//!     .host("Office._ipp._tcp.local")
+//!     .host_with_deadline("Attic._ipp._tcp.local", Duration::from_secs(3))
+//!     .early_exit(EarlyExit::Never)
+//!     .run_with_transport(network.runtime(), laptop.bind(5353));
+//! let report = network.block_on(resolution).unwrap();
+//!
+//! assert_eq!(report.resolved.len(), 1);
+//! assert_eq!(report.resolved[0].0, "Office._ipp._tcp.local");
+//! assert_eq!(report.missing, ["Attic._ipp._tcp.local"]);
+//! // The attic printer was given up on after its own deadline.
+//! assert_eq!(report.elapsed, Duration::from_secs(3));
+//!
+//! // As an error, the office printer is still there.
+//! match report.into_result() {
+//!     Err(mdns::Error::Timeout { partial }) => assert_eq!(partial.len(), 1),
+//!     other => panic!("{:?}", other),
+//! }
+//! ```
+//!
+//! When the name of the instance is known already, [`Resolver::instance`]
+//! asks for it directly instead of browsing the whole service type. A
+//! [`Resolver`] remembers the records it heard, so resolving the instance
+//! again while they are fresh sends no queries at all. Keep one around for
+//! as long as names are resolved, rather than creating one each time.
+//!
+//! For a long-lived connection to one device, [`Resolver::watch`] follows the
+//! instance instead, reporting each [`Change`] to it and keeping its records fresh.
+
+use crate::discover::Discovery;
+use crate::mdns::{mDNSListener, mDNSSender, mdns_interface, mdns_transport};
+use crate::proto::cache::Cache;
+use crate::proto::instance::InstanceResolver;
+use crate::proto::watch::Watcher;
+use crate::runtime::{self, Runtime};
+use crate::socket::SocketConfig;
+use crate::transport::{Fanout, Transport};
+use crate::{trace, Error, RecordKind, Response};
+
+use async_stream::try_stream;
+use futures_core::Stream;
+use futures_util::{pin_mut, StreamExt};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub use crate::proto::watch::Change;
 
-use crate::{runtime, Error, Response};
-use futures_util::{pin_mut, StreamExt, TryFutureExt};
-use std::time::Duration;
+/// A lookup of several instances of one service.
+#[derive(Clone, Debug)]
+pub struct Resolution {
+    service_name: String,
+    timeout: Duration,
+    /// The hosts to look for, each with its own deadline if it has one.
+    hosts: Vec<(String, Option<Duration>)>,
+    early_exit: EarlyExit,
+}
+
+/// When a resolution may end before every host resolved or ran out of time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EarlyExit {
+    /// Wait for every host, until it resolves or its deadline passes.
+    #[default]
+    Never,
+    /// Stop as soon as any host resolved.
+    FirstResolved,
+    /// Stop once this many hosts resolved.
+    Resolved(usize),
+}
+
+/// The outcome of a [`Resolution`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// The hosts that resolved, in the order they did, each with the
+    /// response that resolved it.
+    pub resolved: Vec<(String, Response)>,
+    /// The hosts that didn't resolve, in the order they were asked for.
+    pub missing: Vec<String>,
+    /// How long the resolution took.
+    pub elapsed: Duration,
+}
+
+/// A host still being looked for.
+struct Wanted {
+    host_name: String,
+    deadline: Instant,
+}
+
+impl Resolution {
+    /// Looks for instances of `service_name` for at most `timeout`.
+    pub fn new<S>(service_name: S, timeout: Duration) -> Self
+    where
+        S: Into<String>,
+    {
+        Resolution {
+            service_name: service_name.into(),
+            timeout,
+            hosts: Vec::new(),
+            early_exit: EarlyExit::default(),
+        }
+    }
+
+    /// Adds a host to look for until the resolution's timeout.
+    pub fn host<S>(mut self, host_name: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.hosts.push((host_name.into(), None));
+        self
+    }
+
+    /// Adds hosts to look for until the resolution's timeout.
+    pub fn hosts<I, S>(self, host_names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        host_names.into_iter().fold(self, Resolution::host)
+    }
+
+    /// Adds a host to look for until `deadline` after the start, or the
+    /// resolution's timeout if that comes first.
+    pub fn host_with_deadline<S>(mut self, host_name: S, deadline: Duration) -> Self
+    where
+        S: Into<String>,
+    {
+        self.hosts.push((host_name.into(), Some(deadline)));
+        self
+    }
+
+    /// Sets when the resolution may end early.
+    ///
+    /// Defaults to [`EarlyExit::Never`].
+    pub fn early_exit(mut self, early_exit: EarlyExit) -> Self {
+        self.early_exit = early_exit;
+        self
+    }
+
+    /// Resolves the hosts on all interfaces.
+    ///
+    /// If listening fails partway, the error is an [`Error::Interrupted`]
+    /// holding the report up to then.
+    pub async fn run(self) -> Result<Report, Error> {
+        let runtime = runtime::default();
+        // by setting the query interval higher than the timeout we ensure we only make one query
+        let discovery = crate::discover::all(&self.service_name, self.timeout * 2)?;
+
+        self.run_on(runtime, discovery).await
+    }
+
+    /// Resolves the hosts on the given transport, driven by the given
+    /// runtime.
+    ///
+    /// This is mostly useful with the simulated network in
+    /// [`transport::sim`](crate::transport::sim).
+    pub async fn run_with_transport(
+        self,
+        runtime: Arc<dyn Runtime>,
+        transport: Arc<dyn Transport>,
+    ) -> Result<Report, Error> {
+        let discovery = crate::discover::with_transport(
+            runtime.clone(),
+            transport,
+            &self.service_name,
+            self.timeout * 2,
+        );
+
+        self.run_on(runtime, discovery).await
+    }
+
+    async fn run_on(
+        self,
+        runtime: Arc<dyn Runtime>,
+        discovery: Discovery,
+    ) -> Result<Report, Error> {
+        let host_names: Vec<_> = self.hosts.iter().map(|(host_name, _)| host_name).collect();
+        let span = trace::resolution(&self.service_name, &host_names);
+        trace::future(span, self.resolve(runtime, discovery)).await
+    }
+
+    async fn resolve(
+        self,
+        runtime: Arc<dyn Runtime>,
+        discovery: Discovery,
+    ) -> Result<Report, Error> {
+        let start = runtime.now();
+        let mut wanted: Vec<_> = self
+            .hosts
+            .into_iter()
+            .map(|(host_name, deadline)| Wanted {
+                host_name,
+                deadline: start + deadline.map_or(self.timeout, |d| d.min(self.timeout)),
+            })
+            .collect();
+        let mut report = Report::default();
+        let mut failure = None;
+
+        let stream = discovery.listen();
+        pin_mut!(stream);
+
+        loop {
+            let now = runtime.now();
+            let (expired, left) = wanted.into_iter().partition(|w| w.deadline <= now);
+            wanted = left;
+            report
+                .missing
+                .extend(expired.into_iter().map(|w: Wanted| w.host_name));
+
+            let done = match self.early_exit {
+                EarlyExit::Never => false,
+                EarlyExit::FirstResolved => !report.resolved.is_empty(),
+                EarlyExit::Resolved(count) => report.resolved.len() >= count,
+            };
+            let deadline = match wanted.iter().map(|w| w.deadline).min() {
+                Some(deadline) if !done => deadline,
+                _ => break,
+            };
+
+            let wait = deadline.saturating_duration_since(now);
+            let response = match runtime::timeout(&*runtime, wait, stream.next()).await {
+                Ok(Some(Ok(response))) => response,
+                Ok(Some(Err(e))) => {
+                    failure = Some(e);
+                    break;
+                }
+                Ok(None) => break,
+                Err(_) => continue,
+            };
+
+            for instance in instances(&response) {
+                if let Some(index) = wanted.iter().position(|w| w.host_name == instance) {
+                    let host_name = wanted.remove(index).host_name;
+                    report.resolved.push((host_name, response.clone()));
+                }
+            }
+        }
+
+        report
+            .missing
+            .extend(wanted.into_iter().map(|w| w.host_name));
+        report.elapsed = runtime.now().saturating_duration_since(start);
+        match failure {
+            // what was found before the failure is still worth having
+            Some(e) => Err(Error::Interrupted {
+                partial: Box::new(report),
+                source: Box::new(e),
+            }),
+            None => Ok(report),
+        }
+    }
+}
+
+impl Report {
+    /// Whether every host resolved.
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+    }
+
+    /// The responses of the resolved hosts if every host resolved, or an
+    /// [`Error::Timeout`] holding them otherwise.
+    pub fn into_result(self) -> Result<Vec<Response>, Error> {
+        let complete = self.is_complete();
+        let responses = self
+            .resolved
+            .into_iter()
+            .map(|(_, response)| response)
+            .collect();
+
+        if complete {
+            Ok(responses)
+        } else {
+            Err(Error::Timeout { partial: responses })
+        }
+    }
+}
 
 /// Resolve a single device by hostname
+///
+/// Returns `None` if it wasn't found in time.
 pub async fn one<S>(
     service_name: &str,
     host_name: S,
@@ -33,58 +330,461 @@ pub async fn one<S>(
 where
     S: AsRef<str>,
 {
-    // by setting the query interval higher than the timeout we ensure we only make one query
-    let stream = crate::discover::all(service_name, timeout * 2)?.listen();
-    pin_mut!(stream);
-
-    let process = async {
-        while let Some(Ok(response)) = stream.next().await {
-            match response.hostname() {
-                Some(found_host) if found_host == host_name.as_ref() => return Some(response),
-                _ => {}
-            }
-        }
-
-        None
-    };
+    let report = Resolution::new(service_name, timeout)
+        .host(host_name.as_ref())
+        .run()
+        .await?;
 
-    runtime::timeout(timeout, process)
-        .map_err(|e| e.into())
-        .await
+    Ok(report
+        .resolved
+        .into_iter()
+        .next()
+        .map(|(_, response)| response))
 }
 
 /// Resolve multiple devices by hostname
+///
+/// The report lists the hosts that were found in time and the ones that
+/// weren't. [`Report::into_result`] turns it into an [`Error::Timeout`]
+/// holding the responses found if any host is missing.
 pub async fn multiple<S>(
     service_name: &str,
     host_names: &[S],
     timeout: Duration,
-) -> Result<Vec<Response>, Error>
+) -> Result<Report, Error>
 where
     S: AsRef<str>,
 {
-    // by setting the query interval higher than the timeout we ensure we only make one query
-    let stream = crate::discover::all(service_name, timeout * 2)?.listen();
-    pin_mut!(stream);
+    Resolution::new(service_name, timeout)
+        .hosts(host_names.iter().map(AsRef::as_ref))
+        .run()
+        .await
+}
 
-    let mut found = Vec::new();
+/// Resolves service instances by name, caching the records it hears.
+///
+/// Clones share their cache.
+#[derive(Clone, Debug)]
+pub struct Resolver {
+    runtime: Arc<dyn Runtime>,
+    socket: Socket,
+    cache: Arc<Mutex<Cache>>,
+}
 
-    let process = async {
-        while let Some(Ok(response)) = stream.next().await {
-            match response.hostname() {
-                Some(found_host) if host_names.iter().any(|s| s.as_ref() == found_host) => {
-                    found.push(response);
+/// Where a [`Resolver`] sends its queries.
+#[derive(Clone, Debug)]
+enum Socket {
+    /// A socket opened for each resolution.
+    Config(SocketConfig),
+    /// A transport shared by every resolution, each of which hears every
+    /// datagram.
+    Transport(Arc<Fanout>),
+}
+
+impl Resolver {
+    /// A resolver on all interfaces.
+    pub fn new() -> Self {
+        Self::with_config(runtime::default(), &SocketConfig::new())
+    }
 
-                    if found.len() == host_names.len() {
-                        return;
+    /// A resolver on a socket set up as `config` describes, driven by the
+    /// given runtime.
+    pub fn with_config(runtime: Arc<dyn Runtime>, config: &SocketConfig) -> Self {
+        Resolver {
+            runtime,
+            socket: Socket::Config(config.clone()),
+            cache: Arc::default(),
+        }
+    }
+
+    /// A resolver on the given transport, driven by the given runtime.
+    ///
+    /// Lookups running at once, on this resolver or its clones, share the
+    /// transport: a background task receives from it while any of them
+    /// runs, and each of them hears every datagram.
+    ///
+    /// This is mostly useful with the simulated network in
+    /// [`transport::sim`](crate::transport::sim).
+    pub fn with_transport(runtime: Arc<dyn Runtime>, transport: Arc<dyn Transport>) -> Self {
+        let max_datagram_len = SocketConfig::default().max_datagram_len();
+        Resolver {
+            socket: Socket::Transport(Arc::new(Fanout::new(
+                runtime.clone(),
+                transport,
+                max_datagram_len,
+            ))),
+            runtime,
+            cache: Arc::default(),
+        }
+    }
+
+    /// Looks up the `SRV` and `TXT` records of the instance named
+    /// `instance_name`, e.g. `Kitchen._googlecast._tcp.local`, and the
+    /// addresses of the host it is on.
+    ///
+    /// The response holds all of these as answers. If they didn't all arrive
+    /// within `timeout`, it holds whatever did, as long as the `SRV` record
+    /// did, and `None` is returned otherwise.
+    ///
+    /// ```rust,no_run
+    /// use mdns::resolve::Resolver;
+    /// use std::time::Duration;
+    ///
+    /// # async fn lookup(resolver: &Resolver) -> Result<(), mdns::Error> {
+    /// let kitchen = resolver
+    ///     .instance("Kitchen._googlecast._tcp.local", Duration::from_secs(5))
+    ///     .await?;
+    /// if let Some(address) = kitchen.and_then(|response| response.socket_address()) {
+    ///     println!("kitchen speaker at {}", address);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn instance(
+        &self,
+        instance_name: &str,
+        timeout: Duration,
+    ) -> Result<Option<Response>, Error> {
+        let span = trace::instance(instance_name);
+        trace::future(span, self.resolve_instance(instance_name, timeout)).await
+    }
+
+    async fn resolve_instance(
+        &self,
+        instance_name: &str,
+        timeout: Duration,
+    ) -> Result<Option<Response>, Error> {
+        let runtime = &*self.runtime;
+        let deadline = runtime.now() + timeout;
+        let mut resolver = InstanceResolver::new(instance_name, self.fresh_records());
+
+        // Fresh records might be all we need.
+        if let Some(response) = resolver.instance(runtime.now()) {
+            return Ok(Some(response));
+        }
+
+        let (mut mdns_listener, mdns_sender) = self.open()?;
+        resolver.handle_timeout(runtime.now());
+
+        let result = loop {
+            while let Some(transmit) = resolver.poll_transmit() {
+                if let Err(e) = mdns_sender.send(&transmit).await {
+                    log::warn!("query not sent: {}", e);
+                }
+            }
+
+            let now = runtime.now();
+            if let Some(response) = resolver.instance(now) {
+                break Ok(Some(response));
+            }
+            if now >= deadline {
+                break Ok(resolver.found(now));
+            }
+
+            let wake = resolver
+                .poll_timeout()
+                .map_or(deadline, |t| t.min(deadline));
+            let wait = wake.saturating_duration_since(now);
+            match runtime::timeout(runtime, wait, mdns_listener.recv()).await {
+                Ok(Ok((count, source))) => {
+                    let datagram = &mdns_listener.recv_buffer[..count];
+                    resolver.handle_datagram(runtime.now(), source, datagram);
+                }
+                Ok(Err(e)) => break Err(e),
+                Err(_) => resolver.handle_timeout(runtime.now()),
+            }
+        };
+
+        self.keep(resolver.into_cache());
+        result
+    }
+
+    /// Follows the instance named `instance_name`, yielding a [`Change`]
+    /// each time it is found, moves, changes addresses or TXT attributes, or
+    /// goes away.
+    ///
+    /// Only the instance's own records are asked for, again whenever they
+    /// are about to expire, so the stream never ends unless receiving
+    /// fails.
+    ///
+    /// ```rust,no_run
+    /// use futures_util::{pin_mut, StreamExt};
+    /// use mdns::resolve::Resolver;
+    ///
+    /// # async fn follow(resolver: &Resolver) -> Result<(), mdns::Error> {
+    /// let changes = resolver.watch("Kitchen._googlecast._tcp.local");
+    /// pin_mut!(changes);
+    /// while let Some(change) = changes.next().await {
+    ///     println!("{:?}", change?);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn watch(&self, instance_name: &str) -> impl Stream<Item = Result<Change, Error>> {
+        let resolver = self.clone();
+        let mut watcher = Watcher::new(instance_name, self.fresh_records());
+        let span = trace::watch(instance_name);
+
+        let changes = try_stream! {
+            let runtime = &*resolver.runtime;
+            let (mut mdns_listener, mdns_sender) = resolver.open()?;
+            watcher.handle_timeout(runtime.now());
+
+            loop {
+                while let Some(transmit) = watcher.poll_transmit() {
+                    if let Err(e) = mdns_sender.send(&transmit).await {
+                        log::warn!("query not sent: {}", e);
+                    }
+                }
+
+                let changes: Vec<_> = std::iter::from_fn(|| watcher.poll_event()).collect();
+                if !changes.is_empty() {
+                    resolver.keep(watcher.instance_records());
+                }
+                for change in changes {
+                    yield change;
+                }
+
+                let received = match watcher.poll_timeout() {
+                    Some(deadline) => {
+                        let wait = deadline.saturating_duration_since(runtime.now());
+                        runtime::timeout(runtime, wait, mdns_listener.recv())
+                            .await
+                            .ok()
+                    }
+                    None => Some(mdns_listener.recv().await),
+                };
+
+                match received {
+                    Some(result) => {
+                        let (count, source) = result?;
+                        let datagram = &mdns_listener.recv_buffer[..count];
+                        watcher.handle_datagram(runtime.now(), source, datagram);
                     }
+                    None => watcher.handle_timeout(runtime.now()),
                 }
-                _ => {}
             }
+        };
+
+        trace::stream(span, changes)
+    }
+
+    /// The records in the shared cache that haven't expired yet.
+    fn fresh_records(&self) -> Cache {
+        let mut cache = self.cache.lock().unwrap();
+        cache.expire(self.runtime.now());
+        cache.clone()
+    }
+
+    /// Adds the records heard by a resolution to the shared cache, dropping
+    /// the expired ones so that the cache doesn't keep growing.
+    fn keep(&self, records: Cache) {
+        let mut cache = self.cache.lock().unwrap();
+        cache.expire(self.runtime.now());
+        cache.merge(records);
+    }
+
+    fn open(&self) -> Result<(mDNSListener, mDNSSender), Error> {
+        Ok(match self.socket {
+            Socket::Config(ref config) => mdns_interface(&self.runtime, config)?,
+            Socket::Transport(ref fanout) => mdns_transport(fanout.reader()),
+        })
+    }
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The instances a response points to.
+fn instances(response: &Response) -> impl Iterator<Item = &str> {
+    response.records().filter_map(|record| match record.kind {
+        RecordKind::PTR(ref instance) => Some(instance.as_str()),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::register::{self, Host, Service};
+    use crate::testing::Lan;
+    use futures_util::future::{self, BoxFuture, Either, FutureExt};
+    use futures_util::pin_mut;
+    use std::io;
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    const KITCHEN: &str = "Kitchen._googlecast._tcp.local";
+
+    fn kitchen() -> Service {
+        Service::new("Kitchen", "_googlecast._tcp.local", "device.local", 8009)
+    }
+
+    fn device() -> Host {
+        Host::new("device.local").address(Ipv4Addr::new(192, 168, 1, 20))
+    }
+
+    /// A transport that fails to receive once `broken_at` has come.
+    #[derive(Debug)]
+    struct Breaking {
+        inner: Arc<dyn Transport>,
+        runtime: Arc<dyn Runtime>,
+        broken_at: Instant,
+    }
+
+    impl Transport for Breaking {
+        fn send_to<'a>(
+            &'a self,
+            buf: &'a [u8],
+            target: SocketAddr,
+        ) -> BoxFuture<'a, io::Result<usize>> {
+            self.inner.send_to(buf, target)
+        }
+
+        fn recv_from<'a>(
+            &'a self,
+            buf: &'a mut [u8],
+        ) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>> {
+            self.recv_with_ttl(buf)
+                .map(|received| received.map(|(count, source, _)| (count, source)))
+                .boxed()
+        }
+
+        fn recv_with_ttl<'a>(
+            &'a self,
+            buf: &'a mut [u8],
+        ) -> BoxFuture<'a, io::Result<(usize, SocketAddr, Option<u8>)>> {
+            let wait = self.broken_at.saturating_duration_since(self.runtime.now());
+            let broken = self.runtime.sleep(wait);
+            future::select(self.inner.recv_with_ttl(buf), broken)
+                .map(|either| match either {
+                    Either::Left((received, _)) => received,
+                    Either::Right(_) => Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "the interface went away",
+                    )),
+                })
+                .boxed()
+        }
+    }
+
+    #[test]
+    fn fresh_records_answer_without_asking() {
+        let lan = Lan::new();
+        let responder = register::with_transport(lan.network.runtime(), lan.device.bind(5353));
+        let service = responder.register(kitchen());
+        let host = responder.register_host(device());
+
+        let resolver = Resolver::with_transport(lan.network.runtime(), lan.laptop.bind(5353));
+        let timeout = Duration::from_secs(5);
+        let response = lan
+            .network
+            .block_on(resolver.instance(KITCHEN, timeout))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            response.socket_address(),
+            Some("192.168.1.20:8009".parse().unwrap())
+        );
+
+        // Once the device is gone, the cached records still answer.
+        drop((service, host, responder));
+        let response = lan
+            .network
+            .block_on(resolver.instance(KITCHEN, timeout))
+            .unwrap();
+        assert!(response.is_some());
+    }
+
+    #[test]
+    fn watching_follows_moves_and_goodbyes() {
+        let lan = Lan::new();
+        let responder = register::with_transport(lan.network.runtime(), lan.device.bind(5353));
+        let service = responder.register(kitchen());
+        let _host = responder.register_host(device());
+
+        let resolver = Resolver::with_transport(lan.network.runtime(), lan.laptop.bind(5353));
+        let changes = resolver.watch(KITCHEN);
+        pin_mut!(changes);
+
+        let change = lan.network.block_on(changes.next()).unwrap().unwrap();
+        assert!(matches!(change, Change::Found(_)));
+
+        service.update_port(8010);
+        let change = lan.network.block_on(changes.next()).unwrap().unwrap();
+        assert_eq!(
+            change,
+            Change::Moved {
+                target: "device.local".to_owned(),
+                port: 8010
+            }
+        );
+
+        service.unregister();
+        let change = lan.network.block_on(changes.next()).unwrap().unwrap();
+        assert_eq!(change, Change::Gone);
+    }
+
+    #[test]
+    fn failures_keep_what_was_resolved() {
+        let lan = Lan::new();
+        let responder = register::with_transport(lan.network.runtime(), lan.device.bind(5353));
+        let _service = responder.register(kitchen());
+        let _host = responder.register_host(device());
+
+        let runtime = lan.network.runtime();
+        let transport = Arc::new(Breaking {
+            inner: lan.laptop.bind(5353),
+            runtime: runtime.clone(),
+            broken_at: lan.network.now() + Duration::from_secs(3),
+        });
+        let resolution = Resolution::new("_googlecast._tcp.local", Duration::from_secs(10))
+            .host(KITCHEN)
+            .host("Attic._googlecast._tcp.local");
+
+        match lan
+            .network
+            .block_on(resolution.run_with_transport(runtime, transport))
+        {
+            Err(Error::Interrupted { partial, source }) => {
+                assert!(matches!(*source, Error::Io(_)));
+                assert_eq!(partial.resolved.len(), 1);
+                assert_eq!(partial.resolved[0].0, KITCHEN);
+                assert_eq!(partial.missing, ["Attic._googlecast._tcp.local"]);
+                assert_eq!(partial.elapsed, Duration::from_secs(3));
+            }
+            other => panic!("{:?}", other),
         }
-    };
+    }
+
+    #[test]
+    fn lookups_on_one_transport_all_hear_every_answer() {
+        let lan = Lan::new();
+        let responder = register::with_transport(lan.network.runtime(), lan.device.bind(5353));
+        let _service = responder.register(kitchen());
+        let _host = responder.register_host(device());
+        // Once the device is announced, it answers right away.
+        lan.network
+            .block_on(lan.network.runtime().sleep(Duration::from_secs(5)));
+
+        // A lookup of an instance that isn't there, receiving first, mustn't
+        // take the answers another lookup is waiting for.
+        let resolver = Resolver::with_transport(lan.network.runtime(), lan.laptop.bind(5353));
+        let other = resolver.clone();
+        let timeout = Duration::from_millis(500);
+        let (attic, kitchen) = lan.network.block_on(future::join(
+            other.instance("Attic._googlecast._tcp.local", timeout),
+            resolver.instance(KITCHEN, timeout),
+        ));
 
-    match runtime::timeout(timeout, process).await {
-        Ok(()) => Ok(found),
-        Err(e) => Err(e.into()),
+        assert_eq!(attic.unwrap(), None);
+        assert_eq!(
+            kitchen
+                .unwrap()
+                .and_then(|response| response.socket_address()),
+            Some("192.168.1.20:8009".parse().unwrap())
+        );
     }
 }
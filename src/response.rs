@@ -51,12 +51,13 @@ pub enum RecordKind {
 
 /// A TXT Record's Value for a present Attribute with following variants:
 /// - None:   Attribute present, with no value
-///           (e.g., "passreq" -- password required for this service)
+///   (e.g., "passreq" -- password required for this service)
 /// - Empty:  Attribute present, with empty value
-//            (e.g., "PlugIns=" -- the server supports plugins, but none are presently installed)
+///   (e.g., "PlugIns=" -- the server supports plugins, but none are presently installed)
 /// - Value(BString): Attribute present, with non-empty value
-//                    (e.g., "PlugIns=JPEG,MPEG2,MPEG4")
-/// RFC ref: https://datatracker.ietf.org/doc/html/rfc6763#section-6.4
+///   (e.g., "PlugIns=JPEG,MPEG2,MPEG4")
+///
+/// RFC ref: <https://datatracker.ietf.org/doc/html/rfc6763#section-6.4>
 #[derive(Clone, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TxtRecordValue {
@@ -283,7 +284,7 @@ impl Response {
 }
 
 impl Record {
-    fn from_resource_record(rr: &dns_parser::ResourceRecord) -> Self {
+    pub(crate) fn from_resource_record(rr: &dns_parser::ResourceRecord) -> Self {
         Record {
             name: rr.name.to_string(),
             class: rr.cls,
@@ -1,11 +1,113 @@
+//! Async runtime support.
+//!
+//! Everything the crate needs from an executor is described by the [`Runtime`]
+//! trait. An implementation is provided for every enabled runtime cargo feature,
+//! and several of them can be enabled at once: the runtime is picked when a
+//! [`Discovery`](crate::discover::Discovery) is constructed, either explicitly
+//! or with [`default`].
+
+use crate::errors::TimeoutError;
+use crate::transport::{Listener, Transport};
+
+use futures_core::Future;
+use futures_util::future::{select, BoxFuture, Either};
+use futures_util::pin_mut;
+use futures_util::stream::BoxStream;
+use std::fmt::Debug;
+use std::time::{Duration, Instant};
+use std::{io, sync::Arc};
+
+#[cfg(any(feature = "runtime-async-std", feature = "runtime-smol"))]
+mod async_io;
+
 #[cfg(feature = "runtime-async-std")]
 mod async_std;
 
 #[cfg(feature = "runtime-async-std")]
-pub use crate::runtime::async_std::*;
+pub use self::async_std::AsyncStd;
+
+#[cfg(feature = "runtime-smol")]
+mod smol;
+
+#[cfg(feature = "runtime-smol")]
+pub use self::smol::Smol;
 
 #[cfg(feature = "runtime-tokio")]
 mod tokio;
 
 #[cfg(feature = "runtime-tokio")]
-pub use crate::runtime::tokio::*;
+pub use self::tokio::Tokio;
+
+/// An async runtime that can drive mDNS sockets and timers.
+///
+/// Waiting for a future with a deadline is done by [`timeout`], on top of
+/// [`sleep`](Runtime::sleep), rather than by a method: a method generic over
+/// the future would keep the trait from being used as `dyn Runtime`.
+pub trait Runtime: Send + Sync + Debug + 'static {
+    /// Registers a bound, non-blocking UDP socket with the runtime.
+    fn udp_socket(&self, socket: std::net::UdpSocket) -> io::Result<Arc<dyn Transport>>;
+
+    /// Registers a bound, non-blocking TCP listener with the runtime.
+    ///
+    /// Only the unicast DNS [`gateway`](crate::gateway) needs TCP, so runtimes
+    /// may leave it unsupported.
+    fn tcp_listener(&self, listener: std::net::TcpListener) -> io::Result<Arc<dyn Listener>> {
+        drop(listener);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "this runtime does not support TCP",
+        ))
+    }
+
+    /// Runs a future in the background.
+    fn spawn(&self, future: BoxFuture<'static, ()>);
+
+    /// Completes once `duration` has passed.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+
+    /// Yields once every `period`, starting one period from now.
+    fn interval(&self, period: Duration) -> BoxStream<'static, ()>;
+
+    /// The current time, as seen by this runtime's timers.
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Picks a runtime among the enabled runtime features.
+///
+/// Tokio is used when called from within a Tokio runtime. Otherwise async-std
+/// is preferred, then smol, if they are enabled.
+pub fn default() -> Arc<dyn Runtime> {
+    #[cfg(feature = "runtime-tokio")]
+    if self::tokio::in_context() {
+        return Arc::new(Tokio);
+    }
+
+    #[cfg(feature = "runtime-async-std")]
+    let fallback = Arc::new(AsyncStd);
+    #[cfg(all(feature = "runtime-smol", not(feature = "runtime-async-std")))]
+    let fallback = Arc::new(Smol);
+    #[cfg(not(any(feature = "runtime-async-std", feature = "runtime-smol")))]
+    let fallback = Arc::new(Tokio);
+
+    fallback
+}
+
+/// Polls `future` to completion, giving up once `duration` has passed.
+pub async fn timeout<F, T>(
+    runtime: &dyn Runtime,
+    duration: Duration,
+    future: F,
+) -> Result<T, TimeoutError>
+where
+    F: Future<Output = T>,
+{
+    let sleep = runtime.sleep(duration);
+    pin_mut!(future);
+
+    match select(future, sleep).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(((), _)) => Err(TimeoutError),
+    }
+}
@@ -0,0 +1,37 @@
+use crate::link;
+use crate::transport::Transport;
+
+use async_io::Async;
+use futures_util::future::{BoxFuture, FutureExt};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+/// UDP sockets driven by [async-io](https://docs.rs/async-io), which both
+/// async-std and smol run on.
+impl Transport for Async<UdpSocket> {
+    fn send_to<'a>(
+        &'a self,
+        buf: &'a [u8],
+        target: SocketAddr,
+    ) -> BoxFuture<'a, io::Result<usize>> {
+        Async::<UdpSocket>::send_to(self, buf, target).boxed()
+    }
+
+    fn recv_from<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>> {
+        Async::<UdpSocket>::recv_from(self, buf).boxed()
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn recv_with_ttl<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<(usize, SocketAddr, Option<u8>)>> {
+        use std::os::unix::io::AsRawFd;
+
+        self.read_with(move |socket| link::recv_with_ttl(socket.as_raw_fd(), buf))
+            .boxed()
+    }
+}
@@ -1,23 +1,72 @@
-use crate::Error;
+use super::Runtime;
+use crate::transport::{Connection, Listener, Transport};
 
-use async_std::future::TimeoutError;
-use futures_core::{Future, Stream};
-use std::{sync::Arc, time::Duration};
+use futures_util::future::{BoxFuture, FutureExt};
+use futures_util::stream::{BoxStream, StreamExt};
+use std::net::SocketAddr;
+use std::{io, sync::Arc, time::Duration};
 
-pub use async_std::net::UdpSocket as AsyncUdpSocket;
-pub use async_std::task::spawn;
+/// The [async-std](https://docs.rs/async-std) runtime.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AsyncStd;
 
-pub fn create_interval_stream(request_interval: Duration) -> impl Stream<Item = ()> {
-    async_std::stream::interval(request_interval)
+impl Runtime for AsyncStd {
+    fn udp_socket(&self, socket: std::net::UdpSocket) -> io::Result<Arc<dyn Transport>> {
+        // async-std runs on async-io, whose sockets can tell the TTL of what
+        // they receive.
+        Ok(Arc::new(async_io::Async::new(socket)?))
+    }
+
+    fn tcp_listener(&self, listener: std::net::TcpListener) -> io::Result<Arc<dyn Listener>> {
+        Ok(Arc::new(async_std::net::TcpListener::from(listener)))
+    }
+
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        async_std::task::spawn(future);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        async_std::task::sleep(duration).boxed()
+    }
+
+    fn interval(&self, period: Duration) -> BoxStream<'static, ()> {
+        async_std::stream::interval(period).boxed()
+    }
 }
 
-pub fn make_async_socket(socket: std::net::UdpSocket) -> Result<Arc<AsyncUdpSocket>, Error> {
-    Ok(Arc::new(AsyncUdpSocket::from(socket)))
+impl Transport for async_std::net::UdpSocket {
+    fn send_to<'a>(
+        &'a self,
+        buf: &'a [u8],
+        target: SocketAddr,
+    ) -> BoxFuture<'a, io::Result<usize>> {
+        async_std::net::UdpSocket::send_to(self, buf, target).boxed()
+    }
+
+    fn recv_from<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>> {
+        async_std::net::UdpSocket::recv_from(self, buf).boxed()
+    }
+}
+
+impl Listener for async_std::net::TcpListener {
+    fn accept(&self) -> BoxFuture<'_, io::Result<(Box<dyn Connection>, SocketAddr)>> {
+        async move {
+            let (stream, addr) = async_std::net::TcpListener::accept(self).await?;
+            Ok((Box::new(stream) as Box<dyn Connection>, addr))
+        }
+        .boxed()
+    }
 }
 
-pub async fn timeout<F, T>(timeout: Duration, future: F) -> Result<T, TimeoutError>
-where
-    F: Future<Output = T>,
-{
-    async_std::future::timeout(timeout, future).await
+impl Connection for async_std::net::TcpStream {
+    fn read<'a>(&'a mut self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<usize>> {
+        async_std::io::ReadExt::read(self, buf).boxed()
+    }
+
+    fn write_all<'a>(&'a mut self, buf: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        async_std::io::WriteExt::write_all(self, buf).boxed()
+    }
 }
@@ -0,0 +1,57 @@
+use super::Runtime;
+use crate::transport::{Connection, Listener, Transport};
+
+use async_io::{Async, Timer};
+use futures_util::future::{BoxFuture, FutureExt};
+use futures_util::stream::{BoxStream, StreamExt};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::{io, sync::Arc, time::Duration};
+
+/// The [smol](https://docs.rs/smol) runtime.
+///
+/// Sockets and timers are driven by [async-io](https://docs.rs/async-io), and
+/// background tasks are spawned onto smol's global executor.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Smol;
+
+impl Runtime for Smol {
+    fn udp_socket(&self, socket: UdpSocket) -> io::Result<Arc<dyn Transport>> {
+        Ok(Arc::new(Async::new(socket)?))
+    }
+
+    fn tcp_listener(&self, listener: TcpListener) -> io::Result<Arc<dyn Listener>> {
+        Ok(Arc::new(Async::new(listener)?))
+    }
+
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        smol::spawn(future).detach();
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        FutureExt::map(Timer::after(duration), |_| ()).boxed()
+    }
+
+    fn interval(&self, period: Duration) -> BoxStream<'static, ()> {
+        StreamExt::map(Timer::interval(period), |_| ()).boxed()
+    }
+}
+
+impl Listener for Async<TcpListener> {
+    fn accept(&self) -> BoxFuture<'_, io::Result<(Box<dyn Connection>, SocketAddr)>> {
+        async move {
+            let (stream, addr) = Async::<TcpListener>::accept(self).await?;
+            Ok((Box::new(stream) as Box<dyn Connection>, addr))
+        }
+        .boxed()
+    }
+}
+
+impl Connection for Async<TcpStream> {
+    fn read<'a>(&'a mut self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<usize>> {
+        smol::io::AsyncReadExt::read(self, buf).boxed()
+    }
+
+    fn write_all<'a>(&'a mut self, buf: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        smol::io::AsyncWriteExt::write_all(self, buf).boxed()
+    }
+}
@@ -1,25 +1,104 @@
-use crate::Error;
-
-use futures_core::{Future, Stream};
-use std::{sync::Arc, time::Duration};
-use tokio::time::Instant;
-
-pub use tokio::net::UdpSocket as AsyncUdpSocket;
-pub use tokio::spawn;
-pub fn create_interval_stream(request_interval: Duration) -> impl Stream<Item = Instant> {
-    tokio_stream::wrappers::IntervalStream::new(tokio::time::interval_at(
-        tokio::time::Instant::now() + request_interval,
-        request_interval,
-    ))
+use super::Runtime;
+use crate::link;
+use crate::transport::{Connection, Listener, Transport};
+
+use futures_util::future::{BoxFuture, FutureExt};
+use futures_util::stream::{self, BoxStream, StreamExt};
+use std::net::SocketAddr;
+use std::{io, sync::Arc, time::Duration};
+
+/// The [Tokio](https://tokio.rs) runtime.
+///
+/// Sockets and timers must be created from within a Tokio runtime.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Tokio;
+
+/// Whether we are currently running inside a Tokio runtime.
+pub(super) fn in_context() -> bool {
+    tokio::runtime::Handle::try_current().is_ok()
 }
 
-pub fn make_async_socket(socket: std::net::UdpSocket) -> Result<Arc<AsyncUdpSocket>, Error> {
-    Ok(Arc::new(AsyncUdpSocket::from_std(socket)?))
+impl Runtime for Tokio {
+    fn udp_socket(&self, socket: std::net::UdpSocket) -> io::Result<Arc<dyn Transport>> {
+        Ok(Arc::new(tokio::net::UdpSocket::from_std(socket)?))
+    }
+
+    fn tcp_listener(&self, listener: std::net::TcpListener) -> io::Result<Arc<dyn Listener>> {
+        Ok(Arc::new(tokio::net::TcpListener::from_std(listener)?))
+    }
+
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        tokio::spawn(future);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        tokio::time::sleep(duration).boxed()
+    }
+
+    fn interval(&self, period: Duration) -> BoxStream<'static, ()> {
+        let interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        stream::unfold(interval, |mut interval| async move {
+            interval.tick().await;
+            Some(((), interval))
+        })
+        .boxed()
+    }
 }
 
-pub async fn timeout<F, T>(timeout: Duration, future: F) -> Result<T, crate::errors::TimeoutError>
-where
-    F: Future<Output = T>,
-{
-    tokio::time::timeout(timeout, future).await
+impl Transport for tokio::net::UdpSocket {
+    fn send_to<'a>(
+        &'a self,
+        buf: &'a [u8],
+        target: SocketAddr,
+    ) -> BoxFuture<'a, io::Result<usize>> {
+        tokio::net::UdpSocket::send_to(self, buf, target).boxed()
+    }
+
+    fn recv_from<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>> {
+        tokio::net::UdpSocket::recv_from(self, buf).boxed()
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn recv_with_ttl<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<(usize, SocketAddr, Option<u8>)>> {
+        use std::os::unix::io::AsRawFd;
+
+        async move {
+            loop {
+                self.readable().await?;
+                match self.try_io(tokio::io::Interest::READABLE, || {
+                    link::recv_with_ttl(self.as_raw_fd(), buf)
+                }) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                    received => return received,
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+impl Listener for tokio::net::TcpListener {
+    fn accept(&self) -> BoxFuture<'_, io::Result<(Box<dyn Connection>, SocketAddr)>> {
+        async move {
+            let (stream, addr) = tokio::net::TcpListener::accept(self).await?;
+            Ok((Box::new(stream) as Box<dyn Connection>, addr))
+        }
+        .boxed()
+    }
+}
+
+impl Connection for tokio::net::TcpStream {
+    fn read<'a>(&'a mut self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<usize>> {
+        tokio::io::AsyncReadExt::read(self, buf).boxed()
+    }
+
+    fn write_all<'a>(&'a mut self, buf: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        tokio::io::AsyncWriteExt::write_all(self, buf).boxed()
+    }
 }
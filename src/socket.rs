@@ -0,0 +1,288 @@
+//! Settings for the UDP sockets mDNS traffic is sent and received on.
+//!
+//! The defaults follow RFC 6762: the socket is bound to port 5353 on every
+//! address, joins `224.0.0.251`, and sends with an IP TTL of 255. Our own
+//! multicasts are not looped back to us.
+//!
+//! A non-standard group and port keep traffic away from the real mDNS
+//! responders on a host, which is handy for running tests side by side.
+//! Together with loopback, it lets processes on the same host find each
+//! other over `127.0.0.1` without bothering the network.
+//!
+//! Responses that didn't come from the local link are ignored, as RFC 6762
+//! asks: see [`SourceValidation`].
+//!
+//! ```rust,no_run
+//! use mdns::socket::SocketConfig;
+//! use std::net::Ipv4Addr;
+//! use std::time::Duration;
+//!
+//! # fn main() -> Result<(), mdns::Error> {
+//! let config = SocketConfig::new()
+//!     .interface(Ipv4Addr::LOCALHOST)
+//!     .group(Ipv4Addr::new(239, 255, 0, 251), 15353)
+//!     .multicast_loop(true)
+//!     .recv_buffer_size(1 << 20);
+//!
+//! let discovery = mdns::discover::with_config(
+//!     mdns::runtime::default(),
+//!     &config,
+//!     "_http._tcp.local",
+//!     Duration::from_secs(1),
+//! )?;
+//! # drop(discovery);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::link;
+use crate::mdns::{mDNSListener, mDNSSender, mdns_socket};
+use crate::proto::{MULTICAST_ADDR, MULTICAST_PORT};
+use crate::runtime::Runtime;
+use crate::transport::Resilient;
+use crate::Error;
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+
+#[cfg(not(target_os = "windows"))]
+use net2::unix::UnixUdpBuilderExt;
+
+/// The IP TTL of every mDNS packet (RFC 6762 section 11).
+pub const DEFAULT_TTL: u32 = 255;
+/// The largest datagram received by default.
+pub const DEFAULT_MAX_DATAGRAM_SIZE: usize = 9000;
+
+/// How to set up an mDNS socket.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SocketConfig {
+    interface: Ipv4Addr,
+    bind_addr: Ipv4Addr,
+    group: Ipv4Addr,
+    port: u16,
+    multicast_loop: bool,
+    ttl: u32,
+    recv_buffer_size: Option<usize>,
+    max_datagram_size: usize,
+    source_validation: SourceValidation,
+}
+
+/// What to do with responses that didn't come from the local link.
+///
+/// A response is from the local link if it arrived with an IP TTL of 255, or
+/// if its source address is on a subnet of one of our interfaces. The TTL is
+/// only known on Linux and Android; where neither is known, responses are
+/// let through.
+///
+/// ```rust
+/// use futures_util::{pin_mut, StreamExt};
+/// use mdns::register::{self, Service};
+/// use mdns::transport::sim::Network;
+/// use std::time::Duration;
+///
+/// let network = Network::new();
+/// let lan = network.link();
+/// let laptop = network.host("laptop");
+/// laptop.interface(&lan, [192, 168, 1, 10]);
+///
+/// // A host that is routed to us, and so can't be on our link.
+/// let spoofer = network.host("spoofer");
+/// spoofer.interface(&lan, [203, 0, 113, 20]);
+/// lan.set_ttl(64);
+///
+/// let responder = register::with_transport(network.runtime(), spoofer.bind(5353));
+/// responder.register(Service::new("Office", "_ipp._tcp.local", "printer.local", 631));
+///
+/// let stream = mdns::discover::with_transport(
+///     network.runtime(),
+///     laptop.bind(5353),
+///     "_ipp._tcp.local",
+///     Duration::from_secs(1),
+/// )
+/// .listen();
+/// pin_mut!(stream);
+///
+/// let runtime = network.runtime();
+/// let found = mdns::runtime::timeout(&*runtime, Duration::from_secs(5), stream.next());
+/// assert!(network.block_on(found).is_err());
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SourceValidation {
+    /// Ignore them, as RFC 6762 section 11 asks.
+    #[default]
+    Enforce,
+    /// Log a warning about them, but handle them anyway.
+    Warn,
+    /// Handle every response, wherever it came from.
+    Off,
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        SocketConfig {
+            interface: Ipv4Addr::UNSPECIFIED,
+            bind_addr: Ipv4Addr::UNSPECIFIED,
+            group: MULTICAST_ADDR,
+            port: MULTICAST_PORT,
+            multicast_loop: false,
+            ttl: DEFAULT_TTL,
+            recv_buffer_size: None,
+            max_datagram_size: DEFAULT_MAX_DATAGRAM_SIZE,
+            source_validation: SourceValidation::default(),
+        }
+    }
+}
+
+impl SocketConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the address of the interface to join the group on, and to send
+    /// multicasts from.
+    ///
+    /// Defaults to `0.0.0.0`, which leaves the choice to the OS.
+    pub fn interface(mut self, interface: Ipv4Addr) -> Self {
+        self.interface = interface;
+        self
+    }
+
+    /// Sets the address the socket is bound to.
+    ///
+    /// Defaults to `0.0.0.0`. Some systems only deliver multicasts to
+    /// sockets bound to the group address or to `0.0.0.0`.
+    pub fn bind_addr(mut self, bind_addr: Ipv4Addr) -> Self {
+        self.bind_addr = bind_addr;
+        self
+    }
+
+    /// Sets the multicast group and port to use instead of
+    /// `224.0.0.251:5353`.
+    ///
+    /// Only hosts using the same group and port will hear us, and we them.
+    pub fn group(mut self, group: Ipv4Addr, port: u16) -> Self {
+        self.group = group;
+        self.port = port;
+        self
+    }
+
+    /// Sets whether our own multicasts are delivered back to us, and to
+    /// other sockets on this host.
+    ///
+    /// Defaults to `false`.
+    pub fn multicast_loop(mut self, multicast_loop: bool) -> Self {
+        self.multicast_loop = multicast_loop;
+        self
+    }
+
+    /// Sets the IP TTL, or hop limit, of sent packets.
+    ///
+    /// Defaults to 255, as RFC 6762 requires. Receivers may drop packets
+    /// with any other TTL, as a sign that they didn't come from the local
+    /// link.
+    pub fn ttl(mut self, ttl: u32) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Sets the size of the socket's receive buffer (`SO_RCVBUF`).
+    ///
+    /// Defaults to the OS default.
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets the size of the largest datagram that can be received. Bigger
+    /// datagrams are cut short.
+    ///
+    /// Defaults to 9000 bytes, the most RFC 6762 allows, which is only seen
+    /// on links with jumbo frames.
+    pub fn max_datagram_size(mut self, size: usize) -> Self {
+        self.max_datagram_size = size;
+        self
+    }
+
+    /// Sets what to do with responses that didn't come from the local link.
+    ///
+    /// Defaults to [`SourceValidation::Enforce`].
+    pub fn source_validation(mut self, validation: SourceValidation) -> Self {
+        self.source_validation = validation;
+        self
+    }
+
+    /// The address and port multicasts are sent to.
+    pub fn group_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.group.into(), self.port)
+    }
+
+    pub(crate) fn interface_addr(&self) -> Ipv4Addr {
+        self.interface
+    }
+
+    pub(crate) fn max_datagram_len(&self) -> usize {
+        self.max_datagram_size
+    }
+
+    pub(crate) fn validation(&self) -> SourceValidation {
+        self.source_validation
+    }
+
+    /// Creates a bound, non-blocking socket that has joined the group.
+    pub(crate) fn bind(&self) -> Result<UdpSocket, Error> {
+        use net2::UdpSocketExt;
+
+        let addr = SocketAddr::new(self.bind_addr.into(), self.port);
+        let socket = create_socket(addr).map_err(|e| Error::bind(addr, e))?;
+
+        socket.set_multicast_loop_v4(self.multicast_loop)?;
+        socket.set_multicast_ttl_v4(self.ttl)?;
+        socket.set_ttl(self.ttl)?;
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if !self.interface.is_unspecified() {
+            socket.set_multicast_if_v4(&self.interface)?;
+        }
+        link::enable_recv_ttl(&socket)?;
+        socket.set_nonblocking(true)?; // explicitly set nonblocking for wider compatability
+        socket
+            .join_multicast_v4(&self.group, &self.interface)
+            .map_err(|source| Error::JoinMulticast {
+                group: self.group,
+                interface: self.interface,
+                source,
+            })?;
+
+        Ok(socket)
+    }
+
+    /// Creates the socket and registers it with the runtime. A new socket
+    /// is created whenever it breaks.
+    pub(crate) fn open(
+        &self,
+        runtime: &Arc<dyn Runtime>,
+    ) -> Result<(mDNSListener, mDNSSender), Error> {
+        let config = self.clone();
+        let open_runtime = runtime.clone();
+        let socket = Resilient::new(runtime.clone(), move || {
+            Ok(open_runtime.udp_socket(config.bind()?)?)
+        })?;
+
+        Ok(mdns_socket(Arc::new(socket), self))
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn create_socket(addr: SocketAddr) -> io::Result<UdpSocket> {
+    net2::UdpBuilder::new_v4()?
+        .reuse_address(true)?
+        .reuse_port(true)?
+        .bind(addr)
+}
+
+#[cfg(target_os = "windows")]
+fn create_socket(addr: SocketAddr) -> io::Result<UdpSocket> {
+    net2::UdpBuilder::new_v4()?.reuse_address(true)?.bind(addr)
+}
@@ -0,0 +1,82 @@
+//! Fixtures shared by the unit tests.
+
+use crate::proto::wire::{Message, Resource};
+use crate::transport::sim::{Host, Network};
+use crate::{Record, RecordKind, TxtRecordValue};
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// A simulated link with a laptop and a device on it.
+pub(crate) struct Lan {
+    pub(crate) network: Network,
+    /// At 192.168.1.10, and where lookups are made from.
+    pub(crate) laptop: Host,
+    /// At 192.168.1.20, and what is looked up.
+    pub(crate) device: Host,
+}
+
+impl Lan {
+    pub(crate) fn new() -> Self {
+        let network = Network::new();
+        let link = network.link();
+        let laptop = network.host("laptop");
+        laptop.interface(&link, [192, 168, 1, 10]);
+        let device = network.host("device");
+        device.interface(&link, [192, 168, 1, 20]);
+
+        Lan {
+            network,
+            laptop,
+            device,
+        }
+    }
+}
+
+/// Where the device's datagrams come from, for the sans-IO state machines.
+pub(crate) fn device() -> SocketAddr {
+    "192.168.1.20:5353".parse().unwrap()
+}
+
+/// A record only its owner may hold, with the cache-flush bit set.
+pub(crate) fn unique(name: &str, ttl: u32, kind: RecordKind) -> Resource {
+    Resource::unique(Record {
+        name: name.to_owned(),
+        class: dns_parser::Class::IN,
+        ttl,
+        kind,
+    })
+}
+
+/// A record other hosts may hold too.
+pub(crate) fn shared(name: &str, ttl: u32, kind: RecordKind) -> Resource {
+    Resource::shared(Record {
+        name: name.to_owned(),
+        class: dns_parser::Class::IN,
+        ttl,
+        kind,
+    })
+}
+
+pub(crate) fn srv(target: &str, port: u16) -> RecordKind {
+    RecordKind::SRV {
+        priority: 0,
+        weight: 0,
+        port,
+        target: target.to_owned(),
+    }
+}
+
+/// A TXT record with a single attribute.
+pub(crate) fn txt(key: &str, value: &str) -> RecordKind {
+    let mut txt = HashMap::new();
+    txt.insert(key.to_owned().into(), TxtRecordValue::Value(value.into()));
+    RecordKind::TXT(txt)
+}
+
+/// An encoded response with `answers`.
+pub(crate) fn response(answers: Vec<Resource>) -> Vec<u8> {
+    let mut response = Message::response(0);
+    response.answers = answers;
+    response.encode()
+}
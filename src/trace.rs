@@ -0,0 +1,164 @@
+//! Spans and events for the `tracing` crate.
+//!
+//! Discoveries, resolutions and watches each get a span, and every packet sent or
+//! received is a `TRACE` event in the span it belongs to. Without the
+//! `tracing` feature, all of this compiles to nothing.
+
+use futures_core::Stream;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::Span;
+
+#[cfg(not(feature = "tracing"))]
+#[derive(Clone, Debug)]
+pub(crate) struct Span;
+
+/// The span of a discovery of `service_name`.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn discovery(service_name: &str, interface: IpAddr) -> Span {
+    #[cfg(feature = "tracing")]
+    {
+        tracing::info_span!("discovery", service_name, %interface)
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        Span
+    }
+}
+
+/// The span of a resolution of `host_names` among the instances of
+/// `service_name`.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn resolution<S>(service_name: &str, host_names: &[S]) -> Span
+where
+    S: AsRef<str>,
+{
+    #[cfg(feature = "tracing")]
+    {
+        let host_names: Vec<_> = host_names.iter().map(AsRef::as_ref).collect();
+        tracing::info_span!("resolution", service_name, ?host_names)
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        Span
+    }
+}
+
+/// The span of a resolution of the instance named `instance_name`.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn instance(instance_name: &str) -> Span {
+    #[cfg(feature = "tracing")]
+    {
+        tracing::info_span!("instance", instance_name)
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        Span
+    }
+}
+
+/// The span of a watch of the instance named `instance_name`.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn watch(instance_name: &str) -> Span {
+    #[cfg(feature = "tracing")]
+    {
+        tracing::info_span!("watch", instance_name)
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        Span
+    }
+}
+
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn sent(destination: SocketAddr, datagram: &[u8]) {
+    #[cfg(feature = "tracing")]
+    tracing::trace!(
+        %destination,
+        len = datagram.len(),
+        kind = kind(datagram),
+        "sent packet"
+    );
+}
+
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn received(source: SocketAddr, datagram: &[u8]) {
+    #[cfg(feature = "tracing")]
+    tracing::trace!(
+        %source,
+        len = datagram.len(),
+        kind = kind(datagram),
+        "received packet"
+    );
+}
+
+/// Runs `future` inside `span`.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn future<F>(span: Span, future: F) -> impl Future<Output = F::Output>
+where
+    F: Future,
+{
+    #[cfg(feature = "tracing")]
+    {
+        tracing::Instrument::instrument(future, span)
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        future
+    }
+}
+
+/// Polls `stream` inside `span`.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn stream<S>(span: Span, stream: S) -> impl Stream<Item = S::Item>
+where
+    S: Stream,
+{
+    #[cfg(feature = "tracing")]
+    {
+        instrumented::Instrumented {
+            span,
+            stream: Box::pin(stream),
+        }
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        stream
+    }
+}
+
+#[cfg(feature = "tracing")]
+fn kind(datagram: &[u8]) -> &'static str {
+    match datagram.get(2) {
+        Some(flags) if flags & 0x80 != 0 => "response",
+        Some(_) => "query",
+        None => "empty",
+    }
+}
+
+#[cfg(feature = "tracing")]
+mod instrumented {
+    use futures_core::Stream;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    pub(crate) struct Instrumented<S> {
+        pub(crate) span: tracing::Span,
+        pub(crate) stream: Pin<Box<S>>,
+    }
+
+    impl<S> Stream for Instrumented<S>
+    where
+        S: Stream,
+    {
+        type Item = S::Item;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+            let this = &mut *self;
+            let _entered = this.span.enter();
+            this.stream.as_mut().poll_next(cx)
+        }
+    }
+}
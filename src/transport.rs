@@ -0,0 +1,63 @@
+//! The datagram transports mDNS traffic flows over.
+//!
+//! Discovery only ever talks to the network through the [`Transport`] trait.
+//! The runtimes in [`runtime`](crate::runtime) wrap real UDP sockets, while
+//! [`sim`] provides an in-memory network for deterministic tests. Sockets
+//! are wrapped in a [`Resilient`] transport, which keeps receiving through
+//! errors.
+//!
+//! The unicast DNS [`gateway`](crate::gateway) also answers over TCP, through
+//! the [`Listener`] and [`Connection`] traits.
+
+use futures_util::future::{BoxFuture, FutureExt};
+use std::fmt::Debug;
+use std::io;
+use std::net::SocketAddr;
+
+pub(crate) use self::fanout::Fanout;
+pub use self::resilient::Resilient;
+
+mod fanout;
+mod resilient;
+pub mod sim;
+
+/// Something that can send and receive mDNS datagrams.
+pub trait Transport: Send + Sync + Debug {
+    /// Sends a datagram to the given address.
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: SocketAddr)
+        -> BoxFuture<'a, io::Result<usize>>;
+
+    /// Receives a single datagram, returning its length and source address.
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8])
+        -> BoxFuture<'a, io::Result<(usize, SocketAddr)>>;
+
+    /// Receives a single datagram, returning its length, its source address
+    /// and the IP TTL (or hop limit) it arrived with.
+    ///
+    /// Transports that can't tell the TTL report `None`, which is what this
+    /// does unless overridden.
+    fn recv_with_ttl<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<(usize, SocketAddr, Option<u8>)>> {
+        self.recv_from(buf)
+            .map(|received| received.map(|(count, source)| (count, source, None)))
+            .boxed()
+    }
+}
+
+/// A listening TCP socket.
+pub trait Listener: Send + Sync + Debug {
+    /// Waits for a client to connect.
+    fn accept(&self) -> BoxFuture<'_, io::Result<(Box<dyn Connection>, SocketAddr)>>;
+}
+
+/// An established TCP connection.
+pub trait Connection: Send + Debug {
+    /// Reads some bytes, returning how many were read, or zero once the
+    /// other side closed the connection.
+    fn read<'a>(&'a mut self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<usize>>;
+
+    /// Writes all of `buf`.
+    fn write_all<'a>(&'a mut self, buf: &'a [u8]) -> BoxFuture<'a, io::Result<()>>;
+}
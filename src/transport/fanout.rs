@@ -0,0 +1,146 @@
+use crate::runtime::Runtime;
+use crate::transport::Transport;
+
+use async_channel::{Receiver, Sender, TrySendError};
+use futures_util::future::{BoxFuture, FutureExt};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// How many datagrams wait for a reader that falls behind before more are
+/// dropped, as a socket's receive buffer would.
+const QUEUE_LEN: usize = 64;
+
+/// A datagram handed to a reader, or the error receiving ended with.
+type Received = io::Result<(Vec<u8>, SocketAddr, Option<u8>)>;
+
+/// A transport read by several lookups at once, each of which gets every
+/// datagram.
+///
+/// Reading the transport directly, each datagram would go to whichever
+/// reader happened to be first, and the others would never see it. Instead,
+/// a task receives from the transport while there are readers, and copies
+/// every datagram to each of them.
+#[derive(Debug)]
+pub(crate) struct Fanout {
+    runtime: Arc<dyn Runtime>,
+    transport: Arc<dyn Transport>,
+    /// Where received datagrams go, or `None` while no task receives.
+    readers: Arc<Mutex<Option<Vec<Sender<Received>>>>>,
+    max_datagram_len: usize,
+}
+
+/// One reader of a [`Fanout`], sending straight to the shared transport.
+#[derive(Debug)]
+struct Reader {
+    transport: Arc<dyn Transport>,
+    datagrams: Receiver<Received>,
+}
+
+impl Fanout {
+    pub(crate) fn new(
+        runtime: Arc<dyn Runtime>,
+        transport: Arc<dyn Transport>,
+        max_datagram_len: usize,
+    ) -> Self {
+        Fanout {
+            runtime,
+            transport,
+            readers: Arc::default(),
+            max_datagram_len,
+        }
+    }
+
+    /// A transport that receives every datagram from now on, until it is
+    /// dropped.
+    pub(crate) fn reader(&self) -> Arc<dyn Transport> {
+        let (sender, datagrams) = async_channel::bounded(QUEUE_LEN);
+        let mut readers = self.readers.lock().unwrap();
+        match *readers {
+            Some(ref mut readers) => readers.push(sender),
+            None => {
+                *readers = Some(vec![sender]);
+                let buffer = vec![0; self.max_datagram_len];
+                let receiving = receive(self.transport.clone(), self.readers.clone(), buffer);
+                self.runtime.spawn(receiving.boxed());
+            }
+        }
+
+        Arc::new(Reader {
+            transport: self.transport.clone(),
+            datagrams,
+        })
+    }
+}
+
+/// Hands out datagrams from `transport` until there are no readers left to
+/// take them, or receiving fails.
+///
+/// The readers are only counted when a datagram arrives, so this outlives
+/// the last of them until then.
+async fn receive(
+    transport: Arc<dyn Transport>,
+    readers: Arc<Mutex<Option<Vec<Sender<Received>>>>>,
+    mut buffer: Vec<u8>,
+) {
+    loop {
+        let received = transport.recv_with_ttl(&mut buffer).await;
+        let mut readers = readers.lock().unwrap();
+        let open = readers.as_mut().expect("readers while receiving");
+
+        let done = match received {
+            Ok((count, source, ttl)) => {
+                let datagram = &buffer[..count];
+                open.retain(|reader| {
+                    let received = Ok((datagram.to_vec(), source, ttl));
+                    !matches!(reader.try_send(received), Err(TrySendError::Closed(_)))
+                });
+                open.is_empty()
+            }
+            Err(e) => {
+                for reader in open.iter() {
+                    let _ = reader.try_send(Err(io::Error::new(e.kind(), e.to_string())));
+                }
+                true
+            }
+        };
+        if done {
+            *readers = None;
+            return;
+        }
+    }
+}
+
+impl Transport for Reader {
+    fn send_to<'a>(
+        &'a self,
+        buf: &'a [u8],
+        target: SocketAddr,
+    ) -> BoxFuture<'a, io::Result<usize>> {
+        self.transport.send_to(buf, target)
+    }
+
+    fn recv_from<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>> {
+        self.recv_with_ttl(buf)
+            .map(|received| received.map(|(count, source, _)| (count, source)))
+            .boxed()
+    }
+
+    fn recv_with_ttl<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<(usize, SocketAddr, Option<u8>)>> {
+        async move {
+            let (datagram, source, ttl) = self.datagrams.recv().await.map_err(|_| {
+                io::Error::new(io::ErrorKind::BrokenPipe, "the shared transport failed")
+            })??;
+            let count = datagram.len().min(buf.len());
+            buf[..count].copy_from_slice(&datagram[..count]);
+            Ok((count, source, ttl))
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,204 @@
+use crate::runtime::Runtime;
+use crate::transport::Transport;
+use crate::Error;
+
+use futures_util::future::{BoxFuture, FutureExt};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long to wait after the first failed receive.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+/// The longest wait between receives, however many of them failed.
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// A transport that keeps receiving through errors.
+///
+/// Receive errors that are expected to go away on their own, such as an
+/// ICMP error from an earlier send or a full buffer, are retried after a
+/// while. Any other error means the socket is broken, and a new one is
+/// opened in its place. The wait between attempts doubles with every
+/// failure in a row, up to ten seconds.
+///
+/// Receiving never fails. Sending fails as the underlying socket does.
+///
+/// ```rust
+/// use futures_util::future::{ready, BoxFuture, FutureExt};
+/// use mdns::transport::sim::Network;
+/// use mdns::transport::{Resilient, Transport};
+/// use std::io;
+/// use std::net::SocketAddr;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// /// A socket whose network went away.
+/// #[derive(Debug)]
+/// struct Broken;
+///
+/// impl Transport for Broken {
+///     fn send_to<'a>(&'a self, _: &'a [u8], _: SocketAddr) -> BoxFuture<'a, io::Result<usize>> {
+///         ready(Err(io::Error::new(io::ErrorKind::NotConnected, "down"))).boxed()
+///     }
+///
+///     fn recv_from<'a>(&'a self, _: &'a mut [u8]) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>> {
+///         ready(Err(io::Error::new(io::ErrorKind::NotConnected, "down"))).boxed()
+///     }
+/// }
+///
+/// let network = Network::new();
+/// let lan = network.link();
+/// let laptop = network.host("laptop");
+/// laptop.interface(&lan, [192, 168, 1, 10]);
+/// let printer = network.host("printer").interface(&lan, [192, 168, 1, 20]).bind(5353);
+///
+/// // The first socket is broken, and the second one works.
+/// let opened = AtomicUsize::new(0);
+/// let transport = Resilient::new(network.runtime(), move || {
+///     if opened.fetch_add(1, Ordering::SeqCst) == 0 {
+///         Ok(Arc::new(Broken) as Arc<dyn Transport>)
+///     } else {
+///         Ok(laptop.bind(5353))
+///     }
+/// })
+/// .unwrap();
+///
+/// let runtime = network.runtime();
+/// network.runtime().spawn(Box::pin(async move {
+///     runtime.sleep(Duration::from_secs(1)).await;
+///     let _ = printer.send_to(b"hello", "224.0.0.251:5353".parse().unwrap()).await;
+/// }));
+///
+/// let mut buffer = [0; 16];
+/// let (count, _) = network.block_on(transport.recv_from(&mut buffer)).unwrap();
+/// assert_eq!(&buffer[..count], b"hello");
+/// ```
+pub struct Resilient {
+    runtime: Arc<dyn Runtime>,
+    open: Box<dyn Fn() -> Result<Arc<dyn Transport>, Error> + Send + Sync>,
+    transport: Mutex<Arc<dyn Transport>>,
+    /// How many receives in a row have failed.
+    failures: AtomicU32,
+}
+
+impl Resilient {
+    /// Opens a transport with `open`, which is called again whenever the
+    /// transport breaks.
+    pub fn new<F>(runtime: Arc<dyn Runtime>, open: F) -> Result<Self, Error>
+    where
+        F: Fn() -> Result<Arc<dyn Transport>, Error> + Send + Sync + 'static,
+    {
+        let transport = open()?;
+
+        Ok(Resilient {
+            runtime,
+            open: Box::new(open),
+            transport: Mutex::new(transport),
+            failures: AtomicU32::new(0),
+        })
+    }
+
+    fn current(&self) -> Arc<dyn Transport> {
+        self.transport.lock().unwrap().clone()
+    }
+
+    /// Opens a new transport in place of `broken`, unless that was done
+    /// already.
+    fn reopen(&self, broken: &Arc<dyn Transport>) {
+        match (self.open)() {
+            Ok(transport) => {
+                let mut current = self.transport.lock().unwrap();
+                if Arc::ptr_eq(&current, broken) {
+                    *current = transport;
+                }
+            }
+            Err(e) => log::warn!("failed to reopen socket: {}", e),
+        }
+    }
+}
+
+impl Transport for Resilient {
+    fn send_to<'a>(
+        &'a self,
+        buf: &'a [u8],
+        target: SocketAddr,
+    ) -> BoxFuture<'a, io::Result<usize>> {
+        async move { self.current().send_to(buf, target).await }.boxed()
+    }
+
+    fn recv_from<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>> {
+        self.recv_with_ttl(buf)
+            .map(|received| received.map(|(count, source, _)| (count, source)))
+            .boxed()
+    }
+
+    fn recv_with_ttl<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<(usize, SocketAddr, Option<u8>)>> {
+        async move {
+            loop {
+                let transport = self.current();
+                let error = match transport.recv_with_ttl(buf).await {
+                    Ok(received) => {
+                        self.failures.store(0, Ordering::Relaxed);
+                        return Ok(received);
+                    }
+                    Err(e) => e,
+                };
+
+                if is_transient(&error) {
+                    log::debug!("failed to receive, retrying: {}", error);
+                } else {
+                    log::warn!("socket broke, reopening it: {}", error);
+                    self.reopen(&transport);
+                }
+
+                let failures = self.failures.fetch_add(1, Ordering::Relaxed);
+                self.runtime.sleep(backoff(failures)).await;
+            }
+        }
+        .boxed()
+    }
+}
+
+impl fmt::Debug for Resilient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Resilient")
+            .field("transport", &self.current())
+            .field("failures", &self.failures)
+            .finish()
+    }
+}
+
+/// How long to wait after `failures` earlier failures in a row.
+fn backoff(failures: u32) -> Duration {
+    INITIAL_BACKOFF
+        .checked_mul(1 << failures.min(16))
+        .unwrap_or(MAX_BACKOFF)
+        .min(MAX_BACKOFF)
+}
+
+/// Whether an error is expected to go away without a new socket.
+fn is_transient(error: &io::Error) -> bool {
+    use io::ErrorKind::*;
+
+    if let WouldBlock | Interrupted | TimedOut | ConnectionRefused | ConnectionReset
+    | ConnectionAborted | OutOfMemory = error.kind()
+    {
+        return true;
+    }
+
+    #[cfg(unix)]
+    if let Some(libc::ENOBUFS | libc::ENETUNREACH | libc::EHOSTUNREACH) = error.raw_os_error() {
+        return true;
+    }
+
+    false
+}
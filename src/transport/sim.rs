@@ -0,0 +1,717 @@
+//! An in-memory multicast network running on a virtual clock.
+//!
+//! A [`Network`] is made up of [`Link`]s (broadcast domains) and [`Host`]s,
+//! each of which can have [`Interface`]s on any number of links. Sockets bound
+//! on a host or an interface implement [`Transport`], so they can be handed to
+//! [`discover::with_transport`](crate::discover::with_transport) in place of a
+//! real multicast socket.
+//!
+//! Time only moves when the network says so. [`Network::block_on`] runs a
+//! future, together with anything spawned on [`Network::runtime`], and skips
+//! the clock straight to the next timer or datagram delivery whenever every
+//! task is waiting. Packet loss is drawn from a seeded generator, so a given
+//! simulation always plays out the same way.
+//!
+//! ```rust
+//! use futures_util::{pin_mut, StreamExt};
+//! use mdns::transport::sim::Network;
+//! use std::time::Duration;
+//! # fn ptr_response(service: &str, instance: &str) -> Vec<u8> {
+//! #     fn name(out: &mut Vec<u8>, name: &str) {
+//! #         for label in name.split('.') { out.push(label.len() as u8); out.extend(label.as_bytes()); }
+//! #         out.push(0);
+//! #     }
+//! #     let mut rdata = Vec::new();
+//! #     name(&mut rdata, instance);
+//! #     let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+//! #     name(&mut packet, service);
+//! #     packet.extend([0, 12, 0, 1, 0, 0, 0, 120, 0, rdata.len() as u8]);
+//! #     packet.extend(rdata);
+//! #     packet
+//! # }
+//!
+//! let network = Network::new();
+//! let lan = network.link();
+//! lan.set_latency(Duration::from_millis(5));
+//!
+//! let laptop = network.host("laptop");
+//! laptop.interface(&lan, [192, 168, 1, 10]);
+//! let printer = network.host("printer");
+//! printer.interface(&lan, [192, 168, 1, 20]);
+//!
+//! // The printer answers every query it sees.
+//! let printer_socket = printer.bind(5353);
+//! network.runtime().spawn(Box::pin(async move {
+//!     let mut buffer = [0; 512];
+//!     while let Ok((_, _)) = printer_socket.recv_from(&mut buffer).await {
+//!         let response = ptr_response("_ipp._tcp.local", "Office._ipp._tcp.local");
+//!         let _ = printer_socket.send_to(&response, "224.0.0.251:5353".parse().unwrap()).await;
+//!     }
+//! }));
+//!
+//! let start = network.now();
+//! let stream = mdns::discover::with_transport(
+//!     network.runtime(),
+//!     laptop.bind(5353),
+//!     "_ipp._tcp.local",
+//!     Duration::from_secs(1),
+//! )
+//! .listen();
+//! pin_mut!(stream);
+//!
+//! let response = network.block_on(stream.next()).unwrap().unwrap();
+//! assert_eq!(response.hostname(), Some("Office._ipp._tcp.local"));
+//! // One hop for the query, one hop for the answer.
+//! assert_eq!(network.now() - start, Duration::from_millis(10));
+//! ```
+
+use crate::runtime::Runtime;
+use crate::transport::Transport;
+
+use futures_util::future::{poll_fn, ready, BoxFuture, FutureExt};
+use futures_util::pin_mut;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use futures_util::task::{waker, ArcWake};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use std::{fmt, io};
+
+/// A simulated network.
+///
+/// Cloning a network gives another handle to the same simulation.
+#[derive(Clone)]
+pub struct Network {
+    state: Arc<Mutex<State>>,
+    tasks: Arc<Mutex<Tasks>>,
+}
+
+/// A broadcast domain that interfaces can be attached to.
+#[derive(Clone, Debug)]
+pub struct Link {
+    network: Network,
+    id: usize,
+}
+
+/// A simulated machine.
+#[derive(Clone, Debug)]
+pub struct Host {
+    network: Network,
+    id: usize,
+}
+
+/// A network interface of a [`Host`], attached to a single [`Link`].
+#[derive(Clone, Debug)]
+pub struct Interface {
+    network: Network,
+    id: usize,
+}
+
+/// The [`Runtime`] of a simulated network.
+///
+/// Timers run on the network's virtual clock, and spawned tasks are run by
+/// [`Network::block_on`].
+#[derive(Clone, Debug)]
+pub struct SimRuntime {
+    network: Network,
+}
+
+/// A socket bound on a simulated host or interface.
+struct Socket {
+    state: Arc<Mutex<State>>,
+    id: usize,
+}
+
+struct State {
+    epoch: Instant,
+    elapsed: Duration,
+    rng: u64,
+
+    links: Vec<LinkState>,
+    hosts: Vec<String>,
+    interfaces: Vec<InterfaceState>,
+    sockets: Vec<Option<SocketState>>,
+    timers: Vec<(Instant, Waker)>,
+}
+
+struct LinkState {
+    latency: Duration,
+    packet_loss: f64,
+    ttl: u8,
+}
+
+struct InterfaceState {
+    host: usize,
+    link: usize,
+    addr: IpAddr,
+}
+
+enum Binding {
+    Host(usize),
+    Interface(usize),
+}
+
+struct SocketState {
+    binding: Binding,
+    port: u16,
+    inbox: VecDeque<Datagram>,
+    waker: Option<Waker>,
+}
+
+struct Datagram {
+    deliver_at: Instant,
+    source: SocketAddr,
+    ttl: u8,
+    contents: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Tasks {
+    running: Vec<BoxFuture<'static, ()>>,
+    spawned: Vec<BoxFuture<'static, ()>>,
+}
+
+struct WakeFlag(AtomicBool);
+
+impl Network {
+    /// Creates an empty network.
+    pub fn new() -> Self {
+        Network::with_seed(0x6d64_6e73)
+    }
+
+    /// Creates an empty network whose packet loss is drawn from the given seed.
+    pub fn with_seed(seed: u64) -> Self {
+        Network {
+            state: Arc::new(Mutex::new(State {
+                epoch: Instant::now(),
+                elapsed: Duration::from_secs(0),
+                // xorshift gets stuck on zero
+                rng: seed.max(1),
+                links: Vec::new(),
+                hosts: Vec::new(),
+                interfaces: Vec::new(),
+                sockets: Vec::new(),
+                timers: Vec::new(),
+            })),
+            tasks: Arc::new(Mutex::new(Tasks::default())),
+        }
+    }
+
+    /// Adds a link with no latency and no packet loss.
+    pub fn link(&self) -> Link {
+        let mut state = self.state();
+        state.links.push(LinkState {
+            latency: Duration::from_secs(0),
+            packet_loss: 0.0,
+            ttl: 255,
+        });
+
+        Link {
+            network: self.clone(),
+            id: state.links.len() - 1,
+        }
+    }
+
+    /// Adds a host with no interfaces.
+    pub fn host<S>(&self, name: S) -> Host
+    where
+        S: Into<String>,
+    {
+        let mut state = self.state();
+        state.hosts.push(name.into());
+
+        Host {
+            network: self.clone(),
+            id: state.hosts.len() - 1,
+        }
+    }
+
+    /// A runtime whose timers and tasks live in this network.
+    pub fn runtime(&self) -> Arc<dyn Runtime> {
+        Arc::new(SimRuntime {
+            network: self.clone(),
+        })
+    }
+
+    /// The current virtual time.
+    pub fn now(&self) -> Instant {
+        self.state().now()
+    }
+
+    /// Moves the virtual clock forward, waking every timer and socket that
+    /// became ready along the way.
+    pub fn advance(&self, duration: Duration) {
+        let target = self.now() + duration;
+        self.advance_to(target);
+    }
+
+    /// Runs a future to completion, together with every task spawned on
+    /// [`runtime`](Self::runtime).
+    ///
+    /// Whenever all of them are waiting, the clock jumps to the next timer or
+    /// datagram delivery.
+    ///
+    /// # Panics
+    ///
+    /// Panics if every task is waiting and there is nothing left on the clock
+    /// to wake them up.
+    pub fn block_on<F>(&self, future: F) -> F::Output
+    where
+        F: Future,
+    {
+        let flag = Arc::new(WakeFlag(AtomicBool::new(true)));
+        let waker = waker(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+        pin_mut!(future);
+
+        loop {
+            while flag.0.swap(false, Ordering::SeqCst) || self.has_spawned_tasks() {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                    return output;
+                }
+
+                self.poll_tasks(&mut cx);
+            }
+
+            let deadline = self.state().next_deadline().expect(
+                "simulation stalled: every task is waiting, and nothing is scheduled to wake them",
+            );
+            self.advance_to(deadline);
+        }
+    }
+
+    fn advance_to(&self, target: Instant) {
+        let wakers = {
+            let mut state = self.state();
+            if target > state.now() {
+                state.elapsed = target - state.epoch;
+            }
+            state.take_ready_wakers()
+        };
+
+        wakers.into_iter().for_each(Waker::wake);
+    }
+
+    fn has_spawned_tasks(&self) -> bool {
+        !self.tasks.lock().unwrap().spawned.is_empty()
+    }
+
+    fn poll_tasks(&self, cx: &mut Context<'_>) {
+        let running = {
+            let mut tasks = self.tasks.lock().unwrap();
+            let mut running = std::mem::take(&mut tasks.running);
+            running.append(&mut tasks.spawned);
+            running
+        };
+
+        let mut running: Vec<_> = running
+            .into_iter()
+            .filter_map(|mut task| match task.as_mut().poll(cx) {
+                Poll::Ready(()) => None,
+                Poll::Pending => Some(task),
+            })
+            .collect();
+
+        let mut tasks = self.tasks.lock().unwrap();
+        running.append(&mut tasks.running);
+        tasks.running = running;
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl Default for Network {
+    fn default() -> Self {
+        Network::new()
+    }
+}
+
+impl fmt::Debug for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state();
+        f.debug_struct("Network")
+            .field("elapsed", &state.elapsed)
+            .field("links", &state.links.len())
+            .field("hosts", &state.hosts)
+            .finish()
+    }
+}
+
+impl Link {
+    /// Sets how long datagrams take to cross this link.
+    pub fn set_latency(&self, latency: Duration) {
+        self.network.state().links[self.id].latency = latency;
+    }
+
+    /// Sets the probability, between `0.0` and `1.0`, of a datagram being lost
+    /// on its way to each receiver.
+    pub fn set_packet_loss(&self, probability: f64) {
+        self.network.state().links[self.id].packet_loss = probability;
+    }
+
+    /// Sets the IP TTL datagrams arrive with over this link.
+    ///
+    /// Defaults to 255, the TTL mDNS packets are sent with. Anything lower
+    /// makes them look like they were forwarded by a router.
+    pub fn set_ttl(&self, ttl: u8) {
+        self.network.state().links[self.id].ttl = ttl;
+    }
+}
+
+impl Host {
+    /// The name the host was created with.
+    pub fn name(&self) -> String {
+        self.network.state().hosts[self.id].clone()
+    }
+
+    /// Attaches a new interface with the given address to a link.
+    pub fn interface<A>(&self, link: &Link, addr: A) -> Interface
+    where
+        A: Into<IpAddr>,
+    {
+        let mut state = self.network.state();
+        state.interfaces.push(InterfaceState {
+            host: self.id,
+            link: link.id,
+            addr: addr.into(),
+        });
+
+        Interface {
+            network: self.network.clone(),
+            id: state.interfaces.len() - 1,
+        }
+    }
+
+    /// Binds a socket on every interface of this host, like a socket bound to
+    /// the unspecified address.
+    pub fn bind(&self, port: u16) -> Arc<dyn Transport> {
+        self.network.bind(Binding::Host(self.id), port)
+    }
+}
+
+impl Interface {
+    /// The address of this interface.
+    pub fn addr(&self) -> IpAddr {
+        self.network.state().interfaces[self.id].addr
+    }
+
+    /// Binds a socket that only sends and receives on this interface.
+    pub fn bind(&self, port: u16) -> Arc<dyn Transport> {
+        self.network.bind(Binding::Interface(self.id), port)
+    }
+}
+
+impl Network {
+    fn bind(&self, binding: Binding, port: u16) -> Arc<dyn Transport> {
+        let mut state = self.state();
+        state.sockets.push(Some(SocketState {
+            binding,
+            port,
+            inbox: VecDeque::new(),
+            waker: None,
+        }));
+
+        Arc::new(Socket {
+            state: self.state.clone(),
+            id: state.sockets.len() - 1,
+        })
+    }
+}
+
+impl Runtime for SimRuntime {
+    fn udp_socket(&self, _: std::net::UdpSocket) -> io::Result<Arc<dyn Transport>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "a simulated network cannot drive real sockets",
+        ))
+    }
+
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        self.network.tasks.lock().unwrap().spawned.push(future);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let state = self.network.state.clone();
+        let deadline = self.network.now() + duration;
+
+        poll_fn(move |cx| {
+            let mut state = state.lock().unwrap();
+            if state.now() >= deadline {
+                Poll::Ready(())
+            } else {
+                state.timers.push((deadline, cx.waker().clone()));
+                Poll::Pending
+            }
+        })
+        .boxed()
+    }
+
+    fn interval(&self, period: Duration) -> BoxStream<'static, ()> {
+        let runtime = self.clone();
+        stream::unfold(runtime, move |runtime| async move {
+            runtime.sleep(period).await;
+            Some(((), runtime))
+        })
+        .boxed()
+    }
+
+    fn now(&self) -> Instant {
+        self.network.now()
+    }
+}
+
+impl Transport for Socket {
+    fn send_to<'a>(
+        &'a self,
+        buf: &'a [u8],
+        target: SocketAddr,
+    ) -> BoxFuture<'a, io::Result<usize>> {
+        let wakers = self.state.lock().unwrap().route(self.id, buf, target);
+        wakers.into_iter().for_each(Waker::wake);
+
+        ready(Ok(buf.len())).boxed()
+    }
+
+    fn recv_from<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>> {
+        self.recv_with_ttl(buf)
+            .map(|received| received.map(|(count, source, _)| (count, source)))
+            .boxed()
+    }
+
+    fn recv_with_ttl<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<(usize, SocketAddr, Option<u8>)>> {
+        poll_fn(move |cx| {
+            let mut state = self.state.lock().unwrap();
+            let now = state.now();
+            let socket = state.sockets[self.id].as_mut().unwrap();
+
+            match socket.inbox.front() {
+                Some(datagram) if datagram.deliver_at <= now => {
+                    let datagram = socket.inbox.pop_front().unwrap();
+                    let count = datagram.contents.len().min(buf.len());
+                    buf[..count].copy_from_slice(&datagram.contents[..count]);
+                    Poll::Ready(Ok((count, datagram.source, Some(datagram.ttl))))
+                }
+                _ => {
+                    socket.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+impl fmt::Debug for Socket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Socket").field("id", &self.id).finish()
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.sockets[self.id] = None;
+        }
+    }
+}
+
+impl State {
+    fn now(&self) -> Instant {
+        self.epoch + self.elapsed
+    }
+
+    /// The interfaces a socket sends and receives on.
+    fn socket_interfaces(&self, binding: &Binding) -> Vec<usize> {
+        match *binding {
+            Binding::Host(host) => (0..self.interfaces.len())
+                .filter(|&id| self.interfaces[id].host == host)
+                .collect(),
+            Binding::Interface(id) => vec![id],
+        }
+    }
+
+    /// Queues a datagram on every socket it reaches, returning the wakers of
+    /// the receiving sockets.
+    fn route(&mut self, sender: usize, contents: &[u8], target: SocketAddr) -> Vec<Waker> {
+        let (egress, source_port) = {
+            let socket = self.sockets[sender].as_ref().unwrap();
+            (self.socket_interfaces(&socket.binding), socket.port)
+        };
+
+        let mut deliveries = Vec::new();
+        for &out in egress.iter() {
+            let out_iface = &self.interfaces[out];
+            if out_iface.addr.is_ipv4() != target.is_ipv4() {
+                continue;
+            }
+
+            for (socket_id, socket) in self.sockets.iter().enumerate() {
+                let socket = match socket {
+                    Some(socket) if socket_id != sender && socket.port == target.port() => socket,
+                    _ => continue,
+                };
+
+                for inbound in self.socket_interfaces(&socket.binding) {
+                    let in_iface = &self.interfaces[inbound];
+                    let reachable = in_iface.link == out_iface.link
+                        && if target.ip().is_multicast() {
+                            // multicast loopback is disabled on mDNS sockets
+                            in_iface.host != out_iface.host
+                        } else {
+                            in_iface.addr == target.ip()
+                        };
+
+                    if reachable {
+                        let source = SocketAddr::new(out_iface.addr, source_port);
+                        deliveries.push((socket_id, out_iface.link, source));
+                    }
+                }
+            }
+        }
+
+        let now = self.now();
+        let mut wakers = Vec::new();
+        for (socket_id, link, source) in deliveries {
+            let link = &self.links[link];
+            let (latency, packet_loss, ttl) = (link.latency, link.packet_loss, link.ttl);
+            if self.next_random() < packet_loss {
+                continue;
+            }
+
+            let socket = self.sockets[socket_id].as_mut().unwrap();
+            let deliver_at = now + latency;
+            let position = socket
+                .inbox
+                .iter()
+                .position(|datagram| datagram.deliver_at > deliver_at)
+                .unwrap_or(socket.inbox.len());
+            socket.inbox.insert(
+                position,
+                Datagram {
+                    deliver_at,
+                    source,
+                    ttl,
+                    contents: contents.to_vec(),
+                },
+            );
+
+            if latency == Duration::from_secs(0) {
+                wakers.extend(socket.waker.take());
+            }
+        }
+
+        wakers
+    }
+
+    /// Removes and returns the wakers of every expired timer and every socket
+    /// with a datagram waiting.
+    fn take_ready_wakers(&mut self) -> Vec<Waker> {
+        let now = self.now();
+        let mut wakers = Vec::new();
+
+        let timers = std::mem::take(&mut self.timers);
+        for (deadline, waker) in timers {
+            if deadline <= now {
+                wakers.push(waker);
+            } else {
+                self.timers.push((deadline, waker));
+            }
+        }
+
+        for socket in self.sockets.iter_mut().flatten() {
+            if socket.inbox.front().map_or(false, |d| d.deliver_at <= now) {
+                wakers.extend(socket.waker.take());
+            }
+        }
+
+        wakers
+    }
+
+    /// The earliest instant after now at which a timer expires or a datagram
+    /// arrives.
+    fn next_deadline(&self) -> Option<Instant> {
+        let now = self.now();
+        let timers = self.timers.iter().map(|&(deadline, _)| deadline);
+        let datagrams = self
+            .sockets
+            .iter()
+            .flatten()
+            .flat_map(|socket| socket.inbox.iter().map(|datagram| datagram.deliver_at));
+
+        timers.chain(datagrams).filter(|&at| at > now).min()
+    }
+
+    /// A uniformly distributed number in `[0, 1)`.
+    fn next_random(&mut self) -> f64 {
+        // xorshift64*
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let bits = self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (bits >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl ArcWake for WakeFlag {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime;
+    use crate::testing::Lan;
+    use futures_util::{pin_mut, StreamExt};
+    use std::time::Duration;
+
+    #[test]
+    fn multicast_reaches_everyone_but_the_sender() {
+        let lan = Lan::new();
+        let laptop = lan.laptop.bind(5353);
+        let device = lan.device.bind(5353);
+
+        let group = "224.0.0.251:5353".parse().unwrap();
+        lan.network
+            .block_on(laptop.send_to(b"hello", group))
+            .unwrap();
+
+        let mut buffer = [0; 16];
+        let (count, source) = lan.network.block_on(device.recv_from(&mut buffer)).unwrap();
+        assert_eq!(&buffer[..count], b"hello");
+        assert_eq!(source, "192.168.1.10:5353".parse().unwrap());
+
+        let runtime = lan.network.runtime();
+        let echo = runtime::timeout(
+            &*runtime,
+            Duration::from_secs(1),
+            laptop.recv_from(&mut buffer),
+        );
+        assert!(lan.network.block_on(echo).is_err());
+    }
+
+    #[test]
+    fn intervals_tick_on_the_virtual_clock() {
+        let lan = Lan::new();
+        let start = lan.network.now();
+        let ticks = lan.network.runtime().interval(Duration::from_secs(2));
+        pin_mut!(ticks);
+
+        for tick in 1..=3 {
+            lan.network.block_on(ticks.next()).unwrap();
+            assert_eq!(lan.network.now() - start, Duration::from_secs(2 * tick));
+        }
+    }
+}